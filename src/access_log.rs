@@ -0,0 +1,350 @@
+use std::{
+    fs::OpenOptions,
+    io::{self, BufWriter, Write},
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    str::FromStr,
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Context};
+
+use crate::{
+    date::DateTime,
+    request::Request,
+    response::StatusCode,
+    server::{Config, ConnId},
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    // `host - - [date] "request line" status bytes`
+    #[default]
+    Common,
+    // Common Log Format followed by the quoted referer and user agent, and the milliseconds it took
+    // to answer the request
+    Combined,
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "common" => Ok(Self::Common),
+            "combined" => Ok(Self::Combined),
+            _ => Err(anyhow!(
+                "unknown access log format {s:?}, expected common or combined"
+            )),
+        }
+    }
+}
+
+// a request as it ends up in the access log
+#[derive(Debug)]
+pub(crate) struct Entry<'a> {
+    pub(crate) id: ConnId,
+    pub(crate) peer: Option<SocketAddr>,
+    // `None` when the request couldn't be parsed
+    pub(crate) request: Option<&'a Request>,
+    pub(crate) status: StatusCode,
+    pub(crate) bytes_sent: u64,
+    // from the request head being read to the response being sent, `None` when the head never was
+    pub(crate) latency: Option<Duration>,
+}
+
+impl Entry<'_> {
+    // who made the request, going by a trusted proxy's word for it once the request was read
+    fn client(&self) -> Option<IpAddr> {
+        self.request
+            .and_then(Request::client_ip)
+            .or(self.peer.map(|peer| peer.ip()))
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct AccessLog {
+    format: AccessLogFormat,
+    // lines are handed to the writer thread when logging to a file or stdout, and emitted
+    // under the `access` log target otherwise
+    lines: Option<Sender<String>>,
+}
+
+impl AccessLog {
+    pub(crate) fn start(config: &Config) -> anyhow::Result<Self> {
+        let lines = match &config.access_log {
+            Some(path) => {
+                let out: Box<dyn Write + Send> = if path == Path::new("-") {
+                    Box::new(io::stdout())
+                } else {
+                    Box::new(
+                        OpenOptions::new()
+                            .create(true)
+                            .append(true)
+                            .open(path)
+                            .with_context(|| anyhow!("failed to open access log {path:?}"))?,
+                    )
+                };
+
+                // handlers only queue their line, so they never wait on the disk
+                let (sender, receiver) = mpsc::channel();
+                thread::Builder::new()
+                    .name("access-log".to_owned())
+                    .spawn(move || write_lines(&receiver, BufWriter::new(out)))
+                    .context("failed to spawn the access log thread")?;
+                Some(sender)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            format: config.access_log_format,
+            lines,
+        })
+    }
+
+    pub(crate) fn log(&self, entry: &Entry<'_>) {
+        let line = access_log_line(entry, self.format, SystemTime::now());
+        match &self.lines {
+            Some(lines) => {
+                if lines.send(line).is_err() {
+                    log::error!("the access log thread has stopped");
+                }
+            }
+            // access log lines are emitted under their own target, so they can be filtered with `RUST_LOG=access=info`,
+            // along with their fields for `--log-format json`
+            None => {
+                let peer = entry.client().map(|client| client.to_string());
+                let method = entry.request.map(|request| request.line.method.to_string());
+                log::info!(
+                    target: "access",
+                    conn_id = entry.id,
+                    request_id = entry.request.map(Request::id),
+                    peer = peer.as_deref(),
+                    method = method.as_deref(),
+                    path = entry.request.map(|request| request.line.path.as_str()),
+                    status = entry.status.code(),
+                    bytes_sent = entry.bytes_sent,
+                    duration_ms = entry.latency.map(|latency| latency.as_millis());
+                    "{line}"
+                )
+            }
+        }
+    }
+}
+
+// logs a request that was read under the `timing` target, whether or not there's an access log,
+// as `request_id = <id>, GET /echo/hi 200 0.31ms 2`, the last being the bytes sent
+pub(crate) fn log_timing(entry: &Entry<'_>) {
+    let Some(request) = entry.request else {
+        return;
+    };
+    log::info!(
+        target: "timing",
+        request_id = request.id(),
+        method = request.line.method.to_string().as_str(),
+        path = request.line.path.as_str(),
+        status = entry.status.code(),
+        bytes_sent = entry.bytes_sent,
+        duration_ms = entry.latency.map(|latency| latency.as_secs_f64() * 1000.0);
+        "request_id = {}, {}",
+        request.id(),
+        timing_line(request, entry)
+    );
+}
+
+fn timing_line(request: &Request, entry: &Entry<'_>) -> String {
+    let duration = entry.latency.map_or_else(
+        || "-".to_owned(),
+        |latency| format!("{:.2}ms", latency.as_secs_f64() * 1000.0),
+    );
+    format!(
+        "{} {} {} {duration} {}",
+        request.line.method,
+        request.line.path,
+        entry.status.code(),
+        entry.bytes_sent
+    )
+}
+
+// lines are flushed whenever the queue runs dry, so a quiet server doesn't hold any back
+fn write_lines(receiver: &Receiver<String>, mut out: BufWriter<Box<dyn Write + Send>>) {
+    while let Ok(line) = receiver.recv() {
+        let result = writeln!(out, "{line}")
+            .and_then(|()| {
+                receiver
+                    .try_iter()
+                    .try_for_each(|line| writeln!(out, "{line}"))
+            })
+            .and_then(|()| out.flush());
+        if let Err(err) = result {
+            log::error!("failed to write to the access log: {err}");
+        }
+    }
+}
+
+// formats a request in Common Log Format, e.g.
+// `127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET /echo/hi HTTP/1.1" 200 2`,
+// with `"-" "curl/8.0" 3` added to it in Combined Log Format
+fn access_log_line(entry: &Entry<'_>, format: AccessLogFormat, time: SystemTime) -> String {
+    let host = entry
+        .client()
+        .map_or_else(|| "-".to_owned(), |client| client.to_string());
+    let request = entry.request.map_or_else(
+        || "-".to_owned(),
+        |request| {
+            format!(
+                "{} {} {}",
+                request.line.method, request.line.path, request.line.version
+            )
+        },
+    );
+    let bytes = if entry.bytes_sent == 0 {
+        "-".to_owned()
+    } else {
+        entry.bytes_sent.to_string()
+    };
+
+    let date = DateTime::from_system_time(time);
+
+    let mut line = format!(
+        "{host} - - [{:02}/{}/{}:{:02}:{:02}:{:02} +0000] \"{request}\" {} {bytes}",
+        date.day,
+        date.month_name(),
+        date.year,
+        date.hour,
+        date.minute,
+        date.second,
+        entry.status.code()
+    );
+
+    if format == AccessLogFormat::Combined {
+        let header = |name| {
+            entry
+                .request
+                .and_then(|request| request.header(name))
+                .map_or_else(|| "-".to_owned(), escape)
+        };
+        let latency = entry
+            .latency
+            .map_or_else(|| "-".to_owned(), |latency| latency.as_millis().to_string());
+        line.push_str(&format!(
+            " \"{}\" \"{}\" {latency}",
+            header("referer"),
+            header("user-agent")
+        ));
+    }
+
+    line
+}
+
+// header values are quoted in the log, so quotes in them mustn't end the field early
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use regex::Regex;
+
+    use super::*;
+
+    #[test]
+    fn access_log_line_uses_common_log_format() {
+        let request: Request = "GET /echo/hi?x=1 HTTP/1.1\r\n\r\n".parse().unwrap();
+        let entry = Entry {
+            id: 1,
+            peer: "127.0.0.1:54321".parse().ok(),
+            request: Some(&request),
+            status: StatusCode::Ok,
+            bytes_sent: 2,
+            latency: Some(Duration::from_millis(3)),
+        };
+
+        let log_line = access_log_line(&entry, AccessLogFormat::Common, SystemTime::now());
+
+        let clf = Regex::new(
+            r#"^127\.0\.0\.1 - - \[\d{2}/[A-Z][a-z]{2}/\d{4}:\d{2}:\d{2}:\d{2} \+0000\] "GET /echo/hi HTTP/1\.1" 200 2$"#,
+        )
+        .unwrap();
+        assert!(clf.is_match(&log_line), "{log_line}");
+
+        let entry = Entry {
+            id: 1,
+            peer: None,
+            request: None,
+            status: StatusCode::BadRequest,
+            bytes_sent: 0,
+            latency: None,
+        };
+        assert_eq!(
+            access_log_line(
+                &entry,
+                AccessLogFormat::Common,
+                UNIX_EPOCH + Duration::from_secs(971_182_536)
+            ),
+            r#"- - - [10/Oct/2000:12:55:36 +0000] "-" 400 -"#
+        );
+    }
+
+    #[test]
+    fn timing_lines_say_how_long_requests_took() {
+        let request: Request = "POST /files/a.txt?x=1 HTTP/1.1\r\n\r\n".parse().unwrap();
+        let entry = Entry {
+            id: 1,
+            peer: None,
+            request: Some(&request),
+            status: StatusCode::Created,
+            bytes_sent: 0,
+            latency: Some(Duration::from_micros(1_234)),
+        };
+        assert_eq!(
+            timing_line(&request, &entry),
+            "POST /files/a.txt 201 1.23ms 0"
+        );
+        let entry = Entry {
+            latency: None,
+            ..entry
+        };
+        assert_eq!(timing_line(&request, &entry), "POST /files/a.txt 201 - 0");
+    }
+
+    #[test]
+    fn access_log_line_uses_combined_log_format() {
+        let request: Request =
+            "GET /echo/hi HTTP/1.1\r\nUser-Agent: say \"hi\"\r\nReferer: http://localhost/\r\n\r\n"
+                .parse()
+                .unwrap();
+        let entry = Entry {
+            id: 1,
+            peer: "127.0.0.1:54321".parse().ok(),
+            request: Some(&request),
+            status: StatusCode::Ok,
+            bytes_sent: 2,
+            latency: Some(Duration::from_micros(3_900)),
+        };
+
+        assert_eq!(
+            access_log_line(
+                &entry,
+                AccessLogFormat::Combined,
+                UNIX_EPOCH + Duration::from_secs(971_182_536)
+            ),
+            r#"127.0.0.1 - - [10/Oct/2000:12:55:36 +0000] "GET /echo/hi HTTP/1.1" 200 2 "http://localhost/" "say \"hi\"" 3"#
+        );
+
+        let entry = Entry {
+            request: None,
+            latency: None,
+            ..entry
+        };
+        assert!(
+            access_log_line(&entry, AccessLogFormat::Combined, SystemTime::now())
+                .ends_with(r#""-" 200 2 "-" "-" -"#)
+        );
+    }
+}
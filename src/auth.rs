@@ -0,0 +1,356 @@
+use std::{collections::HashMap, fmt, fs, num::NonZeroU32, path::Path, str::FromStr, sync::Arc};
+
+use anyhow::{anyhow, Context};
+use ring::{digest, pbkdf2};
+
+use crate::{
+    base64,
+    middleware::Middleware,
+    request::{decode_path, Method, Request},
+    response::{Response, StatusCode},
+};
+
+const REALM: &str = "butler";
+
+// asks for a user name and password from an htpasswd-style file before letting requests under a
+// path prefix through; add it to `Config::basic_auth`
+#[derive(Clone)]
+pub struct BasicAuth {
+    // without a trailing '/', empty to cover every path
+    prefix: String,
+    users: Arc<HashMap<String, PasswordHash>>,
+}
+
+enum PasswordHash {
+    // `{SHA}` followed by the base64 SHA-1 of the password, as written by `htpasswd -s`
+    Sha1(Vec<u8>),
+    // `$pbkdf2-sha256$<iterations>$<salt>$<hash>`, as written by passlib's `pbkdf2_sha256`
+    Pbkdf2Sha256 {
+        iterations: NonZeroU32,
+        salt: Vec<u8>,
+        hash: Vec<u8>,
+    },
+}
+
+impl BasicAuth {
+    // `prefix` is a path such as `/files/`, requests for it and anything below it need credentials;
+    // every line of the file is `user:hash`, blank lines and ones starting with '#' are skipped
+    pub fn from_htpasswd_file(prefix: &str, path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)
+            .with_context(|| anyhow!("failed to read credentials file {path:?}"))?;
+
+        let mut users = HashMap::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (user, hash) = line
+                .split_once(':')
+                .with_context(|| anyhow!("line {} of {path:?} isn't `user:hash`", i + 1))?;
+            let hash = hash
+                .parse()
+                .with_context(|| anyhow!("invalid hash for {user:?} in {path:?}"))?;
+            users.insert(user.to_owned(), hash);
+        }
+
+        Ok(Self {
+            prefix: prefix.trim_end_matches('/').to_owned(),
+            users: Arc::new(users),
+        })
+    }
+
+    fn covers(&self, path: &str) -> bool {
+        covers(&self.prefix, path)
+    }
+
+    // whether the request's `Authorization` header names a known user with the right password
+    fn authenticate(&self, request: &Request) -> Option<()> {
+        let (scheme, credentials) = request.header("authorization")?.split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+
+        let credentials = String::from_utf8(base64::decode(credentials.trim())?).ok()?;
+        let (user, password) = credentials.split_once(':')?;
+        self.users.get(user)?.verify(password).then_some(())
+    }
+}
+
+impl Middleware for BasicAuth {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        // paths are matched the way the router sees them, so encoding one doesn't get around this;
+        // one that can't be decoded is answered with a 400 anyway, as long as it doesn't get through
+        let covered = decode_path(&request.line.path).map_or(true, |path| self.covers(&path));
+        if !covered || self.authenticate(request).is_some() {
+            return None;
+        }
+
+        if request.header("authorization").is_some() {
+            log::warn!(
+                "request_id = {}, rejected credentials for {}",
+                request.id,
+                request.line.path
+            );
+        }
+        Some(Response::new(StatusCode::Unauthorized).with_header(
+            "WWW-Authenticate",
+            &format!("Basic realm=\"{REALM}\", charset=\"UTF-8\""),
+        ))
+    }
+}
+
+impl fmt::Debug for BasicAuth {
+    // the hashes stay out of logs
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BasicAuth")
+            .field("prefix", &self.prefix)
+            .field("users", &self.users.len())
+            .finish()
+    }
+}
+
+// turns away uploads, replacements and deletions under `/files/` that don't carry one of
+// `Config::write_tokens` in an `Authorization: Bearer` header, reads stay public
+pub(crate) struct WriteTokens(pub(crate) Vec<String>);
+
+impl Middleware for WriteTokens {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        if !is_write(request.line.method) {
+            return None;
+        }
+        let covered = decode_path(&request.line.path)
+            .map_or(true, |path| path == "/files" || path.starts_with("/files/"));
+        if !covered {
+            return None;
+        }
+
+        let token = request
+            .header("authorization")
+            .and_then(|value| value.split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+            .map(|(_, token)| token.trim());
+        // every token is compared, so the time taken doesn't tell which one came closest
+        let valid = token.is_some_and(|token| {
+            self.0.iter().fold(false, |valid, known| {
+                constant_time_eq(token.as_bytes(), known.as_bytes()) | valid
+            })
+        });
+        if valid {
+            return None;
+        }
+
+        log::warn!(
+            "request_id = {}, {} {} without a valid write token",
+            request.id,
+            request.line.method,
+            request.line.path
+        );
+        Some(Response::forbidden())
+    }
+}
+
+impl fmt::Debug for WriteTokens {
+    // the tokens stay out of logs
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("WriteTokens").field(&self.0.len()).finish()
+    }
+}
+
+// turns away requests under a path prefix that didn't come over a connection whose client
+// presented a verified certificate, see `TlsConfig::from_pem_files_with_client_auth`; add it to
+// `Config::client_cert_paths`, e.g. `ClientCertRule::prefix("/files/").writes_only()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientCertRule {
+    // without a trailing '/', empty to cover every path
+    prefix: String,
+    // only uploads, replacements and deletions need a certificate, reads stay open
+    writes_only: bool,
+}
+
+impl ClientCertRule {
+    pub fn prefix(prefix: &str) -> Self {
+        Self {
+            prefix: prefix.trim_end_matches('/').to_owned(),
+            writes_only: false,
+        }
+    }
+
+    pub fn writes_only(mut self) -> Self {
+        self.writes_only = true;
+        self
+    }
+
+    fn covers(&self, request: &Request) -> bool {
+        if self.writes_only && !is_write(request.line.method) {
+            return false;
+        }
+        decode_path(&request.line.path).map_or(true, |path| covers(&self.prefix, &path))
+    }
+}
+
+// `PREFIX`, or `PREFIX=writes` to only cover uploads, replacements and deletions under it, e.g.
+// `/admin/` or `/files/=writes`
+impl FromStr for ClientCertRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (prefix, writes_only) = match s.split_once('=') {
+            Some((prefix, "writes")) => (prefix.trim(), true),
+            Some((_, scope)) => {
+                return Err(anyhow!("{scope:?} is not a scope such as writes"));
+            }
+            None => (s.trim(), false),
+        };
+        if !prefix.starts_with('/') {
+            return Err(anyhow!("{prefix:?} has to be a path starting with '/'"));
+        }
+        let rule = Self::prefix(prefix);
+        Ok(match writes_only {
+            true => rule.writes_only(),
+            false => rule,
+        })
+    }
+}
+
+// applies `Config::client_cert_paths`
+#[derive(Debug)]
+pub(crate) struct ClientCerts(pub(crate) Vec<ClientCertRule>);
+
+impl Middleware for ClientCerts {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        if request.client_cert.is_some() || !self.0.iter().any(|rule| rule.covers(request)) {
+            return None;
+        }
+
+        log::warn!(
+            "request_id = {}, {} {} without a client certificate",
+            request.id,
+            request.line.method,
+            request.line.path
+        );
+        Some(Response::forbidden())
+    }
+}
+
+fn is_write(method: Method) -> bool {
+    matches!(
+        method,
+        Method::Post | Method::Put | Method::Delete | Method::Patch
+    )
+}
+
+// whether `path` is `prefix` or below it, where a `prefix` without a trailing '/' is expected
+pub(crate) fn covers(prefix: &str, path: &str) -> bool {
+    path == prefix
+        || path
+            .strip_prefix(prefix)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+impl PasswordHash {
+    fn verify(&self, password: &str) -> bool {
+        match self {
+            Self::Sha1(hash) => {
+                let digest = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, password.as_bytes());
+                constant_time_eq(digest.as_ref(), hash)
+            }
+            Self::Pbkdf2Sha256 {
+                iterations,
+                salt,
+                hash,
+            } => pbkdf2::verify(
+                pbkdf2::PBKDF2_HMAC_SHA256,
+                *iterations,
+                salt,
+                password.as_bytes(),
+                hash,
+            )
+            .is_ok(),
+        }
+    }
+}
+
+impl FromStr for PasswordHash {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(hash) = s.strip_prefix("{SHA}") {
+            let hash = base64::decode(hash).context("hash isn't valid base64")?;
+            return Ok(Self::Sha1(hash));
+        }
+
+        if let Some(rest) = s.strip_prefix("$pbkdf2-sha256$") {
+            let mut parts = rest.split('$');
+            let (Some(iterations), Some(salt), Some(hash), None) =
+                (parts.next(), parts.next(), parts.next(), parts.next())
+            else {
+                return Err(anyhow!(
+                    "expected `$pbkdf2-sha256$<iterations>$<salt>$<hash>`"
+                ));
+            };
+            return Ok(Self::Pbkdf2Sha256 {
+                iterations: iterations
+                    .parse()
+                    .with_context(|| anyhow!("{iterations:?} isn't a number of iterations"))?,
+                salt: base64::decode(salt).context("salt isn't valid base64")?,
+                hash: base64::decode(hash).context("hash isn't valid base64")?,
+            });
+        }
+
+        let kind = if s.starts_with("$2") {
+            "bcrypt"
+        } else if s.starts_with("$apr1$") {
+            "MD5"
+        } else {
+            "crypt or plain text"
+        };
+        Err(anyhow!(
+            "{kind} hashes aren't supported, use `htpasswd -s` or a `$pbkdf2-sha256$` hash"
+        ))
+    }
+}
+
+// compares the whole of both, so the time taken doesn't tell how much of a guess was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn password_hashes_verify_the_right_password() {
+        let sha1: PasswordHash = "{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=".parse().unwrap();
+        assert!(sha1.verify("secret"));
+        assert!(!sha1.verify("secret2"));
+
+        let pbkdf2: PasswordHash =
+            "$pbkdf2-sha256$1000$c2FsdHNhbHRzYWx0MTIzNA$fIKZdZ7B5XJPx7My3DohkKloWvdeNcMNXs3u1sIBy.Q"
+                .parse()
+                .unwrap();
+        assert!(pbkdf2.verify("hunter2"));
+        assert!(!pbkdf2.verify("hunter3"));
+
+        assert!("$2y$05$abcdefghijklmnopqrstuv"
+            .parse::<PasswordHash>()
+            .is_err());
+        assert!("plain".parse::<PasswordHash>().is_err());
+    }
+
+    #[test]
+    fn basic_auth_covers_paths_under_its_prefix() {
+        let auth = BasicAuth {
+            prefix: "/files".to_owned(),
+            users: Arc::default(),
+        };
+
+        assert!(auth.covers("/files"));
+        assert!(auth.covers("/files/a.txt"));
+        assert!(!auth.covers("/filesystem"));
+        assert!(!auth.covers("/"));
+    }
+}
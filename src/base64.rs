@@ -0,0 +1,76 @@
+// the standard base64 alphabet, used by `Authorization: Basic`, htpasswd's `{SHA}` hashes and
+// `Sec-WebSocket-Accept`
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// decodes base64 with or without padding; `.` is accepted in place of `+`, as in the
+// "adapted" alphabet passlib writes its hashes in
+pub(crate) fn decode(s: &str) -> Option<Vec<u8>> {
+    let s = s.trim_end_matches('=');
+    if s.len() % 4 == 1 {
+        return None;
+    }
+
+    let mut bytes = Vec::with_capacity(s.len() * 3 / 4);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in s.bytes() {
+        let value = match c {
+            b'.' => 62,
+            c => ALPHABET.iter().position(|&a| a == c)? as u32,
+        };
+        buffer = buffer << 6 | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+
+    Some(bytes)
+}
+
+// encodes with the standard alphabet and padding
+pub(crate) fn encode(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let buffer = chunk.iter().enumerate().fold(0u32, |buffer, (i, &byte)| {
+            buffer | u32::from(byte) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                s.push(ALPHABET[(buffer >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                s.push('=');
+            }
+        }
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_handles_padding_and_the_adapted_alphabet() {
+        assert_eq!(decode("dXNlcjpwYXNz").unwrap(), b"user:pass");
+        assert_eq!(decode("YQ==").unwrap(), b"a");
+        assert_eq!(decode("YWI").unwrap(), b"ab");
+        assert_eq!(decode("").unwrap(), b"");
+        assert_eq!(decode("+/8=").unwrap(), decode("./8").unwrap());
+        assert_eq!(decode("Y"), None);
+        assert_eq!(decode("Y!=="), None);
+    }
+
+    #[test]
+    fn encode_pads_to_whole_groups() {
+        assert_eq!(encode(b"user:pass"), "dXNlcjpwYXNz");
+        assert_eq!(encode(b"a"), "YQ==");
+        assert_eq!(encode(b"ab"), "YWI=");
+        assert_eq!(encode(b""), "");
+        assert_eq!(
+            decode(&encode(&[0xfb, 0xff, 0x00])).unwrap(),
+            [0xfb, 0xff, 0x00]
+        );
+    }
+}
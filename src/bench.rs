@@ -0,0 +1,236 @@
+use std::{
+    collections::BTreeMap,
+    fmt, thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context};
+
+use crate::parse_number;
+
+const DEFAULT_CONCURRENCY: usize = 10;
+const DEFAULT_DURATION: Duration = Duration::from_secs(10);
+const BENCH_USAGE: &str = "\
+usage: butler bench [options] <URL>
+
+sends GET requests for an http:// URL from several connections at once, then reports how many were
+answered and how long that took
+
+options:
+  --concurrency <N>            requests in flight at once, each on a connection of its own [default: 10]
+  --duration <SECS>            seconds to keep sending requests for [default: 10]
+  -h, --help                   print this message
+";
+
+#[derive(Debug)]
+struct BenchArgs {
+    url: Option<String>,
+    concurrency: usize,
+    duration: Duration,
+    help: bool,
+}
+
+impl BenchArgs {
+    fn parse(mut args: impl Iterator<Item = String>) -> anyhow::Result<Self> {
+        let mut parsed = Self {
+            url: None,
+            concurrency: DEFAULT_CONCURRENCY,
+            duration: DEFAULT_DURATION,
+            help: false,
+        };
+
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .with_context(|| anyhow!("missing value for argument {arg:?}"))
+            };
+            match arg.as_str() {
+                "--help" | "-h" => parsed.help = true,
+                "--concurrency" => {
+                    parsed.concurrency = parse_number(&value()?)?;
+                    if parsed.concurrency == 0 {
+                        return Err(anyhow!("--concurrency has to be at least 1"));
+                    }
+                }
+                "--duration" => parsed.duration = Duration::from_secs(parse_number(&value()?)?),
+                _ if arg.starts_with('-') => {
+                    return Err(anyhow!("unknown argument {arg:?}, see butler bench --help"))
+                }
+                _ if parsed.url.is_some() => {
+                    return Err(anyhow!("only one URL can be benchmarked at a time"))
+                }
+                _ => parsed.url = Some(arg),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+// `butler bench`, run with the arguments that follow it
+pub(crate) fn run(args: impl Iterator<Item = String>) -> anyhow::Result<()> {
+    let args = BenchArgs::parse(args).context("failed to parse arguments")?;
+    if args.help {
+        print!("{BENCH_USAGE}");
+        return Ok(());
+    }
+    let url = args
+        .url
+        .context("missing the URL to benchmark, see butler bench --help")?;
+    // a URL the client can't make a request for would only produce errors
+    butler::client::get(&url).with_context(|| anyhow!("failed to get {url}"))?;
+
+    println!(
+        "sending requests for {url} from {} connections for {:?}",
+        args.concurrency, args.duration
+    );
+    let started = Instant::now();
+    let deadline = started + args.duration;
+    let workers: Vec<_> = (0..args.concurrency)
+        .map(|_| {
+            let url = url.clone();
+            thread::spawn(move || {
+                let mut report = Report::default();
+                while Instant::now() < deadline {
+                    let sent = Instant::now();
+                    match butler::client::get(&url) {
+                        Ok(response) => {
+                            report.latencies.push(sent.elapsed());
+                            *report.statuses.entry(response.status().code()).or_default() += 1;
+                        }
+                        Err(err) => {
+                            log::debug!("request for {url} failed: {err:#}");
+                            report.errors += 1;
+                        }
+                    }
+                }
+                report
+            })
+        })
+        .collect();
+
+    let mut report = Report::default();
+    for worker in workers {
+        let finished = worker
+            .join()
+            .map_err(|_| anyhow!("a benchmark thread panicked"))?;
+        report.merge(finished);
+    }
+    report.elapsed = started.elapsed();
+    print!("{report}");
+    Ok(())
+}
+
+// what a benchmark found out
+#[derive(Debug, Default)]
+struct Report {
+    // of every request that was answered
+    latencies: Vec<Duration>,
+    // answered requests by the status code they got
+    statuses: BTreeMap<u16, usize>,
+    // requests that failed before they were answered
+    errors: usize,
+    elapsed: Duration,
+}
+
+impl Report {
+    fn merge(&mut self, other: Report) {
+        self.latencies.extend(other.latencies);
+        for (status, count) in other.statuses {
+            *self.statuses.entry(status).or_default() += count;
+        }
+        self.errors += other.errors;
+    }
+
+    // the latency `percent` of the answered requests took at most, `None` when none were
+    fn percentile(&self, percent: f64) -> Option<Duration> {
+        let mut sorted = self.latencies.clone();
+        sorted.sort_unstable();
+        let rank = (sorted.len() as f64 * percent / 100.0).ceil() as usize;
+        sorted.get(rank.saturating_sub(1)).copied()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let answered = self.latencies.len();
+        writeln!(
+            f,
+            "{answered} requests answered in {:.2}s, {:.1} requests/s, {} errors",
+            self.elapsed.as_secs_f64(),
+            answered as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON),
+            self.errors
+        )?;
+        if !self.statuses.is_empty() {
+            let statuses: Vec<_> = self
+                .statuses
+                .iter()
+                .map(|(status, count)| format!("{status}: {count}"))
+                .collect();
+            writeln!(f, "statuses: {}", statuses.join(", "))?;
+        }
+
+        let millis = |percent| {
+            self.percentile(percent)
+                .map_or("-".to_owned(), |latency: Duration| {
+                    format!("{:.2}ms", latency.as_secs_f64() * 1000.0)
+                })
+        };
+        writeln!(
+            f,
+            "latency: p50 {}, p90 {}, p99 {}, max {}",
+            millis(50.0),
+            millis(90.0),
+            millis(99.0),
+            millis(100.0)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bench_reports_latency_percentiles() {
+        let args = BenchArgs::parse(
+            [
+                "--concurrency",
+                "4",
+                "http://127.0.0.1:4221/",
+                "--duration",
+                "2",
+            ]
+            .into_iter()
+            .map(str::to_owned),
+        )
+        .unwrap();
+        assert_eq!(args.url.as_deref(), Some("http://127.0.0.1:4221/"));
+        assert_eq!(
+            (args.concurrency, args.duration),
+            (4, Duration::from_secs(2))
+        );
+        assert!(BenchArgs::parse(["--concurrency", "0"].into_iter().map(str::to_owned)).is_err());
+
+        let mut report = Report {
+            latencies: (1..=100).map(Duration::from_millis).collect(),
+            statuses: BTreeMap::from([(200, 99), (503, 1)]),
+            errors: 0,
+            elapsed: Duration::from_secs(2),
+        };
+        assert_eq!(report.percentile(50.0), Some(Duration::from_millis(50)));
+        assert_eq!(report.percentile(99.0), Some(Duration::from_millis(99)));
+        assert_eq!(report.percentile(100.0), Some(Duration::from_millis(100)));
+
+        report.merge(Report {
+            errors: 3,
+            ..Report::default()
+        });
+        assert_eq!(
+            report.to_string(),
+            "100 requests answered in 2.00s, 50.0 requests/s, 3 errors\n\
+             statuses: 200: 99, 503: 1\n\
+             latency: p50 50.00ms, p90 90.00ms, p99 99.00ms, max 100.00ms\n"
+        );
+        assert_eq!(Report::default().percentile(50.0), None);
+    }
+}
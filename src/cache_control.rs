@@ -0,0 +1,139 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context};
+
+use crate::header::ContentType;
+
+// a `Cache-Control` value for the files under `/files/` a pattern matches, e.g.
+// `CacheRule::for_path("/files/assets/*", "public, max-age=31536000, immutable")`; add it to
+// `Config::cache_control`, where the first rule that matches a file is the one it's served with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheRule {
+    pattern: Pattern,
+    value: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Pattern {
+    // a decoded request path, where `*` stands for any run of characters
+    Path(String),
+    // a type such as `text/html`, or every one of its kind with `image/*`
+    ContentType(String),
+}
+
+impl CacheRule {
+    pub fn for_path(pattern: &str, value: &str) -> Self {
+        Self::new(Pattern::Path(pattern.to_owned()), value)
+    }
+
+    pub fn for_content_type(pattern: &str, value: &str) -> Self {
+        Self::new(Pattern::ContentType(pattern.trim().to_lowercase()), value)
+    }
+
+    fn new(pattern: Pattern, value: &str) -> Self {
+        assert!(
+            !value.contains(['\r', '\n']),
+            "Cache-Control value {value:?} would corrupt the response"
+        );
+        Self {
+            pattern,
+            value: value.trim().to_owned(),
+        }
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    // whether the file served for `path` with `content_type` gets this rule's value
+    pub(crate) fn matches(&self, path: &str, content_type: &ContentType) -> bool {
+        match &self.pattern {
+            Pattern::Path(pattern) => glob_matches(pattern, path),
+            Pattern::ContentType(pattern) => {
+                let essence = content_type.essence();
+                match pattern.strip_suffix("/*") {
+                    Some(kind) => essence
+                        .strip_prefix(kind)
+                        .is_some_and(|rest| rest.starts_with('/')),
+                    None => essence == *pattern,
+                }
+            }
+        }
+    }
+}
+
+// `PATTERN=VALUE`, where patterns starting with '/' are paths and anything else a type, e.g.
+// `text/html=no-cache`; the value is everything after the first '='
+impl FromStr for CacheRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, value) = s.split_once('=').with_context(|| {
+            anyhow!("{s:?} is not a rule such as /files/assets/*=max-age=31536000")
+        })?;
+        let (pattern, value) = (pattern.trim(), value.trim());
+        if pattern.is_empty() || value.is_empty() || value.contains(['\r', '\n']) {
+            return Err(anyhow!("{s:?} is not a valid Cache-Control rule"));
+        }
+
+        Ok(if pattern.starts_with('/') {
+            Self::for_path(pattern, value)
+        } else {
+            Self::for_content_type(pattern, value)
+        })
+    }
+}
+
+// whether `s` is `pattern` with each `*` in it standing for any run of characters
+fn glob_matches(pattern: &str, s: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = s.strip_prefix(first) else {
+        return false;
+    };
+
+    let mut parts: Vec<_> = parts.collect();
+    // without a `*` the whole of `s` has to match
+    let Some(last) = parts.pop() else {
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_rules_match_paths_and_types() {
+        let assets: CacheRule = "/files/assets/*=public, max-age=31536000, immutable"
+            .parse()
+            .unwrap();
+        assert_eq!(assets.value(), "public, max-age=31536000, immutable");
+        let text = ContentType::TextPlain;
+        assert!(assets.matches("/files/assets/app.js", &text));
+        assert!(assets.matches("/files/assets/", &text));
+        assert!(!assets.matches("/files/assets", &text));
+        assert!(!assets.matches("/files/other/assets/app.js", &text));
+
+        let maps = CacheRule::for_path("/files/*.map", "no-store");
+        assert!(maps.matches("/files/assets/app.js.map", &text));
+        assert!(!maps.matches("/files/assets/app.js", &text));
+
+        let html: CacheRule = "text/html=no-cache".parse().unwrap();
+        assert!(html.matches("/files/index.html", &ContentType::TextHtml));
+        assert!(!html.matches("/files/index.html", &text));
+        let images = CacheRule::for_content_type("image/*", "max-age=86400");
+        assert!(images.matches("/files/cat.png", &ContentType::ImagePng));
+        assert!(!images.matches("/files/cat.png", &text));
+
+        assert!("no-pattern".parse::<CacheRule>().is_err());
+        assert!("/files/=".parse::<CacheRule>().is_err());
+    }
+}
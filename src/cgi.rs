@@ -0,0 +1,381 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+    process::{Child, ChildStdout, Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context};
+
+use crate::{
+    header::{is_field_value, Header},
+    request::Request,
+    response::{Body, Response, StatusCode, SERVER_SOFTWARE},
+    router::{host_name, Handler},
+};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+// how often a running script is checked on
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+// largest header block accepted from a script
+const MAX_HEAD_SIZE: u64 = 64 * 1024;
+// request headers a script isn't passed: credentials meant for butler stay with it, and a client
+// could use `Proxy` to set `HTTP_PROXY`, which many libraries mistake for their own proxy settings
+const WITHHELD_HEADERS: [&str; 4] = ["authorization", "proxy", "content-length", "content-type"];
+
+// runs the executables in a directory for the requests under a path prefix, as CGI/1.1 scripts;
+// set it as `Config::cgi`, or register it for routes of your own as a `Handler`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cgi {
+    // without a trailing '/'
+    prefix: String,
+    directory: PathBuf,
+    // scripts still running after this long are killed
+    timeout: Duration,
+}
+
+impl Cgi {
+    // `/cgi-bin/hello/extra?x=1` runs `hello` from `directory`, with `/extra` as its `PATH_INFO`
+    pub fn new(prefix: &str, directory: impl Into<PathBuf>) -> Self {
+        Self {
+            prefix: prefix.trim_end_matches('/').to_owned(),
+            directory: directory.into(),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub(crate) fn pattern(&self) -> String {
+        format!("{}/{{*path}}", self.prefix)
+    }
+
+    fn run(&self, request: &Request) -> anyhow::Result<Response> {
+        let path = request.param("path").unwrap_or_default();
+        let (name, path_info) = match path.split_once('/') {
+            Some((name, rest)) => (name, format!("/{rest}")),
+            None => (path, String::new()),
+        };
+        // scripts are only ever run from the directory itself
+        if name.is_empty() || name == "." || name == ".." || name.contains('\\') {
+            return Ok(Response::not_found());
+        }
+        let script = self.directory.join(name);
+        match fs::metadata(&script) {
+            Ok(metadata) if metadata.is_file() && is_executable(&metadata) => {}
+            Ok(metadata) if metadata.is_file() => {
+                log::warn!("request_id = {}, {script:?} isn't executable", request.id);
+                return Ok(Response::forbidden());
+            }
+            _ => return Ok(Response::not_found()),
+        }
+
+        // a spooled body is streamed from its file instead of being read into memory; a body that
+        // can't be read is butler's failure, not the script's
+        let body = match request.spooled_body_path() {
+            Some(path) => File::open(path).map(|file| Box::new(file) as Box<dyn Read + Send>),
+            None => request.body().map(|body| {
+                Box::new(io::Cursor::new(body.unwrap_or_default().to_vec())) as Box<dyn Read + Send>
+            }),
+        };
+        let mut body = match body {
+            Ok(body) => body,
+            Err(err) => {
+                log::error!("request_id = {}, failed to read body: {err}", request.id);
+                return Ok(Response::internal_server_error());
+            }
+        };
+
+        let mut child = self
+            .command(&script, name, &path_info, request)
+            .spawn()
+            .with_context(|| anyhow!("failed to run {script:?}"))?;
+
+        // written from a thread of its own, so a script that answers before it has read all of
+        // its input can't leave both sides waiting on each other
+        let mut stdin = child.stdin.take().context("script has no stdin")?;
+        thread::spawn(move || {
+            // a script doesn't have to read its input
+            let _ = io::copy(&mut body, &mut stdin);
+        });
+
+        let stderr = child.stderr.take().context("script has no stderr")?;
+        let id = request.id.clone();
+        thread::spawn(move || {
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                log::warn!("request_id = {id}, script says: {line}");
+            }
+        });
+
+        let mut stdout = BufReader::new(child.stdout.take().context("script has no stdout")?);
+        let timed_out = Arc::new(AtomicBool::new(false));
+        self.watch(child, request, Arc::clone(&timed_out));
+
+        let head = match read_head(&mut stdout) {
+            Ok(head) => head,
+            Err(_) if timed_out.load(Ordering::SeqCst) => {
+                return Ok(Response::new(StatusCode::GatewayTimeout))
+            }
+            Err(err) => return Err(err),
+        };
+        let mut response = parse_head(head)?;
+
+        // a script that outlives its timeout is killed, cutting its output short; its length is
+        // never passed on for that reason
+        if !matches!(
+            response.status,
+            StatusCode::NoContent | StatusCode::NotModified
+        ) {
+            response.body = Some(Body::Stream(Box::new(stdout)));
+        }
+        Ok(response)
+    }
+
+    fn command(&self, script: &Path, name: &str, path_info: &str, request: &Request) -> Command {
+        let mut command = Command::new(script);
+        command
+            .current_dir(&self.directory)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // scripts only see what RFC 3875 says they should, not the server's own environment
+            .env_clear()
+            .env(
+                "PATH",
+                std::env::var_os("PATH").unwrap_or_else(|| "/usr/bin:/bin".into()),
+            )
+            .env("GATEWAY_INTERFACE", "CGI/1.1")
+            .env("SERVER_SOFTWARE", SERVER_SOFTWARE)
+            .env("SERVER_PROTOCOL", request.version().to_string())
+            .env("REQUEST_METHOD", request.method().to_string())
+            .env("SCRIPT_NAME", format!("{}/{name}", self.prefix))
+            .env("PATH_INFO", path_info)
+            .env(
+                "QUERY_STRING",
+                request.line.raw_query.as_deref().unwrap_or_default(),
+            )
+            .env("REQUEST_ID", &request.id);
+
+        if let Some(host) = request.host() {
+            command.env("SERVER_NAME", host_name(host));
+            if let Some((_, port)) = host
+                .rsplit_once(':')
+                .filter(|(_, port)| !port.contains(']'))
+            {
+                command.env("SERVER_PORT", port);
+            }
+        }
+        if let Some(peer) = request.peer_addr() {
+            command
+                .env("REMOTE_ADDR", peer.ip().to_canonical().to_string())
+                .env("REMOTE_PORT", peer.port().to_string());
+        }
+        if let Some(len) = request.body_len() {
+            command.env("CONTENT_LENGTH", len.to_string());
+        }
+        if let Some(content_type) = request.header("content-type") {
+            command.env("CONTENT_TYPE", content_type);
+        }
+
+        let mut names: Vec<String> = request
+            .raw_headers()
+            .iter()
+            .map(|(name, _)| name.to_lowercase())
+            .collect();
+        names.sort_unstable();
+        names.dedup();
+        for name in names {
+            if WITHHELD_HEADERS.contains(&name.as_str()) {
+                continue;
+            }
+            // `X_Foo` would share `HTTP_X_FOO` with `X-Foo`, letting one stand in for the other
+            if name.contains('_') {
+                log::warn!(
+                    "request_id = {}, not passing on {name:?}, its name has an underscore",
+                    request.id
+                );
+                continue;
+            }
+            let value = request
+                .raw_headers()
+                .get_all(&name)
+                .collect::<Vec<_>>()
+                .join(", ");
+            // as for the head `Proxy` sends upstream
+            if !is_field_value(&value) {
+                log::warn!(
+                    "request_id = {}, not passing on {name:?}, its value has a control character",
+                    request.id
+                );
+                continue;
+            }
+            command.env(
+                format!("HTTP_{}", name.to_uppercase().replace('-', "_")),
+                value,
+            );
+        }
+
+        command
+    }
+
+    // kills the script once it has run for longer than the timeout, and reaps it either way
+    fn watch(&self, mut child: Child, request: &Request, timed_out: Arc<AtomicBool>) {
+        let deadline = Instant::now() + self.timeout;
+        let id = request.id.clone();
+        thread::spawn(move || loop {
+            match child.try_wait() {
+                Ok(Some(status)) => {
+                    if !status.success() {
+                        log::warn!("request_id = {id}, script exited with {status}");
+                    }
+                    return;
+                }
+                Ok(None) if Instant::now() >= deadline => {
+                    log::warn!("request_id = {id}, script timed out, killing it");
+                    timed_out.store(true, Ordering::SeqCst);
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return;
+                }
+                Ok(None) => thread::sleep(POLL_INTERVAL),
+                Err(err) => {
+                    log::error!("request_id = {id}, failed to wait for script: {err}");
+                    return;
+                }
+            }
+        });
+    }
+}
+
+impl Handler for Cgi {
+    fn handle(&self, request: &Request) -> anyhow::Result<Response> {
+        match self.run(request) {
+            Ok(response) => Ok(response),
+            Err(err) => {
+                log::error!(
+                    "request_id = {}, CGI script for {} failed: {err:#}",
+                    request.id,
+                    request.path()
+                );
+                Ok(Response::new(StatusCode::BadGateway))
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_metadata: &fs::Metadata) -> bool {
+    true
+}
+
+// the header lines a script writes ahead of its body
+fn read_head(stdout: &mut BufReader<ChildStdout>) -> anyhow::Result<Vec<String>> {
+    let mut head = Vec::new();
+    let mut limited = stdout.take(MAX_HEAD_SIZE);
+    loop {
+        let mut line = String::new();
+        limited
+            .read_line(&mut line)
+            .context("failed to read script output")?;
+        if !line.ends_with('\n') {
+            return Err(anyhow!("script output ended before its headers did"));
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            return Ok(head);
+        }
+        head.push(line.to_owned());
+    }
+}
+
+// `Status` sets the status, and a `Location` without one makes it a redirect
+fn parse_head(head: Vec<String>) -> anyhow::Result<Response> {
+    let mut status = None;
+    let mut redirect = false;
+    let mut headers = Vec::new();
+    for line in head {
+        let (name, value) = line
+            .split_once(':')
+            .with_context(|| anyhow!("script sent an invalid header {line:?}"))?;
+        let (name, value) = (name.trim(), value.trim());
+        match name.to_lowercase().as_str() {
+            "status" => {
+                let code = value
+                    .split_whitespace()
+                    .next()
+                    .and_then(|code| code.parse().ok())
+                    .and_then(StatusCode::from_code)
+                    .with_context(|| anyhow!("script sent an invalid status {value:?}"))?;
+                status = Some(code);
+            }
+            "location" => {
+                redirect = true;
+                headers.push(Header::Other(name.to_owned(), value.to_owned()));
+            }
+            "content-type" => headers.push(Header::ContentType(value.parse()?)),
+            // bodies are streamed as the script writes them, and the connection is butler's
+            "content-length" | "transfer-encoding" | "connection" => {}
+            _ => headers.push(Header::Other(name.to_owned(), value.to_owned())),
+        }
+    }
+
+    let status = status.unwrap_or(if redirect {
+        StatusCode::Found
+    } else {
+        StatusCode::Ok
+    });
+    Ok(Response {
+        headers,
+        ..Response::new(status)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn head(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|line| (*line).to_owned()).collect()
+    }
+
+    #[test]
+    fn parse_head_reads_status_and_redirects() {
+        let response = parse_head(head(&[
+            "Status: 404 Not Found",
+            "Content-Type: text/html",
+            "Content-Length: 12",
+            "X-Custom: yes",
+        ]))
+        .unwrap();
+        assert_eq!(response.status, StatusCode::NotFound);
+        assert_eq!(
+            response.headers,
+            [
+                Header::ContentType("text/html".parse().unwrap()),
+                Header::Other("X-Custom".to_owned(), "yes".to_owned()),
+            ]
+        );
+
+        let response = parse_head(head(&["Location: https://example.com/"])).unwrap();
+        assert_eq!(response.status, StatusCode::Found);
+
+        assert!(parse_head(head(&["Status: teapot"])).is_err());
+        assert!(parse_head(head(&["no colon"])).is_err());
+    }
+}
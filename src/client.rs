@@ -0,0 +1,282 @@
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context};
+use flate2::read::{MultiGzDecoder, ZlibDecoder};
+
+use crate::{
+    header::Header,
+    request::{percent_encode, Method, Request},
+    response::{Body, Response, StatusCode},
+};
+
+// how long a server may take to accept a connection, or go quiet while answering
+const TIMEOUT: Duration = Duration::from_secs(30);
+// largest response head accepted
+const MAX_RESPONSE_HEAD_SIZE: usize = 64 * 1024;
+// request headers the client sets itself
+const OWN_HEADERS: [&str; 4] = ["host", "content-length", "transfer-encoding", "connection"];
+
+// fetches `url`, an `http://` one such as `http://127.0.0.1:3000/health`, see `request`
+pub fn get(url: &str) -> anyhow::Result<Response> {
+    let rest = url
+        .strip_prefix("http://")
+        .with_context(|| anyhow!("{url:?} isn't an http:// URL"))?;
+    let (authority, target) = match rest.find(['/', '?']) {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    if authority.is_empty() {
+        return Err(anyhow!("{url:?} has no host"));
+    }
+    let target = match target.starts_with('?') {
+        true => format!("/{target}"),
+        false => target.to_owned(),
+    };
+
+    let request: Request = format!("GET {target} HTTP/1.1\r\nHost: {authority}\r\n\r\n")
+        .parse()
+        .with_context(|| anyhow!("{url:?} isn't a valid URL"))?;
+    self::request(&request)
+}
+
+// sends `request` to the server its `Host` names, on port 80 unless it gives another, over a
+// connection of its own, and reads the whole response; chunked bodies are put back together, and
+// ones compressed with gzip or deflate decompressed, so that they're left with a `Content-Length`
+// in place of their `Transfer-Encoding` and `Content-Encoding`
+pub fn request(request: &Request) -> anyhow::Result<Response> {
+    let host = request.host().context("the request has no Host header")?;
+    let authority = match host.rsplit_once(':') {
+        Some((_, port)) if !host.ends_with(']') && port.bytes().all(|b| b.is_ascii_digit()) => {
+            host.to_owned()
+        }
+        _ => format!("{host}:80"),
+    };
+    let addr = authority
+        .to_socket_addrs()
+        .with_context(|| anyhow!("failed to resolve {host}"))?
+        .next()
+        .with_context(|| anyhow!("{host} has no address"))?;
+    let stream = TcpStream::connect_timeout(&addr, TIMEOUT)
+        .with_context(|| anyhow!("failed to connect to {host}"))?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    let mut target = percent_encode(request.path());
+    if let Some(query) = &request.line.raw_query {
+        target.push('?');
+        target.push_str(query);
+    }
+    let mut head = format!("{} {target} HTTP/1.1\r\nHost: {host}\r\n", request.method());
+    for (name, value) in request.raw_headers().iter() {
+        if !OWN_HEADERS.contains(&name.to_lowercase().as_str()) {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+    }
+    if request.header("accept-encoding").is_none() {
+        head.push_str("Accept-Encoding: gzip, deflate\r\n");
+    }
+    if let Some(len) = request.body_len() {
+        head.push_str(&format!("Content-Length: {len}\r\n"));
+    }
+    // one request per connection, so the end of a response without a length is never in doubt
+    head.push_str("Connection: close\r\n\r\n");
+
+    let mut writer = &stream;
+    writer.write_all(head.as_bytes())?;
+    io::copy(&mut request.body_reader()?, &mut writer)?;
+    writer.flush()?;
+
+    let mut reader = BufReader::new(stream);
+    let (status, headers) = read_response_head(&mut reader)?;
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.to_lowercase())
+    };
+
+    let mut body = Vec::new();
+    let has_body = !matches!(status, StatusCode::NoContent | StatusCode::NotModified);
+    if request.method() != Method::Head && has_body {
+        let chunked = header("transfer-encoding").is_some_and(|coding| coding.contains("chunked"));
+        let length = header("content-length")
+            .map(|length| length.parse::<u64>())
+            .transpose()
+            .context("the server sent an invalid Content-Length")?;
+        let raw: Box<dyn Read + '_> = match (chunked, length) {
+            (true, _) => Box::new(ChunkedReader::new(&mut reader)),
+            (false, Some(length)) => Box::new((&mut reader).take(length)),
+            (false, None) => Box::new(&mut reader),
+        };
+        let mut decoder: Box<dyn Read + '_> = match header("content-encoding").as_deref() {
+            Some("gzip" | "x-gzip") => Box::new(MultiGzDecoder::new(raw)),
+            Some("deflate") => Box::new(ZlibDecoder::new(raw)),
+            _ => raw,
+        };
+        decoder
+            .read_to_end(&mut body)
+            .context("failed to read the response body")?;
+    }
+
+    let decoded = matches!(
+        header("content-encoding").as_deref(),
+        Some("gzip" | "x-gzip" | "deflate")
+    );
+    let mut response = Response::new(status);
+    for (name, value) in headers {
+        let lowercase = name.to_lowercase();
+        if lowercase == "transfer-encoding"
+            || (lowercase == "content-length" && has_body && request.method() != Method::Head)
+            || (lowercase == "content-encoding" && decoded)
+        {
+            continue;
+        }
+        let header = format!("{name}: {value}").parse();
+        response
+            .headers
+            .push(header.unwrap_or(Header::Other(name, value)));
+    }
+    if request.method() != Method::Head && has_body {
+        response
+            .headers
+            .push(Header::ContentLength(body.len() as u64));
+        response.body = Some(Body::Bytes(body));
+    }
+    Ok(response)
+}
+
+// reads the status line and headers a server answered with, skipping interim responses
+pub(crate) fn read_response_head(
+    reader: &mut impl BufRead,
+) -> anyhow::Result<(StatusCode, Vec<(String, String)>)> {
+    loop {
+        let mut size = 0;
+        let status_line = read_line(reader, &mut size)?;
+        let code = status_line
+            .strip_prefix("HTTP/1.")
+            .and_then(|rest| rest.split_whitespace().nth(1))
+            .and_then(|code| code.parse().ok())
+            .with_context(|| anyhow!("the server sent an invalid status line {status_line:?}"))?;
+        let status = StatusCode::from_code(code)
+            .with_context(|| anyhow!("the server answered with unknown status {code}"))?;
+
+        let mut headers = Vec::new();
+        loop {
+            let line = read_line(reader, &mut size)?;
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line
+                .split_once(':')
+                .with_context(|| anyhow!("the server sent an invalid header {line:?}"))?;
+            headers.push((name.trim().to_owned(), value.trim().to_owned()));
+        }
+
+        if (100..200).contains(&code) {
+            continue;
+        }
+        return Ok((status, headers));
+    }
+}
+
+// reads a line without its line ending, counting it towards the `size` of the head
+fn read_line(reader: &mut impl BufRead, size: &mut usize) -> anyhow::Result<String> {
+    let mut line = String::new();
+    let limit = MAX_RESPONSE_HEAD_SIZE.saturating_sub(*size) as u64;
+    reader.by_ref().take(limit).read_line(&mut line)?;
+    if !line.ends_with('\n') {
+        return Err(anyhow!("the response head is cut short or too large"));
+    }
+    *size += line.len();
+    Ok(line.trim_end_matches(['\r', '\n']).to_owned())
+}
+
+// decodes a chunked body as it's read, dropping chunk extensions and trailers
+pub(crate) struct ChunkedReader<R> {
+    inner: R,
+    // left in the current chunk
+    remaining: u64,
+    done: bool,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        Self {
+            inner,
+            remaining: 0,
+            done: false,
+        }
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let invalid = |reason: &str| io::Error::new(io::ErrorKind::InvalidData, reason.to_owned());
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+
+        if self.remaining == 0 {
+            let mut size = 0;
+            let line = read_line(&mut self.inner, &mut size).map_err(|_| invalid("bad chunk"))?;
+            let size_field = line.split(';').next().unwrap_or_default().trim();
+            self.remaining =
+                u64::from_str_radix(size_field, 16).map_err(|_| invalid("invalid chunk size"))?;
+
+            if self.remaining == 0 {
+                loop {
+                    let mut size = 0;
+                    let trailer = read_line(&mut self.inner, &mut size)
+                        .map_err(|_| invalid("bad trailer"))?;
+                    if trailer.is_empty() {
+                        break;
+                    }
+                }
+                self.done = true;
+                return Ok(0);
+            }
+        }
+
+        let n = self.inner.by_ref().take(self.remaining).read(buf)?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the server closed the connection in the middle of a chunk",
+            ));
+        }
+        self.remaining -= n as u64;
+
+        if self.remaining == 0 {
+            let mut line_ending = [0; 2];
+            self.inner.read_exact(&mut line_ending)?;
+            if line_ending != *b"\r\n" {
+                return Err(invalid("chunk data isn't followed by a CRLF"));
+            }
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunked_reader_decodes_chunks() {
+        let body = b"4;ext=1\r\nWiki\r\n5\r\npedia\r\n0\r\nExpires: never\r\n\r\n";
+        let mut decoded = String::new();
+        ChunkedReader::new(&body[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, "Wikipedia");
+
+        let mut decoded = String::new();
+        assert!(ChunkedReader::new(&b"4\r\nWi"[..])
+            .read_to_string(&mut decoded)
+            .is_err());
+    }
+}
@@ -0,0 +1,444 @@
+use std::{
+    collections::VecDeque,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Condvar, Mutex, MutexGuard, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context};
+
+use crate::{
+    auth::covers,
+    request::{decode_path, Request},
+    response::Response,
+    rewrite::{rewrite_path, RewriteRule},
+    server::Config,
+};
+
+// how long clients turned away by a full limit are told to wait before trying again
+const RETRY_AFTER: Duration = Duration::from_secs(1);
+
+// how many requests under a path prefix are handled at once, e.g.
+// `ConcurrencyLimit::new("/files/", 8)`; add it to `Config::concurrency_limits`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConcurrencyLimit {
+    // without a trailing '/', empty to cover every path
+    prefix: String,
+    max: usize,
+}
+
+impl ConcurrencyLimit {
+    pub fn new(prefix: &str, max: usize) -> Self {
+        Self {
+            prefix: prefix.trim_end_matches('/').to_owned(),
+            max,
+        }
+    }
+}
+
+// `PREFIX=N`, e.g. `/files/=8`
+impl FromStr for ConcurrencyLimit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (prefix, max) = s
+            .split_once('=')
+            .with_context(|| anyhow!("{s:?} is not a limit such as /files/=8"))?;
+        let prefix = prefix.trim();
+        if !prefix.starts_with('/') {
+            return Err(anyhow!("{prefix:?} has to be a path starting with '/'"));
+        }
+        let max = max
+            .trim()
+            .parse()
+            .with_context(|| anyhow!("{max:?} is not a number of requests"))?;
+        if max == 0 {
+            return Err(anyhow!("the limit for {prefix:?} must be at least 1"));
+        }
+        Ok(Self::new(prefix, max))
+    }
+}
+
+// applies `Config::concurrency_limits` and `Config::max_concurrent_requests` to requests as they
+// arrive, before their body is read; they're built when the server starts, so a reload doesn't
+// change them
+#[derive(Debug)]
+pub(crate) struct Limiters {
+    // the first one whose prefix covers a request applies to it
+    routes: Vec<(ConcurrencyLimit, Semaphore)>,
+    global: Option<Semaphore>,
+    queue_timeout: Duration,
+    // a waiting request holds on to its worker, so the ones waiting under every limit together
+    // only get half of them, leaving the rest to requests that aren't over a limit
+    queue: Queue,
+}
+
+#[derive(Debug)]
+struct Queue {
+    max: usize,
+    waiting: AtomicUsize,
+}
+
+// held while a request is handled, giving its slots back once it's dropped
+#[derive(Debug)]
+pub(crate) struct Permits<'a> {
+    _route: Option<Permit<'a>>,
+    _global: Option<Permit<'a>>,
+}
+
+impl Limiters {
+    pub(crate) fn new(config: &Config) -> Self {
+        Self {
+            routes: config
+                .concurrency_limits
+                .iter()
+                .map(|limit| (limit.clone(), Semaphore::new(limit.max)))
+                .collect(),
+            global: config.max_concurrent_requests.map(Semaphore::new),
+            queue_timeout: config.concurrency_queue_timeout,
+            queue: Queue {
+                max: config.workers / 2,
+                waiting: AtomicUsize::new(0),
+            },
+        }
+    }
+
+    // waits for a slot in the limit of the request's path and then in the global one, the route's
+    // first so that requests queued for a busy route don't keep others from the global slots; a
+    // request still waiting after the queue timeout gets a 503, as does one arriving when the
+    // line is full. The path is the one `rewrites` leave, so that an alias for a limited path is
+    // limited as well
+    pub(crate) fn acquire(
+        &self,
+        request: &Request,
+        rewrites: &[RewriteRule],
+    ) -> Result<Permits<'_>, Response> {
+        self.acquire_within(request, rewrites, self.queue_timeout)
+    }
+
+    // like `acquire`, but turns the request away rather than waiting, for a connection whose other
+    // requests would be held up by the wait
+    pub(crate) fn try_acquire(
+        &self,
+        request: &Request,
+        rewrites: &[RewriteRule],
+    ) -> Result<Permits<'_>, Response> {
+        self.acquire_within(request, rewrites, Duration::ZERO)
+    }
+
+    fn acquire_within(
+        &self,
+        request: &Request,
+        rewrites: &[RewriteRule],
+        timeout: Duration,
+    ) -> Result<Permits<'_>, Response> {
+        let deadline = Instant::now() + timeout;
+        let path = rewrite_path(rewrites, &request.line.path);
+        let path = path.as_deref().unwrap_or(&request.line.path);
+        let route = decode_path(path).ok().and_then(|path| {
+            self.routes
+                .iter()
+                .find(|(limit, _)| covers(&limit.prefix, &path))
+        });
+
+        let route = match route {
+            Some((limit, semaphore)) => {
+                let permit = semaphore.acquire(deadline, &self.queue).ok_or_else(|| {
+                    log::warn!(
+                        "request_id = {}, over the concurrency limit for {:?}",
+                        request.id,
+                        limit.prefix
+                    );
+                    unavailable()
+                })?;
+                Some(permit)
+            }
+            None => None,
+        };
+        let global = match &self.global {
+            Some(semaphore) => {
+                let permit = semaphore.acquire(deadline, &self.queue).ok_or_else(|| {
+                    log::warn!(
+                        "request_id = {}, over the global concurrency limit",
+                        request.id
+                    );
+                    unavailable()
+                })?;
+                Some(permit)
+            }
+            None => None,
+        };
+
+        Ok(Permits {
+            _route: route,
+            _global: global,
+        })
+    }
+
+    // renders gauges of every limit's size, the requests holding and waiting for its slots, and a
+    // counter of those it turned away, in the Prometheus text exposition format; nothing without
+    // any limits
+    pub(crate) fn render(&self) -> String {
+        let limits: Vec<_> = self
+            .routes
+            .iter()
+            .map(|(limit, semaphore)| (label(&limit.prefix), semaphore))
+            .chain(
+                self.global
+                    .iter()
+                    .map(|semaphore| ("global".to_owned(), semaphore)),
+            )
+            .collect();
+        if limits.is_empty() {
+            return String::new();
+        }
+
+        let mut text = String::new();
+        let mut family = |name: &str, kind: &str, help: &str, value: &dyn Fn(&Semaphore) -> u64| {
+            text.push_str(&format!("# HELP {name} {help}\n"));
+            text.push_str(&format!("# TYPE {name} {kind}\n"));
+            for (label, semaphore) in &limits {
+                text.push_str(&format!(
+                    "{name}{{limit=\"{label}\"}} {}\n",
+                    value(semaphore)
+                ));
+            }
+        };
+        family(
+            "http_concurrency_limit",
+            "gauge",
+            "Requests each concurrency limit lets be handled at once.",
+            &|semaphore| semaphore.max as u64,
+        );
+        family(
+            "http_concurrency_in_use",
+            "gauge",
+            "Requests being handled under each concurrency limit.",
+            &|semaphore| semaphore.state().in_use as u64,
+        );
+        family(
+            "http_concurrency_queued",
+            "gauge",
+            "Requests waiting for a slot under each concurrency limit.",
+            &|semaphore| semaphore.state().waiting.len() as u64,
+        );
+        family(
+            "http_concurrency_rejected_total",
+            "counter",
+            "Requests turned away with a 503 for want of a slot under each concurrency limit.",
+            &|semaphore| semaphore.rejected.load(Ordering::Relaxed),
+        );
+        text
+    }
+}
+
+fn unavailable() -> Response {
+    Response::service_unavailable().with_header("Retry-After", &RETRY_AFTER.as_secs().to_string())
+}
+
+// a prefix as a label value, empty ones cover every path
+fn label(prefix: &str) -> String {
+    let prefix = if prefix.is_empty() { "/" } else { prefix };
+    prefix.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+// a counting semaphore whose slots go to waiting requests in the order they arrived, so a steady
+// stream of new ones can't keep an older one waiting until it times out; no more requests wait
+// for slots than there are of them
+#[derive(Debug)]
+struct Semaphore {
+    max: usize,
+    state: Mutex<State>,
+    freed: Condvar,
+    rejected: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    in_use: usize,
+    // tickets of the requests waiting for a slot, the first one gets the next one that's freed
+    waiting: VecDeque<u64>,
+    next_ticket: u64,
+}
+
+#[derive(Debug)]
+struct Permit<'a>(&'a Semaphore);
+
+impl Semaphore {
+    fn new(max: usize) -> Self {
+        Self {
+            max,
+            state: Mutex::default(),
+            freed: Condvar::new(),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // takes a slot, waiting behind earlier requests for one until `deadline` if there's room in
+    // line here and in `queue`
+    fn acquire(&self, deadline: Instant, queue: &Queue) -> Option<Permit<'_>> {
+        let mut state = self.state();
+        if state.waiting.is_empty() && state.in_use < self.max {
+            state.in_use += 1;
+            return Some(Permit(self));
+        }
+        if Instant::now() >= deadline || state.waiting.len() >= self.max || !queue.enter() {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.waiting.push_back(ticket);
+        loop {
+            if state.waiting.front() == Some(&ticket) && state.in_use < self.max {
+                state.waiting.pop_front();
+                queue.leave();
+                state.in_use += 1;
+                // there may be a slot left for the next one in line as well
+                self.freed.notify_all();
+                return Some(Permit(self));
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                state.waiting.retain(|&waiting| waiting != ticket);
+                queue.leave();
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                // the one behind this request may be first in line now
+                self.freed.notify_all();
+                return None;
+            }
+            state = self
+                .freed
+                .wait_timeout(state, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+}
+
+impl Queue {
+    // takes a place in line, if there's one left
+    fn enter(&self) -> bool {
+        self.waiting
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |waiting| {
+                (waiting < self.max).then_some(waiting + 1)
+            })
+            .is_ok()
+    }
+
+    fn leave(&self) {
+        self.waiting.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.0.state().in_use -= 1;
+        self.0.freed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use super::*;
+
+    #[test]
+    fn concurrency_limits_parse_prefixes_and_counts() {
+        assert_eq!(
+            "/files/=8".parse::<ConcurrencyLimit>().unwrap(),
+            ConcurrencyLimit::new("/files", 8)
+        );
+        assert!("/files/".parse::<ConcurrencyLimit>().is_err());
+        assert!("files=8".parse::<ConcurrencyLimit>().is_err());
+        assert!("/files/=0".parse::<ConcurrencyLimit>().is_err());
+        assert!("/files/=many".parse::<ConcurrencyLimit>().is_err());
+    }
+
+    fn queue(max: usize) -> Queue {
+        Queue {
+            max,
+            waiting: AtomicUsize::new(0),
+        }
+    }
+
+    #[test]
+    fn semaphore_hands_out_slots_in_order_and_times_out() {
+        let semaphore = Arc::new(Semaphore::new(2));
+        let queue = Arc::new(queue(8));
+        let held = semaphore.acquire(Instant::now(), &queue).unwrap();
+        let _also_held = semaphore.acquire(Instant::now(), &queue).unwrap();
+        assert!(semaphore.acquire(Instant::now(), &queue).is_none());
+        assert_eq!(semaphore.rejected.load(Ordering::Relaxed), 1);
+
+        // one that waits in line until its deadline gives up its place in it
+        let started = Instant::now();
+        let deadline = started + Duration::from_millis(50);
+        assert!(semaphore.acquire(deadline, &queue).is_none());
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(semaphore.rejected.load(Ordering::Relaxed), 2);
+        assert!(semaphore.state().waiting.is_empty());
+        assert_eq!(queue.waiting.load(Ordering::SeqCst), 0);
+
+        // the first waiter gets the slot once it's freed, the second one only after that
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let waiters: Vec<_> = (0..2)
+            .map(|i| {
+                let (waiting, queue, order) = (
+                    Arc::clone(&semaphore),
+                    Arc::clone(&queue),
+                    Arc::clone(&order),
+                );
+                let waiter = thread::spawn(move || {
+                    let deadline = Instant::now() + Duration::from_secs(5);
+                    let _permit = waiting.acquire(deadline, &queue).unwrap();
+                    order.lock().unwrap().push(i);
+                    thread::sleep(Duration::from_millis(20));
+                });
+                // lets it get in line before the next one
+                while semaphore.state().waiting.len() < i + 1 {
+                    thread::yield_now();
+                }
+                waiter
+            })
+            .collect();
+
+        drop(held);
+        for waiter in waiters {
+            waiter.join().unwrap();
+        }
+        assert_eq!(*order.lock().unwrap(), [0, 1]);
+        assert_eq!(semaphore.state().in_use, 1);
+        assert_eq!(queue.waiting.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn semaphore_turns_requests_away_at_once_when_the_line_is_full() {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let semaphore = Semaphore::new(1);
+        let _held = semaphore.acquire(deadline, &queue(8)).unwrap();
+
+        // no room in the line of every limit together
+        let started = Instant::now();
+        assert!(semaphore.acquire(deadline, &queue(0)).is_none());
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // nor in this limit's own, which is as long as it has slots
+        let queue = queue(8);
+        semaphore.state().waiting.push_back(u64::MAX);
+        assert!(semaphore.acquire(deadline, &queue).is_none());
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(semaphore.rejected.load(Ordering::Relaxed), 2);
+        assert_eq!(queue.waiting.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,145 @@
+use std::{collections::HashSet, fs, path::Path};
+
+use anyhow::{anyhow, Context};
+
+use crate::Args;
+
+// applies the options in the TOML file at `path` to `args`; only top-level keys with string,
+// integer or boolean values are supported, which covers every option butler has
+pub(crate) fn load(path: &Path, args: &mut Args) -> anyhow::Result<()> {
+    let contents = fs::read_to_string(path).context("failed to read file")?;
+    let mut seen = HashSet::new();
+
+    for (i, line) in contents.lines().enumerate() {
+        let line_number = i + 1;
+        let Some((key, value)) =
+            parse_line(line).with_context(|| anyhow!("invalid line {line_number}"))?
+        else {
+            continue;
+        };
+
+        // keys are spelled like the flags, with `_` accepted in place of `-`
+        let option = key.replace('_', "-");
+        if !seen.insert(option.clone()) {
+            return Err(anyhow!("key {key:?} on line {line_number} is set twice"));
+        }
+
+        let known = args
+            .set(&option, || Ok(value))
+            .with_context(|| anyhow!("invalid value for {key:?} on line {line_number}"))?;
+        if !known {
+            return Err(anyhow!("unknown key {key:?} on line {line_number}"));
+        }
+    }
+
+    Ok(())
+}
+
+// returns the key and value a line sets, or `None` for blank and comment lines
+fn parse_line(line: &str) -> anyhow::Result<Option<(&str, String)>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+
+    if line.starts_with('[') {
+        return Err(anyhow!(
+            "tables are not supported, options have to be top-level keys"
+        ));
+    }
+
+    let (key, value) = line
+        .split_once('=')
+        .context("expected a `key = value` pair")?;
+    let key = key.trim();
+    if key.is_empty()
+        || !key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(anyhow!("{key:?} is not a valid key"));
+    }
+
+    Ok(Some((key, parse_value(value.trim())?)))
+}
+
+// parses a string, integer or boolean value, followed by an optional comment
+fn parse_value(s: &str) -> anyhow::Result<String> {
+    let (value, rest) = if let Some(s) = s.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = s.char_indices();
+        loop {
+            match chars.next() {
+                Some((i, '"')) => break (value, &s[i + 1..]),
+                Some((_, '\\')) => match chars.next() {
+                    Some((_, '"')) => value.push('"'),
+                    Some((_, '\\')) => value.push('\\'),
+                    Some((_, 'n')) => value.push('\n'),
+                    Some((_, 't')) => value.push('\t'),
+                    Some((_, c)) => return Err(anyhow!("unsupported escape sequence \\{c}")),
+                    None => return Err(anyhow!("unterminated string")),
+                },
+                Some((_, c)) => value.push(c),
+                None => return Err(anyhow!("unterminated string")),
+            }
+        }
+    } else if let Some(s) = s.strip_prefix('\'') {
+        let (value, rest) = s.split_once('\'').context("unterminated string")?;
+        (value.to_owned(), rest)
+    } else {
+        let value = s.split_once('#').map_or(s, |(value, _)| value).trim();
+        if value == "true" || value == "false" {
+            return Ok(value.to_owned());
+        }
+        let digits = value.strip_prefix('+').unwrap_or(value);
+        if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit() || c == '_') {
+            return Err(anyhow!(
+                "unsupported value {value:?}, expected a quoted string, an integer or a boolean"
+            ));
+        }
+        (digits.replace('_', ""), "")
+    };
+
+    let rest = rest.trim();
+    if !rest.is_empty() && !rest.starts_with('#') {
+        return Err(anyhow!("unexpected {rest:?} after the value"));
+    }
+
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_line_reads_strings_integers_and_booleans() {
+        assert_eq!(parse_line("  # comment").unwrap(), None);
+        assert_eq!(
+            parse_line(r#"host = "0.0.0.0" # all interfaces"#).unwrap(),
+            Some(("host", "0.0.0.0".to_owned()))
+        );
+        assert_eq!(
+            parse_line(r"directory = 'C:\files'").unwrap(),
+            Some(("directory", r"C:\files".to_owned()))
+        );
+        assert_eq!(
+            parse_line(r#"log_level = "a\"b""#).unwrap(),
+            Some(("log_level", "a\"b".to_owned()))
+        );
+        assert_eq!(
+            parse_line("port = 4_221").unwrap(),
+            Some(("port", "4221".to_owned()))
+        );
+        assert_eq!(
+            parse_line("directory_listing = false # no indexes").unwrap(),
+            Some(("directory_listing", "false".to_owned()))
+        );
+
+        assert!(parse_line("[server]").is_err());
+        assert!(parse_line("port 4221").is_err());
+        assert!(parse_line("port = 4221 5").is_err());
+        assert!(parse_line("host = \"unterminated").is_err());
+        assert!(parse_line("workers = yes").is_err());
+    }
+}
@@ -0,0 +1,207 @@
+use std::{collections::HashMap, fmt, time::Duration};
+
+use crate::header::{is_token, HeaderMap};
+
+// whether a browser sends a cookie with requests started by other sites
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    // browsers only accept it together with `Secure`
+    None,
+}
+
+impl fmt::Display for SameSite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Strict => "Strict",
+            Self::Lax => "Lax",
+            Self::None => "None",
+        })
+    }
+}
+
+// a `Set-Cookie` header, e.g. `SetCookie::new("theme", "dark").path("/").max_age(week)`, added to
+// a response with `Response::with_cookie`; names and values can't contain whitespace, '"', ',',
+// ';' or '\', so anything else is best encoded first
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SetCookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    max_age: Option<Duration>,
+    http_only: bool,
+    secure: bool,
+    same_site: Option<SameSite>,
+}
+
+impl SetCookie {
+    pub fn new(name: &str, value: &str) -> Self {
+        assert!(
+            is_token(name) && value.bytes().all(is_cookie_octet),
+            "cookie {name:?}={value:?} would corrupt the header"
+        );
+        Self {
+            name: name.to_owned(),
+            value: value.to_owned(),
+            path: None,
+            domain: None,
+            max_age: None,
+            http_only: false,
+            secure: false,
+            same_site: None,
+        }
+    }
+
+    // tells the browser to forget the cookie called `name`, its path and domain have to match the
+    // ones it was set with
+    pub fn removal(name: &str) -> Self {
+        Self::new(name, "").max_age(Duration::ZERO)
+    }
+
+    // the paths the cookie is sent for, those starting with `path`
+    pub fn path(mut self, path: &str) -> Self {
+        self.path = Some(attribute_value(path).to_owned());
+        self
+    }
+
+    // lets subdomains of `domain` see the cookie as well
+    pub fn domain(mut self, domain: &str) -> Self {
+        self.domain = Some(attribute_value(domain).to_owned());
+        self
+    }
+
+    // without one, the cookie is gone once the browser is closed
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    // keeps scripts on the page from reading the cookie
+    pub fn http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+
+    // only sends the cookie over HTTPS
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+}
+
+impl fmt::Display for SetCookie {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+        if let Some(path) = &self.path {
+            write!(f, "; Path={path}")?;
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={domain}")?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+        if self.http_only {
+            f.write_str("; HttpOnly")?;
+        }
+        if self.secure {
+            f.write_str("; Secure")?;
+        }
+        if let Some(same_site) = self.same_site {
+            write!(f, "; SameSite={same_site}")?;
+        }
+        Ok(())
+    }
+}
+
+// the `name=value` pairs of every `Cookie` header; browsers send the cookie with the most specific
+// path first, so that one is kept when a name comes up twice
+pub(crate) fn parse_cookies(headers: &HeaderMap) -> HashMap<String, String> {
+    let mut cookies = HashMap::new();
+    for pair in headers.get_all("cookie").flat_map(|value| value.split(';')) {
+        let Some((name, value)) = pair.split_once('=') else {
+            continue;
+        };
+        let name = name.trim();
+        if name.is_empty() {
+            continue;
+        }
+        let value = value.trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+            .unwrap_or(value);
+        cookies
+            .entry(name.to_owned())
+            .or_insert_with(|| value.to_owned());
+    }
+    cookies
+}
+
+// what RFC 6265 allows in a cookie's value
+fn is_cookie_octet(b: u8) -> bool {
+    matches!(b, 0x21 | 0x23..=0x2b | 0x2d..=0x3a | 0x3c..=0x5b | 0x5d..=0x7e)
+}
+
+// an attribute ends at the next ';', so one in its value would start another
+fn attribute_value(value: &str) -> &str {
+    assert!(
+        !value.contains([';', '\r', '\n']),
+        "cookie attribute {value:?} would corrupt the header"
+    );
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_cookie_writes_its_attributes() {
+        let cookie = SetCookie::new("session", "abc123")
+            .path("/")
+            .domain("example.com")
+            .max_age(Duration::from_secs(3600))
+            .http_only(true)
+            .secure(true)
+            .same_site(SameSite::Lax);
+        assert_eq!(
+            cookie.to_string(),
+            "session=abc123; Path=/; Domain=example.com; Max-Age=3600; HttpOnly; Secure; SameSite=Lax"
+        );
+        assert_eq!(
+            SetCookie::removal("session").to_string(),
+            "session=; Max-Age=0"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "would corrupt the header")]
+    fn set_cookie_rejects_values_that_would_end_it_early() {
+        SetCookie::new("name", "a; Domain=evil.test");
+    }
+
+    #[test]
+    fn parse_cookies_keeps_the_first_of_each_name() {
+        let mut headers = HeaderMap::new();
+        headers.append("Cookie", "theme=dark; session=\"abc\"; theme=light");
+        headers.append("cookie", "lang=en;;broken");
+
+        let cookies = parse_cookies(&headers);
+        assert_eq!(
+            cookies,
+            HashMap::from([
+                ("theme".to_owned(), "dark".to_owned()),
+                ("session".to_owned(), "abc".to_owned()),
+                ("lang".to_owned(), "en".to_owned()),
+            ])
+        );
+    }
+}
@@ -0,0 +1,145 @@
+use std::time::Duration;
+
+use crate::{
+    middleware::Middleware,
+    request::{Method, Request},
+    request_id::REQUEST_ID_HEADER,
+    response::Response,
+};
+
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(600);
+
+// which other origins a browser lets read butler's responses, see `Config::cors`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorsPolicy {
+    // origins such as `https://app.example.com`, or `*` for any origin
+    pub allowed_origins: Vec<String>,
+    // methods a cross-origin request may use beyond the ones browsers allow without asking
+    pub allowed_methods: Vec<Method>,
+    // request headers a cross-origin request may send, matched without regard to case
+    pub allowed_headers: Vec<String>,
+    // how long a browser may reuse the answer to a preflight request
+    pub max_age: Duration,
+}
+
+impl Default for CorsPolicy {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_methods: vec![
+                Method::Get,
+                Method::Head,
+                Method::Post,
+                Method::Put,
+                Method::Delete,
+            ],
+            allowed_headers: vec!["Content-Type".to_owned(), "Authorization".to_owned()],
+            max_age: DEFAULT_MAX_AGE,
+        }
+    }
+}
+
+impl CorsPolicy {
+    // the `Access-Control-Allow-Origin` a request from `origin` is answered with, if it's allowed
+    fn allow_origin<'a>(&self, origin: &'a str) -> Option<&'a str> {
+        if self.allowed_origins.iter().any(|allowed| allowed == "*") {
+            Some("*")
+        } else {
+            self.allowed_origins
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+                .then_some(origin)
+        }
+    }
+
+    fn allows_headers(&self, requested: &str) -> bool {
+        requested
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .all(|name| {
+                self.allowed_headers
+                    .iter()
+                    .any(|allowed| allowed.eq_ignore_ascii_case(name))
+            })
+    }
+}
+
+// answers preflight requests and marks the responses to allowed origins as readable by them;
+// added ahead of authentication, as browsers don't send credentials with a preflight
+#[derive(Debug)]
+pub(crate) struct Cors(pub(crate) CorsPolicy);
+
+impl Middleware for Cors {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        if request.method() != Method::Options {
+            return None;
+        }
+        let origin = request.header("origin")?;
+        let method = request.header("access-control-request-method")?;
+
+        // a preflight that isn't allowed is still answered, without the headers that would let the
+        // browser go ahead with the request it asked about
+        let mut response = Response::no_content();
+        let allowed = self.0.allow_origin(origin).is_some()
+            && method
+                .parse()
+                .is_ok_and(|method| self.0.allowed_methods.contains(&method))
+            && self.0.allows_headers(
+                request
+                    .header("access-control-request-headers")
+                    .unwrap_or(""),
+            );
+        if !allowed {
+            log::debug!(
+                "request_id = {}, refused preflight from {origin} for {method} {}",
+                request.id,
+                request.line.path
+            );
+            return Some(response);
+        }
+
+        response = response
+            .with_header(
+                "Access-Control-Allow-Methods",
+                &join(self.0.allowed_methods.iter()),
+            )
+            .with_header(
+                "Access-Control-Max-Age",
+                &self.0.max_age.as_secs().to_string(),
+            );
+        if !self.0.allowed_headers.is_empty() {
+            response = response.with_header(
+                "Access-Control-Allow-Headers",
+                &join(self.0.allowed_headers.iter()),
+            );
+        }
+        Some(response)
+    }
+
+    fn after(&self, request: &Request, mut response: Response) -> Response {
+        let Some(origin) = request.header("origin") else {
+            return response;
+        };
+        let allowed = self.0.allow_origin(origin);
+        // responses differ by origin unless every origin gets the same one
+        if allowed != Some("*") {
+            response = response.with_vary("Origin");
+        }
+        let Some(allowed) = allowed else {
+            return response;
+        };
+
+        // scripts can only read the headers browsers consider safe, unless they're listed
+        response
+            .with_header("Access-Control-Allow-Origin", allowed)
+            .with_header("Access-Control-Expose-Headers", REQUEST_ID_HEADER)
+    }
+}
+
+fn join<T: ToString>(items: impl Iterator<Item = T>) -> String {
+    items
+        .map(|item| item.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
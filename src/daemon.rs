@@ -0,0 +1,228 @@
+// running as a service without a wrapper: in the background, with a PID file, and as an
+// unprivileged user once the listeners are bound
+
+use std::{fs, path::PathBuf};
+
+#[cfg(unix)]
+use std::{
+    ffi::CString,
+    fs::{File, OpenOptions},
+    io, mem,
+    os::fd::AsRawFd,
+    path::Path,
+    ptr,
+};
+
+use anyhow::{anyhow, Context};
+
+// the file with the server's process id, removed again once the server has shut down
+#[derive(Debug)]
+pub(crate) struct PidFile(PathBuf);
+
+impl PidFile {
+    pub(crate) fn create(path: PathBuf) -> anyhow::Result<Self> {
+        fs::write(&path, format!("{}\n", std::process::id()))
+            .with_context(|| anyhow!("failed to write PID file {path:?}"))?;
+        Ok(Self(path))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(&self.0) {
+            log::warn!("failed to remove PID file {:?}: {err}", self.0);
+        }
+    }
+}
+
+// appends everything written to stdout and stderr, the log included, to the file at `path`
+#[cfg(unix)]
+pub(crate) fn redirect_output(path: &Path) -> anyhow::Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| anyhow!("failed to open log file {path:?}"))?;
+    replace_output(&file).with_context(|| anyhow!("failed to redirect output to {path:?}"))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn redirect_output(_path: &std::path::Path) -> anyhow::Result<()> {
+    Err(anyhow!("--log-file is only supported on Unix"))
+}
+
+#[cfg(unix)]
+fn replace_output(file: &File) -> io::Result<()> {
+    for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        // SAFETY: `file` stays open for the call, and the standard descriptors are only made to
+        // refer to it
+        if unsafe { libc::dup2(file.as_raw_fd(), fd) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+// detaches from the terminal and carries on in a background process, with the parent exiting
+// right away; output that `redirect_output` didn't send to a file is dropped. The working
+// directory is kept, so relative paths such as --directory still resolve. This has to happen
+// before any thread is spawned, since only the calling one survives a fork
+#[cfg(unix)]
+pub(crate) fn daemonize(keep_output: bool) -> anyhow::Result<()> {
+    fork_to_background()?;
+    // SAFETY: only makes the child the leader of a new session, without a controlling terminal
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error()).context("failed to start a new session");
+    }
+    // a process that isn't a session leader can't get a controlling terminal again
+    fork_to_background()?;
+
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .context("failed to open /dev/null")?;
+    // SAFETY: as in `replace_output`
+    if unsafe { libc::dup2(null.as_raw_fd(), libc::STDIN_FILENO) } == -1 {
+        return Err(io::Error::last_os_error()).context("failed to detach stdin");
+    }
+    if !keep_output {
+        replace_output(&null).context("failed to detach stdout and stderr")?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn daemonize(_keep_output: bool) -> anyhow::Result<()> {
+    Err(anyhow!("--daemon is only supported on Unix"))
+}
+
+// forks, returning in the child while the parent exits
+#[cfg(unix)]
+fn fork_to_background() -> anyhow::Result<()> {
+    // SAFETY: no other thread has been spawned yet, so the child is left in a consistent state
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()).context("failed to fork"),
+        0 => Ok(()),
+        // SAFETY: `_exit` skips destructors and atexit handlers, which belong to the child now
+        _ => unsafe { libc::_exit(0) },
+    }
+}
+
+// switches to `group`, or else the primary group of `user`, and then to `user`, for good; names
+// or numeric ids are accepted
+#[cfg(unix)]
+pub(crate) fn drop_privileges(user: Option<&str>, group: Option<&str>) -> anyhow::Result<()> {
+    let user = user.map(lookup_user).transpose()?;
+    let gid = match group {
+        Some(group) => Some(lookup_group(group)?),
+        None => user.map(|(_, gid)| gid),
+    };
+
+    if let Some(gid) = gid {
+        // SAFETY: plain system calls, `setgroups` reads one id from a live local
+        unsafe {
+            // the supplementary groups root is in would be kept otherwise, which only root can change
+            if libc::geteuid() == 0 && libc::setgroups(1, &gid) == -1 {
+                return Err(io::Error::last_os_error()).context("failed to set groups");
+            }
+            if libc::setgid(gid) == -1 {
+                return Err(io::Error::last_os_error())
+                    .with_context(|| anyhow!("failed to switch to group {gid}"));
+            }
+        }
+    }
+    if let Some((uid, _)) = user {
+        // SAFETY: a plain system call
+        if unsafe { libc::setuid(uid) } == -1 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| anyhow!("failed to switch to user {uid}"));
+        }
+    }
+
+    // SAFETY: plain system calls without arguments
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    log::info!("running as user {uid} and group {gid}");
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn drop_privileges(_user: Option<&str>, _group: Option<&str>) -> anyhow::Result<()> {
+    Err(anyhow!("--user and --group are only supported on Unix"))
+}
+
+// the user id and primary group id of `user`
+#[cfg(unix)]
+fn lookup_user(user: &str) -> anyhow::Result<(libc::uid_t, libc::gid_t)> {
+    let name = CString::new(user).with_context(|| anyhow!("{user:?} is not a user name"))?;
+    // SAFETY: `passwd` is plain data, for which all zeroes is a valid value
+    let mut entry: libc::passwd = unsafe { mem::zeroed() };
+    let mut buf = vec![0; 16 * 1024];
+    let mut found = ptr::null_mut();
+    // SAFETY: every pointer is to a live local, and `buf.len()` is the size of `buf`
+    let code = unsafe {
+        match user.parse() {
+            Ok(uid) => libc::getpwuid_r(uid, &mut entry, buf.as_mut_ptr(), buf.len(), &mut found),
+            Err(_) => libc::getpwnam_r(
+                name.as_ptr(),
+                &mut entry,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut found,
+            ),
+        }
+    };
+    if code != 0 {
+        return Err(io::Error::from_raw_os_error(code))
+            .with_context(|| anyhow!("failed to look up user {user:?}"));
+    }
+    if found.is_null() {
+        return Err(anyhow!("there is no user {user:?}"));
+    }
+    Ok((entry.pw_uid, entry.pw_gid))
+}
+
+#[cfg(unix)]
+fn lookup_group(group: &str) -> anyhow::Result<libc::gid_t> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = CString::new(group).with_context(|| anyhow!("{group:?} is not a group name"))?;
+    // SAFETY: as for `passwd` in `lookup_user`
+    let mut entry: libc::group = unsafe { mem::zeroed() };
+    let mut buf = vec![0; 16 * 1024];
+    let mut found = ptr::null_mut();
+    // SAFETY: as in `lookup_user`
+    let code = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut entry,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut found,
+        )
+    };
+    if code != 0 {
+        return Err(io::Error::from_raw_os_error(code))
+            .with_context(|| anyhow!("failed to look up group {group:?}"));
+    }
+    if found.is_null() {
+        return Err(anyhow!("there is no group {group:?}"));
+    }
+    Ok(entry.gr_gid)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn users_and_groups_are_looked_up_by_name_or_id() {
+        assert_eq!(lookup_user("root").unwrap(), (0, 0));
+        assert_eq!(lookup_user("0").unwrap(), (0, 0));
+        assert!(lookup_user("no-such-user-here").is_err());
+        assert_eq!(lookup_group("root").unwrap(), 0);
+        assert_eq!(lookup_group("42").unwrap(), 42);
+        assert!(lookup_group("no-such-group-here").is_err());
+    }
+}
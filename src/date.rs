@@ -0,0 +1,236 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const WEEKDAY_NAMES: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+
+const MONTH_NAMES: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+// a calendar date and time in UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct DateTime {
+    pub(crate) year: i64,
+    pub(crate) month: u32,
+    pub(crate) day: u32,
+    pub(crate) hour: u32,
+    pub(crate) minute: u32,
+    pub(crate) second: u32,
+    // days since Sunday
+    pub(crate) weekday: u32,
+}
+
+impl DateTime {
+    pub(crate) fn from_system_time(time: SystemTime) -> Self {
+        let secs = match time.duration_since(UNIX_EPOCH) {
+            Ok(duration) => duration.as_secs() as i64,
+            Err(err) => -(err.duration().as_secs() as i64),
+        };
+
+        let days = secs.div_euclid(86_400);
+        let secs_of_day = secs.rem_euclid(86_400) as u32;
+
+        // converts days since the epoch to a proleptic Gregorian date,
+        // see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let day_of_era = z.rem_euclid(146_097);
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+        Self {
+            year,
+            month,
+            day,
+            hour: secs_of_day / 3600,
+            minute: secs_of_day % 3600 / 60,
+            second: secs_of_day % 60,
+            // the epoch was a Thursday
+            weekday: (days + 4).rem_euclid(7) as u32,
+        }
+    }
+
+    // the inverse of `from_system_time`, ignoring `weekday`,
+    // see http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    pub(crate) fn to_system_time(self) -> Option<SystemTime> {
+        let (month, day) = (i64::from(self.month), i64::from(self.day));
+        let year = if month <= 2 { self.year - 1 } else { self.year };
+        let era = year.div_euclid(400);
+        let year_of_era = year.rem_euclid(400);
+        let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+
+        let secs = days * 86_400
+            + i64::from(self.hour) * 3600
+            + i64::from(self.minute) * 60
+            + i64::from(self.second);
+        let offset = Duration::from_secs(secs.unsigned_abs());
+        if secs >= 0 {
+            UNIX_EPOCH.checked_add(offset)
+        } else {
+            UNIX_EPOCH.checked_sub(offset)
+        }
+    }
+
+    pub(crate) fn month_name(&self) -> &'static str {
+        MONTH_NAMES[self.month as usize - 1]
+    }
+
+    pub(crate) fn weekday_name(&self) -> &'static str {
+        WEEKDAY_NAMES[self.weekday as usize]
+    }
+}
+
+// formats `time` as an HTTP date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`
+pub(crate) fn http_date(time: SystemTime) -> String {
+    let date = DateTime::from_system_time(time);
+
+    format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+        date.weekday_name(),
+        date.day,
+        date.month_name(),
+        date.year,
+        date.hour,
+        date.minute,
+        date.second
+    )
+}
+
+// parses an HTTP date in any of the three formats recipients have to accept, e.g.
+// `Sun, 06 Nov 1994 08:49:37 GMT`, `Sunday, 06-Nov-94 08:49:37 GMT` or `Sun Nov  6 08:49:37 1994`
+pub(crate) fn parse_http_date(s: &str) -> Option<SystemTime> {
+    let parts: Vec<&str> = s.split_whitespace().collect();
+
+    let (day, month, year, time) = match parts.as_slice() {
+        [weekday, day, month, year, time, "GMT"] if weekday.ends_with(',') => {
+            (*day, *month, year.parse().ok()?, *time)
+        }
+        [weekday, date, time, "GMT"] if weekday.ends_with(',') => {
+            let mut date = date.split('-');
+            let (day, month, year) = (date.next()?, date.next()?, date.next()?);
+            if year.len() != 2 {
+                return None;
+            }
+            // two digit years are taken to be within a century around the epoch
+            let year: i64 = year.parse().ok()?;
+            (
+                day,
+                month,
+                year + if year < 70 { 2000 } else { 1900 },
+                *time,
+            )
+        }
+        [_, month, day, time, year] => (*day, *month, year.parse().ok()?, *time),
+        _ => return None,
+    };
+
+    let mut time = time.split(':').map(|part| part.parse::<u32>().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+    if time.next().is_some() {
+        return None;
+    }
+
+    let date = DateTime {
+        year,
+        month: MONTH_NAMES.iter().position(|name| *name == month)? as u32 + 1,
+        day: day.parse().ok()?,
+        hour,
+        minute,
+        second,
+        weekday: 0,
+    };
+
+    // dates such as Feb 30 or 25:00 don't survive being converted there and back
+    let time = date.to_system_time()?;
+    let converted = DateTime::from_system_time(time);
+    (DateTime {
+        weekday: 0,
+        ..converted
+    } == date)
+        .then_some(time)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn date_time_converts_unix_timestamps() {
+        let date = |secs| DateTime::from_system_time(UNIX_EPOCH + Duration::from_secs(secs));
+
+        assert_eq!(
+            date(0),
+            DateTime {
+                year: 1970,
+                month: 1,
+                day: 1,
+                hour: 0,
+                minute: 0,
+                second: 0,
+                weekday: 4
+            }
+        );
+        assert_eq!(
+            date(784_111_777),
+            DateTime {
+                year: 1994,
+                month: 11,
+                day: 6,
+                hour: 8,
+                minute: 49,
+                second: 37,
+                weekday: 0
+            }
+        );
+        assert_eq!(
+            date(951_782_400),
+            DateTime {
+                year: 2000,
+                month: 2,
+                day: 29,
+                hour: 0,
+                minute: 0,
+                second: 0,
+                weekday: 2
+            }
+        );
+    }
+
+    #[test]
+    fn http_date_uses_imf_fixdate() {
+        assert_eq!(
+            http_date(UNIX_EPOCH + Duration::from_secs(784_111_777)),
+            "Sun, 06 Nov 1994 08:49:37 GMT"
+        );
+        assert_eq!(
+            http_date(UNIX_EPOCH + Duration::from_secs(1_709_251_199)),
+            "Thu, 29 Feb 2024 23:59:59 GMT"
+        );
+        assert_eq!(http_date(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+    }
+
+    #[test]
+    fn parse_http_date_accepts_all_three_formats() {
+        let expected = Some(UNIX_EPOCH + Duration::from_secs(784_111_777));
+
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), expected);
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), expected);
+        assert_eq!(parse_http_date("Sun Nov  6 08:49:37 1994"), expected);
+        assert_eq!(
+            parse_http_date(&http_date(UNIX_EPOCH + Duration::from_secs(1_709_251_199))),
+            Some(UNIX_EPOCH + Duration::from_secs(1_709_251_199))
+        );
+
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 UTC"), None);
+        assert_eq!(parse_http_date("Fri, 30 Feb 2024 00:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 24:00:00 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Foo 1994 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("yesterday"), None);
+    }
+}
@@ -0,0 +1,207 @@
+#[cfg(not(unix))]
+compile_error!("the `event-loop` feature is only supported on Unix");
+
+use std::{
+    collections::HashMap,
+    io,
+    os::fd::AsRawFd,
+    sync::{
+        atomic::Ordering,
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use mio::{unix::SourceFd, Events, Interest, Poll, Token, Waker};
+use threadpool::ThreadPool;
+
+use crate::server::{resume, CurrentSite, Http1Connection, Site, Stats};
+
+const WAKE: Token = Token(usize::MAX);
+// longest the loop sleeps before noticing the server is shutting down
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// waits on kept-alive connections between requests, so they don't hold on to a worker meanwhile;
+// one that becomes readable is handed back to the pool to read its next request
+pub(crate) struct EventLoop {
+    parked: Sender<Http1Connection>,
+    waker: Waker,
+}
+
+impl EventLoop {
+    pub(crate) fn start(
+        pool: ThreadPool,
+        site: Arc<CurrentSite>,
+        stats: Arc<Stats>,
+    ) -> anyhow::Result<Arc<Self>> {
+        let poll = Poll::new().context("failed to create poller")?;
+        let waker = Waker::new(poll.registry(), WAKE).context("failed to create waker")?;
+        let (parked, receiver) = mpsc::channel();
+        let event_loop = Arc::new(Self { parked, waker });
+
+        let mut idle = Idle {
+            poll,
+            receiver,
+            connections: HashMap::new(),
+            next_token: 0,
+            pool,
+            site,
+            stats,
+            event_loop: Arc::clone(&event_loop),
+        };
+        thread::Builder::new()
+            .name("event-loop".to_owned())
+            .spawn(move || {
+                if let Err(err) = idle.run() {
+                    log::error!("event loop failed, closing the connections it held: {err:#}");
+                }
+            })
+            .context("failed to spawn the event loop thread")?;
+
+        Ok(event_loop)
+    }
+
+    pub(crate) fn park(&self, conn: Http1Connection) {
+        // the loop only stops once the server is shutting down, when idle connections are closed anyway
+        if let Err(mpsc::SendError(conn)) = self.parked.send(conn) {
+            close(conn);
+            return;
+        }
+        if let Err(err) = self.waker.wake() {
+            log::error!("failed to wake the event loop: {err}");
+        }
+    }
+}
+
+impl std::fmt::Debug for EventLoop {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EventLoop").finish_non_exhaustive()
+    }
+}
+
+// the state of the event loop thread
+struct Idle {
+    poll: Poll,
+    receiver: Receiver<Http1Connection>,
+    // each with the time it's closed at unless it becomes readable first
+    connections: HashMap<Token, (Http1Connection, Instant)>,
+    next_token: usize,
+    pool: ThreadPool,
+    // read as connections wake up, so they're served with a reloaded configuration from then on
+    site: Arc<CurrentSite>,
+    stats: Arc<Stats>,
+    event_loop: Arc<EventLoop>,
+}
+
+impl Idle {
+    fn run(&mut self) -> anyhow::Result<()> {
+        let mut events = Events::with_capacity(1024);
+
+        while !self.stats.shutting_down.load(Ordering::SeqCst) {
+            let now = Instant::now();
+            let timeout = self
+                .connections
+                .values()
+                .map(|(_, deadline)| deadline.saturating_duration_since(now))
+                .fold(SHUTDOWN_POLL_INTERVAL, Duration::min);
+
+            match self.poll.poll(&mut events, Some(timeout)) {
+                Ok(()) => {}
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err).context("failed to poll connections"),
+            }
+
+            for event in &events {
+                if event.token() == WAKE {
+                    continue;
+                }
+                if let Some((conn, _)) = self.connections.remove(&event.token()) {
+                    self.deregister(&conn);
+                    self.dispatch(conn);
+                }
+            }
+
+            while let Ok(conn) = self.receiver.try_recv() {
+                self.register(conn);
+            }
+
+            let now = Instant::now();
+            let expired: Vec<_> = self
+                .connections
+                .iter()
+                .filter(|(_, (_, deadline))| *deadline <= now)
+                .map(|(&token, _)| token)
+                .collect();
+            for token in expired {
+                if let Some((conn, _)) = self.connections.remove(&token) {
+                    log::info!("id = {}, closing idle connection", conn.id);
+                    self.deregister(&conn);
+                    close(conn);
+                }
+            }
+        }
+
+        // kept-alive connections are closed once the server stops accepting new ones
+        for (_, (conn, _)) in self.connections.drain() {
+            close(conn);
+        }
+        while let Ok(conn) = self.receiver.try_recv() {
+            close(conn);
+        }
+        Ok(())
+    }
+
+    fn register(&mut self, conn: Http1Connection) {
+        let token = Token(self.next_token);
+        self.next_token = (self.next_token + 1) % WAKE.0;
+
+        // a connection that's readable already is reported by the next poll
+        let fd = conn.socket().as_raw_fd();
+        if let Err(err) =
+            self.poll
+                .registry()
+                .register(&mut SourceFd(&fd), token, Interest::READABLE)
+        {
+            log::error!("id = {}, failed to wait on connection: {err}", conn.id);
+            close(conn);
+            return;
+        }
+
+        let deadline = Instant::now() + self.site.get().config.idle_timeout;
+        self.connections.insert(token, (conn, deadline));
+    }
+
+    fn deregister(&self, conn: &Http1Connection) {
+        let fd = conn.socket().as_raw_fd();
+        if let Err(err) = self.poll.registry().deregister(&mut SourceFd(&fd)) {
+            log::error!(
+                "id = {}, failed to stop waiting on connection: {err}",
+                conn.id
+            );
+        }
+    }
+
+    fn dispatch(&self, conn: Http1Connection) {
+        let Site { config, router } = self.site.get();
+        let stats = Arc::clone(&self.stats);
+        let event_loop = Arc::clone(&self.event_loop);
+        stats.queued_connections.fetch_add(1, Ordering::SeqCst);
+        self.pool.execute(move || {
+            stats.queued_connections.fetch_sub(1, Ordering::SeqCst);
+            stats.active_connections.fetch_add(1, Ordering::SeqCst);
+            if let Err(err) = resume(conn, &config, &stats, &router, Some(&event_loop)) {
+                log::error!("error while handling connection: {err}");
+            }
+            stats.active_connections.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+fn close(conn: Http1Connection) {
+    if let Err(err) = conn.close() {
+        log::error!("error while closing connection: {err:#}");
+    }
+}
@@ -0,0 +1,235 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use crate::{header::Encoding, response::compress_bytes};
+
+// larger files are read from disk every time, sendfile serves them well enough
+const MAX_CACHED_FILE_SIZE: u64 = 1024 * 1024;
+
+// the contents of recently served files under `/files/`, shared by every worker; once they take up
+// more than its capacity the least recently used are dropped, and a file that changed on disk since
+// it was read, going by its modification time and length, is read again
+#[derive(Debug)]
+pub(crate) struct FileCache {
+    capacity: u64,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    entries: HashMap<PathBuf, Entry>,
+    // bytes held by every entry, compressed copies included
+    size: u64,
+    // goes up with every use, so the entry with the lowest `last_used` is the least recently used
+    clock: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    file: CachedFile,
+    // compressed the first time a client asks for it
+    gzip: Option<Arc<[u8]>>,
+    last_used: u64,
+}
+
+impl Entry {
+    fn size(&self) -> u64 {
+        (self.file.contents.len() + self.gzip.as_ref().map_or(0, |gzip| gzip.len())) as u64
+    }
+}
+
+// a file's contents, with the metadata its headers are made from
+#[derive(Debug, Clone)]
+pub(crate) struct CachedFile {
+    pub(crate) contents: Arc<[u8]>,
+    pub(crate) metadata: fs::Metadata,
+}
+
+impl FileCache {
+    pub(crate) fn new(capacity: u64) -> Self {
+        Self {
+            capacity,
+            state: Mutex::default(),
+        }
+    }
+
+    // the file at `path`, which `metadata` describes as it is now; `None` for files too large to
+    // keep, or that changed while they were read, which are best served from disk
+    pub(crate) fn get(&self, path: &Path, metadata: &fs::Metadata) -> Option<CachedFile> {
+        // without a modification time there's no telling whether a file changed
+        let modified = metadata.modified().ok()?;
+        if !metadata.is_file() || metadata.len() > MAX_CACHED_FILE_SIZE.min(self.capacity) {
+            return None;
+        }
+
+        {
+            let mut state = self.state();
+            state.clock += 1;
+            let clock = state.clock;
+            if let Some(entry) = state.entries.get_mut(path) {
+                if entry.file.metadata.modified().ok() == Some(modified)
+                    && entry.file.metadata.len() == metadata.len()
+                {
+                    entry.last_used = clock;
+                    return Some(entry.file.clone());
+                }
+            }
+        }
+
+        // read without holding the lock, so that other files are served in the meantime
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(err) => {
+                log::debug!("failed to read {path:?} into the cache: {err}");
+                return None;
+            }
+        };
+        if contents.len() as u64 != metadata.len() {
+            return None;
+        }
+
+        let file = CachedFile {
+            contents: contents.into(),
+            metadata: metadata.clone(),
+        };
+        let mut state = self.state();
+        state.clock += 1;
+        let entry = Entry {
+            file: file.clone(),
+            gzip: None,
+            last_used: state.clock,
+        };
+        state.size += entry.size();
+        if let Some(old) = state.entries.insert(path.to_owned(), entry) {
+            state.size -= old.size();
+        }
+        self.evict(&mut state);
+        Some(file)
+    }
+
+    // `file` compressed with gzip, which is only done once for as long as it stays cached
+    pub(crate) fn gzip(&self, path: &Path, file: &CachedFile, level: u32) -> Option<Arc<[u8]>> {
+        let is_entry = |entry: &&mut Entry| Arc::ptr_eq(&entry.file.contents, &file.contents);
+        if let Some(gzip) = self
+            .state()
+            .entries
+            .get_mut(path)
+            .filter(is_entry)
+            .and_then(|entry| entry.gzip.clone())
+        {
+            return Some(gzip);
+        }
+
+        let gzip: Arc<[u8]> = match compress_bytes(&file.contents, Encoding::Gzip, level) {
+            Ok(gzip) => gzip.into(),
+            Err(err) => {
+                log::error!("failed to compress {path:?} with gzip: {err}");
+                return None;
+            }
+        };
+
+        let mut state = self.state();
+        if let Some(entry) = state.entries.get_mut(path).filter(is_entry) {
+            if entry.gzip.is_none() {
+                entry.gzip = Some(Arc::clone(&gzip));
+                state.size += gzip.len() as u64;
+                self.evict(&mut state);
+            }
+        }
+        Some(gzip)
+    }
+
+    // forgets the file at `path`, once it's been written or deleted through `/files/`
+    pub(crate) fn remove(&self, path: &Path) {
+        let mut state = self.state();
+        if let Some(entry) = state.entries.remove(path) {
+            state.size -= entry.size();
+        }
+    }
+
+    // forgets every file in `dir`, once it's been deleted through `/files/`
+    pub(crate) fn remove_all_under(&self, dir: &Path) {
+        let mut state = self.state();
+        let mut removed = 0;
+        state.entries.retain(|path, entry| {
+            let keep = !path.starts_with(dir);
+            if !keep {
+                removed += entry.size();
+            }
+            keep
+        });
+        state.size -= removed;
+    }
+
+    fn evict(&self, state: &mut State) {
+        while state.size > self.capacity {
+            let Some(path) = state
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(path, _)| path.clone())
+            else {
+                return;
+            };
+            if let Some(entry) = state.entries.remove(&path) {
+                state.size -= entry.size();
+            }
+        }
+    }
+
+    // a worker that panicked while holding the lock doesn't leave the cache unusable
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use super::*;
+
+    fn write(dir: &Path, name: &str, contents: &str) -> (PathBuf, fs::Metadata) {
+        let path = dir.join(name);
+        fs::write(&path, contents).unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        (path, metadata)
+    }
+
+    #[test]
+    fn file_cache_drops_the_least_recently_used_files() {
+        let dir = std::env::temp_dir().join(format!("butler-file-cache-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cache = FileCache::new(10);
+
+        let (a, a_metadata) = write(&dir, "a", "aaaa");
+        let (b, b_metadata) = write(&dir, "b", "bbbb");
+        let a_contents = cache.get(&a, &a_metadata).unwrap().contents;
+        cache.get(&b, &b_metadata).unwrap();
+        // `a` is used again, so `b` is the one dropped to make room for `c`
+        assert!(Arc::ptr_eq(
+            &cache.get(&a, &a_metadata).unwrap().contents,
+            &a_contents
+        ));
+        let (c, c_metadata) = write(&dir, "c", "cccc");
+        cache.get(&c, &c_metadata).unwrap();
+        let state = cache.state();
+        assert!(state.entries.contains_key(&a) && !state.entries.contains_key(&b));
+        assert_eq!(state.size, 8);
+        drop(state);
+
+        // a file that has changed is read again
+        thread::sleep(Duration::from_millis(10));
+        let (a, a_metadata) = write(&dir, "a", "AAAA");
+        assert_eq!(&*cache.get(&a, &a_metadata).unwrap().contents, b"AAAA");
+
+        // and one that doesn't fit isn't kept at all
+        let (big, big_metadata) = write(&dir, "big", "too big to cache");
+        assert!(cache.get(&big, &big_metadata).is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
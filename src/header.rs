@@ -0,0 +1,668 @@
+use std::{fmt, path::Path, str::FromStr, time::SystemTime};
+
+use anyhow::{anyhow, Context};
+
+use crate::{
+    date::{http_date, parse_http_date},
+    request::Method,
+    response::content_type_from_extension,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Header {
+    ContentType(ContentType),
+    ContentLength(u64),
+    UserAgent(String),
+    Host(String),
+    AcceptEncoding(Vec<AcceptedEncoding>),
+    ContentEncoding(Encoding),
+    Connection(ConnectionMode),
+    Allow(Vec<Method>),
+    // kept unparsed so that a malformed range can be answered with a 416
+    Range(String),
+    ContentRange(ContentRange),
+    // byte ranges are the only kind we serve
+    AcceptRanges,
+    TransferEncoding(TransferCoding),
+    // entity tags keep their quotes and weakness prefix, e.g. `W/"abc"`
+    ETag(String),
+    IfNoneMatch(Vec<String>),
+    IfMatch(Vec<String>),
+    LastModified(SystemTime),
+    IfModifiedSince(SystemTime),
+    Date(SystemTime),
+    Server(String),
+    // the only expectation defined is `100-continue`, anything else is answered with a 417
+    Expect(String),
+    // any header without a variant of its own, as a name and value
+    Other(String, String),
+}
+
+impl fmt::Display for Header {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ContentType(content_type) => write!(f, "Content-Type: {content_type}"),
+            Self::ContentLength(length) => write!(f, "Content-Length: {length}"),
+            Self::ContentEncoding(encoding) => write!(f, "Content-Encoding: {encoding}"),
+            Self::Host(host) => write!(f, "Host: {host}"),
+            Self::Connection(mode) => write!(f, "Connection: {mode}"),
+            Self::ContentRange(range) => write!(f, "Content-Range: {range}"),
+            Self::AcceptRanges => f.write_str("Accept-Ranges: bytes"),
+            Self::ETag(etag) => write!(f, "ETag: {etag}"),
+            Self::Date(time) => write!(f, "Date: {}", http_date(*time)),
+            Self::LastModified(time) => write!(f, "Last-Modified: {}", http_date(*time)),
+            Self::Server(server) => write!(f, "Server: {server}"),
+            Self::Expect(expectation) => write!(f, "Expect: {expectation}"),
+            Self::Other(name, value) => write!(f, "{name}: {value}"),
+            Self::TransferEncoding(coding) => write!(f, "Transfer-Encoding: {coding}"),
+            Self::Allow(methods) => write!(
+                f,
+                "Allow: {}",
+                methods
+                    .iter()
+                    .map(Method::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Self::UserAgent(agent) => write!(f, "User-Agent: {agent}"),
+            Self::Range(range) => write!(f, "Range: {range}"),
+            Self::IfNoneMatch(tags) => write!(f, "If-None-Match: {}", tags.join(", ")),
+            Self::IfMatch(tags) => write!(f, "If-Match: {}", tags.join(", ")),
+            Self::IfModifiedSince(time) => write!(f, "If-Modified-Since: {}", http_date(*time)),
+            Self::AcceptEncoding(accepted) => write!(
+                f,
+                "Accept-Encoding: {}",
+                accepted
+                    .iter()
+                    .map(AcceptedEncoding::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+impl Header {
+    pub fn name(&self) -> &str {
+        match self {
+            Self::ContentType(_) => "Content-Type",
+            Self::ContentLength(_) => "Content-Length",
+            Self::UserAgent(_) => "User-Agent",
+            Self::Host(_) => "Host",
+            Self::AcceptEncoding(_) => "Accept-Encoding",
+            Self::ContentEncoding(_) => "Content-Encoding",
+            Self::Connection(_) => "Connection",
+            Self::Allow(_) => "Allow",
+            Self::Range(_) => "Range",
+            Self::ContentRange(_) => "Content-Range",
+            Self::AcceptRanges => "Accept-Ranges",
+            Self::TransferEncoding(_) => "Transfer-Encoding",
+            Self::ETag(_) => "ETag",
+            Self::IfNoneMatch(_) => "If-None-Match",
+            Self::IfMatch(_) => "If-Match",
+            Self::LastModified(_) => "Last-Modified",
+            Self::IfModifiedSince(_) => "If-Modified-Since",
+            Self::Date(_) => "Date",
+            Self::Server(_) => "Server",
+            Self::Expect(_) => "Expect",
+            Self::Other(name, _) => name,
+        }
+    }
+}
+
+impl FromStr for Header {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // only the first ':' separates the name, values such as `localhost:4221` may contain more
+        let (name, value) = s
+            .split_once(':')
+            .context("failed to find header value, maybe it's missing a ':'?")?;
+        let (name, value) = (name.trim(), value.trim());
+
+        match name.to_lowercase().as_ref() {
+            "user-agent" => Ok(Self::UserAgent(value.to_owned())),
+            "host" => Ok(Self::Host(value.to_owned())),
+            "connection" => Ok(Self::Connection(value.parse()?)),
+            "range" => Ok(Self::Range(value.to_owned())),
+            "expect" => Ok(Self::Expect(value.to_owned())),
+            "transfer-encoding" => Ok(Self::TransferEncoding(value.parse()?)),
+            "content-type" => Ok(Self::ContentType(value.parse()?)),
+            "if-none-match" => Ok(Self::IfNoneMatch(parse_entity_tags(value))),
+            "if-match" => Ok(Self::IfMatch(parse_entity_tags(value))),
+            "if-modified-since" => Ok(Self::IfModifiedSince(
+                parse_http_date(value)
+                    .with_context(|| anyhow!("{value:?} is not a valid HTTP date"))?,
+            )),
+            "content-length" => Ok(Self::ContentLength(parse_content_length(value)?)),
+            "accept-encoding" => Ok(Self::AcceptEncoding(parse_accept_encoding(value))),
+            name => Err(anyhow!("unknown header: {name:?}")),
+        }
+    }
+}
+
+// a length, or a list that repeats the same one as a proxy that merged repeated headers would
+// send, e.g. `42, 42`
+fn parse_content_length(value: &str) -> anyhow::Result<u64> {
+    let invalid = || anyhow!("failed to parse 'Content-Length': {value:?} is not a valid length");
+    let lengths = value
+        .split(',')
+        .map(|length| {
+            let length = length.trim();
+            // `parse` would take a leading '+', which isn't part of a valid length
+            if !length.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(invalid());
+            }
+            length.parse::<u64>().map_err(|_| invalid())
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    match lengths[..] {
+        [first, ref rest @ ..] if rest.iter().all(|length| *length == first) => Ok(first),
+        _ => Err(anyhow!(
+            "failed to parse 'Content-Length': {value:?} lists conflicting lengths"
+        )),
+    }
+}
+
+// what header names, cookie names and the like may be made of
+pub(crate) fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+// what header values may be made of: anything but control characters, tabs aside; a bare LF or CR
+// could end the line early for whoever reads it next, even where the parser reading it first didn't
+pub(crate) fn is_field_value(s: &str) -> bool {
+    s.chars().all(|c| c == '\t' || !c.is_ascii_control())
+}
+
+// a comma-separated list of entity tags, or `*`
+fn parse_entity_tags(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|tag| tag.trim().to_owned())
+        .filter(|tag| !tag.is_empty())
+        .collect()
+}
+
+// headers by name, compared case-insensitively, in the order they were added;
+// a name may appear more than once
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeaderMap {
+    entries: Vec<(String, String)>,
+}
+
+impl HeaderMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // the first value of the header called `name`
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find_map(|(n, value)| n.eq_ignore_ascii_case(name).then_some(value.as_str()))
+    }
+
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // every value of the header called `name` joined with ", ", the way repeated list headers
+    // such as `Accept` or `Cache-Control` are meant to be read; not for `Set-Cookie` or `Cookie`,
+    // whose values can't be split up again
+    pub fn get_combined(&self, name: &str) -> Option<String> {
+        let values: Vec<_> = self.get_all(name).collect();
+        (!values.is_empty()).then(|| values.join(", "))
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    // adds a value without replacing any existing ones of the same name
+    pub fn append(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.entries.push((name.into(), value.into()));
+    }
+
+    // removes every value of the header called `name`
+    pub fn remove(&mut self, name: &str) {
+        self.entries.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    }
+
+    // names keep the case they were added with
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ByteRange {
+    // `bytes=start-end`, both ends inclusive
+    Bounded(u64, u64),
+    // `bytes=start-`
+    From(u64),
+    // `bytes=-length`, the last `length` bytes
+    Suffix(u64),
+}
+
+impl ByteRange {
+    // returns the inclusive start and end offsets of the range in a file of length `len`
+    pub(crate) fn resolve(self, len: u64) -> Option<(u64, u64)> {
+        match self {
+            Self::Bounded(start, end) if start <= end && start < len => {
+                Some((start, end.min(len - 1)))
+            }
+            Self::From(start) if start < len => Some((start, len - 1)),
+            Self::Suffix(length) if length > 0 && len > 0 => Some((len - length.min(len), len - 1)),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for ByteRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let spec = s.trim().strip_prefix("bytes=").with_context(|| {
+            anyhow!("unsupported range unit in {s:?}, only 'bytes' is supported")
+        })?;
+
+        if spec.contains(',') {
+            return Err(anyhow!("multiple ranges are not supported: {s:?}"));
+        }
+
+        let (start, end) = spec
+            .split_once('-')
+            .with_context(|| anyhow!("range {s:?} is missing a '-'"))?;
+
+        let parse = |offset: &str| {
+            offset
+                .trim()
+                .parse::<u64>()
+                .with_context(|| anyhow!("{offset:?} is not a valid range offset"))
+        };
+
+        match (start.trim(), end.trim()) {
+            ("", "") => Err(anyhow!("range {s:?} has neither a start nor an end")),
+            ("", length) => Ok(Self::Suffix(parse(length)?)),
+            (start, "") => Ok(Self::From(parse(start)?)),
+            (start, end) => Ok(Self::Bounded(parse(start)?, parse(end)?)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentRange {
+    // `None` when the requested range could not be satisfied
+    pub range: Option<(u64, u64)>,
+    pub complete_length: u64,
+}
+
+impl fmt::Display for ContentRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.range {
+            Some((start, end)) => write!(f, "bytes {start}-{end}/{}", self.complete_length),
+            None => write!(f, "bytes */{}", self.complete_length),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionMode {
+    KeepAlive,
+    Close,
+}
+
+impl fmt::Display for ConnectionMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionMode::KeepAlive => f.write_str("keep-alive"),
+            ConnectionMode::Close => f.write_str("close"),
+        }
+    }
+}
+
+impl FromStr for ConnectionMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // a list of options such as `keep-alive, Upgrade`, where `close` wins over `keep-alive`
+        let options: Vec<_> = s
+            .split(',')
+            .map(|option| option.trim().to_lowercase())
+            .collect();
+        if options.iter().any(|option| option == "close") {
+            Ok(Self::Close)
+        } else if options.iter().any(|option| option == "keep-alive") {
+            Ok(Self::KeepAlive)
+        } else {
+            Err(anyhow!("failed to parse 'Connection': unknown mode {s:?}"))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferCoding {
+    Chunked,
+}
+
+impl fmt::Display for TransferCoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferCoding::Chunked => f.write_str("chunked"),
+        }
+    }
+}
+
+impl FromStr for TransferCoding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "chunked" => Ok(Self::Chunked),
+            _ => Err(anyhow!(
+                "failed to parse 'Transfer-Encoding': unsupported coding {s:?}"
+            )),
+        }
+    }
+}
+
+// the `brotli` and `zstd` features add the codings of the same names, `br` for brotli
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Deflate,
+    #[cfg(feature = "brotli")]
+    Brotli,
+    #[cfg(feature = "zstd")]
+    Zstd,
+    Identity,
+}
+
+impl Encoding {
+    // every coding responses can be compressed with, in the order a `*` in `Accept-Encoding` adds them
+    pub(crate) const COMPRESSING: &[Self] = &[
+        Self::Gzip,
+        Self::Deflate,
+        #[cfg(feature = "brotli")]
+        Self::Brotli,
+        #[cfg(feature = "zstd")]
+        Self::Zstd,
+    ];
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Encoding::Gzip => f.write_str("gzip"),
+            Encoding::Deflate => f.write_str("deflate"),
+            #[cfg(feature = "brotli")]
+            Encoding::Brotli => f.write_str("br"),
+            #[cfg(feature = "zstd")]
+            Encoding::Zstd => f.write_str("zstd"),
+            Encoding::Identity => f.write_str("identity"),
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.trim();
+
+        match name.to_lowercase().as_ref() {
+            "gzip" => Ok(Self::Gzip),
+            "deflate" => Ok(Self::Deflate),
+            #[cfg(feature = "brotli")]
+            "br" => Ok(Self::Brotli),
+            #[cfg(feature = "zstd")]
+            "zstd" => Ok(Self::Zstd),
+            "identity" => Ok(Self::Identity),
+            _ => Err(anyhow!("unsupported encoding {name:?}")),
+        }
+    }
+}
+
+// an entry of `Accept-Encoding`, with its q-value in thousandths so that 1000 is `q=1`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AcceptedEncoding {
+    pub encoding: Encoding,
+    pub quality: u16,
+}
+
+impl fmt::Display for AcceptedEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.quality == 1000 {
+            return write!(f, "{}", self.encoding);
+        }
+
+        let thousandths = format!("{:03}", self.quality);
+        match thousandths.trim_end_matches('0') {
+            "" => write!(f, "{};q=0", self.encoding),
+            fraction => write!(f, "{};q=0.{fraction}", self.encoding),
+        }
+    }
+}
+
+// codings we don't support are left out, and `*` stands for every supported one not listed
+fn parse_accept_encoding(value: &str) -> Vec<AcceptedEncoding> {
+    let mut accepted = Vec::new();
+    let mut wildcard = None;
+
+    for element in value
+        .split(',')
+        .filter(|element| !element.trim().is_empty())
+    {
+        let (name, params) = element.split_once(';').unwrap_or((element, ""));
+        let name = name.trim();
+
+        let quality = match parse_quality(params) {
+            Ok(quality) => quality,
+            Err(err) => {
+                log::debug!("ignoring accepted encoding {name:?}: {err}");
+                continue;
+            }
+        };
+
+        if name == "*" {
+            wildcard = Some(quality);
+            continue;
+        }
+
+        match name.parse() {
+            Ok(encoding) => accepted.push(AcceptedEncoding { encoding, quality }),
+            Err(err) => log::debug!("ignoring accepted encoding: {err}"),
+        }
+    }
+
+    if let Some(quality) = wildcard {
+        for &encoding in Encoding::COMPRESSING {
+            if !accepted
+                .iter()
+                .any(|accepted| accepted.encoding == encoding)
+            {
+                accepted.push(AcceptedEncoding { encoding, quality });
+            }
+        }
+    }
+
+    accepted
+}
+
+// the media ranges of an `Accept` header, lowercase and without parameters, with their q-values in
+// thousandths, e.g. `("text/*", 500)` for `text/*;q=0.5`; ranges with an invalid q-value are left out
+pub(crate) fn parse_accept(value: &str) -> Vec<(String, u16)> {
+    let mut accepted = Vec::new();
+    for element in value
+        .split(',')
+        .filter(|element| !element.trim().is_empty())
+    {
+        let (range, params) = element.split_once(';').unwrap_or((element, ""));
+        let range = range.trim().to_lowercase();
+        match parse_quality(params) {
+            Ok(quality) => accepted.push((range, quality)),
+            Err(err) => log::debug!("ignoring accepted type {range:?}: {err}"),
+        }
+    }
+    accepted
+}
+
+// the q-value of the most specific range in `accepted` that covers `content_type`, where `text/html`
+// beats `text/*`, which beats `*/*`; 0 when none covers it
+pub(crate) fn accepted_quality(accepted: &[(String, u16)], content_type: &ContentType) -> u16 {
+    let essence = content_type.essence();
+    let kind = essence.split('/').next().unwrap_or_default();
+    accepted
+        .iter()
+        .filter_map(|(range, quality)| {
+            let specificity = match range.split_once('/')? {
+                _ if *range == essence => 3,
+                (range_kind, "*") if range_kind == kind => 2,
+                ("*", "*") => 1,
+                _ => return None,
+            };
+            Some((specificity, *quality))
+        })
+        .max_by_key(|(specificity, _)| *specificity)
+        .map_or(0, |(_, quality)| quality)
+}
+
+// parses the `q` parameter among `params`, which defaults to 1
+fn parse_quality(params: &str) -> anyhow::Result<u16> {
+    let Some(q) = params.split(';').find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("q")
+            .then_some(value.trim())
+    }) else {
+        return Ok(1000);
+    };
+
+    let invalid = || anyhow!("invalid quality value {q:?}");
+    let (whole, fraction) = q.split_once('.').unwrap_or((q, ""));
+    if fraction.len() > 3 || !fraction.bytes().all(|byte| byte.is_ascii_digit()) {
+        return Err(invalid());
+    }
+
+    let fraction = format!("{fraction:0<3}")
+        .parse::<u16>()
+        .map_err(|_| invalid())?;
+    match whole {
+        "0" => Ok(fraction),
+        "1" if fraction == 0 => Ok(1000),
+        _ => Err(invalid()),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum ContentType {
+    #[default]
+    TextPlain,
+    TextHtml,
+    TextCss,
+    TextJavascript,
+    ApplicationJson,
+    ApplicationOctetStream,
+    ImagePng,
+    ImageJpeg,
+    // anything else, including known types with parameters, is kept exactly as the client sent it
+    Other(String),
+}
+
+impl fmt::Display for ContentType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContentType::TextPlain => f.write_str("text/plain"),
+            ContentType::TextHtml => f.write_str("text/html"),
+            ContentType::TextCss => f.write_str("text/css"),
+            ContentType::TextJavascript => f.write_str("text/javascript"),
+            ContentType::ApplicationJson => f.write_str("application/json"),
+            ContentType::ApplicationOctetStream => f.write_str("application/octet-stream"),
+            ContentType::ImagePng => f.write_str("image/png"),
+            ContentType::ImageJpeg => f.write_str("image/jpeg"),
+            ContentType::Other(content_type) => f.write_str(content_type),
+        }
+    }
+}
+
+impl ContentType {
+    // the type a file is served with when nothing says otherwise, going by its extension
+    pub fn from_path(path: &Path) -> Self {
+        content_type_from_extension(path)
+    }
+
+    // formats whose data is compressed already, so compressing them again only costs time
+    pub fn is_compressed(&self) -> bool {
+        match self {
+            Self::ImagePng | Self::ImageJpeg => true,
+            Self::Other(_) => {
+                let essence = self.essence();
+                essence.starts_with("audio/")
+                    || essence.starts_with("video/")
+                    || matches!(
+                        essence.as_str(),
+                        "image/gif"
+                            | "image/webp"
+                            | "image/avif"
+                            | "font/woff"
+                            | "font/woff2"
+                            | "application/zip"
+                            | "application/gzip"
+                    )
+            }
+            _ => false,
+        }
+    }
+
+    // the lowercase media type without parameters, e.g. `text/html` for `text/html; charset=utf-8`
+    pub(crate) fn essence(&self) -> String {
+        let content_type = self.to_string();
+        content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_lowercase()
+    }
+}
+
+impl FromStr for ContentType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if !s.contains('/') {
+            return Err(anyhow!(
+                "failed to parse content type: {s:?} is not a media type"
+            ));
+        }
+
+        match s.to_lowercase().as_str() {
+            "text/plain" => Ok(Self::TextPlain),
+            "text/html" => Ok(Self::TextHtml),
+            "text/css" => Ok(Self::TextCss),
+            "text/javascript" => Ok(Self::TextJavascript),
+            "application/json" => Ok(Self::ApplicationJson),
+            "application/octet-stream" => Ok(Self::ApplicationOctetStream),
+            "image/png" => Ok(Self::ImagePng),
+            "image/jpeg" => Ok(Self::ImageJpeg),
+            _ => Ok(Self::Other(s.to_owned())),
+        }
+    }
+}
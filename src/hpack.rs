@@ -0,0 +1,770 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::OnceLock,
+};
+
+use anyhow::{anyhow, Context};
+
+// every entry counts this much towards the dynamic table's size, on top of its name and value
+const ENTRY_OVERHEAD: usize = 32;
+
+// decodes the header blocks of one connection, which share a dynamic table
+#[derive(Debug)]
+pub(crate) struct Decoder {
+    // the most recently added entry comes first
+    table: VecDeque<(String, String)>,
+    size: usize,
+    max_size: usize,
+    // the size we told the peer its updates may go up to
+    size_limit: usize,
+    // the header list size, as SETTINGS_MAX_HEADER_LIST_SIZE counts it, and the number of fields a
+    // block may decode to
+    max_list_size: usize,
+    max_fields: usize,
+}
+
+// a block that decoded to more than `Decoder::with_list_limits` allows; it was decoded in full all
+// the same, so the table is still in sync and only the stream has to be refused
+#[derive(Debug)]
+pub(crate) struct HeaderListTooLarge;
+
+impl fmt::Display for HeaderListTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("header list is too large")
+    }
+}
+
+impl std::error::Error for HeaderListTooLarge {}
+
+impl Decoder {
+    pub(crate) fn new(size_limit: usize) -> Self {
+        Self {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: size_limit,
+            size_limit,
+            max_list_size: usize::MAX,
+            max_fields: usize::MAX,
+        }
+    }
+
+    // a few bytes referring to a large table entry over and over can decode to far more than the
+    // block's size, so the fields are only kept while they're within these limits
+    pub(crate) fn with_list_limits(mut self, max_list_size: usize, max_fields: usize) -> Self {
+        self.max_list_size = max_list_size;
+        self.max_fields = max_fields;
+        self
+    }
+
+    // returns the header fields of a complete block, in the order they were sent, or a
+    // `HeaderListTooLarge`; any other error leaves the table out of sync with the peer's, so the
+    // connection can't go on
+    pub(crate) fn decode(&mut self, mut block: &[u8]) -> anyhow::Result<Vec<(String, String)>> {
+        let mut list = HeaderList {
+            fields: Vec::new(),
+            size: 0,
+            count: 0,
+            max_size: self.max_list_size,
+            max_fields: self.max_fields,
+        };
+
+        while let Some(&first) = block.first() {
+            if first & 0x80 != 0 {
+                let index = decode_integer(&mut block, 7)?;
+                let (name, value) = self.get(index)?;
+                list.push(name, value);
+            } else if first & 0x40 != 0 {
+                let (name, value) = self.decode_literal(&mut block, 6)?;
+                list.push(&name, &value);
+                self.insert((name, value));
+            } else if first & 0x20 != 0 {
+                if list.count > 0 {
+                    return Err(anyhow!(
+                        "table size updates have to come before the header fields"
+                    ));
+                }
+                let max_size = decode_integer(&mut block, 5)?;
+                if max_size > self.size_limit {
+                    return Err(anyhow!(
+                        "table size {max_size} is larger than the {} allowed",
+                        self.size_limit
+                    ));
+                }
+                self.max_size = max_size;
+                self.evict();
+            } else {
+                // literals without indexing and never indexed ones only differ for intermediaries
+                let (name, value) = self.decode_literal(&mut block, 4)?;
+                list.push(&name, &value);
+            }
+        }
+
+        if list.fields.len() < list.count {
+            return Err(HeaderListTooLarge.into());
+        }
+        Ok(list.fields)
+    }
+
+    fn decode_literal(
+        &self,
+        block: &mut &[u8],
+        prefix_bits: u8,
+    ) -> anyhow::Result<(String, String)> {
+        let name = match decode_integer(block, prefix_bits)? {
+            0 => decode_string(block)?,
+            index => self.get(index)?.0.to_owned(),
+        };
+        let value = decode_string(block)?;
+        Ok((name, value))
+    }
+
+    fn get(&self, index: usize) -> anyhow::Result<(&str, &str)> {
+        match index {
+            0 => Err(anyhow!("index 0 doesn't name a header field")),
+            1..=61 => Ok(STATIC_TABLE[index - 1]),
+            _ => self
+                .table
+                .get(index - STATIC_TABLE.len() - 1)
+                .map(|(name, value)| (name.as_str(), value.as_str()))
+                .with_context(|| anyhow!("index {index} is past the end of the table")),
+        }
+    }
+
+    fn insert(&mut self, field: (String, String)) {
+        // an entry larger than the whole table just empties it
+        self.size += entry_size(&field.0, &field.1);
+        self.table.push_front(field);
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.size > self.max_size {
+            let Some(field) = self.table.pop_back() else {
+                break;
+            };
+            self.size -= entry_size(&field.0, &field.1);
+        }
+    }
+}
+
+// the fields a block decodes to, for as long as they're within the decoder's limits
+struct HeaderList {
+    fields: Vec<(String, String)>,
+    // of every field decoded, kept or not
+    size: usize,
+    count: usize,
+    max_size: usize,
+    max_fields: usize,
+}
+
+impl HeaderList {
+    // once the list has gone over the limits, none of its fields are kept
+    fn push(&mut self, name: &str, value: &str) {
+        self.size += entry_size(name, value);
+        self.count += 1;
+        if self.size > self.max_size || self.count > self.max_fields {
+            self.fields = Vec::new();
+        } else {
+            self.fields.push((name.to_owned(), value.to_owned()));
+        }
+    }
+}
+
+// also how much a field counts towards the size of a header list
+fn entry_size(name: &str, value: &str) -> usize {
+    name.len() + value.len() + ENTRY_OVERHEAD
+}
+
+// encodes `fields` as literals the peer doesn't add to its dynamic table, so there's no table to
+// keep in sync on our side and whatever table size the peer picks is fine
+pub(crate) fn encode(fields: &[(&str, &str)]) -> Vec<u8> {
+    let mut block = Vec::new();
+
+    for &(name, value) in fields {
+        if let Some(i) = STATIC_TABLE
+            .iter()
+            .position(|&field| field == (name, value))
+        {
+            encode_integer(&mut block, 0x80, 7, i + 1);
+            continue;
+        }
+
+        match STATIC_TABLE.iter().position(|&(n, _)| n == name) {
+            Some(i) => encode_integer(&mut block, 0, 4, i + 1),
+            None => {
+                block.push(0);
+                encode_string(&mut block, name);
+            }
+        }
+        encode_string(&mut block, value);
+    }
+
+    block
+}
+
+// integers fill the low `prefix_bits` of their first byte, continuing 7 bits at a time once
+// they don't fit
+fn decode_integer(block: &mut &[u8], prefix_bits: u8) -> anyhow::Result<usize> {
+    let mut next = || {
+        let (&byte, rest) = block
+            .split_first()
+            .context("header block ends in the middle of an integer")?;
+        *block = rest;
+        anyhow::Ok(byte)
+    };
+
+    let max_prefix = (1 << prefix_bits) - 1;
+    let mut value = usize::from(next()? & max_prefix);
+    if value < usize::from(max_prefix) {
+        return Ok(value);
+    }
+
+    let mut shift = 0;
+    loop {
+        if shift > 28 {
+            return Err(anyhow!("integer in header block is too large"));
+        }
+        let byte = next()?;
+        value += usize::from(byte & 0x7f) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+fn encode_integer(block: &mut Vec<u8>, flags: u8, prefix_bits: u8, value: usize) {
+    let max_prefix = (1 << prefix_bits) - 1;
+    if value < max_prefix {
+        block.push(flags | value as u8);
+        return;
+    }
+
+    block.push(flags | max_prefix as u8);
+    let mut value = value - max_prefix;
+    while value >= 0x80 {
+        block.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    block.push(value as u8);
+}
+
+fn decode_string(block: &mut &[u8]) -> anyhow::Result<String> {
+    let huffman = block.first().is_some_and(|&first| first & 0x80 != 0);
+    let len = decode_integer(block, 7)?;
+    if len > block.len() {
+        return Err(anyhow!("header block ends in the middle of a string"));
+    }
+
+    let (bytes, rest) = block.split_at(len);
+    *block = rest;
+
+    // values may carry arbitrary bytes, which we don't interpret
+    let bytes = if huffman {
+        huffman_decode(bytes)?
+    } else {
+        bytes.to_vec()
+    };
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+// strings are sent as-is, Huffman coding them is optional
+fn encode_string(block: &mut Vec<u8>, s: &str) {
+    encode_integer(block, 0, 7, s.len());
+    block.extend_from_slice(s.as_bytes());
+}
+
+fn huffman_decode(bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+    static SYMBOLS: OnceLock<HashMap<(u8, u32), u16>> = OnceLock::new();
+    let symbols = SYMBOLS.get_or_init(|| {
+        (0..)
+            .zip(HUFFMAN_CODES)
+            .map(|(symbol, (code, len))| ((len, code), symbol))
+            .collect()
+    });
+
+    let mut decoded = Vec::with_capacity(bytes.len() * 8 / 5);
+    let (mut code, mut len) = (0, 0);
+    for byte in bytes {
+        for bit in (0..8).rev() {
+            code = code << 1 | u32::from(byte >> bit & 1);
+            len += 1;
+
+            match symbols.get(&(len, code)) {
+                Some(256) => return Err(anyhow!("Huffman coded string contains EOS")),
+                Some(&symbol) => {
+                    decoded.push(symbol as u8);
+                    (code, len) = (0, 0);
+                }
+                None if len == 30 => return Err(anyhow!("invalid Huffman code")),
+                None => {}
+            }
+        }
+    }
+
+    // whatever is left pads the string to a whole byte with the first bits of EOS, all ones
+    if len > 7 || code != (1 << len) - 1 {
+        return Err(anyhow!("invalid padding in Huffman coded string"));
+    }
+
+    Ok(decoded)
+}
+
+// RFC 7541 appendix A, index 1 is the first entry
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+// RFC 7541 appendix B, the code and its length in bits for every byte value and EOS
+const HUFFMAN_CODES: [(u32, u8); 257] = [
+    (0x1ff8, 13),
+    (0x7fffd8, 23),
+    (0xfffffe2, 28),
+    (0xfffffe3, 28),
+    (0xfffffe4, 28),
+    (0xfffffe5, 28),
+    (0xfffffe6, 28),
+    (0xfffffe7, 28),
+    (0xfffffe8, 28),
+    (0xffffea, 24),
+    (0x3ffffffc, 30),
+    (0xfffffe9, 28),
+    (0xfffffea, 28),
+    (0x3ffffffd, 30),
+    (0xfffffeb, 28),
+    (0xfffffec, 28),
+    (0xfffffed, 28),
+    (0xfffffee, 28),
+    (0xfffffef, 28),
+    (0xffffff0, 28),
+    (0xffffff1, 28),
+    (0xffffff2, 28),
+    (0x3ffffffe, 30),
+    (0xffffff3, 28),
+    (0xffffff4, 28),
+    (0xffffff5, 28),
+    (0xffffff6, 28),
+    (0xffffff7, 28),
+    (0xffffff8, 28),
+    (0xffffff9, 28),
+    (0xffffffa, 28),
+    (0xffffffb, 28),
+    (0x14, 6),
+    (0x3f8, 10),
+    (0x3f9, 10),
+    (0xffa, 12),
+    (0x1ff9, 13),
+    (0x15, 6),
+    (0xf8, 8),
+    (0x7fa, 11),
+    (0x3fa, 10),
+    (0x3fb, 10),
+    (0xf9, 8),
+    (0x7fb, 11),
+    (0xfa, 8),
+    (0x16, 6),
+    (0x17, 6),
+    (0x18, 6),
+    (0x0, 5),
+    (0x1, 5),
+    (0x2, 5),
+    (0x19, 6),
+    (0x1a, 6),
+    (0x1b, 6),
+    (0x1c, 6),
+    (0x1d, 6),
+    (0x1e, 6),
+    (0x1f, 6),
+    (0x5c, 7),
+    (0xfb, 8),
+    (0x7ffc, 15),
+    (0x20, 6),
+    (0xffb, 12),
+    (0x3fc, 10),
+    (0x1ffa, 13),
+    (0x21, 6),
+    (0x5d, 7),
+    (0x5e, 7),
+    (0x5f, 7),
+    (0x60, 7),
+    (0x61, 7),
+    (0x62, 7),
+    (0x63, 7),
+    (0x64, 7),
+    (0x65, 7),
+    (0x66, 7),
+    (0x67, 7),
+    (0x68, 7),
+    (0x69, 7),
+    (0x6a, 7),
+    (0x6b, 7),
+    (0x6c, 7),
+    (0x6d, 7),
+    (0x6e, 7),
+    (0x6f, 7),
+    (0x70, 7),
+    (0x71, 7),
+    (0x72, 7),
+    (0xfc, 8),
+    (0x73, 7),
+    (0xfd, 8),
+    (0x1ffb, 13),
+    (0x7fff0, 19),
+    (0x1ffc, 13),
+    (0x3ffc, 14),
+    (0x22, 6),
+    (0x7ffd, 15),
+    (0x3, 5),
+    (0x23, 6),
+    (0x4, 5),
+    (0x24, 6),
+    (0x5, 5),
+    (0x25, 6),
+    (0x26, 6),
+    (0x27, 6),
+    (0x6, 5),
+    (0x74, 7),
+    (0x75, 7),
+    (0x28, 6),
+    (0x29, 6),
+    (0x2a, 6),
+    (0x7, 5),
+    (0x2b, 6),
+    (0x76, 7),
+    (0x2c, 6),
+    (0x8, 5),
+    (0x9, 5),
+    (0x2d, 6),
+    (0x77, 7),
+    (0x78, 7),
+    (0x79, 7),
+    (0x7a, 7),
+    (0x7b, 7),
+    (0x7ffe, 15),
+    (0x7fc, 11),
+    (0x3ffd, 14),
+    (0x1ffd, 13),
+    (0xffffffc, 28),
+    (0xfffe6, 20),
+    (0x3fffd2, 22),
+    (0xfffe7, 20),
+    (0xfffe8, 20),
+    (0x3fffd3, 22),
+    (0x3fffd4, 22),
+    (0x3fffd5, 22),
+    (0x7fffd9, 23),
+    (0x3fffd6, 22),
+    (0x7fffda, 23),
+    (0x7fffdb, 23),
+    (0x7fffdc, 23),
+    (0x7fffdd, 23),
+    (0x7fffde, 23),
+    (0xffffeb, 24),
+    (0x7fffdf, 23),
+    (0xffffec, 24),
+    (0xffffed, 24),
+    (0x3fffd7, 22),
+    (0x7fffe0, 23),
+    (0xffffee, 24),
+    (0x7fffe1, 23),
+    (0x7fffe2, 23),
+    (0x7fffe3, 23),
+    (0x7fffe4, 23),
+    (0x1fffdc, 21),
+    (0x3fffd8, 22),
+    (0x7fffe5, 23),
+    (0x3fffd9, 22),
+    (0x7fffe6, 23),
+    (0x7fffe7, 23),
+    (0xffffef, 24),
+    (0x3fffda, 22),
+    (0x1fffdd, 21),
+    (0xfffe9, 20),
+    (0x3fffdb, 22),
+    (0x3fffdc, 22),
+    (0x7fffe8, 23),
+    (0x7fffe9, 23),
+    (0x1fffde, 21),
+    (0x7fffea, 23),
+    (0x3fffdd, 22),
+    (0x3fffde, 22),
+    (0xfffff0, 24),
+    (0x1fffdf, 21),
+    (0x3fffdf, 22),
+    (0x7fffeb, 23),
+    (0x7fffec, 23),
+    (0x1fffe0, 21),
+    (0x1fffe1, 21),
+    (0x3fffe0, 22),
+    (0x1fffe2, 21),
+    (0x7fffed, 23),
+    (0x3fffe1, 22),
+    (0x7fffee, 23),
+    (0x7fffef, 23),
+    (0xfffea, 20),
+    (0x3fffe2, 22),
+    (0x3fffe3, 22),
+    (0x3fffe4, 22),
+    (0x7ffff0, 23),
+    (0x3fffe5, 22),
+    (0x3fffe6, 22),
+    (0x7ffff1, 23),
+    (0x3ffffe0, 26),
+    (0x3ffffe1, 26),
+    (0xfffeb, 20),
+    (0x7fff1, 19),
+    (0x3fffe7, 22),
+    (0x7ffff2, 23),
+    (0x3fffe8, 22),
+    (0x1ffffec, 25),
+    (0x3ffffe2, 26),
+    (0x3ffffe3, 26),
+    (0x3ffffe4, 26),
+    (0x7ffffde, 27),
+    (0x7ffffdf, 27),
+    (0x3ffffe5, 26),
+    (0xfffff1, 24),
+    (0x1ffffed, 25),
+    (0x7fff2, 19),
+    (0x1fffe3, 21),
+    (0x3ffffe6, 26),
+    (0x7ffffe0, 27),
+    (0x7ffffe1, 27),
+    (0x3ffffe7, 26),
+    (0x7ffffe2, 27),
+    (0xfffff2, 24),
+    (0x1fffe4, 21),
+    (0x1fffe5, 21),
+    (0x3ffffe8, 26),
+    (0x3ffffe9, 26),
+    (0xffffffd, 28),
+    (0x7ffffe3, 27),
+    (0x7ffffe4, 27),
+    (0x7ffffe5, 27),
+    (0xfffec, 20),
+    (0xfffff3, 24),
+    (0xfffed, 20),
+    (0x1fffe6, 21),
+    (0x3fffe9, 22),
+    (0x1fffe7, 21),
+    (0x1fffe8, 21),
+    (0x7ffff3, 23),
+    (0x3fffea, 22),
+    (0x3fffeb, 22),
+    (0x1ffffee, 25),
+    (0x1ffffef, 25),
+    (0xfffff4, 24),
+    (0xfffff5, 24),
+    (0x3ffffea, 26),
+    (0x7ffff4, 23),
+    (0x3ffffeb, 26),
+    (0x7ffffe6, 27),
+    (0x3ffffec, 26),
+    (0x3ffffed, 26),
+    (0x7ffffe7, 27),
+    (0x7ffffe8, 27),
+    (0x7ffffe9, 27),
+    (0x7ffffea, 27),
+    (0x7ffffeb, 27),
+    (0xffffffe, 28),
+    (0x7ffffec, 27),
+    (0x7ffffed, 27),
+    (0x7ffffee, 27),
+    (0x7ffffef, 27),
+    (0x7fffff0, 27),
+    (0x3ffffee, 26),
+    (0x3fffffff, 30),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn fields(fields: &[(&str, &str)]) -> Vec<(String, String)> {
+        fields
+            .iter()
+            .map(|&(name, value)| (name.to_owned(), value.to_owned()))
+            .collect()
+    }
+
+    // the request examples of RFC 7541 appendix C.4, which build on each other's table entries
+    #[test]
+    fn decoder_decodes_huffman_coded_requests() {
+        let mut decoder = Decoder::new(4096);
+
+        assert_eq!(
+            decoder
+                .decode(&hex("828684418cf1e3c2e5f23a6ba0ab90f4ff"))
+                .unwrap(),
+            fields(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+            ])
+        );
+        assert_eq!(
+            decoder.decode(&hex("828684be5886a8eb10649cbf")).unwrap(),
+            fields(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+                ("cache-control", "no-cache"),
+            ])
+        );
+        assert_eq!(
+            decoder
+                .decode(&hex("828785bf408825a849e95ba97d7f8925a849e95bb8e8b4bf"))
+                .unwrap(),
+            fields(&[
+                (":method", "GET"),
+                (":scheme", "https"),
+                (":path", "/index.html"),
+                (":authority", "www.example.com"),
+                ("custom-key", "custom-value"),
+            ])
+        );
+        assert_eq!(decoder.size, 164);
+    }
+
+    #[test]
+    fn decoder_evicts_entries_beyond_the_table_size() {
+        let mut decoder = Decoder::new(4096);
+
+        // a size update down to 60 bytes, then a 55 byte entry, then a 54 byte one that replaces it
+        decoder
+            .decode(&hex(
+                "3f1d400a637573746f6d2d6b65790d637573746f6d2d686561646572",
+            ))
+            .unwrap();
+        assert_eq!(decoder.table.len(), 1);
+        decoder
+            .decode(&hex("400a637573746f6d2d6b65790c637573746f6d2d76616c7565"))
+            .unwrap();
+        assert_eq!(decoder.table.len(), 1);
+        assert_eq!(decoder.get(62).unwrap().1, "custom-value");
+
+        // updates past what we allowed, or after a field, are errors
+        assert!(Decoder::new(4096).decode(&hex("3fe21f")).is_err());
+        assert!(Decoder::new(4096).decode(&hex("8220")).is_err());
+    }
+
+    #[test]
+    fn decoder_stops_keeping_fields_past_the_list_limits() {
+        let mut decoder = Decoder::new(4096).with_list_limits(200, 10);
+
+        // a 55 byte entry, then three references to it
+        let block = hex("400a637573746f6d2d6b65790d637573746f6d2d686561646572bebebe");
+        let err = decoder.decode(&block).unwrap_err();
+        assert!(err.is::<HeaderListTooLarge>(), "{err:#}");
+        // the entry was still added, so later blocks can refer to it
+        assert_eq!(
+            decoder.decode(&hex("be")).unwrap(),
+            fields(&[("custom-key", "custom-header")])
+        );
+
+        let err = decoder
+            .decode(&hex("828282828282828282828282"))
+            .unwrap_err();
+        assert!(err.is::<HeaderListTooLarge>(), "{err:#}");
+    }
+
+    #[test]
+    fn decoder_rejects_malformed_blocks() {
+        let mut decoder = Decoder::new(4096);
+
+        assert!(decoder.decode(&hex("80")).is_err());
+        assert!(decoder.decode(&hex("be")).is_err());
+        assert!(decoder.decode(&hex("ff")).is_err());
+        assert!(decoder.decode(&hex("0003616263")).is_err());
+        // padding longer than 7 bits
+        assert!(decoder.decode(&hex("0081ff81ff")).is_err());
+    }
+
+    #[test]
+    fn encoded_fields_decode_to_themselves() {
+        let long_value = "x".repeat(300);
+        let encoded = [
+            (":status", "200"),
+            (":status", "418"),
+            ("content-type", "text/plain"),
+            ("x-custom", long_value.as_str()),
+        ];
+
+        let block = encode(&encoded);
+        assert_eq!(block[0], 0x88);
+
+        let mut decoder = Decoder::new(4096);
+        assert_eq!(decoder.decode(&block).unwrap(), fields(&encoded));
+        assert!(decoder.table.is_empty());
+    }
+}
@@ -0,0 +1,1084 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    io::{self, prelude::*},
+    mem,
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc},
+    time::Instant,
+};
+
+use anyhow::{anyhow, Context};
+
+use crate::{
+    access_log::Entry,
+    header::is_token,
+    hpack::{self, Decoder, HeaderListTooLarge},
+    ip_filter::forwarded_client,
+    request::{Method, Request, UnsupportedMethod, Version},
+    request_id::REQUEST_ID_HEADER,
+    response::Response,
+    router::Router,
+    routes::respond,
+    server::{is_timeout, with_error_page, Config, ConnId, Socket, Stats},
+    spool::{Spooled, Spooler},
+};
+
+// sent by the client before anything else, RFC 9113 section 3.4
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+const FRAME_HEADER_SIZE: usize = 9;
+// neither side may send larger frames unless told otherwise, and we never tell the client otherwise
+const MAX_FRAME_SIZE: usize = 16_384;
+const DEFAULT_WINDOW_SIZE: i64 = 65_535;
+const MAX_WINDOW_SIZE: i64 = (1 << 31) - 1;
+const HEADER_TABLE_SIZE: usize = 4096;
+// streams a client may have open at once, more are refused
+const MAX_CONCURRENT_STREAMS: usize = 100;
+
+// frame types, RFC 9113 section 6
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+// frame flags, which mean different things on different frame types
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY_FLAG: u8 = 0x20;
+
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+// fields that manage an HTTP/1.1 connection, which HTTP/2 does through its framing instead
+const CONNECTION_SPECIFIC_FIELDS: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+#[derive(Debug, Clone, Copy)]
+enum ErrorCode {
+    NoError = 0x0,
+    ProtocolError = 0x1,
+    InternalError = 0x2,
+    FlowControlError = 0x3,
+    StreamClosed = 0x5,
+    FrameSizeError = 0x6,
+    RefusedStream = 0x7,
+    CompressionError = 0x9,
+    EnhanceYourCalm = 0xb,
+}
+
+// why a connection has to be given up on
+#[derive(Debug)]
+enum ConnectionError {
+    // the client broke the protocol, which it's told with a GOAWAY
+    Protocol(ErrorCode, String),
+    Io(io::Error),
+}
+
+impl ConnectionError {
+    fn protocol(reason: impl Into<String>) -> Self {
+        Self::Protocol(ErrorCode::ProtocolError, reason.into())
+    }
+
+    fn frame_size(kind: &str) -> Self {
+        Self::Protocol(
+            ErrorCode::FrameSizeError,
+            format!("{kind} frame has the wrong size"),
+        )
+    }
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Protocol(code, reason) => write!(f, "{reason} ({code:?})"),
+            Self::Io(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl From<io::Error> for ConnectionError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+#[derive(Debug)]
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream_id: u32,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    // the payload without the padding the client may have added
+    fn unpadded(&self) -> Result<&[u8], ConnectionError> {
+        if self.flags & PADDED == 0 {
+            return Ok(&self.payload);
+        }
+
+        let (&pad_len, rest) = self
+            .payload
+            .split_first()
+            .ok_or_else(|| ConnectionError::protocol("padded frame is empty"))?;
+        rest.len()
+            .checked_sub(pad_len.into())
+            .map(|len| &rest[..len])
+            .ok_or_else(|| ConnectionError::protocol("padding is longer than the frame"))
+    }
+}
+
+#[derive(Debug)]
+struct Stream {
+    fields: Vec<(String, String)>,
+    // in a temporary file once it's larger than `Config::max_in_memory_body_size`, as over HTTP/1.1
+    body: Spooler,
+    // set once the client has sent all of the request
+    complete: bool,
+    // how many more body bytes the client will accept on this stream
+    send_window: i64,
+    // when its request headers arrived
+    opened: Instant,
+}
+
+// a header block whose fragments are still arriving in CONTINUATION frames
+#[derive(Debug)]
+struct PendingHeaders {
+    stream_id: u32,
+    end_stream: bool,
+    block: Vec<u8>,
+}
+
+// serves a connection that negotiated HTTP/2, answering its streams one at a time in the order
+// their requests are completed, while frames of the others keep being read in between
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn serve(
+    stream: impl Read + Write,
+    socket: &Socket,
+    peer: Option<SocketAddr>,
+    client_cert: Option<Arc<str>>,
+    id: ConnId,
+    config: &Config,
+    stats: &Stats,
+    router: &Router,
+) -> anyhow::Result<()> {
+    let mut conn = Connection {
+        stream,
+        socket,
+        peer,
+        client_cert,
+        id,
+        config,
+        stats,
+        router,
+        // what we advertise in SETTINGS_MAX_HEADER_LIST_SIZE
+        decoder: Decoder::new(HEADER_TABLE_SIZE)
+            .with_list_limits(config.max_header_size, config.max_header_count),
+        streams: HashMap::new(),
+        ready: VecDeque::new(),
+        last_stream_id: 0,
+        pending_headers: None,
+        send_window: DEFAULT_WINDOW_SIZE,
+        initial_send_window: DEFAULT_WINDOW_SIZE,
+        sent_goaway: false,
+        received_goaway: false,
+        requests_served: 0,
+    };
+
+    match conn.run() {
+        Ok(()) => {}
+        Err(ConnectionError::Protocol(code, reason)) => {
+            log::warn!("id = {id}, {reason}, closing HTTP/2 connection");
+            conn.go_away(code)
+                .map_err(|err| anyhow!("failed to write to client: {err}"))?;
+        }
+        Err(ConnectionError::Io(err)) => return Err(err).context("failed to talk to client"),
+    }
+
+    log::info!(conn_id = id; "closing connection {id}");
+    Ok(())
+}
+
+struct Connection<'a, S> {
+    stream: S,
+    socket: &'a Socket,
+    peer: Option<SocketAddr>,
+    // the subject of the client's certificate, see `Request::client_cert_subject`
+    client_cert: Option<Arc<str>>,
+    id: ConnId,
+    config: &'a Config,
+    stats: &'a Stats,
+    router: &'a Router,
+    decoder: Decoder,
+    // streams the client has opened and we haven't answered or reset yet
+    streams: HashMap<u32, Stream>,
+    // streams whose request has arrived in full, in the order they completed
+    ready: VecDeque<u32>,
+    // the highest stream id the client has used
+    last_stream_id: u32,
+    pending_headers: Option<PendingHeaders>,
+    // how many more body bytes the client will accept across all streams
+    send_window: i64,
+    // what the send window of every new stream starts at, as set by the client
+    initial_send_window: i64,
+    sent_goaway: bool,
+    received_goaway: bool,
+    requests_served: usize,
+}
+
+impl<S: Read + Write> Connection<'_, S> {
+    fn run(&mut self) -> Result<(), ConnectionError> {
+        let mut preface = [0; PREFACE.len()];
+        self.stream.read_exact(&mut preface)?;
+        if preface != PREFACE {
+            return Err(ConnectionError::protocol(
+                "connection didn't start with the HTTP/2 preface",
+            ));
+        }
+
+        // the client's own SETTINGS follow its preface, and are handled like any other frame
+        let settings = [
+            (
+                SETTINGS_MAX_CONCURRENT_STREAMS,
+                MAX_CONCURRENT_STREAMS as u32,
+            ),
+            (
+                SETTINGS_MAX_HEADER_LIST_SIZE,
+                self.config.max_header_size as u32,
+            ),
+        ];
+        let payload: Vec<u8> = settings
+            .iter()
+            .flat_map(|(id, value)| [&id.to_be_bytes()[..], &value.to_be_bytes()].concat())
+            .collect();
+        self.write_frame(SETTINGS, 0, 0, &payload)?;
+
+        loop {
+            while let Some(stream_id) = self.ready.pop_front() {
+                self.answer(stream_id)?;
+            }
+
+            // streams already open are still answered, but the client has to open new ones elsewhere
+            let done = self.stats.shutting_down.load(Ordering::SeqCst)
+                || self
+                    .config
+                    .max_requests_per_connection
+                    .is_some_and(|max| self.requests_served >= max);
+            if done && !self.sent_goaway {
+                self.go_away(ErrorCode::NoError)?;
+            }
+            if (self.sent_goaway || self.received_goaway) && self.streams.is_empty() {
+                return Ok(());
+            }
+
+            // a connection without open streams waits for its next request no longer than the idle timeout
+            let timeout = if self.streams.is_empty() {
+                self.config.idle_timeout
+            } else {
+                self.config.read_timeout
+            };
+            self.socket.set_read_timeout(Some(timeout))?;
+
+            let frame = match self.read_frame() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => return Ok(()),
+                Err(ConnectionError::Io(err)) if is_timeout(&err) && self.streams.is_empty() => {
+                    log::info!("id = {}, closing idle connection", self.id);
+                    self.go_away(ErrorCode::NoError)?;
+                    return Ok(());
+                }
+                Err(err) => return Err(err),
+            };
+            self.handle_frame(frame)?;
+        }
+    }
+
+    // returns `None` if the client closed the connection between frames
+    fn read_frame(&mut self) -> Result<Option<Frame>, ConnectionError> {
+        let mut header = [0; FRAME_HEADER_SIZE];
+        if self.stream.read(&mut header[..1])? == 0 {
+            return Ok(None);
+        }
+        self.stream.read_exact(&mut header[1..])?;
+
+        let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+        if len > MAX_FRAME_SIZE {
+            return Err(ConnectionError::Protocol(
+                ErrorCode::FrameSizeError,
+                format!("frame of {len} bytes is larger than the {MAX_FRAME_SIZE} allowed"),
+            ));
+        }
+
+        let mut payload = vec![0; len];
+        self.stream.read_exact(&mut payload)?;
+
+        Ok(Some(Frame {
+            kind: header[3],
+            flags: header[4],
+            // the highest bit is reserved
+            stream_id: u32::from_be_bytes([header[5], header[6], header[7], header[8]])
+                & 0x7fff_ffff,
+            payload,
+        }))
+    }
+
+    fn handle_frame(&mut self, frame: Frame) -> Result<(), ConnectionError> {
+        // the fragments of a header block can't be interleaved with anything else
+        if let Some(pending) = &self.pending_headers {
+            if frame.kind != CONTINUATION || frame.stream_id != pending.stream_id {
+                return Err(ConnectionError::protocol(
+                    "header block was interrupted by another frame",
+                ));
+            }
+        }
+
+        let on_connection = matches!(frame.kind, SETTINGS | PING | GOAWAY);
+        let on_stream = matches!(
+            frame.kind,
+            DATA | HEADERS | PRIORITY | RST_STREAM | CONTINUATION
+        );
+        if on_connection && frame.stream_id != 0 || on_stream && frame.stream_id == 0 {
+            return Err(ConnectionError::protocol(format!(
+                "frame of type {} can't be sent on stream {}",
+                frame.kind, frame.stream_id
+            )));
+        }
+
+        match frame.kind {
+            DATA => self.handle_data(frame),
+            HEADERS => self.handle_headers(frame),
+            CONTINUATION => self.handle_continuation(frame),
+            PRIORITY if frame.payload.len() != 5 => Err(ConnectionError::frame_size("PRIORITY")),
+            // streams are answered in the order they complete, whatever their priority
+            PRIORITY => Ok(()),
+            RST_STREAM => self.handle_rst_stream(frame),
+            SETTINGS => self.handle_settings(frame),
+            PUSH_PROMISE => Err(ConnectionError::protocol("clients can't push streams")),
+            PING if frame.payload.len() != 8 => Err(ConnectionError::frame_size("PING")),
+            PING if frame.flags & ACK != 0 => Ok(()),
+            PING => self.write_frame(PING, ACK, 0, &frame.payload),
+            GOAWAY => {
+                log::info!("id = {}, client is going away", self.id);
+                self.received_goaway = true;
+                Ok(())
+            }
+            WINDOW_UPDATE => self.handle_window_update(frame),
+            // frames of unknown types have to be ignored
+            _ => Ok(()),
+        }
+    }
+
+    fn handle_data(&mut self, frame: Frame) -> Result<(), ConnectionError> {
+        let stream_id = frame.stream_id;
+        let data = frame.unpadded()?;
+
+        // flow control counts the whole payload, padding included; what's received is handed back
+        // right away, since `Config::max_body_size` is what bounds the memory a stream takes
+        let len = frame.payload.len();
+        if len > 0 {
+            self.write_window_update(0, len)?;
+        }
+
+        let max_body_size = self.config.max_body_size;
+        let Some(stream) = self.streams.get_mut(&stream_id) else {
+            if stream_id > self.last_stream_id {
+                return Err(ConnectionError::protocol(format!(
+                    "DATA frame on stream {stream_id}, which isn't open"
+                )));
+            }
+            // the stream was answered or reset already, what the client still had in flight is dropped
+            return Ok(());
+        };
+        if stream.complete {
+            return self.reset(stream_id, ErrorCode::StreamClosed);
+        }
+
+        if data.len() as u64 > max_body_size.saturating_sub(stream.body.len()) {
+            log::warn!(
+                "id = {}, stream = {stream_id}, request body exceeds the limit of {max_body_size} bytes",
+                self.id
+            );
+
+            // the client is told why before the rest of its body is refused
+            self.send_response(stream_id, None, Response::payload_too_large(), true)?;
+            return self.reset(stream_id, ErrorCode::NoError);
+        }
+
+        if let Err(err) = stream.body.write_all(data) {
+            log::error!(
+                "id = {}, stream = {stream_id}, failed to store request body: {err}",
+                self.id
+            );
+            self.send_response(stream_id, None, Response::internal_server_error(), true)?;
+            return self.reset(stream_id, ErrorCode::NoError);
+        }
+        if frame.flags & END_STREAM != 0 {
+            stream.complete = true;
+            self.ready.push_back(stream_id);
+        } else if len > 0 {
+            self.write_window_update(stream_id, len)?;
+        }
+        Ok(())
+    }
+
+    fn handle_headers(&mut self, frame: Frame) -> Result<(), ConnectionError> {
+        let mut fragment = frame.unpadded()?;
+        // the stream's priority comes first, which we don't use
+        if frame.flags & PRIORITY_FLAG != 0 {
+            fragment = fragment
+                .get(5..)
+                .ok_or_else(|| ConnectionError::frame_size("HEADERS"))?;
+        }
+
+        let pending = PendingHeaders {
+            stream_id: frame.stream_id,
+            end_stream: frame.flags & END_STREAM != 0,
+            block: fragment.to_vec(),
+        };
+        self.continue_headers(pending, frame.flags & END_HEADERS != 0)
+    }
+
+    fn handle_continuation(&mut self, frame: Frame) -> Result<(), ConnectionError> {
+        let Some(mut pending) = self.pending_headers.take() else {
+            return Err(ConnectionError::protocol(
+                "CONTINUATION frame without a header block to continue",
+            ));
+        };
+
+        pending.block.extend_from_slice(&frame.payload);
+        self.continue_headers(pending, frame.flags & END_HEADERS != 0)
+    }
+
+    fn continue_headers(
+        &mut self,
+        pending: PendingHeaders,
+        end_headers: bool,
+    ) -> Result<(), ConnectionError> {
+        // a block has to be decoded in full to keep the table in sync, so one that's too large
+        // can't be answered with a 431 like over HTTP/1.1
+        if pending.block.len() > self.config.max_header_size {
+            return Err(ConnectionError::Protocol(
+                ErrorCode::EnhanceYourCalm,
+                format!(
+                    "header block is larger than {} bytes",
+                    self.config.max_header_size
+                ),
+            ));
+        }
+
+        if end_headers {
+            self.finish_headers(pending)
+        } else {
+            self.pending_headers = Some(pending);
+            Ok(())
+        }
+    }
+
+    fn finish_headers(&mut self, pending: PendingHeaders) -> Result<(), ConnectionError> {
+        // `None` for a list over the limits we advertised, which only the stream is refused for
+        let fields = match self.decoder.decode(&pending.block) {
+            Ok(fields) => Some(fields),
+            Err(err) if err.is::<HeaderListTooLarge>() => None,
+            Err(err) => {
+                return Err(ConnectionError::Protocol(
+                    ErrorCode::CompressionError,
+                    format!("{err:#}"),
+                ))
+            }
+        };
+        let stream_id = pending.stream_id;
+
+        // a second header block ends the request as its trailer
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            if stream.complete || !pending.end_stream {
+                return self.reset(stream_id, ErrorCode::ProtocolError);
+            }
+            let Some(fields) = fields else {
+                return self.refuse_header_list(stream_id, true);
+            };
+            if fields.iter().any(|(name, _)| name.starts_with(':')) {
+                return self.reset(stream_id, ErrorCode::ProtocolError);
+            }
+
+            stream.fields.extend(fields);
+            stream.complete = true;
+            self.ready.push_back(stream_id);
+            return Ok(());
+        }
+
+        if stream_id <= self.last_stream_id {
+            // the stream was answered or reset already
+            return Ok(());
+        }
+        if stream_id.is_multiple_of(2) {
+            return Err(ConnectionError::protocol(format!(
+                "clients can't open even-numbered stream {stream_id}"
+            )));
+        }
+        self.last_stream_id = stream_id;
+
+        // the client knows from our GOAWAY that this stream won't be answered
+        if self.sent_goaway {
+            return Ok(());
+        }
+        if self.streams.len() >= MAX_CONCURRENT_STREAMS {
+            return self.reset(stream_id, ErrorCode::RefusedStream);
+        }
+
+        let too_large = fields.is_none();
+        self.streams.insert(
+            stream_id,
+            Stream {
+                fields: fields.unwrap_or_default(),
+                body: Spooler::new(self.config.max_in_memory_body_size),
+                complete: pending.end_stream,
+                send_window: self.initial_send_window,
+                opened: Instant::now(),
+            },
+        );
+        if too_large {
+            return self.refuse_header_list(stream_id, pending.end_stream);
+        }
+        if pending.end_stream {
+            self.ready.push_back(stream_id);
+        }
+        Ok(())
+    }
+
+    // answers a stream whose header list is over the limits with a 431, as over HTTP/1.1, and
+    // refuses the rest of its request
+    fn refuse_header_list(
+        &mut self,
+        stream_id: u32,
+        end_stream: bool,
+    ) -> Result<(), ConnectionError> {
+        log::warn!(
+            "id = {}, stream = {stream_id}, header list exceeds {} bytes or {} fields",
+            self.id,
+            self.config.max_header_size,
+            self.config.max_header_count
+        );
+        self.send_response(
+            stream_id,
+            None,
+            Response::request_header_fields_too_large(),
+            true,
+        )?;
+        match end_stream {
+            true => Ok(()),
+            false => self.reset(stream_id, ErrorCode::NoError),
+        }
+    }
+
+    fn handle_rst_stream(&mut self, frame: Frame) -> Result<(), ConnectionError> {
+        if frame.payload.len() != 4 {
+            return Err(ConnectionError::frame_size("RST_STREAM"));
+        }
+        if frame.stream_id > self.last_stream_id {
+            return Err(ConnectionError::protocol(format!(
+                "RST_STREAM frame on stream {}, which isn't open",
+                frame.stream_id
+            )));
+        }
+
+        self.close(frame.stream_id);
+        Ok(())
+    }
+
+    fn handle_settings(&mut self, frame: Frame) -> Result<(), ConnectionError> {
+        if frame.flags & ACK != 0 {
+            return match frame.payload.is_empty() {
+                true => Ok(()),
+                false => Err(ConnectionError::frame_size("SETTINGS")),
+            };
+        }
+        if !frame.payload.len().is_multiple_of(6) {
+            return Err(ConnectionError::frame_size("SETTINGS"));
+        }
+
+        for setting in frame.payload.chunks_exact(6) {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+
+            match id {
+                SETTINGS_ENABLE_PUSH if value > 1 => {
+                    return Err(ConnectionError::protocol(format!(
+                        "invalid SETTINGS_ENABLE_PUSH {value}"
+                    )))
+                }
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    let value = i64::from(value);
+                    if value > MAX_WINDOW_SIZE {
+                        return Err(ConnectionError::Protocol(
+                            ErrorCode::FlowControlError,
+                            format!("initial window size {value} is too large"),
+                        ));
+                    }
+
+                    // streams already open have their windows moved by the difference
+                    let delta = value - self.initial_send_window;
+                    for stream in self.streams.values_mut() {
+                        stream.send_window += delta;
+                    }
+                    self.initial_send_window = value;
+                }
+                // we keep our frames at the smallest size every client accepts
+                SETTINGS_MAX_FRAME_SIZE if !(16_384..=16_777_215).contains(&value) => {
+                    return Err(ConnectionError::protocol(format!(
+                        "invalid SETTINGS_MAX_FRAME_SIZE {value}"
+                    )))
+                }
+                // we never add to the client's dynamic table, and the rest don't affect a server
+                _ => {}
+            }
+        }
+
+        self.write_frame(SETTINGS, ACK, 0, &[])
+    }
+
+    fn handle_window_update(&mut self, frame: Frame) -> Result<(), ConnectionError> {
+        let [a, b, c, d] = frame.payload[..] else {
+            return Err(ConnectionError::frame_size("WINDOW_UPDATE"));
+        };
+        let increment = i64::from(u32::from_be_bytes([a, b, c, d]) & 0x7fff_ffff);
+
+        if frame.stream_id == 0 {
+            if increment == 0 {
+                return Err(ConnectionError::protocol("window update of 0"));
+            }
+            self.send_window += increment;
+            if self.send_window > MAX_WINDOW_SIZE {
+                return Err(ConnectionError::Protocol(
+                    ErrorCode::FlowControlError,
+                    "connection window grew too large".to_owned(),
+                ));
+            }
+            return Ok(());
+        }
+
+        if frame.stream_id > self.last_stream_id {
+            return Err(ConnectionError::protocol(format!(
+                "WINDOW_UPDATE frame on stream {}, which isn't open",
+                frame.stream_id
+            )));
+        }
+        let Some(stream) = self.streams.get_mut(&frame.stream_id) else {
+            return Ok(());
+        };
+
+        stream.send_window += increment;
+        if increment == 0 {
+            self.reset(frame.stream_id, ErrorCode::ProtocolError)
+        } else if stream.send_window > MAX_WINDOW_SIZE {
+            self.reset(frame.stream_id, ErrorCode::FlowControlError)
+        } else {
+            Ok(())
+        }
+    }
+
+    // answers a stream whose request has arrived in full
+    fn answer(&mut self, stream_id: u32) -> Result<(), ConnectionError> {
+        // the client may have reset it while it waited
+        let Some(stream) = self.streams.get_mut(&stream_id) else {
+            return Ok(());
+        };
+        let fields = mem::take(&mut stream.fields);
+        let body = mem::replace(&mut stream.body, Spooler::new(0)).finish();
+
+        let body = match body {
+            Ok(body) => body,
+            Err(err) => {
+                log::error!(
+                    "id = {}, stream = {stream_id}, failed to store request body: {err}",
+                    self.id
+                );
+                return self.send_response(
+                    stream_id,
+                    None,
+                    Response::internal_server_error(),
+                    true,
+                );
+            }
+        };
+        let mut request = match to_request(fields, body, self.config) {
+            Ok(request) => request,
+            Err(BadRequest::Malformed(reason)) => {
+                log::warn!("id = {}, stream = {stream_id}, {reason}", self.id);
+                return self.reset(stream_id, ErrorCode::ProtocolError);
+            }
+            Err(BadRequest::Rejected(response)) => {
+                return self.send_response(stream_id, None, response, true);
+            }
+        };
+
+        request.id = self.stats.request_ids.assign(&request);
+        request.peer = self.peer;
+        request.client_cert = self.client_cert.clone();
+        request.client = forwarded_client(&request, &self.config.trusted_proxies);
+        if let Err(response) = request.decode_body(
+            self.config.max_body_size,
+            self.config.max_in_memory_body_size,
+        ) {
+            let response = response.with_header(REQUEST_ID_HEADER, &request.id);
+            return self.send_response(stream_id, Some(&request), response, true);
+        }
+
+        // held until the response has been sent; streams are answered one at a time, so waiting
+        // for a slot would hold up every other stream on the connection
+        let stats = self.stats;
+        let _permits = match stats
+            .concurrency
+            .try_acquire(&request, &self.config.rewrites)
+        {
+            Ok(permits) => permits,
+            Err(response) => {
+                let response = response.with_header(REQUEST_ID_HEADER, &request.id);
+                return self.send_response(stream_id, Some(&request), response, true);
+            }
+        };
+
+        log::trace!("request_id = {}, request = {request:#?}", request.id);
+
+        let mut response =
+            respond(&mut request, self.router).with_header(REQUEST_ID_HEADER, &request.id);
+        response.version = Version::Http2;
+        self.requests_served += 1;
+
+        log::trace!("request_id = {}, response = {response:#?}", request.id);
+
+        // HEAD responses carry the same headers as GET, but never a body
+        let include_body = request.line.method != Method::Head;
+        self.send_response(stream_id, Some(&request), response, include_body)
+    }
+
+    fn send_response(
+        &mut self,
+        stream_id: u32,
+        request: Option<&Request>,
+        response: Response,
+        include_body: bool,
+    ) -> Result<(), ConnectionError> {
+        let mut response = with_error_page(response, request, self.config);
+        response.add_default_headers(self.config.server_header.as_deref());
+        let status = response.status;
+
+        let mut fields = vec![(":status".to_owned(), status.code().to_string())];
+        for header in &response.headers {
+            let name = header.name().to_lowercase();
+            if CONNECTION_SPECIFIC_FIELDS.contains(&name.as_str()) {
+                continue;
+            }
+
+            let line = header.to_string();
+            let value = line[header.name().len() + 1..].trim_start().to_owned();
+            fields.push((name, value));
+        }
+        let fields: Vec<_> = fields
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        let block = hpack::encode(&fields);
+
+        let body = response.body.take().filter(|_| include_body);
+
+        // the first fragment goes in the HEADERS frame, the rest follow in CONTINUATION frames
+        let fragments: Vec<_> = block.chunks(MAX_FRAME_SIZE).collect();
+        for (i, fragment) in fragments.iter().enumerate() {
+            let mut flags = 0;
+            if i + 1 == fragments.len() {
+                flags |= END_HEADERS;
+            }
+            let kind = if i == 0 {
+                if body.is_none() {
+                    flags |= END_STREAM;
+                }
+                HEADERS
+            } else {
+                CONTINUATION
+            };
+            self.write_frame(kind, flags, stream_id, fragment)?;
+        }
+
+        let mut bytes_sent = 0;
+        if let Some(body) = body {
+            let mut reader = body.into_reader();
+            let mut buf = vec![0; MAX_FRAME_SIZE];
+            loop {
+                let len = match reader.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(len) => len,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => {
+                        log::error!(
+                            "id = {}, stream = {stream_id}, failed to read response body: {err}",
+                            self.id
+                        );
+                        return self.reset(stream_id, ErrorCode::InternalError);
+                    }
+                };
+
+                let mut chunk = &buf[..len];
+                while !chunk.is_empty() {
+                    let Some(capacity) = self.send_capacity(stream_id)? else {
+                        log::info!("id = {}, stream = {stream_id}, reset by client", self.id);
+                        return Ok(());
+                    };
+                    let (sent, rest) = chunk.split_at(chunk.len().min(capacity));
+                    self.write_frame(DATA, 0, stream_id, sent)?;
+
+                    self.send_window -= sent.len() as i64;
+                    if let Some(stream) = self.streams.get_mut(&stream_id) {
+                        stream.send_window -= sent.len() as i64;
+                    }
+                    bytes_sent += sent.len() as u64;
+                    chunk = rest;
+                }
+            }
+
+            // an empty frame doesn't count against the windows, so it can always end the stream
+            self.write_frame(DATA, END_STREAM, stream_id, &[])?;
+        }
+
+        let latency = self
+            .streams
+            .get(&stream_id)
+            .map(|stream| stream.opened.elapsed());
+        self.close(stream_id);
+        self.stats.record(&Entry {
+            id: self.id,
+            peer: self.peer,
+            request,
+            status,
+            bytes_sent,
+            latency,
+        });
+        Ok(())
+    }
+
+    // how many body bytes may be sent on `stream_id` right now, reading frames until the client
+    // makes room for some; `None` once the client has reset the stream
+    fn send_capacity(&mut self, stream_id: u32) -> Result<Option<usize>, ConnectionError> {
+        loop {
+            let Some(stream) = self.streams.get(&stream_id) else {
+                return Ok(None);
+            };
+            let window = self.send_window.min(stream.send_window);
+            if window > 0 {
+                return Ok(Some((window as usize).min(MAX_FRAME_SIZE)));
+            }
+
+            // a client that doesn't make room is one that doesn't read what it's sent
+            self.socket
+                .set_read_timeout(Some(self.config.write_timeout))?;
+            let Some(frame) = self.read_frame()? else {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed in the middle of a response",
+                )
+                .into());
+            };
+            self.handle_frame(frame)?;
+        }
+    }
+
+    fn reset(&mut self, stream_id: u32, code: ErrorCode) -> Result<(), ConnectionError> {
+        self.close(stream_id);
+        self.write_frame(RST_STREAM, 0, stream_id, &(code as u32).to_be_bytes())
+    }
+
+    fn close(&mut self, stream_id: u32) {
+        self.streams.remove(&stream_id);
+        self.ready.retain(|&ready| ready != stream_id);
+    }
+
+    // tells the client no streams past the last one it opened will be answered
+    fn go_away(&mut self, code: ErrorCode) -> Result<(), ConnectionError> {
+        self.sent_goaway = true;
+        let payload = [
+            self.last_stream_id.to_be_bytes(),
+            (code as u32).to_be_bytes(),
+        ]
+        .concat();
+        self.write_frame(GOAWAY, 0, 0, &payload)
+    }
+
+    fn write_window_update(
+        &mut self,
+        stream_id: u32,
+        increment: usize,
+    ) -> Result<(), ConnectionError> {
+        self.write_frame(
+            WINDOW_UPDATE,
+            0,
+            stream_id,
+            &(increment as u32).to_be_bytes(),
+        )
+    }
+
+    fn write_frame(
+        &mut self,
+        kind: u8,
+        flags: u8,
+        stream_id: u32,
+        payload: &[u8],
+    ) -> Result<(), ConnectionError> {
+        let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+        frame.push(kind);
+        frame.push(flags);
+        frame.extend_from_slice(&stream_id.to_be_bytes());
+        frame.extend_from_slice(payload);
+
+        self.stream.write_all(&frame)?;
+        self.stream.flush()?;
+        Ok(())
+    }
+}
+
+// why the header fields of a stream don't make a request we can answer
+enum BadRequest {
+    // the fields break the rules of HTTP/2 itself, which resets the stream
+    Malformed(String),
+    // a well-formed request answered with an error, as it would be over HTTP/1.1
+    Rejected(Response),
+}
+
+// turns the decoded header fields of a stream into the request they stand for, with the
+// pseudo-header fields in place of the request line
+fn to_request(
+    fields: Vec<(String, String)>,
+    body: Spooled,
+    config: &Config,
+) -> Result<Request, BadRequest> {
+    let size: usize = fields
+        .iter()
+        .map(|(name, value)| name.len() + value.len())
+        .sum();
+    if fields.len() > config.max_header_count || size > config.max_header_size {
+        return Err(BadRequest::Rejected(
+            Response::request_header_fields_too_large(),
+        ));
+    }
+
+    let (mut method, mut scheme, mut path, mut authority) = (None, None, None, None);
+    let mut has_host = false;
+    let mut headers = String::new();
+    for (name, value) in &fields {
+        if name.is_empty() || name.bytes().any(|byte| byte.is_ascii_uppercase()) {
+            return Err(BadRequest::Malformed(format!(
+                "field name {name:?} isn't lowercase"
+            )));
+        }
+        if [name, value].iter().any(|s| s.contains(['\r', '\n', '\0'])) {
+            return Err(BadRequest::Malformed(format!(
+                "field {name:?} contains a line break or NUL"
+            )));
+        }
+
+        if let Some(pseudo) = name.strip_prefix(':') {
+            if !headers.is_empty() || has_host {
+                return Err(BadRequest::Malformed(
+                    "pseudo-header fields have to come before the others".to_owned(),
+                ));
+            }
+            let slot = match pseudo {
+                "method" => &mut method,
+                "scheme" => &mut scheme,
+                "path" => &mut path,
+                "authority" => &mut authority,
+                _ => {
+                    return Err(BadRequest::Malformed(format!(
+                        "unknown pseudo-header field {name:?}"
+                    )))
+                }
+            };
+            if slot.replace(value.as_str()).is_some() {
+                return Err(BadRequest::Malformed(format!("{name:?} is repeated")));
+            }
+            continue;
+        }
+
+        // a `:` or a space would split the field differently once it's in an HTTP/1.1 head
+        if !is_token(name) {
+            return Err(BadRequest::Malformed(format!(
+                "field name {name:?} isn't a token"
+            )));
+        }
+        if CONNECTION_SPECIFIC_FIELDS.contains(&name.as_str())
+            || name == "te" && value != "trailers"
+        {
+            return Err(BadRequest::Malformed(format!(
+                "{name:?} isn't allowed in HTTP/2"
+            )));
+        }
+        has_host |= name == "host";
+        headers.push_str(&format!("{name}: {value}\r\n"));
+    }
+
+    // a CONNECT names only the authority to tunnel to
+    let (method, path) = match (method, scheme, path, authority) {
+        (Some("CONNECT"), None, None, Some(authority)) => ("CONNECT", authority),
+        (Some(method), Some(_), Some(path), _) => (method, path),
+        _ => {
+            return Err(BadRequest::Malformed(
+                "request is missing :method, :scheme or :path".to_owned(),
+            ))
+        }
+    };
+    if path.is_empty()
+        || [method, path]
+            .iter()
+            .any(|s| s.contains(char::is_whitespace))
+    {
+        return Err(BadRequest::Malformed(format!(
+            "invalid :method {method:?} or :path {path:?}"
+        )));
+    }
+
+    // routes find the authority where HTTP/1.1 puts it
+    if let Some(authority) = authority.filter(|_| !has_host) {
+        headers.insert_str(0, &format!("host: {authority}\r\n"));
+    }
+
+    let head = format!("{method} {path} {}\r\n{headers}\r\n", Version::Http11);
+    let mut request: Request = head.parse().map_err(|err: anyhow::Error| {
+        if err.downcast_ref::<UnsupportedMethod>().is_some() {
+            return BadRequest::Rejected(Response::not_implemented());
+        }
+        BadRequest::Rejected(Response::bad_request(format!(
+            "{:#}",
+            err.context("failed to parse request")
+        )))
+    })?;
+    request.line.version = Version::Http2;
+
+    if request
+        .content_length()
+        .is_some_and(|length| length != body.len())
+    {
+        return Err(BadRequest::Malformed(
+            "content-length doesn't match the body".to_owned(),
+        ));
+    }
+    if body.len() > 0 || request.content_length().is_some() {
+        request.body = Some(body.into());
+    }
+
+    Ok(request)
+}
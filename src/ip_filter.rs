@@ -0,0 +1,255 @@
+use std::{net::IpAddr, str::FromStr};
+
+use anyhow::{anyhow, Context};
+
+use crate::{middleware::Middleware, request::Request, response::Response};
+
+// a range of addresses such as `10.0.0.0/8` or `fd00::/8`, a bare address stands for itself
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // clients on a dual-stack socket can show up as IPv4-mapped IPv6 addresses
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u32::from(range) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(range), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u128::from(range) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr = addr
+            .parse::<IpAddr>()
+            .with_context(|| anyhow!("{addr:?} is not an IP address"))?
+            .to_canonical();
+
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .parse()
+                .ok()
+                .filter(|&len| len <= max_len)
+                .with_context(|| anyhow!("{len:?} is not a prefix length up to {max_len}"))?,
+            None => max_len,
+        };
+
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+// which client addresses are served at all, see `Config::ip_filter`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IpFilter {
+    // when there are any, only clients in one of these ranges are served
+    pub allow: Vec<Cidr>,
+    // clients in these ranges are turned away, even when `allow` has them
+    pub deny: Vec<Cidr>,
+}
+
+impl IpFilter {
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|range| range.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|range| range.contains(ip)))
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+}
+
+// the address of the client a trusted proxy forwarded `request` for, `None` when it didn't come
+// through one: the last hop in its `Forwarded` headers, or its `X-Forwarded-For` ones when it has
+// none, that isn't one of the proxies, as the ones before it were written by whoever sent them
+pub(crate) fn forwarded_client(request: &Request, trusted_proxies: &[Cidr]) -> Option<IpAddr> {
+    let peer = request.peer_addr()?.ip();
+    let is_trusted = |ip| {
+        trusted_proxies
+            .iter()
+            .any(|range: &Cidr| range.contains(ip))
+    };
+    if !is_trusted(peer) {
+        return None;
+    }
+
+    let headers = request.raw_headers();
+    let hops: Vec<Option<IpAddr>> = if headers.get("forwarded").is_some() {
+        headers
+            .get_all("forwarded")
+            .flat_map(|value| value.split(','))
+            .map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (name, value) = pair.split_once('=')?;
+                    name.trim()
+                        .eq_ignore_ascii_case("for")
+                        .then(|| parse_node(value))?
+                })
+            })
+            .collect()
+    } else {
+        headers
+            .get_all("x-forwarded-for")
+            .flat_map(|value| value.split(','))
+            .map(parse_node)
+            .collect()
+    };
+
+    let mut client = peer;
+    // a hop that didn't give its address, such as `for=unknown`, is as far back as can be told
+    for hop in hops.into_iter().rev() {
+        let Some(ip) = hop else {
+            break;
+        };
+        client = ip;
+        if !is_trusted(ip) {
+            break;
+        }
+    }
+
+    Some(client)
+}
+
+// an address as proxies write it, e.g. `203.0.113.7`, `"[2001:db8::1]:4711"` or `192.0.2.1:80`
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    node.parse()
+        .ok()
+        .or_else(|| node.rsplit_once(':')?.0.parse().ok())
+}
+
+// applies `Config::ip_filter` to the clients trusted proxies forward requests for, once their
+// headers have been read; everyone else is filtered as soon as they connect
+#[derive(Debug)]
+pub(crate) struct ForwardedFilter {
+    pub(crate) filter: IpFilter,
+}
+
+impl Middleware for ForwardedFilter {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        // the others were filtered already
+        let client = request.client?;
+        if self.filter.permits(client) {
+            return None;
+        }
+
+        log::warn!(
+            "request_id = {}, rejected request forwarded for {client}",
+            request.id
+        );
+        Some(Response::forbidden())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_contains_addresses_in_its_range() {
+        let private: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(private.contains(ip("10.1.2.3")));
+        assert!(private.contains(ip("::ffff:10.1.2.3")));
+        assert!(!private.contains(ip("11.0.0.1")));
+        assert!(!private.contains(ip("fd00::1")));
+
+        let single: Cidr = "fd00::1".parse().unwrap();
+        assert!(single.contains(ip("fd00::1")));
+        assert!(!single.contains(ip("fd00::2")));
+
+        let everything: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains(ip("203.0.113.7")));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("example.com".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn ip_filter_lets_deny_override_allow() {
+        let filter = IpFilter {
+            allow: vec!["10.0.0.0/8".parse().unwrap()],
+            deny: vec!["10.0.0.13".parse().unwrap()],
+        };
+
+        assert!(filter.permits(ip("10.0.0.12")));
+        assert!(!filter.permits(ip("10.0.0.13")));
+        assert!(!filter.permits(ip("192.168.0.1")));
+        assert!(IpFilter::default().permits(ip("192.168.0.1")));
+    }
+
+    #[test]
+    fn forwarded_client_skips_trusted_proxies() {
+        let trusted = ["10.0.0.0/8".parse().unwrap()];
+        let request = |forwarded_for: &str| {
+            let mut request: Request = format!(
+                "GET / HTTP/1.1\r\nX-Forwarded-For: {forwarded_for}\r\nX-Forwarded-For: 10.0.0.2\r\n\r\n"
+            )
+            .parse()
+            .unwrap();
+            request.peer = Some("10.0.0.1:4000".parse().unwrap());
+            request
+        };
+
+        assert_eq!(
+            forwarded_client(&request("1.2.3.4, 203.0.113.7"), &trusted),
+            Some(ip("203.0.113.7"))
+        );
+        assert_eq!(
+            forwarded_client(&request("nonsense"), &trusted),
+            Some(ip("10.0.0.2"))
+        );
+
+        let mut direct = request("1.2.3.4");
+        direct.peer = Some("198.51.100.1:4000".parse().unwrap());
+        assert_eq!(forwarded_client(&direct, &trusted), None);
+
+        // `Forwarded` wins over `X-Forwarded-For`, and a hop without an address ends the search
+        let mut forwarded: Request = concat!(
+            "GET / HTTP/1.1\r\n",
+            "X-Forwarded-For: 1.2.3.4\r\n",
+            "Forwarded: for=198.51.100.9, for=\"[2001:db8::1]:4711\";proto=https\r\n",
+            "Forwarded: for=10.0.0.3:80\r\n",
+            "\r\n"
+        )
+        .parse()
+        .unwrap();
+        forwarded.peer = Some("10.0.0.1:4000".parse().unwrap());
+        assert_eq!(
+            forwarded_client(&forwarded, &trusted),
+            Some(ip("2001:db8::1"))
+        );
+        let mut unknown: Request =
+            "GET / HTTP/1.1\r\nForwarded: for=203.0.113.7, for=unknown, for=10.0.0.3\r\n\r\n"
+                .parse()
+                .unwrap();
+        unknown.peer = Some("10.0.0.1:4000".parse().unwrap());
+        assert_eq!(forwarded_client(&unknown, &trusted), Some(ip("10.0.0.3")));
+    }
+}
@@ -0,0 +1,68 @@
+#![warn(rust_2018_idioms)]
+#![warn(missing_debug_implementations)]
+
+mod access_log;
+mod auth;
+mod base64;
+mod cache_control;
+mod cgi;
+pub mod client;
+mod concurrency;
+mod cookie;
+mod cors;
+mod date;
+#[cfg(feature = "event-loop")]
+mod event_loop;
+mod file_cache;
+mod header;
+mod hpack;
+mod http2;
+mod ip_filter;
+mod metrics;
+mod middleware;
+mod multipart;
+mod proxy;
+mod rate_limit;
+mod request;
+mod request_id;
+mod response;
+mod rewrite;
+mod router;
+mod routes;
+mod sendfile;
+mod server;
+mod session;
+mod spool;
+mod sse;
+mod template;
+mod testing;
+mod tls;
+mod websocket;
+
+pub use access_log::AccessLogFormat;
+pub use auth::{BasicAuth, ClientCertRule};
+pub use cache_control::CacheRule;
+pub use cgi::Cgi;
+pub use concurrency::ConcurrencyLimit;
+pub use cookie::{SameSite, SetCookie};
+pub use cors::CorsPolicy;
+pub use header::{
+    AcceptedEncoding, ConnectionMode, ContentRange, ContentType, Encoding, Header, HeaderMap,
+    TransferCoding,
+};
+pub use ip_filter::{Cidr, IpFilter};
+pub use middleware::Middleware;
+pub use multipart::Part;
+pub use proxy::Proxy;
+pub use rate_limit::RateLimit;
+pub use request::{Method, Request, Version};
+pub use response::{CompressionPolicy, Response, ResponseBuilder, StatusCode};
+pub use rewrite::RewriteRule;
+pub use router::{Handler, IntoResponse, Router};
+pub use server::{Config, Reloader, Server};
+pub use session::{Session, Sessions};
+pub use sse::{Event, EventStream};
+pub use template::{Template, TemplateContext, Value};
+pub use testing::{TestConnection, TestResponse, TestServer};
+pub use tls::{ClientAuth, TlsConfig};
+pub use websocket::{Message, WebSocket};
@@ -0,0 +1,173 @@
+use std::{
+    fmt::Write as _,
+    io::{self, Write as _},
+    str::FromStr,
+};
+
+use anyhow::anyhow;
+use env_logger::fmt::Formatter;
+use log::{
+    kv::{self, VisitSource, VisitValue},
+    Record,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum LogFormat {
+    // env_logger's own lines, meant for people
+    #[default]
+    Text,
+    // one JSON object per line, meant for log shippers
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(anyhow!("unknown log format {s:?}, expected text or json")),
+        }
+    }
+}
+
+// the formatter env_logger uses with `--log-format json`
+pub(crate) fn format_json(buf: &mut Formatter, record: &Record<'_>) -> io::Result<()> {
+    let timestamp = buf.timestamp_millis().to_string();
+    writeln!(buf, "{}", json_line(&timestamp, record))
+}
+
+// a record as a JSON object, e.g.
+// `{"ts":"2024-10-10T13:55:36.000Z","level":"INFO","target":"butler::server","message":"accepted connection 3","conn_id":3}`,
+// with the fields logged alongside its message added after the fixed ones
+fn json_line(timestamp: &str, record: &Record<'_>) -> String {
+    let mut line = String::from("{\"ts\":");
+    push_json_str(&mut line, timestamp);
+    line.push_str(",\"level\":");
+    push_json_str(&mut line, record.level().as_str());
+    line.push_str(",\"target\":");
+    push_json_str(&mut line, record.target());
+    line.push_str(",\"message\":");
+    push_json_str(&mut line, &record.args().to_string());
+
+    // none of the visitors below fail
+    let _ = record.key_values().visit(&mut Fields(&mut line));
+
+    line.push('}');
+    line
+}
+
+struct Fields<'a>(&'a mut String);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.push(',');
+        push_json_str(self.0, key.as_str());
+        self.0.push(':');
+        value.visit(JsonValue(self.0))
+    }
+}
+
+struct JsonValue<'a>(&'a mut String);
+
+impl JsonValue<'_> {
+    fn push(&mut self, value: impl std::fmt::Display) -> Result<(), kv::Error> {
+        // writing to a `String` can't fail
+        let _ = write!(self.0, "{value}");
+        Ok(())
+    }
+}
+
+impl<'v> VisitValue<'v> for JsonValue<'_> {
+    // anything without a JSON counterpart is written as the string it displays as
+    fn visit_any(&mut self, value: kv::Value<'_>) -> Result<(), kv::Error> {
+        push_json_str(self.0, &value.to_string());
+        Ok(())
+    }
+
+    fn visit_null(&mut self) -> Result<(), kv::Error> {
+        self.0.push_str("null");
+        Ok(())
+    }
+
+    fn visit_u64(&mut self, value: u64) -> Result<(), kv::Error> {
+        self.push(value)
+    }
+
+    fn visit_i64(&mut self, value: i64) -> Result<(), kv::Error> {
+        self.push(value)
+    }
+
+    fn visit_u128(&mut self, value: u128) -> Result<(), kv::Error> {
+        self.push(value)
+    }
+
+    fn visit_i128(&mut self, value: i128) -> Result<(), kv::Error> {
+        self.push(value)
+    }
+
+    // JSON has no NaN or infinity
+    fn visit_f64(&mut self, value: f64) -> Result<(), kv::Error> {
+        if value.is_finite() {
+            self.push(value)
+        } else {
+            self.visit_null()
+        }
+    }
+
+    fn visit_bool(&mut self, value: bool) -> Result<(), kv::Error> {
+        self.push(value)
+    }
+
+    fn visit_str(&mut self, value: &str) -> Result<(), kv::Error> {
+        push_json_str(self.0, value);
+        Ok(())
+    }
+}
+
+fn push_json_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => {
+                let _ = write!(out, "\\u{:04x}", u32::from(c));
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use log::Level;
+
+    use super::*;
+
+    #[test]
+    fn json_line_includes_the_logged_fields() {
+        let fields: [(&str, kv::Value<'_>); 4] = [
+            ("conn_id", 3u64.into()),
+            ("path", "/echo/\"hi\"".into()),
+            ("duration_ms", kv::Value::null()),
+            ("ok", true.into()),
+        ];
+        let record = Record::builder()
+            .level(Level::Info)
+            .target("access")
+            .args(format_args!("line\none"))
+            .key_values(&fields)
+            .build();
+
+        assert_eq!(
+            json_line("2024-10-10T13:55:36.000Z", &record),
+            r#"{"ts":"2024-10-10T13:55:36.000Z","level":"INFO","target":"access","message":"line\none","conn_id":3,"path":"/echo/\"hi\"","duration_ms":null,"ok":true}"#
+        );
+    }
+}
@@ -0,0 +1,63 @@
+use std::sync::{OnceLock, PoisonError, RwLock, RwLockReadGuard};
+
+use log::{Log, Metadata, Record};
+
+use crate::log_format::{self, LogFormat};
+
+// env_logger's logger behind a lock, so that reloading the configuration can change its filter and
+// format after it's been installed, which `log` only lets happen once
+#[derive(Debug)]
+struct Logger(RwLock<env_logger::Logger>);
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+// logs with `level`, the filter used when RUST_LOG isn't set, and `format`
+pub(crate) fn init(level: Option<&str>, format: LogFormat) -> anyhow::Result<()> {
+    let inner = build(level, format);
+    log::set_max_level(inner.filter());
+    let logger = LOGGER.get_or_init(|| Logger(RwLock::new(inner)));
+    log::set_logger(logger)?;
+    Ok(())
+}
+
+// switches to logging with `level` and `format` from now on
+pub(crate) fn reload(level: Option<&str>, format: LogFormat) {
+    let Some(logger) = LOGGER.get() else {
+        return;
+    };
+    let inner = build(level, format);
+    log::set_max_level(inner.filter());
+    *logger.0.write().unwrap_or_else(PoisonError::into_inner) = inner;
+}
+
+fn build(level: Option<&str>, format: LogFormat) -> env_logger::Logger {
+    let mut env = env_logger::Env::default();
+    if let Some(level) = level {
+        env = env.default_filter_or(level);
+    }
+    let mut builder = env_logger::Builder::from_env(env);
+    if format == LogFormat::Json {
+        builder.format(log_format::format_json);
+    }
+    builder.build()
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        self.inner().enabled(metadata)
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        self.inner().log(record);
+    }
+
+    fn flush(&self) {
+        self.inner().flush();
+    }
+}
+
+impl Logger {
+    fn inner(&self) -> RwLockReadGuard<'_, env_logger::Logger> {
+        self.0.read().unwrap_or_else(PoisonError::into_inner)
+    }
+}
@@ -1,375 +1,1090 @@
 #![warn(rust_2018_idioms)]
 #![warn(missing_debug_implementations)]
 
+mod bench;
+mod config_file;
+mod daemon;
+mod log_format;
+mod logger;
+mod precompress;
+
 use std::{
-    fmt,
-    fs::{self, File},
-    io::{self, prelude::*},
-    net::{TcpListener, TcpStream},
+    collections::{HashMap, HashSet},
+    path::PathBuf,
     str::FromStr,
+    sync::atomic::Ordering,
+    time::Duration,
 };
+#[cfg(unix)]
+use std::{io, sync::atomic::AtomicBool, thread};
 
 use anyhow::{anyhow, Context};
+use log_format::LogFormat;
 
-use threadpool::ThreadPool;
-
-use flate2::{write::GzEncoder, Compression};
-
-const NUM_THREADS: usize = 500;
+use butler::{
+    AccessLogFormat, BasicAuth, CacheRule, Cgi, Cidr, ClientAuth, ClientCertRule,
+    CompressionPolicy, ConcurrencyLimit, Config, ContentType, CorsPolicy, IpFilter, Proxy,
+    RateLimit, RewriteRule, Server, StatusCode, TlsConfig,
+};
 
-type ConnId = usize;
+const DEFAULT_HOST: &str = "127.0.0.1";
+const DEFAULT_PORT: u16 = 4221;
+// read when it exists in the working directory and no `--config` is given
+const DEFAULT_CONFIG_FILE: &str = "butler.toml";
+// how often the reload thread checks whether a SIGHUP arrived
+#[cfg(unix)]
+const HANGUP_POLL_INTERVAL: Duration = Duration::from_millis(200);
+const USAGE: &str = "\
+usage: butler [options]
+       butler bench [options] <URL>, see butler bench --help
+       butler precompress [options] <DIR>, see butler precompress --help
+
+options:
+  --host, --bind <HOSTS>       comma-separated addresses to listen on, such as 0.0.0.0 or [::]:8080,
+                               or unix:<PATH> for a Unix domain socket; may be repeated
+                               [default: 127.0.0.1]
+  --port <PORT>                port to listen on [default: 4221]
+  --directory <DIR>            directory served and written by /files/ [default: files]
+  --workers, --threads <N>     number of worker threads handling connections [default: 500]
+  --max-queued <N>             connections allowed to wait for a busy worker [default: unlimited]
+  --max-connections-per-ip <N> connections one client address may have at once [default: unlimited]
+  --read-timeout <SECS>        seconds a client may stay silent before it gets a 408 [default: 30]
+  --header-timeout <SECS>      seconds a client may take to send a request's headers [default: 10]
+  --idle-timeout <SECS>        seconds a kept-alive connection may wait for its next request [default: 15]
+  --max-requests-per-connection <N>
+                               requests answered before a connection is closed [default: unlimited]
+  --write-timeout <SECS>       seconds a client may take to read a response before it's dropped [default: 30]
+  --drain-timeout <SECS>       seconds to wait for in-flight connections when shutting down [default: 30]
+  --reuse-address <BOOL>       bind the port while connections of a previous server are closing,
+                               Unix only [default: true]
+  --reuse-port <BOOL>          let several servers bind the same port, Unix only [default: false]
+  --tcp-nodelay <BOOL>         send small writes right away instead of batching them [default: true]
+  --listen-backlog <N>         connections queued by the kernel before they're accepted [default: 1024]
+  --min-compress-size <BYTES>  smallest body that gets compressed [default: 1024]
+  --compression-level <0-9>    how hard to compress, 9 is smallest and slowest [default: 6]
+  --compress-types <TYPES>     comma-separated media types to compress [default: all already compressed ones]
+  --mime-types <EXT=TYPE,..>   extra or overriding types for served files, e.g. md=text/markdown
+  --error-pages <CODE=FILE,..> pages sent with error responses, e.g. 404=errors/404.html
+  --server-header <NAME>       the Server header sent with responses, empty for none
+                               [default: butler/VERSION]
+  --index-files <NAMES>        comma-separated files served in place of a directory [default: index.html]
+  --directory-listing <BOOL>   list the entries of directories without an index file [default: true]
+  --slash-redirects <BOOL>     redirect directories without a trailing '/' to ones with it, and files
+                               the other way around [default: false]
+  --recursive-deletes <BOOL>   let DELETE remove directories and everything in them [default: false]
+  --cache-control <PATTERN=VALUE;..>
+                               Cache-Control for files whose path or type matches, first match wins,
+                               e.g. '/files/assets/*=max-age=31536000, immutable;text/html=no-cache'
+  --rewrites <PATTERN=TARGET;..>
+                               paths changed before routing, first match wins; patterns starting with
+                               ^ are regexes, others prefixes, and =STATUS redirects instead, e.g.
+                               '/static/=/files/assets/;^/posts/([0-9]+)$=/blog?id=$1=301'
+  --file-cache-size <BYTES>    memory for keeping small, often requested files and their compressed
+                               form, 0 for none [default: 0]
+  --max-body-size <BYTES>      largest request body accepted, also once decompressed, bigger ones get
+                               a 413 [default: 16777216]
+  --max-in-memory-body-size <BYTES>
+                               larger request bodies are written to a temporary file as they arrive
+                               [default: 1048576]
+  --max-header-size <BYTES>    largest request head accepted, bigger ones get a 431 [default: 8192]
+  --max-header-count <N>       most request headers accepted, more get a 431 [default: 100]
+  --event-loop <BOOL>          let idle keep-alive connections wait without a worker, needs the
+                               event-loop feature [default: false]
+  --basic-auth <PREFIX=FILE,..>
+                               paths that need a password from an htpasswd file, e.g. /files/=users.htpasswd
+  --write-tokens <TOKENS>      comma-separated bearer tokens, one of which uploads, replacements and
+                               deletions under /files/ need [default: none needed]
+  --cors-origins <ORIGINS>     comma-separated origins browsers may make requests from, * for any
+                               [default: none]
+  --cors-methods <METHODS>     methods allowed to those origins [default: GET,HEAD,POST,PUT,DELETE]
+  --cors-headers <HEADERS>     request headers allowed to those origins
+                               [default: Content-Type,Authorization]
+  --cors-max-age <SECS>        seconds browsers may cache a preflight answer [default: 600]
+  --rate-limit <N>             requests per second one client address may make, more get a 429
+                               [default: unlimited]
+  --rate-burst <N>             requests a client may make at once under --rate-limit
+                               [default: the rate]
+  --max-concurrent-requests <N>
+                               requests handled at once, more wait for a slot and then get a 503
+                               [default: unlimited]
+  --concurrency-limits <PREFIX=N;..>
+                               requests under a path handled at once, on top of
+                               --max-concurrent-requests, e.g. '/files/=8;/api/=32'
+  --concurrency-queue-timeout <SECS>
+                               seconds a request over a concurrency limit waits for a slot, 0 to
+                               turn it away at once [default: 1]
+  --allow-ips <RANGES>         comma-separated addresses or CIDR ranges that are the only clients
+                               served [default: everyone]
+  --deny-ips <RANGES>          addresses or CIDR ranges whose connections get a 403, even if allowed
+  --trusted-proxies <RANGES>   proxies whose Forwarded or X-Forwarded-For names the client that's
+                               logged, rate limited and checked against --allow-ips and --deny-ips
+                               [default: none]
+  --virtual-hosts <HOST=DIR,..>
+                               hosts whose /files/ serve a directory of their own, e.g.
+                               example.com=sites/example
+  --misdirect-unknown-hosts <BOOL>
+                               answer requests for any other host with a 421 [default: false]
+  --proxy <PREFIX=URL,..>      paths forwarded to an upstream server, e.g. /api=http://127.0.0.1:3000
+  --cgi <PREFIX=DIR>           run the executables in DIR as CGI scripts for the paths under PREFIX,
+                               e.g. /cgi-bin=scripts
+  --cgi-timeout <SECS>         seconds a CGI script may run before it's killed [default: 30]
+  --tls-cert <FILE>            PEM certificate chain, serves HTTPS together with --tls-key
+  --tls-key <FILE>             PEM private key for --tls-cert
+  --tls-port <PORT>            serve HTTPS on this port and plain HTTP on --port, instead of HTTPS on --port
+  --tls-client-ca <FILE>       PEM CA certificates client certificates are verified against, every
+                               HTTPS client needs one unless --client-cert-paths is given
+  --client-cert-paths <PREFIX[=writes];..>
+                               paths only clients with a certificate may request, or only upload to,
+                               replace and delete under with =writes, e.g. '/admin/;/files/=writes'
+  --daemon <BOOL>              fork to the background once the listeners are bound [default: false]
+  --pid-file <FILE>            file the server's process id is written to, removed on shutdown
+  --log-file <FILE>            file the log and anything else written to stdout or stderr is
+                               appended to [default: the terminal, nowhere with --daemon]
+  --user <NAME>                user to switch to once the listeners are bound, e.g. to serve port 80
+                               without staying root
+  --group <NAME>               group to switch to along with --user [default: the user's group]
+  --access-log <FILE>          file completed requests are appended to, - for stdout
+                               [default: logged under the access target]
+  --access-log-format <FORMAT> common, or combined to add the referer, user agent and
+                               milliseconds taken [default: common]
+  --log-level <FILTER>         log filter used when RUST_LOG isn't set, e.g. info
+  --log-format <FORMAT>        text, or json for one object per line with fields such as conn_id
+                               and status [default: text]
+  --config <FILE>              TOML file setting any of the options above, overridden by flags; the
+                               first --host, --basic-auth or --proxy flag replaces its list
+                               [default: butler.toml]
+  -h, --help                   print this message
+";
 
 fn main() -> anyhow::Result<()> {
-    env_logger::init();
-
-    let pool = ThreadPool::new(NUM_THREADS);
-    let listener = TcpListener::bind("127.0.0.1:4221")?;
-
-    log::info!("Listening on port 4221");
-
-    for (conn_id, stream) in listener.incoming().enumerate() {
-        match stream {
-            Ok(stream) => {
-                pool.execute(move || {
-                    if let Err(err) = handle_connection(stream, conn_id) {
-                        log::error!("error while handling connection: {err}");
-                    }
-                });
-            }
-            Err(err) => log::error!("error while attempting to establish a connection: {err}"),
+    let subcommand = std::env::args().nth(1);
+    if let Some(name @ ("bench" | "precompress")) = subcommand.as_deref() {
+        logger::init(None, LogFormat::Text).context("failed to install the logger")?;
+        let args = std::env::args().skip(2);
+        return match name {
+            "bench" => bench::run(args),
+            _ => precompress::run(args),
         };
     }
 
-    Ok(())
-}
+    let args = Args::parse(std::env::args().skip(1)).context("failed to parse arguments")?;
+    if args.help {
+        print!("{USAGE}");
+        return Ok(());
+    }
 
-fn handle_connection(mut stream: TcpStream, id: ConnId) -> anyhow::Result<()> {
-    log::info!("accepted connection {id}");
+    logger::init(args.log_level.as_deref(), args.log_format)
+        .context("failed to install the logger")?;
+    if let Some(path) = &args.log_file {
+        daemon::redirect_output(path)?;
+    }
 
-    let mut buf = [0; 4096];
-    let bytes_read = stream
-        .read(&mut buf)
-        .context("failed to read from client")?;
+    let tls = match (&args.tls_cert, &args.tls_key, &args.tls_client_ca) {
+        (Some(cert), Some(key), None) => {
+            Some(TlsConfig::from_pem_files(cert, key).context("failed to load TLS configuration")?)
+        }
+        // with paths of their own for certificates, clients without one can still use the rest
+        (Some(cert), Some(key), Some(ca)) => {
+            let auth = match args.client_cert_paths.is_empty() {
+                true => ClientAuth::Required,
+                false => ClientAuth::Optional,
+            };
+            Some(
+                TlsConfig::from_pem_files_with_client_auth(cert, key, ca, auth)
+                    .context("failed to load TLS configuration")?,
+            )
+        }
+        _ => None,
+    };
 
-    let request = String::from_utf8_lossy(&buf);
+    let (hosts, port, tls_port) = (args.hosts.clone(), args.port, args.tls_port);
+    let (daemonized, pid_file) = (args.daemon, args.pid_file.clone());
+    let keep_output = args.log_file.is_some();
+    let (user, group) = (args.user.clone(), args.group.clone());
+    let config = config(args, tls.clone())?;
 
-    log::debug!("id = {id}, request string = {}", &request[..bytes_read]);
+    // sockets passed on by systemd take the place of --host and --port
+    let server = match from_systemd(&config) {
+        Some(server) => server?,
+        None => bind(&hosts, port, tls_port, config, tls.clone())?,
+    };
 
-    let request: Request = request[..bytes_read]
-        .parse()
-        .context("failed to parse request")?;
-
-    log::debug!("id = {id}, request = {request:#?}");
-
-    let mut response = match request.line.url.as_ref() {
-        "/" => Response::empty(),
-        "/user-agent" => {
-            let user_agent = request
-                .headers
-                .iter()
-                .find_map(|header| {
-                    if let Header::UserAgent(agent) = header {
-                        Some(agent)
-                    } else {
-                        None
-                    }
-                })
-                .context("request does not have a 'User-Agent' header")?;
+    // privileged ports are bound by now, and no thread has been spawned yet
+    if daemonized {
+        daemon::daemonize(keep_output)?;
+    }
+    let _pid_file = pid_file.map(daemon::PidFile::create).transpose()?;
+    if user.is_some() || group.is_some() {
+        daemon::drop_privileges(user.as_deref(), group.as_deref())
+            .context("failed to drop privileges")?;
+    }
 
-            Response::text(user_agent.to_owned())
-        }
-        url => {
-            if let Some(string) = url.strip_prefix("/echo/") {
-                Response::text(string.to_owned())
-            } else if let Some(file_name) = url.strip_prefix("/files/") {
-                match request.line.method {
-                    Method::Get => Response::file(file_name),
-                    Method::Post => {
-                        let contents = request
-                            .body
-                            .context("POST request to /files must have a body")?;
-                        fs::write(format!("files/{file_name}"), contents)
-                            .context("failed to write file to disk")?;
-                        Response::created()
-                    }
-                }
-            } else {
-                Response::not_found()
+    {
+        let shutting_down = server.shutdown_flag();
+        ctrlc::set_handler(move || {
+            if shutting_down.swap(true, Ordering::SeqCst) {
+                log::warn!("received a second signal, exiting without waiting for connections");
+                std::process::exit(130);
             }
-        }
-    };
 
-    if request.headers.contains(&Header::AcceptEncoding) {
-        response = response.compressed();
+            log::info!("received an interrupt or termination signal, shutting down");
+        })
+        .context("failed to install the signal handler")?;
     }
+    reload_on_hangup(&server, tls)?;
+
+    server.run()
+}
+
+// the configuration `args` describe, with the htpasswd files they name read; `tls` is the
+// certificate loaded at startup, which is kept across reloads
+fn config(args: Args, tls: Option<TlsConfig>) -> anyhow::Result<Config> {
+    let basic_auth = args
+        .basic_auth
+        .iter()
+        .map(|(prefix, path)| BasicAuth::from_htpasswd_file(prefix, path))
+        .collect::<anyhow::Result<_>>()
+        .context("failed to load credentials")?;
+
+    Ok(Config {
+        files_root: args.directory,
+        workers: args.workers,
+        max_queued: args.max_queued,
+        max_connections_per_ip: args.max_connections_per_ip,
+        read_timeout: args.read_timeout,
+        header_timeout: args.header_timeout,
+        idle_timeout: args.idle_timeout,
+        max_requests_per_connection: args.max_requests_per_connection,
+        write_timeout: args.write_timeout,
+        drain_timeout: args.drain_timeout,
+        reuse_address: args.reuse_address,
+        reuse_port: args.reuse_port,
+        tcp_nodelay: args.tcp_nodelay,
+        listen_backlog: args.listen_backlog,
+        compression: args.compression,
+        mime_types: args.mime_types,
+        error_pages: args.error_pages,
+        server_header: args.server_header,
+        index_files: args.index_files,
+        directory_listing: args.directory_listing,
+        slash_redirects: args.slash_redirects,
+        recursive_deletes: args.recursive_deletes,
+        cache_control: args.cache_control,
+        rewrites: args.rewrites,
+        file_cache_size: args.file_cache_size,
+        max_body_size: args.max_body_size,
+        max_in_memory_body_size: args.max_in_memory_body_size,
+        max_header_size: args.max_header_size,
+        max_header_count: args.max_header_count,
+        event_loop: args.event_loop,
+        access_log: args.access_log,
+        access_log_format: args.access_log_format,
+        basic_auth,
+        write_tokens: args.write_tokens,
+        client_cert_paths: args.client_cert_paths,
+        cors: Some(args.cors).filter(|cors| !cors.allowed_origins.is_empty()),
+        rate_limit: args.rate_limit.map(|rate| RateLimit {
+            rate,
+            burst: args.rate_burst.unwrap_or(rate),
+        }),
+        max_concurrent_requests: args.max_concurrent_requests,
+        concurrency_limits: args.concurrency_limits,
+        concurrency_queue_timeout: args.concurrency_queue_timeout,
+        ip_filter: args.ip_filter,
+        trusted_proxies: args.trusted_proxies,
+        virtual_hosts: args.virtual_hosts,
+        misdirect_unknown_hosts: args.misdirect_unknown_hosts,
+        proxies: args.proxies,
+        cgi: args.cgi.map(|(prefix, directory)| {
+            let cgi = Cgi::new(&prefix, directory);
+            match args.cgi_timeout {
+                Some(timeout) => cgi.with_timeout(timeout),
+                None => cgi,
+            }
+        }),
+        // with a separate TLS port, --port stays plain HTTP
+        tls: tls.filter(|_| args.tls_port.is_none()),
+    })
+}
 
-    log::debug!("id = {id}, response = {response:#?}");
+// re-reads the arguments, and the config file and htpasswd files they name, whenever the process
+// gets a SIGHUP, and has the server switch to them; a configuration that fails to load is logged
+// and the current one kept
+#[cfg(unix)]
+fn reload_on_hangup(server: &Server, tls: Option<TlsConfig>) -> anyhow::Result<()> {
+    static HUNG_UP: AtomicBool = AtomicBool::new(false);
 
-    response
-        .write_to(&mut stream)
-        .context("failed to write to client")?;
+    extern "C" fn on_hangup(_: libc::c_int) {
+        HUNG_UP.store(true, Ordering::SeqCst);
+    }
+
+    // SAFETY: the handler only stores to an atomic, which is safe to do in a signal handler
+    let previous =
+        unsafe { libc::signal(libc::SIGHUP, on_hangup as *const () as libc::sighandler_t) };
+    if previous == libc::SIG_ERR {
+        return Err(io::Error::last_os_error()).context("failed to install the SIGHUP handler");
+    }
 
-    stream.flush().context("failed to write to client")?;
+    let reloader = server.reloader();
+    thread::Builder::new()
+        .name("reload".to_owned())
+        .spawn(move || loop {
+            thread::sleep(HANGUP_POLL_INTERVAL);
+            if !HUNG_UP.swap(false, Ordering::SeqCst) {
+                continue;
+            }
 
-    log::info!("closing connection {id}");
+            log::info!("received a hangup signal, reloading the configuration");
+            let reloaded = Args::parse(std::env::args().skip(1))
+                .context("failed to parse arguments")
+                .and_then(|args| {
+                    let (log_level, log_format) = (args.log_level.clone(), args.log_format);
+                    Ok((config(args, tls.clone())?, log_level, log_format))
+                });
+            match reloaded {
+                Ok((config, log_level, log_format)) => {
+                    logger::reload(log_level.as_deref(), log_format);
+                    reloader.reload(config);
+                }
+                Err(err) => {
+                    log::error!(
+                        "failed to reload the configuration, keeping the current one: {err:#}"
+                    )
+                }
+            }
+        })
+        .context("failed to spawn the reload thread")?;
     Ok(())
 }
 
-#[derive(Debug, Clone)]
-struct Request {
-    line: RequestLine,
-    headers: Vec<Header>,
-    body: Option<String>,
+// only Unix has SIGHUP
+#[cfg(not(unix))]
+fn reload_on_hangup(_server: &Server, _tls: Option<TlsConfig>) -> anyhow::Result<()> {
+    Ok(())
 }
 
-impl FromStr for Request {
-    type Err = anyhow::Error;
+// listens on every --host, with an HTTPS listener next to each TCP one when there's a --tls-port
+fn bind(
+    hosts: &[String],
+    port: u16,
+    tls_port: Option<u16>,
+    config: Config,
+    tls: Option<TlsConfig>,
+) -> anyhow::Result<Server> {
+    let hosts = match hosts {
+        [] => vec![DEFAULT_HOST.to_owned()],
+        hosts => hosts.to_vec(),
+    };
+    let bind_error = |host: &str| {
+        let (name, port) = listen_addr(host, port);
+        anyhow!("failed to bind to {name}:{port}")
+    };
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut parts = s.split_terminator("\r\n");
+    let (first, rest) = hosts.split_first().context("no host to listen on")?;
+    let mut server = match first.strip_prefix("unix:") {
+        Some(path) => bind_unix(path, config)?,
+        None => {
+            Server::bind(listen_addr(first, port), config).with_context(|| bind_error(first))?
+        }
+    };
+    for host in rest {
+        server = match host.strip_prefix("unix:") {
+            Some(path) => listen_unix(server, path)?,
+            None => server
+                .listen(listen_addr(host, port))
+                .with_context(|| bind_error(host))?,
+        };
+    }
+    if let (Some(port), Some(tls)) = (tls_port, tls) {
+        for host in hosts.iter().filter(|host| !host.starts_with("unix:")) {
+            let (name, _) = split_host(host);
+            server = server
+                .listen_tls((name, port), tls.clone())
+                .with_context(|| anyhow!("failed to bind to {name}:{port}"))?;
+        }
+    }
 
-        let line: RequestLine = parts
-            .next()
-            .with_context(|| anyhow!("did not find request line in request {s}"))?
-            .parse()
-            .context("failed to parse request line")?;
+    Ok(server)
+}
 
-        let headers: Vec<Header> = parts
-            .by_ref()
-            .take_while(|header_str| !header_str.is_empty())
-            .filter_map(|header_str| {
-                header_str
-                    .parse()
-                    .inspect_err(|err| {
-                        log::warn!("failed to parse HTTP header, skipping...: {err}")
-                    })
-                    .ok()
-            })
-            .collect();
-
-        let body = parts.next().map(String::from);
-
-        Ok(Self {
-            line,
-            headers,
-            body,
-        })
+// splits a --host into the address and the port it names, if any, e.g. `[::]:8080`,
+// `0.0.0.0:8080`, `::` or `localhost`
+fn split_host(host: &str) -> (&str, Option<u16>) {
+    if let Some((name, port)) = host.rsplit_once(':') {
+        // a bare IPv6 address has colons of its own
+        let name = match name
+            .strip_prefix('[')
+            .and_then(|name| name.strip_suffix(']'))
+        {
+            Some(name) => Some(name),
+            None => Some(name).filter(|name| !name.contains(':')),
+        };
+        if let (Some(name), Ok(port)) = (name, port.parse()) {
+            return (name, Some(port));
+        }
     }
-}
 
-#[derive(Debug, Clone)]
-struct RequestLine {
-    method: Method,
-    url: String,
+    let name = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'));
+    (name.unwrap_or(host), None)
 }
 
-impl FromStr for RequestLine {
-    type Err = anyhow::Error;
+// the address a --host is bound to, on `port` unless it names one of its own
+fn listen_addr(host: &str, port: u16) -> (&str, u16) {
+    let (name, own_port) = split_host(host);
+    (name, own_port.unwrap_or(port))
+}
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut parts = s.split_whitespace();
+#[cfg(unix)]
+fn from_systemd(config: &Config) -> Option<anyhow::Result<Server>> {
+    Server::socket_activated().then(|| Server::from_systemd(config.clone()))
+}
 
-        let method: Method = parts
-            .next()
-            .context("could not find HTTP method in request line")?
-            .parse()
-            .context("failed to parse HTTP method")?;
+#[cfg(not(unix))]
+fn from_systemd(_config: &Config) -> Option<anyhow::Result<Server>> {
+    None
+}
 
-        let url = parts
-            .next()
-            .context("could find URL in request line")?
-            .to_owned();
+#[cfg(unix)]
+fn bind_unix(path: &str, config: Config) -> anyhow::Result<Server> {
+    Server::bind_unix(path, config)
+}
 
-        Ok(Self { method, url })
-    }
+#[cfg(not(unix))]
+fn bind_unix(_path: &str, _config: Config) -> anyhow::Result<Server> {
+    Err(anyhow!(
+        "Unix domain sockets aren't supported on this platform"
+    ))
 }
 
-#[derive(Debug, Clone)]
-struct Response {
-    // TODO: change to enum
-    status_code: u32,
-    headers: Vec<Header>,
-    body: Option<Vec<u8>>,
+#[cfg(unix)]
+fn listen_unix(server: Server, path: &str) -> anyhow::Result<Server> {
+    server.listen_unix(path)
 }
 
-impl Response {
-    fn empty() -> Self {
-        Self {
-            status_code: 200,
-            headers: Vec::new(),
-            body: None,
-        }
-    }
+#[cfg(not(unix))]
+fn listen_unix(_server: Server, _path: &str) -> anyhow::Result<Server> {
+    Err(anyhow!(
+        "Unix domain sockets aren't supported on this platform"
+    ))
+}
 
-    fn not_found() -> Self {
-        Self {
-            status_code: 404,
-            headers: Vec::new(),
-            body: None,
-        }
-    }
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Args {
+    // `DEFAULT_HOST` when there are none
+    hosts: Vec<String>,
+    port: u16,
+    directory: PathBuf,
+    workers: usize,
+    max_queued: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    read_timeout: Duration,
+    header_timeout: Duration,
+    idle_timeout: Duration,
+    max_requests_per_connection: Option<usize>,
+    write_timeout: Duration,
+    drain_timeout: Duration,
+    reuse_address: bool,
+    reuse_port: bool,
+    tcp_nodelay: bool,
+    listen_backlog: u32,
+    compression: CompressionPolicy,
+    mime_types: HashMap<String, ContentType>,
+    error_pages: HashMap<StatusCode, PathBuf>,
+    server_header: Option<String>,
+    index_files: Vec<String>,
+    directory_listing: bool,
+    slash_redirects: bool,
+    recursive_deletes: bool,
+    cache_control: Vec<CacheRule>,
+    rewrites: Vec<RewriteRule>,
+    file_cache_size: u64,
+    max_body_size: u64,
+    max_in_memory_body_size: u64,
+    max_header_size: usize,
+    max_header_count: usize,
+    event_loop: bool,
+    access_log: Option<PathBuf>,
+    access_log_format: AccessLogFormat,
+    // path prefixes and the htpasswd files with the users allowed under them
+    basic_auth: Vec<(String, PathBuf)>,
+    write_tokens: Vec<String>,
+    client_cert_paths: Vec<ClientCertRule>,
+    // only used once some origins are allowed
+    cors: CorsPolicy,
+    rate_limit: Option<u32>,
+    rate_burst: Option<u32>,
+    max_concurrent_requests: Option<usize>,
+    concurrency_limits: Vec<ConcurrencyLimit>,
+    concurrency_queue_timeout: Duration,
+    ip_filter: IpFilter,
+    trusted_proxies: Vec<Cidr>,
+    virtual_hosts: HashMap<String, PathBuf>,
+    misdirect_unknown_hosts: bool,
+    proxies: Vec<Proxy>,
+    // the path prefix and the directory its scripts are run from
+    cgi: Option<(String, PathBuf)>,
+    cgi_timeout: Option<Duration>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    tls_port: Option<u16>,
+    tls_client_ca: Option<PathBuf>,
+    daemon: bool,
+    pid_file: Option<PathBuf>,
+    log_file: Option<PathBuf>,
+    user: Option<String>,
+    group: Option<String>,
+    // a filter such as `info` or `access=info,butler=debug`, `RUST_LOG` takes precedence over it
+    log_level: Option<String>,
+    log_format: LogFormat,
+    help: bool,
+}
 
-    fn text(text: String) -> Self {
-        Self {
-            status_code: 200,
-            headers: vec![
-                Header::ContentType(ContentType::TextPlain),
-                Header::ContentLength(text.len()),
-            ],
-            body: Some(text.into_bytes()),
-        }
-    }
+impl Default for Args {
+    fn default() -> Self {
+        let config = Config::default();
 
-    fn created() -> Self {
         Self {
-            status_code: 201,
-            headers: Vec::new(),
-            body: None,
+            hosts: Vec::new(),
+            port: DEFAULT_PORT,
+            directory: config.files_root,
+            workers: config.workers,
+            max_queued: config.max_queued,
+            max_connections_per_ip: config.max_connections_per_ip,
+            read_timeout: config.read_timeout,
+            header_timeout: config.header_timeout,
+            idle_timeout: config.idle_timeout,
+            max_requests_per_connection: config.max_requests_per_connection,
+            write_timeout: config.write_timeout,
+            drain_timeout: config.drain_timeout,
+            reuse_address: config.reuse_address,
+            reuse_port: config.reuse_port,
+            tcp_nodelay: config.tcp_nodelay,
+            listen_backlog: config.listen_backlog,
+            compression: config.compression,
+            mime_types: config.mime_types,
+            error_pages: config.error_pages,
+            server_header: config.server_header,
+            index_files: config.index_files,
+            directory_listing: config.directory_listing,
+            slash_redirects: config.slash_redirects,
+            recursive_deletes: config.recursive_deletes,
+            cache_control: config.cache_control,
+            rewrites: config.rewrites,
+            file_cache_size: config.file_cache_size,
+            max_body_size: config.max_body_size,
+            max_in_memory_body_size: config.max_in_memory_body_size,
+            max_header_size: config.max_header_size,
+            max_header_count: config.max_header_count,
+            event_loop: config.event_loop,
+            access_log: config.access_log,
+            access_log_format: config.access_log_format,
+            basic_auth: Vec::new(),
+            write_tokens: config.write_tokens,
+            client_cert_paths: config.client_cert_paths,
+            cors: config.cors.unwrap_or_default(),
+            rate_limit: config.rate_limit.map(|limit| limit.rate),
+            rate_burst: config.rate_limit.map(|limit| limit.burst),
+            max_concurrent_requests: config.max_concurrent_requests,
+            concurrency_limits: config.concurrency_limits,
+            concurrency_queue_timeout: config.concurrency_queue_timeout,
+            ip_filter: config.ip_filter,
+            trusted_proxies: config.trusted_proxies,
+            virtual_hosts: config.virtual_hosts,
+            misdirect_unknown_hosts: config.misdirect_unknown_hosts,
+            proxies: config.proxies,
+            cgi: None,
+            cgi_timeout: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_port: None,
+            tls_client_ca: None,
+            daemon: false,
+            pid_file: None,
+            log_file: None,
+            user: None,
+            group: None,
+            log_level: None,
+            log_format: LogFormat::default(),
+            help: false,
         }
     }
+}
 
-    fn file(file_name: &str) -> Self {
-        let Ok(mut file) = File::open(format!("files/{file_name}")) else {
-            return Self::not_found();
+impl Args {
+    fn parse(args: impl Iterator<Item = String>) -> anyhow::Result<Self> {
+        let args: Vec<String> = args.collect();
+        let mut parsed = Self::default();
+
+        // the config file is applied first so that flags override it, wherever `--config` appears
+        let config_path = match args.iter().position(|arg| arg == "--config") {
+            Some(i) => Some(PathBuf::from(
+                args.get(i + 1)
+                    .context("missing value for argument \"--config\"")?,
+            )),
+            None => Some(PathBuf::from(DEFAULT_CONFIG_FILE)).filter(|path| path.exists()),
         };
-
-        // TODO: change to server error
-        let mut content = Vec::new();
-        if file.read_to_end(&mut content).is_err() {
-            log::error!("failed to read file {file_name:?}");
-            return Self::not_found();
+        if let Some(path) = config_path {
+            config_file::load(&path, &mut parsed)
+                .with_context(|| anyhow!("failed to load config file {path:?}"))?;
         }
 
-        Response {
-            status_code: 200,
-            headers: vec![
-                Header::ContentType(ContentType::ApplicationOctetStream),
-                Header::ContentLength(content.len()),
-            ],
-            body: Some(content),
+        // lists the command line has started over, so that the config file's are replaced by its
+        // first flag for them and added to by the ones after it
+        let mut replaced = HashSet::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--help" | "-h" => parsed.help = true,
+                "--config" => {
+                    args.next();
+                }
+                _ => {
+                    let option = arg
+                        .strip_prefix("--")
+                        .with_context(|| anyhow!("unknown argument {arg:?}, see --help"))?;
+                    let value = || {
+                        args.next()
+                            .with_context(|| anyhow!("missing value for argument {arg:?}"))
+                    };
+                    let list = if option == "bind" { "host" } else { option };
+                    if !replaced.contains(list) && parsed.clear_list(list) {
+                        replaced.insert(list.to_owned());
+                    }
+                    if !parsed.set(option, value)? {
+                        return Err(anyhow!("unknown argument {arg:?}, see --help"));
+                    }
+                }
+            }
         }
-    }
 
-    fn compressed(mut self) -> Self {
-        debug_assert!(!self.headers.contains(&Header::ContentEncoding));
-
-        self.headers.push(Header::ContentEncoding);
-
-        if let Some(body) = self.body.as_mut() {
-            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
-            encoder.write_all(body).unwrap();
-            *body = encoder.finish().unwrap();
-            let content_len_header = self
-                .headers
-                .iter_mut()
-                .find(|header| matches!(header, Header::ContentLength(_)))
-                .expect("expected to have 'Content-Length' header in response with body");
-
-            *content_len_header = Header::ContentLength(body.len());
+        if parsed.tls_cert.is_some() != parsed.tls_key.is_some() {
+            return Err(anyhow!(
+                "--tls-cert and --tls-key have to be given together"
+            ));
+        }
+        if parsed.rate_burst.is_some() && parsed.rate_limit.is_none() {
+            return Err(anyhow!("--rate-burst needs --rate-limit"));
+        }
+        if parsed.cgi_timeout.is_some() && parsed.cgi.is_none() {
+            return Err(anyhow!("--cgi-timeout needs --cgi"));
+        }
+        if parsed.tls_port.is_some() && parsed.tls_cert.is_none() {
+            return Err(anyhow!("--tls-port needs --tls-cert and --tls-key"));
+        }
+        if parsed.tls_client_ca.is_some() && parsed.tls_cert.is_none() {
+            return Err(anyhow!("--tls-client-ca needs --tls-cert and --tls-key"));
+        }
+        if !parsed.client_cert_paths.is_empty() && parsed.tls_client_ca.is_none() {
+            return Err(anyhow!("--client-cert-paths needs --tls-client-ca"));
+        }
+        if parsed.tls_port.is_some()
+            && !parsed.hosts.is_empty()
+            && parsed.hosts.iter().all(|host| host.starts_with("unix:"))
+        {
+            return Err(anyhow!(
+                "--tls-port needs a --host that isn't a Unix socket"
+            ));
         }
 
-        self
+        Ok(parsed)
     }
 
-    fn write_to(&self, mut w: impl io::Write) -> io::Result<()> {
-        write!(
-            w,
-            "HTTP/1.1 {status}\r\n{headers}\r\n",
-            status = match self.status_code {
-                200 => "200 OK",
-                201 => "201 Created",
-                404 => "404 Not Found",
-                code => todo!("unhandled status code: {code}"),
-            },
-            headers = self
-                .headers
-                .iter()
-                .map(|header| format!("{header}\r\n"))
-                .fold(String::new(), |acc, s| acc + &s),
-        )?;
-
-        if let Some(body) = self.body.as_ref() {
-            w.write_all(body)?;
+    // empties the list `option` adds to, returning false if it isn't one
+    fn clear_list(&mut self, option: &str) -> bool {
+        match option {
+            "host" => self.hosts.clear(),
+            "basic-auth" => self.basic_auth.clear(),
+            "proxy" => self.proxies.clear(),
+            _ => return false,
         }
-
-        Ok(())
+        true
     }
-}
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum Method {
-    Get,
-    Post,
-}
-
-impl FromStr for Method {
-    type Err = anyhow::Error;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.to_lowercase().as_ref() {
-            "get" => Ok(Self::Get),
-            "post" => Ok(Self::Post),
-            _ => Err(anyhow!("{s} is not a valid HTTP method")),
+    // sets the option a flag or config file key names, returning false if there is no such option;
+    // `value` is only taken for known options
+    fn set(
+        &mut self,
+        option: &str,
+        value: impl FnOnce() -> anyhow::Result<String>,
+    ) -> anyhow::Result<bool> {
+        match option {
+            // every one given is listened on, though the command line's replace the config file's
+            "host" | "bind" => self.hosts.extend(parse_list(&value()?)),
+            "port" => self.port = parse_number(&value()?)?,
+            "directory" => self.directory = PathBuf::from(value()?),
+            "workers" | "threads" => {
+                self.workers = parse_number(&value()?)?;
+                if self.workers == 0 {
+                    return Err(anyhow!("{option} must be at least 1"));
+                }
+            }
+            "max-queued" => self.max_queued = Some(parse_number(&value()?)?),
+            "read-timeout" => {
+                let secs = parse_number(&value()?)?;
+                if secs == 0 {
+                    return Err(anyhow!("read-timeout must be at least 1 second"));
+                }
+                self.read_timeout = Duration::from_secs(secs);
+            }
+            "max-connections-per-ip" => {
+                self.max_connections_per_ip = Some(parse_number(&value()?)?);
+                if self.max_connections_per_ip == Some(0) {
+                    return Err(anyhow!("max-connections-per-ip must be at least 1"));
+                }
+            }
+            "header-timeout" => {
+                let secs = parse_number(&value()?)?;
+                if secs == 0 {
+                    return Err(anyhow!("header-timeout must be at least 1 second"));
+                }
+                self.header_timeout = Duration::from_secs(secs);
+            }
+            "idle-timeout" => {
+                let secs = parse_number(&value()?)?;
+                if secs == 0 {
+                    return Err(anyhow!("idle-timeout must be at least 1 second"));
+                }
+                self.idle_timeout = Duration::from_secs(secs);
+            }
+            "max-requests-per-connection" => {
+                self.max_requests_per_connection = Some(parse_number(&value()?)?);
+                if self.max_requests_per_connection == Some(0) {
+                    return Err(anyhow!("max-requests-per-connection must be at least 1"));
+                }
+            }
+            "write-timeout" => {
+                let secs = parse_number(&value()?)?;
+                if secs == 0 {
+                    return Err(anyhow!("write-timeout must be at least 1 second"));
+                }
+                self.write_timeout = Duration::from_secs(secs);
+            }
+            "drain-timeout" => self.drain_timeout = Duration::from_secs(parse_number(&value()?)?),
+            "reuse-address" => {
+                let value = value()?;
+                self.reuse_address = value
+                    .parse()
+                    .with_context(|| anyhow!("{value:?} is neither true nor false"))?;
+            }
+            "reuse-port" => {
+                let value = value()?;
+                self.reuse_port = value
+                    .parse()
+                    .with_context(|| anyhow!("{value:?} is neither true nor false"))?;
+            }
+            "tcp-nodelay" => {
+                let value = value()?;
+                self.tcp_nodelay = value
+                    .parse()
+                    .with_context(|| anyhow!("{value:?} is neither true nor false"))?;
+            }
+            "listen-backlog" => {
+                self.listen_backlog = parse_number(&value()?)?;
+                if self.listen_backlog == 0 {
+                    return Err(anyhow!("listen-backlog must be at least 1"));
+                }
+            }
+            "min-compress-size" => self.compression.min_size = parse_number(&value()?)?,
+            "compression-level" => {
+                self.compression.level = parse_number(&value()?)?;
+                if self.compression.level > 9 {
+                    return Err(anyhow!("compression-level must be between 0 and 9"));
+                }
+            }
+            "compress-types" => {
+                self.compression.content_types = Some(
+                    value()?
+                        .split(',')
+                        .map(str::parse)
+                        .collect::<anyhow::Result<_>>()?,
+                )
+            }
+            "mime-types" => {
+                for mapping in value()?.split(',') {
+                    let (extension, content_type) = mapping.split_once('=').with_context(|| {
+                        anyhow!("{mapping:?} is not a mapping such as md=text/markdown")
+                    })?;
+                    let extension = extension.trim().trim_start_matches('.').to_lowercase();
+                    self.mime_types.insert(extension, content_type.parse()?);
+                }
+            }
+            "error-pages" => {
+                for mapping in value()?.split(',') {
+                    let (code, page) = mapping.split_once('=').with_context(|| {
+                        anyhow!("{mapping:?} is not a mapping such as 404=errors/404.html")
+                    })?;
+                    let status = StatusCode::from_code(parse_number(code.trim())?)
+                        .filter(|status| status.code() >= 400)
+                        .with_context(|| anyhow!("{code:?} is not an error status code"))?;
+                    self.error_pages.insert(status, PathBuf::from(page.trim()));
+                }
+            }
+            "server-header" => {
+                let value = value()?;
+                if value.contains(['\r', '\n']) {
+                    return Err(anyhow!("{value:?} would corrupt the Server header"));
+                }
+                self.server_header = Some(value.trim().to_owned()).filter(|name| !name.is_empty());
+            }
+            // an empty list turns index files off
+            "index-files" => self.index_files = parse_list(&value()?),
+            "directory-listing" => {
+                let value = value()?;
+                self.directory_listing = value
+                    .parse()
+                    .with_context(|| anyhow!("{value:?} is neither true nor false"))?;
+            }
+            "slash-redirects" => {
+                let value = value()?;
+                self.slash_redirects = value
+                    .parse()
+                    .with_context(|| anyhow!("{value:?} is neither true nor false"))?;
+            }
+            "recursive-deletes" => {
+                let value = value()?;
+                self.recursive_deletes = value
+                    .parse()
+                    .with_context(|| anyhow!("{value:?} is neither true nor false"))?;
+            }
+            "cache-control" => {
+                self.cache_control = value()?
+                    .split(';')
+                    .filter(|rule| !rule.trim().is_empty())
+                    .map(str::parse)
+                    .collect::<anyhow::Result<_>>()?
+            }
+            "rewrites" => {
+                self.rewrites = value()?
+                    .split(';')
+                    .filter(|rule| !rule.trim().is_empty())
+                    .map(str::parse)
+                    .collect::<anyhow::Result<_>>()?
+            }
+            "file-cache-size" => self.file_cache_size = parse_number(&value()?)?,
+            "max-body-size" => self.max_body_size = parse_number(&value()?)?,
+            "max-in-memory-body-size" => self.max_in_memory_body_size = parse_number(&value()?)?,
+            "max-header-size" => self.max_header_size = parse_number(&value()?)?,
+            "max-header-count" => self.max_header_count = parse_number(&value()?)?,
+            "event-loop" => {
+                let value = value()?;
+                self.event_loop = value
+                    .parse()
+                    .with_context(|| anyhow!("{value:?} is neither true nor false"))?;
+            }
+            "access-log" => self.access_log = Some(PathBuf::from(value()?)),
+            "access-log-format" => self.access_log_format = value()?.parse()?,
+            "basic-auth" => {
+                for mapping in value()?.split(',') {
+                    let (prefix, path) = mapping.split_once('=').with_context(|| {
+                        anyhow!("{mapping:?} is not a mapping such as /files/=users.htpasswd")
+                    })?;
+                    self.basic_auth
+                        .push((prefix.trim().to_owned(), PathBuf::from(path.trim())));
+                }
+            }
+            "write-tokens" => self.write_tokens = parse_list(&value()?),
+            "cors-origins" => self.cors.allowed_origins = parse_list(&value()?),
+            "cors-methods" => {
+                self.cors.allowed_methods = parse_list(&value()?)
+                    .iter()
+                    .map(|method| method.parse())
+                    .collect::<anyhow::Result<_>>()?
+            }
+            "cors-headers" => self.cors.allowed_headers = parse_list(&value()?),
+            "cors-max-age" => self.cors.max_age = Duration::from_secs(parse_number(&value()?)?),
+            "rate-limit" => {
+                self.rate_limit = Some(parse_number(&value()?)?);
+                if self.rate_limit == Some(0) {
+                    return Err(anyhow!("rate-limit must be at least 1"));
+                }
+            }
+            "rate-burst" => {
+                self.rate_burst = Some(parse_number(&value()?)?);
+                if self.rate_burst == Some(0) {
+                    return Err(anyhow!("rate-burst must be at least 1"));
+                }
+            }
+            "max-concurrent-requests" => {
+                self.max_concurrent_requests = Some(parse_number(&value()?)?);
+                if self.max_concurrent_requests == Some(0) {
+                    return Err(anyhow!("max-concurrent-requests must be at least 1"));
+                }
+            }
+            "concurrency-limits" => {
+                self.concurrency_limits = value()?
+                    .split(';')
+                    .filter(|limit| !limit.trim().is_empty())
+                    .map(str::parse)
+                    .collect::<anyhow::Result<_>>()?
+            }
+            "concurrency-queue-timeout" => {
+                self.concurrency_queue_timeout = Duration::from_secs(parse_number(&value()?)?)
+            }
+            "allow-ips" => self.ip_filter.allow = parse_ranges(&value()?)?,
+            "deny-ips" => self.ip_filter.deny = parse_ranges(&value()?)?,
+            "trusted-proxies" => self.trusted_proxies = parse_ranges(&value()?)?,
+            "virtual-hosts" => {
+                for mapping in value()?.split(',') {
+                    let (host, root) = mapping.split_once('=').with_context(|| {
+                        anyhow!("{mapping:?} is not a mapping such as example.com=sites/example")
+                    })?;
+                    self.virtual_hosts
+                        .insert(host.trim().to_lowercase(), PathBuf::from(root.trim()));
+                }
+            }
+            "misdirect-unknown-hosts" => {
+                let value = value()?;
+                self.misdirect_unknown_hosts = value
+                    .parse()
+                    .with_context(|| anyhow!("{value:?} is neither true nor false"))?;
+            }
+            "proxy" => {
+                for mapping in value()?.split(',') {
+                    let (prefix, upstream) = mapping.split_once('=').with_context(|| {
+                        anyhow!("{mapping:?} is not a mapping such as /api=http://127.0.0.1:3000")
+                    })?;
+                    self.proxies
+                        .push(Proxy::new(prefix.trim(), upstream.trim())?);
+                }
+            }
+            "cgi" => {
+                let value = value()?;
+                let (prefix, directory) = value.split_once('=').with_context(|| {
+                    anyhow!("{value:?} is not a mapping such as /cgi-bin=scripts")
+                })?;
+                self.cgi = Some((prefix.trim().to_owned(), PathBuf::from(directory.trim())));
+            }
+            "cgi-timeout" => {
+                let secs = parse_number(&value()?)?;
+                if secs == 0 {
+                    return Err(anyhow!("cgi-timeout must be at least 1 second"));
+                }
+                self.cgi_timeout = Some(Duration::from_secs(secs));
+            }
+            "tls-cert" => self.tls_cert = Some(PathBuf::from(value()?)),
+            "tls-key" => self.tls_key = Some(PathBuf::from(value()?)),
+            "tls-port" => self.tls_port = Some(parse_number(&value()?)?),
+            "tls-client-ca" => self.tls_client_ca = Some(PathBuf::from(value()?)),
+            "daemon" => {
+                let value = value()?;
+                self.daemon = value
+                    .parse()
+                    .with_context(|| anyhow!("{value:?} is neither true nor false"))?;
+            }
+            "pid-file" => self.pid_file = Some(PathBuf::from(value()?)),
+            "log-file" => self.log_file = Some(PathBuf::from(value()?)),
+            "user" => self.user = Some(value()?),
+            "group" => self.group = Some(value()?),
+            "client-cert-paths" => {
+                self.client_cert_paths = value()?
+                    .split(';')
+                    .filter(|rule| !rule.trim().is_empty())
+                    .map(str::parse)
+                    .collect::<anyhow::Result<_>>()?
+            }
+            "log-level" => self.log_level = Some(value()?),
+            "log-format" => self.log_format = value()?.parse()?,
+            _ => return Ok(false),
         }
+
+        Ok(true)
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-enum Header {
-    ContentType(ContentType),
-    ContentLength(usize),
-    UserAgent(String),
-    // assume gzip
-    AcceptEncoding,
-    ContentEncoding,
+// splits a comma-separated list, leaving out empty items
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_owned)
+        .collect()
 }
 
-impl fmt::Display for Header {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Self::ContentType(content_type) => write!(f, "Content-Type: {content_type}"),
-            Self::ContentLength(length) => write!(f, "Content-Length: {length}"),
-            Self::ContentEncoding => write!(f, "Content-Encoding: gzip"),
-            _ => todo!(),
-        }
-    }
+fn parse_ranges(value: &str) -> anyhow::Result<Vec<Cidr>> {
+    parse_list(value)
+        .iter()
+        .map(|range| range.parse())
+        .collect()
 }
 
-impl FromStr for Header {
-    type Err = anyhow::Error;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut parts = s.split(':').map(str::trim);
-
-        let name = parts
-            .next()
-            .context("failed to find header name, maybe it's missing a ':'?")?;
-
-        let value = parts
-            .next()
-            .context("failed to find header value, maybe it's missing a ':'?")?;
+fn parse_number<T>(value: &str) -> anyhow::Result<T>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    value
+        .parse()
+        .with_context(|| anyhow!("{value:?} is not a valid number"))
+}
 
-        match name.to_lowercase().as_ref() {
-            "user-agent" => Ok(Self::UserAgent(value.to_owned())),
-            "accept-encoding" if value == "gzip" => Ok(Self::AcceptEncoding),
-            "accept-encoding" => Err(anyhow!("failed to parse 'Accept-Encoding': unknown encoding {value:?}, only 'gzip' is supported")),
-            name => Err(anyhow!("unknown header: {name:?}")),
-        }
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn split_host_separates_ports_from_addresses() {
+        assert_eq!(split_host("0.0.0.0:8080"), ("0.0.0.0", Some(8080)));
+        assert_eq!(split_host("[::]:8080"), ("::", Some(8080)));
+        assert_eq!(split_host("localhost:8080"), ("localhost", Some(8080)));
+        assert_eq!(split_host("localhost"), ("localhost", None));
+        assert_eq!(split_host("::"), ("::", None));
+        assert_eq!(split_host("::1"), ("::1", None));
+        assert_eq!(split_host("[::1]"), ("::1", None));
     }
-}
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
-enum ContentType {
-    #[default]
-    TextPlain,
-    ApplicationOctetStream,
-}
+    #[test]
+    fn command_line_lists_replace_the_config_files() {
+        let path = std::env::temp_dir().join(format!("butler-config-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            "host = \"0.0.0.0\"\nbasic_auth = \"/=users\"\nproxy = \"/api=http://127.0.0.1:3000\"\n",
+        )
+        .unwrap();
+        let args = |flags: &[&str]| {
+            let config = ["--config", path.to_str().unwrap()];
+            Args::parse(config.iter().chain(flags).map(|arg| arg.to_string())).unwrap()
+        };
 
-impl fmt::Display for ContentType {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            ContentType::TextPlain => f.write_str("text/plain"),
-            ContentType::ApplicationOctetStream => f.write_str("application/octet-stream"),
-        }
+        let parsed = args(&[]);
+        assert_eq!(parsed.hosts, ["0.0.0.0"]);
+        assert_eq!(parsed.basic_auth.len(), 1);
+        assert_eq!(parsed.proxies.len(), 1);
+
+        // the first flag for a list starts it over, the ones after it add to it
+        let parsed = args(&[
+            "--host",
+            "127.0.0.1",
+            "--bind",
+            "[::1]",
+            "--host",
+            "unix:a.sock",
+        ]);
+        assert_eq!(parsed.hosts, ["127.0.0.1", "[::1]", "unix:a.sock"]);
+        assert_eq!(parsed.basic_auth.len(), 1);
+
+        let parsed = args(&[
+            "--basic-auth",
+            "/files/=admins",
+            "--basic-auth",
+            "/cgi-bin/=admins",
+            "--proxy",
+            "/other=http://127.0.0.1:4000",
+        ]);
+        assert_eq!(parsed.hosts, ["0.0.0.0"]);
+        assert_eq!(
+            parsed.basic_auth,
+            [
+                ("/files/".to_owned(), PathBuf::from("admins")),
+                ("/cgi-bin/".to_owned(), PathBuf::from("admins"))
+            ]
+        );
+        assert_eq!(parsed.proxies.len(), 1);
+
+        fs::remove_file(&path).unwrap();
     }
 }
@@ -0,0 +1,244 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, PoisonError,
+    },
+    time::Duration,
+};
+
+use crate::{request::Method, response::StatusCode};
+
+// upper bounds of the request duration histogram's buckets, in seconds
+const DURATION_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+// counters for everything answered since the server started, shared by every worker
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    // by method and status code, the method is `None` for requests that couldn't be parsed
+    requests: Mutex<HashMap<(Option<Method>, StatusCode), u64>>,
+    // indexed by the first digit of the status code, minus one
+    responses_by_class: [AtomicU64; 5],
+    body_bytes_sent: AtomicU64,
+    // requests that took no longer than each of `DURATION_BUCKETS`, but longer than the one before
+    durations: [AtomicU64; DURATION_BUCKETS.len()],
+    durations_over: AtomicU64,
+    duration_sum_micros: AtomicU64,
+    // connections turned away because every worker was busy and the queue was full
+    connections_shed: AtomicU64,
+}
+
+impl Metrics {
+    // `latency` is `None` for responses sent before a request head was read, which aren't timed
+    pub(crate) fn record(
+        &self,
+        method: Option<Method>,
+        status: StatusCode,
+        body_bytes_sent: u64,
+        latency: Option<Duration>,
+    ) {
+        *self
+            .requests
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry((method, status))
+            .or_default() += 1;
+        self.responses_by_class[usize::from(status.code() / 100 - 1)]
+            .fetch_add(1, Ordering::Relaxed);
+        self.body_bytes_sent
+            .fetch_add(body_bytes_sent, Ordering::Relaxed);
+
+        if let Some(latency) = latency {
+            let secs = latency.as_secs_f64();
+            match DURATION_BUCKETS.iter().position(|&bound| secs <= bound) {
+                Some(bucket) => self.durations[bucket].fetch_add(1, Ordering::Relaxed),
+                None => self.durations_over.fetch_add(1, Ordering::Relaxed),
+            };
+            self.duration_sum_micros.fetch_add(
+                u64::try_from(latency.as_micros()).unwrap_or(u64::MAX),
+                Ordering::Relaxed,
+            );
+        }
+    }
+
+    pub(crate) fn record_shed(&self) {
+        self.connections_shed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // renders the counters in the Prometheus text exposition format, along with gauges of the
+    // connections currently held by a worker and waiting for one
+    pub(crate) fn render(&self, active_connections: usize, queued_connections: usize) -> String {
+        let mut text = String::new();
+
+        let mut requests: Vec<_> = self
+            .requests
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|(&(method, status), &count)| {
+                let method =
+                    method.map_or_else(|| "unknown".to_owned(), |method| method.to_string());
+                (method, status.code(), count)
+            })
+            .collect();
+        requests.sort_unstable();
+        text.push_str(
+            "# HELP http_requests_total Requests answered since the server started, by method and status code.\n",
+        );
+        text.push_str("# TYPE http_requests_total counter\n");
+        for (method, code, count) in requests {
+            text.push_str(&format!(
+                "http_requests_total{{method=\"{method}\",status=\"{code}\"}} {count}\n"
+            ));
+        }
+
+        text.push_str(
+            "# HELP http_request_duration_seconds Time from reading a request head to sending its response.\n",
+        );
+        text.push_str("# TYPE http_request_duration_seconds histogram\n");
+        let mut cumulative = 0;
+        for (bound, count) in DURATION_BUCKETS.iter().zip(&self.durations) {
+            cumulative += count.load(Ordering::Relaxed);
+            text.push_str(&format!(
+                "http_request_duration_seconds_bucket{{le=\"{bound}\"}} {cumulative}\n"
+            ));
+        }
+        cumulative += self.durations_over.load(Ordering::Relaxed);
+        text.push_str(&format!(
+            "http_request_duration_seconds_bucket{{le=\"+Inf\"}} {cumulative}\n"
+        ));
+        text.push_str(&format!(
+            "http_request_duration_seconds_sum {}\n",
+            self.duration_sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+        ));
+        text.push_str(&format!(
+            "http_request_duration_seconds_count {cumulative}\n"
+        ));
+
+        text.push_str("# HELP http_responses_total Responses sent, by status code class.\n");
+        text.push_str("# TYPE http_responses_total counter\n");
+        for (class, count) in self.responses_by_class.iter().enumerate() {
+            text.push_str(&format!(
+                "http_responses_total{{class=\"{}xx\"}} {}\n",
+                class + 1,
+                count.load(Ordering::Relaxed)
+            ));
+        }
+
+        text.push_str("# HELP http_response_body_bytes_total Response body bytes sent.\n");
+        text.push_str("# TYPE http_response_body_bytes_total counter\n");
+        text.push_str(&format!(
+            "http_response_body_bytes_total {}\n",
+            self.body_bytes_sent.load(Ordering::Relaxed)
+        ));
+
+        text.push_str(
+            "# HELP http_connections_shed_total Connections turned away because the queue was full.\n",
+        );
+        text.push_str("# TYPE http_connections_shed_total counter\n");
+        text.push_str(&format!(
+            "http_connections_shed_total {}\n",
+            self.connections_shed.load(Ordering::Relaxed)
+        ));
+
+        text.push_str("# HELP http_active_connections Connections held by a worker.\n");
+        text.push_str("# TYPE http_active_connections gauge\n");
+        text.push_str(&format!("http_active_connections {active_connections}\n"));
+
+        text.push_str("# HELP http_queued_connections Connections waiting for a free worker.\n");
+        text.push_str("# TYPE http_queued_connections gauge\n");
+        text.push_str(&format!("http_queued_connections {queued_connections}\n"));
+
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_render_recorded_responses() {
+        let metrics = Metrics::default();
+        metrics.record(
+            Some(Method::Get),
+            StatusCode::Ok,
+            10,
+            Some(Duration::from_millis(3)),
+        );
+        metrics.record(
+            Some(Method::Get),
+            StatusCode::NotFound,
+            0,
+            Some(Duration::from_millis(40)),
+        );
+        metrics.record(
+            Some(Method::Get),
+            StatusCode::Ok,
+            5,
+            Some(Duration::from_secs(11)),
+        );
+        metrics.record(None, StatusCode::BadRequest, 0, None);
+        metrics.record_shed();
+
+        let text = metrics.render(2, 7);
+
+        assert!(
+            text.contains("\nhttp_requests_total{method=\"GET\",status=\"200\"} 2\n"),
+            "{text}"
+        );
+        assert!(
+            text.contains("\nhttp_requests_total{method=\"GET\",status=\"404\"} 1\n"),
+            "{text}"
+        );
+        assert!(
+            text.contains("\nhttp_requests_total{method=\"unknown\",status=\"400\"} 1\n"),
+            "{text}"
+        );
+        assert!(
+            text.contains("\nhttp_request_duration_seconds_bucket{le=\"0.005\"} 1\n"),
+            "{text}"
+        );
+        assert!(
+            text.contains("\nhttp_request_duration_seconds_bucket{le=\"0.05\"} 2\n"),
+            "{text}"
+        );
+        assert!(
+            text.contains("\nhttp_request_duration_seconds_bucket{le=\"10\"} 2\n"),
+            "{text}"
+        );
+        assert!(
+            text.contains("\nhttp_request_duration_seconds_bucket{le=\"+Inf\"} 3\n"),
+            "{text}"
+        );
+        assert!(
+            text.contains("\nhttp_request_duration_seconds_sum 11.043\n"),
+            "{text}"
+        );
+        assert!(
+            text.contains("\nhttp_request_duration_seconds_count 3\n"),
+            "{text}"
+        );
+        assert!(
+            text.contains("\nhttp_responses_total{class=\"2xx\"} 2\n"),
+            "{text}"
+        );
+        assert!(
+            text.contains("\nhttp_responses_total{class=\"4xx\"} 2\n"),
+            "{text}"
+        );
+        assert!(
+            text.contains("\nhttp_responses_total{class=\"5xx\"} 0\n"),
+            "{text}"
+        );
+        assert!(
+            text.contains("\nhttp_response_body_bytes_total 15\n"),
+            "{text}"
+        );
+        assert!(text.contains("\nhttp_connections_shed_total 1\n"), "{text}");
+        assert!(text.contains("\nhttp_active_connections 2\n"), "{text}");
+        assert!(text.contains("\nhttp_queued_connections 7\n"), "{text}");
+    }
+}
@@ -0,0 +1,51 @@
+use crate::{
+    request::{Request, Version},
+    response::{Body, CompressionPolicy, Response, StatusCode},
+};
+
+// hooks run around every request, `before` in the order the middleware was added and `after`
+// in reverse, so the first one added sees the request first and the response last
+pub trait Middleware: Send + Sync {
+    // returning a response answers the request with it, skipping the routes and the `before` of
+    // any middleware added later; their `after` is skipped too, while earlier ones still run theirs
+    fn before(&self, _request: &mut Request) -> Option<Response> {
+        None
+    }
+
+    fn after(&self, _request: &Request, response: Response) -> Response {
+        response
+    }
+}
+
+// compresses responses with the encoding the client prefers, wrapped around everything else so
+// that it sees the responses the other middleware made
+#[derive(Debug)]
+pub(crate) struct Compression(pub(crate) CompressionPolicy);
+
+impl Middleware for Compression {
+    fn after(&self, request: &Request, mut response: Response) -> Response {
+        // whether the body is compressed depends on what the client accepts, even for clients that
+        // get it as it is, and a 304 stands in for a body that may have been
+        if response.status == StatusCode::NotModified || response.is_compressible(&self.0) {
+            response = response.with_vary("Accept-Encoding");
+        }
+
+        // compressing a file on the fly needs chunked encoding, which HTTP/1.0 clients don't
+        // understand, and partial content is sent as-is
+        let can_compress = response.status != StatusCode::PartialContent
+            && (request.line.version != Version::Http10
+                || !matches!(response.body, Some(Body::File(_))));
+
+        let Some(encoding) = request.preferred_encoding().filter(|_| can_compress) else {
+            return response;
+        };
+
+        match response.compressed(encoding, &self.0) {
+            Ok(response) => response,
+            Err(err) => {
+                log::error!("{err:#}");
+                Response::internal_server_error()
+            }
+        }
+    }
+}
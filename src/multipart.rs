@@ -0,0 +1,205 @@
+use anyhow::{anyhow, Context};
+
+use crate::header::ContentType;
+
+// one part of a `multipart/form-data` body, a form field or an uploaded file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Part {
+    // the name of the form field it belongs to
+    pub name: String,
+    // the name of the file on the client, only set for file uploads
+    pub filename: Option<String>,
+    pub content_type: Option<ContentType>,
+    pub data: Vec<u8>,
+}
+
+// the boundary of a `Content-Type: multipart/form-data; boundary=...` value, `None` for other types
+pub(crate) fn boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';');
+    if !params
+        .next()?
+        .trim()
+        .eq_ignore_ascii_case("multipart/form-data")
+    {
+        return None;
+    }
+    parse_params(&params.collect::<Vec<_>>().join(";"))
+        .into_iter()
+        .find_map(|(name, value)| name.eq_ignore_ascii_case("boundary").then_some(value))
+        .filter(|boundary| !boundary.is_empty())
+}
+
+// splits `body` at every `--boundary` line, skipping anything before the first and after the last
+pub(crate) fn parse(body: &[u8], boundary: &str) -> anyhow::Result<Vec<Part>> {
+    let delimiter = format!("--{boundary}");
+    let delimiter = delimiter.as_bytes();
+    // every delimiter but a first one at the very start of the body follows a line break
+    let separator = [b"\r\n", delimiter].concat();
+
+    let start = if body.starts_with(delimiter) {
+        0
+    } else {
+        find(body, &separator).context("multipart body has no boundary")? + 2
+    };
+    let mut rest = &body[start + delimiter.len()..];
+
+    let mut parts = Vec::new();
+    loop {
+        if rest.starts_with(b"--") {
+            return Ok(parts);
+        }
+        // padding is allowed after a delimiter
+        let line_end = find(rest, b"\r\n").context("multipart body ends too early")?;
+        if !rest[..line_end].iter().all(|&b| b == b' ' || b == b'\t') {
+            return Err(anyhow!("multipart boundary is followed by something else"));
+        }
+        rest = &rest[line_end + 2..];
+
+        let end = find(rest, &separator).context("multipart body ends in the middle of a part")?;
+        parts.push(parse_part(&rest[..end])?);
+        rest = &rest[end + separator.len()..];
+    }
+}
+
+fn parse_part(part: &[u8]) -> anyhow::Result<Part> {
+    let (head, data) = match part.strip_prefix(b"\r\n") {
+        // a part without headers
+        Some(data) => ("", data),
+        None => {
+            let end =
+                find(part, b"\r\n\r\n").context("multipart part has no end to its headers")?;
+            let head =
+                std::str::from_utf8(&part[..end]).context("multipart part headers aren't UTF-8")?;
+            (head, &part[end + 4..])
+        }
+    };
+
+    let mut disposition = None;
+    let mut content_type = None;
+    for line in head.split("\r\n") {
+        let (name, value) = line
+            .split_once(':')
+            .with_context(|| anyhow!("invalid multipart header {line:?}"))?;
+        match name.trim().to_lowercase().as_str() {
+            "content-disposition" => disposition = Some(value.trim()),
+            "content-type" => content_type = Some(value.trim().parse()?),
+            _ => {}
+        }
+    }
+
+    let disposition = disposition.context("multipart part has no 'Content-Disposition'")?;
+    let (kind, params) = disposition.split_once(';').unwrap_or((disposition, ""));
+    if !kind.trim().eq_ignore_ascii_case("form-data") {
+        return Err(anyhow!("multipart part isn't form-data"));
+    }
+    let params = parse_params(params);
+    let param = |wanted: &str| {
+        params
+            .iter()
+            .find_map(|(name, value)| name.eq_ignore_ascii_case(wanted).then(|| value.clone()))
+    };
+
+    Ok(Part {
+        name: param("name").context("multipart part has no name")?,
+        filename: param("filename"),
+        content_type,
+        data: data.to_vec(),
+    })
+}
+
+// `name=value` pairs separated by ';', where values may be quoted
+fn parse_params(s: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut chars = s.chars().peekable();
+    loop {
+        let name: String = chars
+            .by_ref()
+            .skip_while(|c| *c == ';' || c.is_whitespace())
+            .take_while(|c| *c != '=')
+            .collect();
+        if name.is_empty() {
+            return params;
+        }
+
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    // browsers send the backslashes of Windows paths as they are
+                    '\\' if matches!(chars.peek(), Some('"' | '\\')) => value.extend(chars.next()),
+                    c => value.push(c),
+                }
+            }
+            // anything between the closing quote and the next ';'
+            chars.by_ref().take_while(|c| *c != ';').for_each(drop);
+        } else {
+            value = chars.by_ref().take_while(|c| *c != ';').collect();
+        }
+        params.push((name.trim().to_owned(), value.trim().to_owned()));
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boundary_is_read_from_the_content_type() {
+        assert_eq!(
+            boundary("multipart/form-data; boundary=abc").as_deref(),
+            Some("abc")
+        );
+        assert_eq!(
+            boundary("Multipart/Form-Data; charset=utf-8; boundary=\"a; b\"").as_deref(),
+            Some("a; b")
+        );
+        assert_eq!(boundary("multipart/form-data"), None);
+        assert_eq!(boundary("text/plain; boundary=abc"), None);
+    }
+
+    #[test]
+    fn parse_extracts_fields_and_files() {
+        let body = b"preamble\r\n--XyZ\r\n\
+            Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+            hello\r\n\
+            --XyZ\r\n\
+            Content-Disposition: form-data; name=\"upload\"; filename=\"a \\\"b\\\".bin\"\r\n\
+            Content-Type: application/octet-stream\r\n\r\n\
+            \x00\r\n--X\xff\r\n\
+            --XyZ--\r\nepilogue";
+
+        let parts = parse(body, "XyZ").unwrap();
+        assert_eq!(
+            parts,
+            [
+                Part {
+                    name: "title".to_owned(),
+                    filename: None,
+                    content_type: None,
+                    data: b"hello".to_vec(),
+                },
+                Part {
+                    name: "upload".to_owned(),
+                    filename: Some("a \"b\".bin".to_owned()),
+                    content_type: Some(ContentType::ApplicationOctetStream),
+                    data: b"\x00\r\n--X\xff".to_vec(),
+                },
+            ]
+        );
+
+        assert!(parse(
+            b"--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nx",
+            "XyZ"
+        )
+        .is_err());
+        assert!(parse(b"no boundary here", "XyZ").is_err());
+    }
+}
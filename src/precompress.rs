@@ -0,0 +1,197 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+use butler::ContentType;
+use flate2::{write::GzEncoder, Compression};
+
+use crate::parse_number;
+
+// the same as the compression middleware's defaults, except for the level: a copy is made once,
+// so it's worth the time it takes to make it as small as it gets
+const DEFAULT_MIN_SIZE: u64 = 1024;
+const DEFAULT_LEVEL: u32 = 9;
+const PRECOMPRESS_USAGE: &str = "\
+usage: butler precompress [options] <DIR>
+
+saves a gzipped copy of every file under a directory next to it as <name>.gz, which the server sends
+to clients that accept gzip in place of compressing the file for each of them
+
+options:
+  --min-size <BYTES>           files smaller than this are left alone [default: 1024]
+  --level <0-9>                gzip compression level [default: 9]
+  -h, --help                   print this message
+";
+
+#[derive(Debug)]
+struct PrecompressArgs {
+    directory: Option<PathBuf>,
+    min_size: u64,
+    level: u32,
+    help: bool,
+}
+
+impl PrecompressArgs {
+    fn parse(mut args: impl Iterator<Item = String>) -> anyhow::Result<Self> {
+        let mut parsed = Self {
+            directory: None,
+            min_size: DEFAULT_MIN_SIZE,
+            level: DEFAULT_LEVEL,
+            help: false,
+        };
+
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .with_context(|| anyhow!("missing value for argument {arg:?}"))
+            };
+            match arg.as_str() {
+                "--help" | "-h" => parsed.help = true,
+                "--min-size" => parsed.min_size = parse_number(&value()?)?,
+                "--level" => {
+                    parsed.level = parse_number(&value()?)?;
+                    if parsed.level > 9 {
+                        return Err(anyhow!("--level has to be from 0 to 9"));
+                    }
+                }
+                _ if arg.starts_with('-') => {
+                    return Err(anyhow!(
+                        "unknown argument {arg:?}, see butler precompress --help"
+                    ))
+                }
+                _ if parsed.directory.is_some() => {
+                    return Err(anyhow!("only one directory can be precompressed at a time"))
+                }
+                _ => parsed.directory = Some(PathBuf::from(arg)),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+// `butler precompress`, run with the arguments that follow it
+pub(crate) fn run(args: impl Iterator<Item = String>) -> anyhow::Result<()> {
+    let args = PrecompressArgs::parse(args).context("failed to parse arguments")?;
+    if args.help {
+        print!("{PRECOMPRESS_USAGE}");
+        return Ok(());
+    }
+    let directory = args
+        .directory
+        .as_deref()
+        .context("missing the directory to precompress, see butler precompress --help")?;
+
+    let mut summary = Summary::default();
+    precompress_dir(directory, &args, &mut summary)?;
+    println!(
+        "compressed {} files, {} were up to date and {} not worth compressing",
+        summary.compressed, summary.up_to_date, summary.skipped
+    );
+    Ok(())
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Summary {
+    compressed: usize,
+    up_to_date: usize,
+    // too small, compressed already, or no smaller gzipped
+    skipped: usize,
+}
+
+fn precompress_dir(
+    dir: &Path,
+    args: &PrecompressArgs,
+    summary: &mut Summary,
+) -> anyhow::Result<()> {
+    let entries = fs::read_dir(dir).with_context(|| anyhow!("failed to read directory {dir:?}"))?;
+    for entry in entries {
+        let entry = entry.with_context(|| anyhow!("failed to read entry of directory {dir:?}"))?;
+        let path = entry.path();
+        // symlinks are skipped, so nothing outside the directory is written next to
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            precompress_dir(&path, args, summary)?;
+        } else if file_type.is_file() {
+            precompress_file(&path, args, summary)?;
+        }
+    }
+    Ok(())
+}
+
+fn precompress_file(
+    path: &Path,
+    args: &PrecompressArgs,
+    summary: &mut Summary,
+) -> anyhow::Result<()> {
+    let metadata = fs::metadata(path)?;
+    if metadata.len() < args.min_size || ContentType::from_path(path).is_compressed() {
+        summary.skipped += 1;
+        return Ok(());
+    }
+
+    let mut gzipped = path.as_os_str().to_owned();
+    gzipped.push(".gz");
+    let gzipped = PathBuf::from(gzipped);
+    let gzipped_modified = fs::metadata(&gzipped).and_then(|metadata| metadata.modified());
+    if gzipped_modified.is_ok_and(|modified| metadata.modified().is_ok_and(|m| modified >= m)) {
+        summary.up_to_date += 1;
+        return Ok(());
+    }
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::new(args.level));
+    io::copy(&mut File::open(path)?, &mut encoder)
+        .with_context(|| anyhow!("failed to compress {path:?}"))?;
+    let compressed = encoder.finish()?;
+    if compressed.len() as u64 >= metadata.len() {
+        summary.skipped += 1;
+        return Ok(());
+    }
+
+    let mut file =
+        File::create(&gzipped).with_context(|| anyhow!("failed to create {gzipped:?}"))?;
+    file.write_all(&compressed)
+        .with_context(|| anyhow!("failed to write {gzipped:?}"))?;
+    log::info!("compressed {path:?} to {} bytes", compressed.len());
+    summary.compressed += 1;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn precompress_saves_gzipped_copies_worth_keeping() {
+        let dir = std::env::temp_dir().join(format!("butler-precompress-{}", std::process::id()));
+        fs::create_dir_all(dir.join("assets")).unwrap();
+        fs::write(dir.join("assets/app.js"), "let x = 1;\n".repeat(200)).unwrap();
+        fs::write(dir.join("small.txt"), "tiny").unwrap();
+        fs::write(dir.join("cat.png"), vec![7; 4096]).unwrap();
+
+        let args =
+            PrecompressArgs::parse([dir.to_str().unwrap()].map(str::to_owned).into_iter()).unwrap();
+        assert_eq!((args.min_size, args.level), (1024, 9));
+        assert!(PrecompressArgs::parse(["--level", "10"].map(str::to_owned).into_iter()).is_err());
+
+        let mut summary = Summary::default();
+        precompress_dir(&dir, &args, &mut summary).unwrap();
+        assert_eq!(
+            summary,
+            Summary {
+                compressed: 1,
+                up_to_date: 0,
+                skipped: 2
+            }
+        );
+        assert!(dir.join("assets/app.js.gz").is_file());
+        assert!(!dir.join("small.txt.gz").exists());
+
+        // the copy made the first time is skipped the second, and not compressed itself
+        let mut summary = Summary::default();
+        precompress_dir(&dir, &args, &mut summary).unwrap();
+        assert_eq!((summary.compressed, summary.up_to_date), (0, 1));
+    }
+}
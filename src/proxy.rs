@@ -0,0 +1,355 @@
+use std::{
+    io::{self, BufReader, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context};
+
+use crate::{
+    client::{read_response_head, ChunkedReader},
+    date::parse_http_date,
+    header::{is_field_value, Header},
+    request::{percent_encode, Method, Request, Version},
+    request_id::REQUEST_ID_HEADER,
+    response::{Body, Response, StatusCode, SERVER_NAME},
+    router::Handler,
+    server::is_timeout,
+};
+
+// how long the upstream may take to accept a connection, or go quiet while answering
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(30);
+// upstream bodies up to this size are read before they're relayed, so their length can be passed on
+const MAX_BUFFERED_BODY_SIZE: u64 = 1024 * 1024;
+// headers that only describe a single connection, so they're never passed on
+const HOP_BY_HOP: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+// forwards the requests under a path prefix to an upstream HTTP server and relays its responses;
+// add it to `Config::proxies`, or register it for routes of your own as a `Handler`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Proxy {
+    // without a trailing '/'
+    prefix: String,
+    // `host:port`, connected to and sent as the `Host`
+    authority: String,
+    // what the prefix is replaced with, `None` keeps paths as they are
+    base_path: Option<String>,
+}
+
+impl Proxy {
+    // `upstream` is a URL such as `http://127.0.0.1:3000`, which gets request paths as they are,
+    // or `http://127.0.0.1:3000/v1/`, which gets them with `prefix` replaced by its path
+    pub fn new(prefix: &str, upstream: &str) -> anyhow::Result<Self> {
+        let rest = upstream
+            .strip_prefix("http://")
+            .with_context(|| anyhow!("{upstream:?} isn't an http:// URL"))?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], Some(rest[i..].trim_end_matches('/'))),
+            None => (rest, None),
+        };
+        if authority.is_empty() {
+            return Err(anyhow!("{upstream:?} has no host"));
+        }
+        let authority = if authority
+            .rsplit_once(':')
+            .is_some_and(|(_, port)| !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()))
+        {
+            authority.to_owned()
+        } else {
+            format!("{authority}:80")
+        };
+
+        Ok(Self {
+            prefix: prefix.trim_end_matches('/').to_owned(),
+            authority,
+            base_path: path.map(str::to_owned),
+        })
+    }
+
+    // the route patterns the proxy answers, the prefix itself and everything below it
+    pub(crate) fn patterns(&self) -> [String; 2] {
+        [
+            if self.prefix.is_empty() {
+                "/".to_owned()
+            } else {
+                self.prefix.clone()
+            },
+            format!("{}/{{*path}}", self.prefix),
+        ]
+    }
+
+    // the path and query the upstream is asked for
+    fn target(&self, request: &Request) -> String {
+        let rest = request.param("path").map(percent_encode);
+        let base = self.base_path.as_deref().unwrap_or(&self.prefix);
+        let mut target = match rest {
+            Some(rest) => format!("{base}/{rest}"),
+            None if base.is_empty() => "/".to_owned(),
+            None => base.to_owned(),
+        };
+        if let Some(query) = &request.line.raw_query {
+            target.push('?');
+            target.push_str(query);
+        }
+        target
+    }
+
+    fn forward(&self, request: &Request) -> anyhow::Result<Response> {
+        let addr = self
+            .authority
+            .to_socket_addrs()
+            .context("failed to resolve upstream")?
+            .next()
+            .context("upstream has no address")?;
+        let stream = TcpStream::connect_timeout(&addr, UPSTREAM_TIMEOUT)
+            .context("failed to connect to upstream")?;
+        stream.set_read_timeout(Some(UPSTREAM_TIMEOUT))?;
+        stream.set_write_timeout(Some(UPSTREAM_TIMEOUT))?;
+
+        let head = self.request_head(request);
+        log::debug!("request_id = {}, upstream request = {head:?}", request.id);
+        let mut writer = &stream;
+        writer.write_all(head.as_bytes())?;
+        io::copy(&mut request.body_reader()?, &mut writer)?;
+        writer.flush()?;
+
+        let mut reader = BufReader::new(stream);
+        let (status, headers) = read_response_head(&mut reader)?;
+
+        let mut response = Response::new(status);
+        let mut content_length = None;
+        let mut chunked = false;
+        let mut via_sent = false;
+        for (name, value) in headers {
+            let lowercase = name.to_lowercase();
+            // the upstream is sent the request's ID, which is added to the response later
+            if HOP_BY_HOP.contains(&lowercase.as_str()) || lowercase == "x-request-id" {
+                continue;
+            }
+            let header = match lowercase.as_str() {
+                "content-length" => {
+                    content_length = Some(value.parse::<u64>().with_context(|| {
+                        anyhow!("upstream sent an invalid Content-Length {value:?}")
+                    })?);
+                    continue;
+                }
+                "transfer-encoding" => {
+                    chunked = value.to_lowercase().contains("chunked");
+                    continue;
+                }
+                // butler compresses responses itself, and has to know what it's compressing
+                "content-type" => value.parse().map(Header::ContentType).ok(),
+                "content-encoding" => value.parse().map(Header::ContentEncoding).ok(),
+                "date" => parse_http_date(&value).map(Header::Date),
+                "last-modified" => parse_http_date(&value).map(Header::LastModified),
+                "etag" => Some(Header::ETag(value.clone())),
+                "server" => Some(Header::Server(value.clone())),
+                "via" => {
+                    via_sent = true;
+                    Some(Header::Other(
+                        name.clone(),
+                        format!("{value}, {}", via(Version::Http11)),
+                    ))
+                }
+                _ => None,
+            };
+            response
+                .headers
+                .push(header.unwrap_or(Header::Other(name, value)));
+        }
+        if !via_sent {
+            response = response.with_header("Via", &via(Version::Http11));
+        }
+
+        let has_body = !matches!(status, StatusCode::NoContent | StatusCode::NotModified);
+        if request.method() == Method::Head || !has_body {
+            // the length of the body a GET would have gotten is still worth passing on
+            if let Some(length) = content_length.filter(|_| has_body) {
+                response.headers.push(Header::ContentLength(length));
+                response.body = Some(Body::Bytes(Vec::new()));
+            }
+            return Ok(response);
+        }
+
+        // small bodies keep their length, others are passed on as they arrive instead of being held
+        // in memory
+        response.body = Some(match (chunked, content_length) {
+            (false, Some(length)) if length <= MAX_BUFFERED_BODY_SIZE => {
+                let mut body = vec![0; length as usize];
+                reader
+                    .read_exact(&mut body)
+                    .context("failed to read response body from upstream")?;
+                response.headers.push(Header::ContentLength(length));
+                Body::Bytes(body)
+            }
+            (true, _) => Body::Stream(Box::new(ChunkedReader::new(reader))),
+            (false, Some(length)) => Body::Stream(Box::new(reader.take(length))),
+            (false, None) => Body::Stream(Box::new(reader)),
+        });
+        Ok(response)
+    }
+
+    fn request_head(&self, request: &Request) -> String {
+        let mut head = format!("{} {} HTTP/1.1\r\n", request.method(), self.target(request));
+        head.push_str(&format!("Host: {}\r\n", self.authority));
+
+        let mut forwarded_for = None;
+        let mut via_sent = None;
+        // headers the client lists in `Connection` are hop-by-hop as well
+        let connection_options: Vec<String> = request
+            .header("connection")
+            .unwrap_or_default()
+            .split(',')
+            .map(|option| option.trim().to_lowercase())
+            .collect();
+        for (name, value) in request.raw_headers().iter() {
+            // ones a middleware or handler set haven't been checked like the client's were, and
+            // could add lines of their own to the head
+            if !is_field_value(value) {
+                log::warn!(
+                    "request_id = {}, not forwarding {name:?}, its value has a control character",
+                    request.id
+                );
+                continue;
+            }
+            let lowercase = name.to_lowercase();
+            match lowercase.as_str() {
+                "x-forwarded-for" => {
+                    forwarded_for = Some(match forwarded_for {
+                        Some(earlier) => format!("{earlier}, {value}"),
+                        None => value.to_owned(),
+                    });
+                    continue;
+                }
+                "via" => {
+                    via_sent = Some(value.to_owned());
+                    continue;
+                }
+                // bodies are sent with a length, butler compresses what it relays itself, and the
+                // request's ID is sent whether or not the client picked it
+                "host" | "content-length" | "transfer-encoding" | "accept-encoding" | "expect"
+                | "x-request-id" => continue,
+                _ => {}
+            }
+            if HOP_BY_HOP.contains(&lowercase.as_str()) || connection_options.contains(&lowercase) {
+                continue;
+            }
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+
+        if let Some(peer) = request.peer_addr() {
+            let ip = peer.ip().to_canonical();
+            head.push_str(&format!(
+                "X-Forwarded-For: {}\r\n",
+                match forwarded_for {
+                    Some(earlier) => format!("{earlier}, {ip}"),
+                    None => ip.to_string(),
+                }
+            ));
+        }
+        head.push_str(&format!("{REQUEST_ID_HEADER}: {}\r\n", request.id));
+        if let Some(host) = request.host() {
+            head.push_str(&format!("X-Forwarded-Host: {host}\r\n"));
+        }
+        let via = via(request.version());
+        head.push_str(&match via_sent {
+            Some(earlier) => format!("Via: {earlier}, {via}\r\n"),
+            None => format!("Via: {via}\r\n"),
+        });
+        if let Some(len) = request.body_len() {
+            head.push_str(&format!("Content-Length: {len}\r\n"));
+        }
+        // butler asks for one request per connection, so the end of the response is never in doubt
+        head.push_str("Accept-Encoding: identity\r\nConnection: close\r\n\r\n");
+        head
+    }
+}
+
+impl Handler for Proxy {
+    fn handle(&self, request: &Request) -> anyhow::Result<Response> {
+        match self.forward(request) {
+            Ok(response) => Ok(response),
+            Err(err) => {
+                log::error!(
+                    "request_id = {}, failed to proxy {} to {}: {err:#}",
+                    request.id,
+                    request.path(),
+                    self.authority
+                );
+                let timed_out = err
+                    .chain()
+                    .any(|cause| cause.downcast_ref::<io::Error>().is_some_and(is_timeout));
+                Ok(Response::new(if timed_out {
+                    StatusCode::GatewayTimeout
+                } else {
+                    StatusCode::BadGateway
+                }))
+            }
+        }
+    }
+}
+
+// the `Via` entry for a message received over `version`, e.g. `1.1 butler`
+fn via(version: Version) -> String {
+    let protocol = version.to_string();
+    format!(
+        "{} {SERVER_NAME}",
+        protocol.strip_prefix("HTTP/").unwrap_or(&protocol)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proxy_targets_keep_or_replace_the_prefix() {
+        let request = |path: &str| -> Request {
+            let mut request: Request = format!("GET {path} HTTP/1.1\r\n\r\n").parse().unwrap();
+            if let Some(rest) = path.split('?').next().unwrap().strip_prefix("/api/") {
+                request.params = vec![("path".to_owned(), rest.replace("%20", " "))];
+            }
+            request
+        };
+
+        let keep = Proxy::new("/api/", "http://localhost").unwrap();
+        assert_eq!(keep.authority, "localhost:80");
+        assert_eq!(
+            keep.target(&request("/api/a%20b?x=1&y")),
+            "/api/a%20b?x=1&y"
+        );
+        assert_eq!(keep.target(&request("/api")), "/api");
+
+        let replace = Proxy::new("/api", "http://127.0.0.1:3000/").unwrap();
+        assert_eq!(replace.target(&request("/api/users")), "/users");
+        assert_eq!(replace.target(&request("/api")), "/");
+
+        let rebase = Proxy::new("/api", "http://127.0.0.1:3000/v1").unwrap();
+        assert_eq!(rebase.target(&request("/api/users")), "/v1/users");
+
+        assert!(Proxy::new("/api", "https://example.com").is_err());
+        assert!(Proxy::new("/api", "http:///").is_err());
+    }
+
+    #[test]
+    fn proxy_leaves_out_header_values_with_control_characters() {
+        let mut request: Request = "GET /api HTTP/1.1\r\nX-Kept: a\tb\r\n\r\n".parse().unwrap();
+        // as a middleware could have set it
+        request.raw_headers.append("X-Set", "a\nInjected: yes");
+
+        let head = Proxy::new("/api", "http://localhost")
+            .unwrap()
+            .request_head(&request);
+        assert!(head.contains("\r\nX-Kept: a\tb\r\n"), "{head}");
+        assert!(!head.contains("Injected"), "{head}");
+    }
+}
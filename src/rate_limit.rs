@@ -0,0 +1,132 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use crate::{middleware::Middleware, request::Request, response::Response};
+
+// how often buckets that have filled up again are dropped
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// how many requests each client address may make, see `Config::rate_limit`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    // requests per second a client can keep up
+    pub rate: u32,
+    // requests a client that has been quiet can make at once
+    pub burst: u32,
+}
+
+// a token bucket per client address, refilled at `RateLimit::rate` tokens per second up to
+// `RateLimit::burst`; every request takes a token, and ones that find the bucket empty get a 429
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    limit: RateLimit,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    buckets: HashMap<IpAddr, Bucket>,
+    last_sweep: Instant,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            state: Mutex::new(State {
+                buckets: HashMap::new(),
+                last_sweep: Instant::now(),
+            }),
+        }
+    }
+
+    // takes a token from `ip`'s bucket, or returns how long it'll be until there is one
+    fn take(&self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        let burst = f64::from(self.limit.burst);
+        let rate = f64::from(self.limit.rate);
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+
+        // a full bucket is no different from one that doesn't exist, so those are dropped instead of
+        // keeping every address that ever made a request
+        if now.duration_since(state.last_sweep) >= SWEEP_INTERVAL {
+            state
+                .buckets
+                .retain(|_, bucket| bucket.refilled(now, rate) < burst);
+            state.last_sweep = now;
+        }
+
+        let bucket = state.buckets.entry(ip).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        bucket.tokens = bucket.refilled(now, rate).min(burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else if rate > 0.0 {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        } else {
+            Err(Duration::MAX)
+        }
+    }
+}
+
+impl Bucket {
+    fn refilled(&self, now: Instant, rate: f64) -> f64 {
+        self.tokens + now.duration_since(self.updated).as_secs_f64() * rate
+    }
+}
+
+impl Middleware for RateLimiter {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        // only connections the server accepted itself have an address
+        let ip = request.client_ip()?;
+        let wait = self.take(ip, Instant::now()).err()?;
+
+        log::debug!("request_id = {}, {ip} is over its rate limit", request.id);
+        // whole seconds, rounded up so that a client that waits as long as it's told isn't turned away
+        let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+        Some(Response::too_many_requests().with_header("Retry-After", &retry_after.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limiter_refills_buckets_over_time() {
+        let limiter = RateLimiter::new(RateLimit { rate: 2, burst: 3 });
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert_eq!(limiter.take(ip, start), Ok(()));
+        }
+        assert_eq!(limiter.take(ip, start), Err(Duration::from_millis(500)));
+        // other clients have buckets of their own
+        assert_eq!(limiter.take(IpAddr::from([127, 0, 0, 2]), start), Ok(()));
+
+        let later = start + Duration::from_millis(500);
+        assert_eq!(limiter.take(ip, later), Ok(()));
+        assert!(limiter.take(ip, later).is_err());
+
+        // a sweep drops the buckets that are full again
+        let much_later = start + SWEEP_INTERVAL;
+        limiter.take(ip, much_later).unwrap();
+        let state = limiter.state.lock().unwrap();
+        assert_eq!(state.buckets.keys().collect::<Vec<_>>(), [&ip]);
+    }
+}
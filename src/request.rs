@@ -0,0 +1,1355 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    fmt,
+    fs::{self, File},
+    io::{self, Read},
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    path::Path,
+    str::FromStr,
+    sync::{Arc, OnceLock},
+    time::SystemTime,
+};
+
+use anyhow::{anyhow, Context};
+use flate2::read::{MultiGzDecoder, ZlibDecoder};
+
+use crate::{
+    cookie::parse_cookies,
+    header::{
+        accepted_quality, is_field_value, is_token, parse_accept, AcceptedEncoding, ConnectionMode,
+        ContentType, Encoding, Header, HeaderMap, TransferCoding,
+    },
+    multipart::{self, Part},
+    response::{Response, StatusCode},
+    session::Session,
+    spool::{Spooled, SpooledFile, Spooler},
+};
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub(crate) line: RequestLine,
+    pub(crate) headers: Vec<Header>,
+    // every header as it was sent, including the ones `headers` has no variant for
+    pub(crate) raw_headers: HeaderMap,
+    pub(crate) body: Option<RequestBody>,
+    // segments captured by the route that matched the request
+    pub(crate) params: Vec<(String, String)>,
+    // set once the server has read the request, see `Request::id`
+    pub(crate) id: String,
+    // set along with `id`, `None` when the socket couldn't tell
+    pub(crate) peer: Option<SocketAddr>,
+    // the client a trusted proxy forwarded the request for, see `Request::client_ip`
+    pub(crate) client: Option<IpAddr>,
+    // the subject of the verified certificate the client presented over TLS, shared by every
+    // request on the connection
+    pub(crate) client_cert: Option<Arc<str>>,
+    // set by the `Sessions` middleware
+    pub(crate) session: Option<Session>,
+}
+
+impl FromStr for Request {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_terminator("\r\n");
+
+        let line: RequestLine = parts
+            .next()
+            .with_context(|| anyhow!("did not find request line in request {s}"))?
+            .parse()
+            .context("failed to parse request line")?;
+
+        // lines starting with whitespace continue the previous header (obsolete line folding)
+        let mut header_strs: Vec<String> = Vec::new();
+        for header_str in parts.take_while(|header_str| !header_str.is_empty()) {
+            match header_strs.last_mut() {
+                Some(previous) if header_str.starts_with([' ', '\t']) => {
+                    previous.push(' ');
+                    previous.push_str(header_str.trim());
+                }
+                _ => header_strs.push(header_str.to_owned()),
+            }
+        }
+
+        let mut headers = Vec::new();
+        let mut raw_headers = HeaderMap::new();
+        for header_str in header_strs {
+            let (name, value) = header_str
+                .split_once(':')
+                .with_context(|| anyhow!("header {header_str:?} is missing a ':'"))?;
+            // whitespace before the ':' has been used to smuggle headers past proxies that drop it
+            if !is_token(name) {
+                return Err(anyhow!("{name:?} is not a valid header name"));
+            }
+            if !is_field_value(value) {
+                return Err(anyhow!(
+                    "header {name:?} has a control character in its value"
+                ));
+            }
+            raw_headers.append(name, value.trim());
+
+            // headers we can't interpret are still available through `raw_headers`, except for a
+            // `Content-Length`, without which the end of the body can't be found
+            match header_str.parse() {
+                Ok(header) => push_header(&mut headers, header)?,
+                Err(err) if name.eq_ignore_ascii_case("content-length") => return Err(err),
+                Err(err) => log::debug!("not interpreting HTTP header: {err}"),
+            }
+        }
+
+        // the host of an absolute-form target is the one the request is for, whatever `Host` says
+        if let Some(authority) = &line.authority {
+            headers.retain(|header| !matches!(header, Header::Host(_)));
+            headers.push(Header::Host(authority.clone()));
+            raw_headers.remove("host");
+            raw_headers.append("Host", authority.clone());
+        }
+        let host = headers.iter().find_map(|header| match header {
+            Header::Host(host) => Some(host),
+            _ => None,
+        });
+        if let Some(host) = host.filter(|host| !is_valid_host(host)) {
+            return Err(anyhow!("{host:?} is not a valid host"));
+        }
+
+        Ok(Self {
+            line,
+            headers,
+            raw_headers,
+            body: None,
+            params: Vec::new(),
+            id: String::new(),
+            peer: None,
+            client: None,
+            client_cert: None,
+            session: None,
+        })
+    }
+}
+
+// repeated list headers are merged as if their values had been sent comma-separated on one line;
+// a repeated `Content-Length` has to repeat the same length, and `Host` can't be repeated at all,
+// since the peers a request passes through could each pick a different one
+fn push_header(headers: &mut Vec<Header>, header: Header) -> anyhow::Result<()> {
+    for existing in headers.iter_mut() {
+        match (existing, &header) {
+            (Header::AcceptEncoding(existing), Header::AcceptEncoding(encodings)) => {
+                existing.extend(encodings);
+                return Ok(());
+            }
+            (Header::IfNoneMatch(existing), Header::IfNoneMatch(tags))
+            | (Header::IfMatch(existing), Header::IfMatch(tags)) => {
+                existing.extend(tags.iter().cloned());
+                return Ok(());
+            }
+            // closing wins over keeping the connection alive
+            (Header::Connection(existing), Header::Connection(mode)) => {
+                if *mode == ConnectionMode::Close {
+                    *existing = ConnectionMode::Close;
+                }
+                return Ok(());
+            }
+            (Header::ContentLength(existing), Header::ContentLength(length)) => {
+                if existing != length {
+                    return Err(anyhow!(
+                        "conflicting 'Content-Length' values {existing} and {length}"
+                    ));
+                }
+                return Ok(());
+            }
+            (Header::Host(_), Header::Host(_)) => {
+                return Err(anyhow!("request has more than one 'Host' header"))
+            }
+            _ => {}
+        }
+    }
+
+    headers.push(header);
+    Ok(())
+}
+
+impl Request {
+    pub fn method(&self) -> Method {
+        self.line.method
+    }
+
+    // the path as it was sent, before percent-decoding
+    pub fn path(&self) -> &str {
+        &self.line.path
+    }
+
+    pub fn version(&self) -> Version {
+        self.line.version
+    }
+
+    pub fn headers(&self) -> &[Header] {
+        &self.headers
+    }
+
+    pub fn raw_headers(&self) -> &HeaderMap {
+        &self.raw_headers
+    }
+
+    // the first value of the header called `name`, compared case-insensitively
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.raw_headers.get(name)
+    }
+
+    // every cookie the client sent, by name
+    pub fn cookies(&self) -> HashMap<String, String> {
+        parse_cookies(&self.raw_headers)
+    }
+
+    pub fn cookie(&self, name: &str) -> Option<String> {
+        self.cookies().remove(name)
+    }
+
+    // the client's session, `None` unless the server was given the `Sessions` middleware
+    pub fn session(&self) -> Option<&Session> {
+        self.session.as_ref()
+    }
+
+    // the `X-Request-Id` the client sent, or one the server made up; it's logged with the request
+    // and sent back with its response
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    // the address of the client the request came from
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+
+    // the address of the client that made the request: the peer's, or for requests from one of
+    // `Config::trusted_proxies` the one named in its `Forwarded` or `X-Forwarded-For` header; it's
+    // what's logged, rate limited and checked against `Config::ip_filter`
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        self.client.or(self.peer.map(|peer| peer.ip()))
+    }
+
+    // the subject of the certificate the client presented, such as `CN=alice,O=Example`, when
+    // the connection is over TLS with client certificates asked for, see
+    // `TlsConfig::from_pem_files_with_client_auth`
+    pub fn client_cert_subject(&self) -> Option<&str> {
+        self.client_cert.as_deref()
+    }
+
+    // the whole body, read from disk first if it was too large to keep in memory; `body_reader`
+    // doesn't hold it all in memory at once
+    pub fn body(&self) -> io::Result<Option<&[u8]>> {
+        match self.body.as_ref() {
+            None => Ok(None),
+            Some(RequestBody::Bytes(bytes)) => Ok(Some(bytes)),
+            Some(RequestBody::Spooled(file, loaded)) => {
+                // a failed read isn't kept, so that it's reported to every caller
+                if let Some(bytes) = loaded.get() {
+                    return Ok(Some(bytes));
+                }
+                let bytes = fs::read(file.path())?;
+                Ok(Some(loaded.get_or_init(|| bytes)))
+            }
+        }
+    }
+
+    // the length of the body, without reading it
+    pub fn body_len(&self) -> Option<u64> {
+        match self.body.as_ref()? {
+            RequestBody::Bytes(bytes) => Some(bytes.len() as u64),
+            RequestBody::Spooled(file, _) => Some(file.len()),
+        }
+    }
+
+    // reads the body from wherever it's kept, an empty one if there's none
+    pub fn body_reader(&self) -> io::Result<Box<dyn Read + Send + '_>> {
+        match &self.body {
+            None => Ok(Box::new(io::empty())),
+            Some(RequestBody::Bytes(bytes)) => Ok(Box::new(bytes.as_slice())),
+            Some(RequestBody::Spooled(file, _)) => Ok(Box::new(File::open(file.path())?)),
+        }
+    }
+
+    // the temporary file the body was spooled to, if it was too large to keep in memory
+    pub(crate) fn spooled_body_path(&self) -> Option<&Path> {
+        match self.body.as_ref()? {
+            RequestBody::Bytes(_) => None,
+            RequestBody::Spooled(file, _) => Some(file.path()),
+        }
+    }
+
+    // the request with `body` in place of the one it had and a `Content-Length` to match, e.g. for
+    // sending it with `client::request`
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        let body = body.into();
+        let len = body.len() as u64;
+        self.body = Some(RequestBody::Bytes(body));
+        self.headers
+            .retain(|header| !matches!(header, Header::ContentLength(_)));
+        self.headers.push(Header::ContentLength(len));
+        self.raw_headers.remove("content-length");
+        self.raw_headers.append("Content-Length", len.to_string());
+        self
+    }
+
+    // the body as text, for handlers that want one; bodies are kept as the bytes that were sent,
+    // so one that isn't UTF-8 is an error here rather than being mangled
+    pub fn text(&self) -> anyhow::Result<&str> {
+        let body = self.body().context("failed to read request body")?;
+        std::str::from_utf8(body.unwrap_or_default()).context("request body isn't UTF-8")
+    }
+
+    // the decoded `key=value` pairs of an `application/x-www-form-urlencoded` body, in the order
+    // they were sent, `None` if the request doesn't have one
+    pub fn form(&self) -> Option<anyhow::Result<Vec<(String, String)>>> {
+        if self.content_type()?.essence() != "application/x-www-form-urlencoded" {
+            return None;
+        }
+        Some(
+            self.body()
+                .context("failed to read request body")
+                .and_then(|body| {
+                    std::str::from_utf8(body.unwrap_or_default()).context("form body isn't UTF-8")
+                })
+                .and_then(parse_query),
+        )
+    }
+
+    // deserializes a body sent as `application/json`, or another `+json` type; an error means the
+    // client sent something else, and is best answered with a 400
+    #[cfg(feature = "json")]
+    pub fn json<T: serde::de::DeserializeOwned>(&self) -> anyhow::Result<T> {
+        let essence = self
+            .content_type()
+            .map(ContentType::essence)
+            .unwrap_or_default();
+        if essence != "application/json" && !essence.ends_with("+json") {
+            return Err(anyhow!("expected a JSON body, not {essence:?}"));
+        }
+        let body = self.body().context("failed to read request body")?;
+        serde_json::from_slice(body.unwrap_or_default()).context("failed to parse JSON body")
+    }
+
+    // the parts of a `multipart/form-data` body, `None` if the request doesn't have one
+    pub fn multipart(&self) -> Option<anyhow::Result<Vec<Part>>> {
+        let boundary = multipart::boundary(self.header("content-type")?)?;
+        Some(
+            self.body()
+                .context("failed to read request body")
+                .and_then(|body| multipart::parse(body.unwrap_or_default(), &boundary)),
+        )
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find_map(|(n, value)| (n == name).then_some(value.as_str()))
+    }
+
+    // every decoded `key=value` pair of the query string, in the order they were sent
+    pub fn query_pairs(&self) -> &[(String, String)] {
+        &self.line.query
+    }
+
+    pub fn query(&self, key: &str) -> Option<&str> {
+        self.line
+            .query
+            .iter()
+            .find_map(|(k, value)| (k == key).then_some(value.as_str()))
+    }
+
+    pub fn accept_encoding(&self) -> Option<&[AcceptedEncoding]> {
+        self.headers.iter().find_map(|header| {
+            if let Header::AcceptEncoding(encodings) = header {
+                Some(encodings.as_slice())
+            } else {
+                None
+            }
+        })
+    }
+
+    // the accepted encoding with the highest quality, the one listed first wins a tie
+    pub fn preferred_encoding(&self) -> Option<Encoding> {
+        self.accept_encoding()?
+            .iter()
+            .enumerate()
+            .filter(|(_, accepted)| accepted.quality > 0)
+            .max_by_key(|(i, accepted)| (accepted.quality, Reverse(*i)))
+            .map(|(_, accepted)| accepted.encoding)
+    }
+
+    // which of `offered` the client's `Accept` header ranks highest, the one offered first wins a
+    // tie and is taken when there's no `Accept` header; `None` when none of them is acceptable
+    pub fn preferred_type(&self, offered: &[ContentType]) -> Option<ContentType> {
+        let Some(accept) = self.raw_headers.get_combined("accept") else {
+            return offered.first().cloned();
+        };
+        let accepted = parse_accept(&accept);
+        offered
+            .iter()
+            .enumerate()
+            .map(|(i, content_type)| (i, accepted_quality(&accepted, content_type)))
+            .filter(|(_, quality)| *quality > 0)
+            .max_by_key(|(i, quality)| (*quality, Reverse(*i)))
+            .map(|(i, _)| offered[i].clone())
+    }
+
+    pub fn host(&self) -> Option<&str> {
+        self.headers.iter().find_map(|header| {
+            if let Header::Host(host) = header {
+                Some(host.as_str())
+            } else {
+                None
+            }
+        })
+    }
+
+    pub fn range(&self) -> Option<&str> {
+        self.headers.iter().find_map(|header| {
+            if let Header::Range(range) = header {
+                Some(range.as_str())
+            } else {
+                None
+            }
+        })
+    }
+
+    pub fn connection(&self) -> Option<ConnectionMode> {
+        self.headers.iter().find_map(|header| {
+            if let Header::Connection(mode) = header {
+                Some(*mode)
+            } else {
+                None
+            }
+        })
+    }
+
+    pub fn if_none_match(&self) -> Option<&[String]> {
+        self.headers.iter().find_map(|header| {
+            if let Header::IfNoneMatch(tags) = header {
+                Some(tags.as_slice())
+            } else {
+                None
+            }
+        })
+    }
+
+    pub fn if_match(&self) -> Option<&[String]> {
+        self.headers.iter().find_map(|header| {
+            if let Header::IfMatch(tags) = header {
+                Some(tags.as_slice())
+            } else {
+                None
+            }
+        })
+    }
+
+    pub fn if_modified_since(&self) -> Option<SystemTime> {
+        self.headers.iter().find_map(|header| {
+            if let Header::IfModifiedSince(time) = header {
+                Some(*time)
+            } else {
+                None
+            }
+        })
+    }
+
+    pub fn transfer_encoding(&self) -> Option<TransferCoding> {
+        self.headers.iter().find_map(|header| {
+            if let Header::TransferEncoding(coding) = header {
+                Some(*coding)
+            } else {
+                None
+            }
+        })
+    }
+
+    // every coding the `Transfer-Encoding` headers list, lowercase and in the order they were
+    // applied, however many lines they were sent on
+    pub(crate) fn transfer_codings(&self) -> Vec<String> {
+        self.raw_headers
+            .get_all("transfer-encoding")
+            .flat_map(|value| value.split(','))
+            .map(|coding| coding.trim().to_lowercase())
+            .filter(|coding| !coding.is_empty())
+            .collect()
+    }
+
+    pub fn expect(&self) -> Option<&str> {
+        self.headers.iter().find_map(|header| {
+            if let Header::Expect(expectation) = header {
+                Some(expectation.as_str())
+            } else {
+                None
+            }
+        })
+    }
+
+    pub fn content_type(&self) -> Option<&ContentType> {
+        self.headers.iter().find_map(|header| {
+            if let Header::ContentType(content_type) = header {
+                Some(content_type)
+            } else {
+                None
+            }
+        })
+    }
+
+    pub fn content_length(&self) -> Option<u64> {
+        self.headers.iter().find_map(|header| {
+            if let Header::ContentLength(length) = header {
+                Some(*length)
+            } else {
+                None
+            }
+        })
+    }
+
+    // undoes a `Content-Encoding` of gzip or deflate, so handlers see the body the way it was before
+    // the client compressed it, and its headers describe it that way; `Err` holds the response for a
+    // body in another coding, one that doesn't decompress, or one that decompresses to more than
+    // `max_size` bytes, which keeps small bodies from expanding into huge ones
+    pub(crate) fn decode_body(
+        &mut self,
+        max_size: u64,
+        max_in_memory_size: u64,
+    ) -> Result<(), Response> {
+        let Some(coding) = self.header("content-encoding").map(str::to_lowercase) else {
+            return Ok(());
+        };
+        let coding = coding.trim();
+        if coding == "identity" {
+            self.raw_headers.remove("content-encoding");
+            return Ok(());
+        }
+        if !matches!(coding, "gzip" | "x-gzip" | "deflate") {
+            log::warn!(
+                "request_id = {}, unsupported content coding {coding:?}",
+                self.id
+            );
+            return Err(Response {
+                status: StatusCode::UnsupportedMediaType,
+                ..Response::text(format!("request bodies can't be encoded with {coding:?}"))
+            }
+            .with_header("Accept-Encoding", "gzip, deflate"));
+        }
+        if self.body.is_none() {
+            return Ok(());
+        }
+
+        let internal_error = |err: io::Error| {
+            log::error!(
+                "request_id = {}, failed to store decompressed body: {err}",
+                self.id
+            );
+            Response::internal_server_error()
+        };
+        let reader = self.body_reader().map_err(internal_error)?;
+        let decoder: Box<dyn Read + '_> = if coding == "deflate" {
+            Box::new(ZlibDecoder::new(reader))
+        } else {
+            Box::new(MultiGzDecoder::new(reader))
+        };
+        let mut body = Spooler::new(max_in_memory_size);
+        match io::copy(&mut decoder.take(max_size + 1), &mut body) {
+            Ok(len) if len > max_size => {
+                log::warn!(
+                    "request_id = {}, request body decompresses to more than {max_size} bytes",
+                    self.id
+                );
+                return Err(Response::payload_too_large());
+            }
+            Ok(_) => {}
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::InvalidData
+                        | io::ErrorKind::InvalidInput
+                        | io::ErrorKind::UnexpectedEof
+                ) =>
+            {
+                log::warn!(
+                    "request_id = {}, failed to decompress request body: {err}",
+                    self.id
+                );
+                return Err(Response::bad_request(format!(
+                    "request body isn't valid {coding}: {err}"
+                )));
+            }
+            Err(err) => return Err(internal_error(err)),
+        }
+
+        let len = body.len();
+        self.body = Some(body.finish().map_err(internal_error)?.into());
+        self.raw_headers.remove("content-encoding");
+        if self.content_length().is_some() {
+            for header in &mut self.headers {
+                if let Header::ContentLength(length) = header {
+                    *length = len;
+                }
+            }
+            self.raw_headers.remove("content-length");
+            self.raw_headers.append("Content-Length", len.to_string());
+        }
+        Ok(())
+    }
+}
+
+// the body of a request, kept in memory unless it's larger than `Config::max_in_memory_body_size`
+#[derive(Debug, Clone)]
+pub(crate) enum RequestBody {
+    Bytes(Vec<u8>),
+    // only read into memory once `Request::body` is called
+    Spooled(Arc<SpooledFile>, OnceLock<Vec<u8>>),
+}
+
+impl From<Spooled> for RequestBody {
+    fn from(spooled: Spooled) -> Self {
+        match spooled {
+            Spooled::Memory(bytes) => Self::Bytes(bytes),
+            Spooled::File(file) => Self::Spooled(Arc::new(file), OnceLock::new()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct RequestLine {
+    pub(crate) method: Method,
+    pub(crate) path: String,
+    pub(crate) query: Vec<(String, String)>,
+    // the query string as it was sent, for passing it on unchanged
+    pub(crate) raw_query: Option<String>,
+    pub(crate) version: Version,
+    // the host and port of an absolute-form target such as `http://example.com/path`, which
+    // proxy-style clients send, and which takes the place of the `Host` header
+    pub(crate) authority: Option<String>,
+}
+
+impl FromStr for RequestLine {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+        let method = parts
+            .next()
+            .context("could not find HTTP method in request line")?;
+        let url = parts.next().context("could not find URL in request line")?;
+        let version = parts
+            .next()
+            .context("could not find HTTP version in request line")?;
+
+        // only a method we don't know in an otherwise whole line is unsupported rather than malformed
+        let method: Method = method.parse().context("failed to parse HTTP method")?;
+
+        let (path, raw_query) = match url.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (url, None),
+        };
+        // CONNECT names the host and port to tunnel to instead of a path, it's routed as a request
+        // for the server as a whole
+        let (authority, path) = match split_absolute_form(path) {
+            Some((authority, path)) => (Some(authority), path),
+            None if method == Method::Connect => (Some(path), "*"),
+            None => (None, path),
+        };
+        let query = match raw_query {
+            Some(query) => parse_query(query).context("failed to parse query string")?,
+            None => Vec::new(),
+        };
+
+        let version = version.parse().context("failed to parse HTTP version")?;
+
+        Ok(Self {
+            method,
+            path: path.to_owned(),
+            query,
+            raw_query: raw_query.map(str::to_owned),
+            version,
+            authority: authority.map(str::to_owned),
+        })
+    }
+}
+
+// the authority and path of an `http` or `https` URL, where an empty path stands for `/`
+fn split_absolute_form(target: &str) -> Option<(&str, &str)> {
+    let (scheme, rest) = target.split_once("://")?;
+    if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") {
+        return None;
+    }
+    match rest.find('/') {
+        Some(i) => Some((&rest[..i], &rest[i..])),
+        None => Some((rest, "/")),
+    }
+}
+
+// whether `host` is a `Host` value: a name or an IPv4 address, or an IPv6 one in brackets, with an
+// optional port; anything else, such as `user@host` or a second host after a space, is refused
+pub(crate) fn is_valid_host(host: &str) -> bool {
+    let (name, port) = match host.strip_prefix('[') {
+        Some(rest) => {
+            let Some((addr, after)) = rest.split_once(']') else {
+                return false;
+            };
+            if addr.parse::<Ipv6Addr>().is_err() || !(after.is_empty() || after.starts_with(':')) {
+                return false;
+            }
+            ("", after.strip_prefix(':'))
+        }
+        None => match host.split_once(':') {
+            Some((name, port)) => (name, Some(port)),
+            None => (host, None),
+        },
+    };
+    name.bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=%".contains(&b))
+        && port.is_none_or(|port| port.bytes().all(|b| b.is_ascii_digit()))
+}
+
+// parses `key=value` pairs separated by `&`, keeping repeated keys in order
+pub(crate) fn parse_query(query: &str) -> anyhow::Result<Vec<(String, String)>> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let decode = |s: &str| percent_decode(&s.replace('+', " "));
+            Ok((decode(key)?, decode(value)?))
+        })
+        .collect()
+}
+
+// decodes each segment of `path` on its own, so an encoded '/' can't introduce new segments,
+// then normalizes the result so routes only ever see one spelling of a path
+pub(crate) fn decode_path(path: &str) -> anyhow::Result<String> {
+    let segments = path
+        .split('/')
+        .map(|segment| {
+            let decoded = percent_decode(segment)?;
+            if decoded.contains(['/', '\0']) {
+                return Err(anyhow!("path segment {segment:?} decodes to a '/' or NUL"));
+            }
+            Ok(decoded)
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    Ok(normalize_path(&segments))
+}
+
+// collapses duplicate slashes and resolves `.` and `..`, which can't climb above the root;
+// a trailing slash is kept since it's how directories are asked for
+fn normalize_path(segments: &[String]) -> String {
+    // only origin-form paths are normalized, leaving e.g. the `*` of `OPTIONS *` alone
+    let Some((first, segments)) = segments.split_first().filter(|(first, _)| first.is_empty())
+    else {
+        return segments.join("/");
+    };
+
+    let mut normalized: Vec<&str> = vec![first];
+    for (i, segment) in segments.iter().enumerate() {
+        let is_last = i + 1 == segments.len();
+        match segment.as_str() {
+            "" | "." => {}
+            ".." => {
+                if normalized.len() > 1 {
+                    normalized.pop();
+                }
+            }
+            segment => {
+                normalized.push(segment);
+                continue;
+            }
+        }
+
+        if is_last {
+            normalized.push("");
+        }
+    }
+
+    if normalized.len() == 1 {
+        return "/".to_owned();
+    }
+    normalized.join("/")
+}
+
+fn percent_decode(s: &str) -> anyhow::Result<String> {
+    let mut bytes = s.bytes();
+    let mut decoded = Vec::with_capacity(s.len());
+
+    while let Some(byte) = bytes.next() {
+        if byte != b'%' {
+            decoded.push(byte);
+            continue;
+        }
+
+        let escape = [bytes.next(), bytes.next()];
+        let hex = |digit: Option<u8>| {
+            digit
+                .and_then(|digit| char::from(digit).to_digit(16))
+                .with_context(|| anyhow!("malformed percent-escape in {s:?}"))
+        };
+
+        decoded.push((hex(escape[0])? * 16 + hex(escape[1])?) as u8);
+    }
+
+    String::from_utf8(decoded).with_context(|| anyhow!("{s:?} does not decode to valid UTF-8"))
+}
+
+// escapes everything but unreserved characters and '/', the inverse of `decode_path`
+pub(crate) fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~' | b'/') {
+            encoded.push(char::from(byte));
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+    // only ever negotiated through ALPN, never parsed from a request line
+    Http2,
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Version::Http10 => f.write_str("HTTP/1.0"),
+            Version::Http11 => f.write_str("HTTP/1.1"),
+            Version::Http2 => f.write_str("HTTP/2"),
+        }
+    }
+}
+
+impl FromStr for Version {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "HTTP/1.0" => Ok(Self::Http10),
+            "HTTP/1.1" => Ok(Self::Http11),
+            _ => {
+                let is_version = s.strip_prefix("HTTP/").is_some_and(|number| {
+                    let (major, minor) = number.split_once('.').unwrap_or_default();
+                    [major, minor].iter().all(|part| {
+                        part.len() == 1 && part.bytes().all(|byte| byte.is_ascii_digit())
+                    })
+                });
+                if is_version {
+                    Err(UnsupportedVersion(s.to_owned()).into())
+                } else {
+                    Err(anyhow!("{s:?} is not an HTTP version"))
+                }
+            }
+        }
+    }
+}
+
+// a well-formed version other than the ones we speak, which is answered with a 505
+#[derive(Debug)]
+pub(crate) struct UnsupportedVersion(String);
+
+impl fmt::Display for UnsupportedVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unsupported HTTP version {:?}", self.0)
+    }
+}
+
+impl std::error::Error for UnsupportedVersion {}
+
+// the methods RFC 9110 defines, and PATCH; a route can be added for any of them, and the ones a
+// path has no route for get a 405
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Connect,
+    Options,
+    Trace,
+    Patch,
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Method::Get => f.write_str("GET"),
+            Method::Head => f.write_str("HEAD"),
+            Method::Post => f.write_str("POST"),
+            Method::Put => f.write_str("PUT"),
+            Method::Delete => f.write_str("DELETE"),
+            Method::Connect => f.write_str("CONNECT"),
+            Method::Options => f.write_str("OPTIONS"),
+            Method::Trace => f.write_str("TRACE"),
+            Method::Patch => f.write_str("PATCH"),
+        }
+    }
+}
+
+impl FromStr for Method {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "get" => Ok(Self::Get),
+            "head" => Ok(Self::Head),
+            "post" => Ok(Self::Post),
+            "put" => Ok(Self::Put),
+            "delete" => Ok(Self::Delete),
+            "connect" => Ok(Self::Connect),
+            "options" => Ok(Self::Options),
+            "trace" => Ok(Self::Trace),
+            "patch" => Ok(Self::Patch),
+            _ if is_token(s) => Err(UnsupportedMethod(s.to_owned()).into()),
+            _ => Err(anyhow!("{s} is not a valid HTTP method")),
+        }
+    }
+}
+
+// a well-formed method other than the ones we know, such as a WebDAV one, which is answered with
+// a 501
+#[derive(Debug)]
+pub(crate) struct UnsupportedMethod(String);
+
+impl fmt::Display for UnsupportedMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unsupported HTTP method {:?}", self.0)
+    }
+}
+
+impl std::error::Error for UnsupportedMethod {}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn request_line_splits_query_from_path() {
+        let line: RequestLine = "GET /echo/hi?x=1&y=a%20b+c HTTP/1.1".parse().unwrap();
+
+        assert_eq!(line.path, "/echo/hi");
+        assert_eq!(
+            line.query,
+            [
+                ("x".to_owned(), "1".to_owned()),
+                ("y".to_owned(), "a b c".to_owned())
+            ]
+        );
+    }
+
+    #[test]
+    fn request_line_tells_unknown_methods_from_malformed_ones() {
+        let line: RequestLine = "TRACE /echo/hi HTTP/1.1".parse().unwrap();
+        assert_eq!(line.method, Method::Trace);
+
+        let line: RequestLine = "CONNECT example.com:443 HTTP/1.1".parse().unwrap();
+        assert_eq!(line.method, Method::Connect);
+        assert_eq!(line.authority.as_deref(), Some("example.com:443"));
+        assert_eq!(line.path, "*");
+
+        let err = "PROPFIND / HTTP/1.1".parse::<RequestLine>().unwrap_err();
+        assert!(err.downcast_ref::<UnsupportedMethod>().is_some(), "{err:#}");
+        let err = "GE(T / HTTP/1.1".parse::<RequestLine>().unwrap_err();
+        assert!(err.downcast_ref::<UnsupportedMethod>().is_none(), "{err:#}");
+    }
+
+    #[test]
+    fn text_only_accepts_utf8_bodies() {
+        let mut request: Request = "POST /notes HTTP/1.1\r\n\r\n".parse().unwrap();
+        assert_eq!(request.text().unwrap(), "");
+
+        request.body = Some(RequestBody::Bytes("naïve".as_bytes().to_vec()));
+        assert_eq!(request.text().unwrap(), "naïve");
+
+        let png = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n', 0xff];
+        request.body = Some(RequestBody::Bytes(png.clone()));
+        assert!(request.text().is_err());
+        assert_eq!(request.body().unwrap(), Some(png.as_slice()));
+    }
+
+    #[test]
+    fn spooled_body_that_cant_be_read_is_an_error_not_an_empty_body() {
+        let mut request: Request = "POST /notes HTTP/1.1\r\n\r\n".parse().unwrap();
+        let mut spooler = Spooler::new(4);
+        spooler.write_all(b"more than four bytes").unwrap();
+        request.body = Some(spooler.finish().unwrap().into());
+        let path = request.spooled_body_path().unwrap().to_owned();
+
+        fs::remove_file(&path).unwrap();
+        assert!(request.body().is_err());
+        assert!(request.text().is_err());
+
+        // the error isn't kept, so the body is there once the file is
+        fs::write(&path, b"more than four bytes").unwrap();
+        assert_eq!(request.body().unwrap(), Some(&b"more than four bytes"[..]));
+    }
+
+    #[test]
+    fn decode_body_undoes_gzip_and_deflate_up_to_a_limit() {
+        use flate2::{
+            write::{GzEncoder, ZlibEncoder},
+            Compression,
+        };
+        use std::io::Write;
+
+        let encoded = |coding: &str, body: &[u8]| {
+            let mut request: Request = format!(
+                "POST /files/a.txt HTTP/1.1\r\nContent-Encoding: {coding}\r\nContent-Length: {}\r\n\r\n",
+                body.len()
+            )
+            .parse()
+            .unwrap();
+            request.body = Some(RequestBody::Bytes(body.to_vec()));
+            request
+        };
+        let text = "hello ".repeat(100);
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(text.as_bytes()).unwrap();
+        let gzip = gzip.finish().unwrap();
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+        zlib.write_all(text.as_bytes()).unwrap();
+        let zlib = zlib.finish().unwrap();
+
+        for (coding, body) in [("gzip", &gzip), ("Deflate", &zlib)] {
+            let mut request = encoded(coding, body);
+            request.decode_body(1000, 1000).unwrap();
+            assert_eq!(request.text().unwrap(), text);
+            assert_eq!(request.header("content-encoding"), None);
+            assert_eq!(request.content_length(), Some(text.len() as u64));
+            assert_eq!(request.header("content-length"), Some("600"));
+        }
+
+        let mut request = encoded("gzip", &gzip);
+        let response = request.decode_body(599, 1000).unwrap_err();
+        assert_eq!(response.status, StatusCode::PayloadTooLarge);
+        // a body that was refused is left as it was
+        request.decode_body(1000, 1000).unwrap();
+        assert_eq!(request.text().unwrap(), text);
+
+        let mut request = encoded("gzip", b"not gzip at all");
+        let response = request.decode_body(1000, 1000).unwrap_err();
+        assert_eq!(response.status, StatusCode::BadRequest);
+        let response = encoded("br", b"").decode_body(1000, 1000).unwrap_err();
+        assert_eq!(response.status, StatusCode::UnsupportedMediaType);
+    }
+
+    #[test]
+    fn form_decodes_urlencoded_bodies() {
+        let request = |content_type: &str, body: &str| {
+            let mut request: Request =
+                format!("POST /login HTTP/1.1\r\nContent-Type: {content_type}\r\n\r\n")
+                    .parse()
+                    .unwrap();
+            request.body = Some(RequestBody::Bytes(body.as_bytes().to_vec()));
+            request
+        };
+
+        let form = request(
+            "application/x-www-form-urlencoded; charset=UTF-8",
+            "user=ada+lovelace&note=a%26b%3Dc&empty=",
+        )
+        .form()
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            form,
+            [
+                ("user".to_owned(), "ada lovelace".to_owned()),
+                ("note".to_owned(), "a&b=c".to_owned()),
+                ("empty".to_owned(), String::new()),
+            ]
+        );
+
+        assert!(request("application/x-www-form-urlencoded", "bad=%zz")
+            .form()
+            .unwrap()
+            .is_err());
+        assert!(request("application/json", "{}").form().is_none());
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_deserializes_json_bodies() {
+        let request = |content_type: &str, body: &str| {
+            let mut request: Request =
+                format!("POST /items HTTP/1.1\r\nContent-Type: {content_type}\r\n\r\n")
+                    .parse()
+                    .unwrap();
+            request.body = Some(RequestBody::Bytes(body.as_bytes().to_vec()));
+            request
+        };
+
+        let value: serde_json::Value = request("application/json", r#"{"id":1}"#).json().unwrap();
+        assert_eq!(value, serde_json::json!({ "id": 1 }));
+        let value: Vec<u32> = request("application/merge-patch+json", "[1, 2]")
+            .json()
+            .unwrap();
+        assert_eq!(value, [1, 2]);
+
+        assert!(request("text/plain", "[]").json::<Vec<u32>>().is_err());
+        assert!(request("application/json", "[").json::<Vec<u32>>().is_err());
+    }
+
+    #[test]
+    fn parse_query_handles_edge_cases() {
+        assert_eq!(parse_query("").unwrap(), []);
+        assert_eq!(
+            parse_query("a=1&a=2&flag").unwrap(),
+            [
+                ("a".to_owned(), "1".to_owned()),
+                ("a".to_owned(), "2".to_owned()),
+                ("flag".to_owned(), String::new())
+            ]
+        );
+        assert_eq!(
+            parse_query("expr=a=b").unwrap(),
+            [("expr".to_owned(), "a=b".to_owned())]
+        );
+        assert!(parse_query("bad=%ZZ").is_err());
+    }
+
+    #[test]
+    fn decode_path_decodes_segments() {
+        assert_eq!(
+            decode_path("/files/my%20file.txt").unwrap(),
+            "/files/my file.txt"
+        );
+        assert!(decode_path("/files/..%2fsecret").is_err());
+        assert!(decode_path("/echo/%ZZ").is_err());
+        assert!(decode_path("/echo/%4").is_err());
+    }
+
+    #[test]
+    fn decode_path_normalizes_segments() {
+        assert_eq!(decode_path("/").unwrap(), "/");
+        assert_eq!(decode_path("//echo///hi").unwrap(), "/echo/hi");
+        assert_eq!(decode_path("/files/./nested/").unwrap(), "/files/nested/");
+        assert_eq!(decode_path("/files/nested/..").unwrap(), "/files/");
+        assert_eq!(decode_path("/files/nested/.").unwrap(), "/files/nested/");
+        assert_eq!(decode_path("/../../etc/passwd").unwrap(), "/etc/passwd");
+        assert_eq!(
+            decode_path("/files/%2e%2e/%2E%2E/secret").unwrap(),
+            "/secret"
+        );
+        assert_eq!(decode_path("/a/b/../../..").unwrap(), "/");
+        assert_eq!(decode_path("*").unwrap(), "*");
+    }
+
+    #[test]
+    fn request_merges_repeated_and_folded_headers() {
+        let request: Request = "GET / HTTP/1.1\r\n\
+            Accept-Encoding: gzip\r\n\
+            User-Agent: curl/8.0\r\n\
+            \t(folded)\r\n\
+            Accept-Encoding: deflate\r\n\
+            \r\n"
+            .parse()
+            .unwrap();
+
+        assert_eq!(
+            request.headers,
+            [
+                Header::AcceptEncoding(vec![
+                    AcceptedEncoding {
+                        encoding: Encoding::Gzip,
+                        quality: 1000
+                    },
+                    AcceptedEncoding {
+                        encoding: Encoding::Deflate,
+                        quality: 1000
+                    },
+                ]),
+                Header::UserAgent("curl/8.0 (folded)".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn request_checks_repeated_headers() {
+        let request: Request = "GET / HTTP/1.1\r\n\
+            Host: localhost:4221\r\n\
+            Content-Length: 0\r\n\
+            If-None-Match: \"a\"\r\n\
+            Connection: keep-alive, Upgrade\r\n\
+            Accept: text/html\r\n\
+            content-length: 0, 0\r\n\
+            If-None-Match: \"b\", \"c\"\r\n\
+            Accept: */*;q=0.1\r\n\
+            Connection: close\r\n\
+            \r\n"
+            .parse()
+            .unwrap();
+        assert_eq!(request.host(), Some("localhost:4221"));
+        assert_eq!(request.content_length(), Some(0));
+        assert_eq!(
+            request.if_none_match(),
+            Some(&["\"a\"".to_owned(), "\"b\"".to_owned(), "\"c\"".to_owned()][..])
+        );
+        assert_eq!(request.connection(), Some(ConnectionMode::Close));
+        assert_eq!(
+            request.raw_headers().get_combined("accept").as_deref(),
+            Some("text/html, */*;q=0.1")
+        );
+        assert_eq!(request.raw_headers().get_combined("x-missing"), None);
+
+        for head in [
+            "Content-Length: 5\r\nContent-Length: 6\r\n",
+            "Content-Length: 5, 6\r\n",
+            "Content-Length: +5\r\n",
+            "Host: a\r\nHost: b\r\n",
+            "X-Custom: a\rb\r\n",
+        ] {
+            assert!(
+                format!("POST / HTTP/1.1\r\n{head}\r\n")
+                    .parse::<Request>()
+                    .is_err(),
+                "{head:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn request_takes_the_host_of_absolute_form_targets() {
+        let request: Request = "GET http://example.com:8080/echo/hi?x=1 HTTP/1.1\r\n\
+            Host: other\r\n\
+            \r\n"
+            .parse()
+            .unwrap();
+        assert_eq!(request.line.path, "/echo/hi");
+        assert_eq!(request.line.query, [("x".to_owned(), "1".to_owned())]);
+        assert_eq!(request.host(), Some("example.com:8080"));
+        assert_eq!(request.header("host"), Some("example.com:8080"));
+
+        let line: RequestLine = "GET HTTPS://example.com HTTP/1.1".parse().unwrap();
+        assert_eq!(line.path, "/");
+        assert_eq!(line.authority.as_deref(), Some("example.com"));
+
+        for host in [
+            "localhost",
+            "127.0.0.1:4221",
+            "[::1]:80",
+            "[::1]",
+            "xn--bcher-kva.example",
+        ] {
+            assert!(is_valid_host(host), "{host:?}");
+        }
+        for host in [
+            "a b",
+            "user@host",
+            "host:8x",
+            "[::1",
+            "[nope]",
+            "[::1]x",
+            "a/b",
+        ] {
+            assert!(!is_valid_host(host), "{host:?}");
+            assert!(
+                format!("GET / HTTP/1.1\r\nHost: {host}\r\n\r\n")
+                    .parse::<Request>()
+                    .is_err(),
+                "{host:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn preferred_encoding_honors_quality_values() {
+        let preferred = |accept_encoding: &str| {
+            format!("GET / HTTP/1.1\r\nAccept-Encoding: {accept_encoding}\r\n\r\n")
+                .parse::<Request>()
+                .unwrap()
+                .preferred_encoding()
+        };
+
+        assert_eq!(preferred("gzip, deflate, compress"), Some(Encoding::Gzip));
+        assert_eq!(
+            preferred("compress;q=1.0, gzip;q=0.8, deflate"),
+            Some(Encoding::Deflate)
+        );
+        assert_eq!(
+            preferred("gzip;q=0.5, deflate;Q=0.501"),
+            Some(Encoding::Deflate)
+        );
+        assert_eq!(preferred("gzip;q=0, identity"), Some(Encoding::Identity));
+        assert_eq!(preferred("compress, *;q=0.1"), Some(Encoding::Gzip));
+        assert_eq!(preferred("*;q=0.5, deflate;q=0.9"), Some(Encoding::Deflate));
+        assert_eq!(preferred("gzip;q=0"), None);
+        assert_eq!(preferred("gzip;q=1.5, deflate;q=0.1234"), None);
+        assert_eq!(preferred("compress"), None);
+
+        // brotli and zstd are only negotiated when they're built in
+        #[cfg(feature = "brotli")]
+        assert_eq!(preferred("gzip;q=0.9, br"), Some(Encoding::Brotli));
+        #[cfg(not(feature = "brotli"))]
+        assert_eq!(preferred("gzip;q=0.9, br"), Some(Encoding::Gzip));
+        #[cfg(feature = "zstd")]
+        assert_eq!(preferred("zstd, gzip;q=0.9"), Some(Encoding::Zstd));
+        #[cfg(not(feature = "zstd"))]
+        assert_eq!(preferred("zstd, gzip;q=0.9"), Some(Encoding::Gzip));
+    }
+
+    #[test]
+    fn preferred_type_picks_the_most_specific_range() {
+        let offered = [
+            ContentType::TextPlain,
+            ContentType::TextHtml,
+            ContentType::ApplicationJson,
+        ];
+        let preferred = |accept: Option<&str>| {
+            let accept = accept.map_or_else(String::new, |accept| format!("Accept: {accept}\r\n"));
+            format!("GET / HTTP/1.1\r\n{accept}\r\n")
+                .parse::<Request>()
+                .unwrap()
+                .preferred_type(&offered)
+        };
+
+        assert_eq!(preferred(None), Some(ContentType::TextPlain));
+        assert_eq!(preferred(Some("*/*")), Some(ContentType::TextPlain));
+        assert_eq!(
+            preferred(Some(
+                "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"
+            )),
+            Some(ContentType::TextHtml)
+        );
+        assert_eq!(
+            preferred(Some("Application/JSON, text/*;q=0.5")),
+            Some(ContentType::ApplicationJson)
+        );
+        // `text/plain;q=0` wins over `text/*` for plain text
+        assert_eq!(
+            preferred(Some("text/*, text/plain;q=0")),
+            Some(ContentType::TextHtml)
+        );
+        assert_eq!(preferred(Some("image/png, */*;q=0")), None);
+        assert_eq!(preferred(Some("application/json;q=2")), None);
+    }
+
+    #[test]
+    fn request_keeps_every_raw_header() {
+        let request: Request = "GET / HTTP/1.1\r\n\
+            Host: localhost:4221\r\n\
+            Cookie: a=1\r\n\
+            X-Custom: one\r\n\
+            cookie: b=2\r\n\
+            \r\n"
+            .parse()
+            .unwrap();
+
+        assert_eq!(request.header("host"), Some("localhost:4221"));
+        assert_eq!(request.header("COOKIE"), Some("a=1"));
+        assert_eq!(
+            request.raw_headers().get_all("Cookie").collect::<Vec<_>>(),
+            ["a=1", "b=2"]
+        );
+        assert_eq!(request.header("x-custom"), Some("one"));
+        assert_eq!(request.header("authorization"), None);
+        assert_eq!(request.raw_headers().len(), 4);
+    }
+}
@@ -0,0 +1,71 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::request::Request;
+
+pub(crate) const REQUEST_ID_HEADER: &str = "X-Request-Id";
+// longest `X-Request-Id` taken from a client, longer ones are replaced
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+// hands out the IDs requests are logged and answered with
+#[derive(Debug)]
+pub(crate) struct RequestIds {
+    // the time the server started, so IDs don't repeat across restarts
+    prefix: String,
+    next: AtomicU64,
+}
+
+impl RequestIds {
+    pub(crate) fn new() -> Self {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        Self {
+            prefix: format!("{:x}", started.as_millis()),
+            next: AtomicU64::new(0),
+        }
+    }
+
+    // the ID a client sent along with `request` when it's safe to log, or a new one, e.g. `192d1b4c8a0-2a`
+    pub(crate) fn assign(&self, request: &Request) -> String {
+        if let Some(id) = request.header(REQUEST_ID_HEADER).filter(|id| is_valid(id)) {
+            return id.to_owned();
+        }
+
+        let n = self.next.fetch_add(1, Ordering::Relaxed);
+        format!("{}-{n:x}", self.prefix)
+    }
+}
+
+// client IDs end up in log lines and response headers, so they're kept to short, printable tokens
+fn is_valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_ids_honor_valid_client_ids() {
+        let ids = RequestIds::new();
+        let request = |id: &str| -> Request {
+            format!("GET / HTTP/1.1\r\nX-Request-Id: {id}\r\n\r\n")
+                .parse()
+                .unwrap()
+        };
+
+        assert_eq!(ids.assign(&request("abc-123")), "abc-123");
+
+        let plain: Request = "GET / HTTP/1.1\r\n\r\n".parse().unwrap();
+        let first = ids.assign(&plain);
+        let second = ids.assign(&request("has spaces"));
+        let third = ids.assign(&request(&"x".repeat(MAX_REQUEST_ID_LEN + 1)));
+        assert!(first.starts_with(&ids.prefix), "{first}");
+        assert_ne!(first, second);
+        assert_ne!(second, third);
+    }
+}
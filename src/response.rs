@@ -0,0 +1,1881 @@
+use std::{
+    fmt,
+    fs::{self, File},
+    hash::{DefaultHasher, Hash, Hasher},
+    io::{self, prelude::*, SeekFrom},
+    path::Path,
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context};
+use flate2::{
+    read,
+    write::{GzEncoder, ZlibEncoder},
+    Compression,
+};
+
+use crate::{
+    cookie::SetCookie,
+    date::http_date,
+    file_cache::CachedFile,
+    header::{ByteRange, ContentRange, ContentType, Encoding, Header, TransferCoding},
+    request::{percent_encode, Method, Request, Version},
+    sendfile::{Copying, SendFile},
+    sse::EventStream,
+    template::{Template, TemplateContext},
+    websocket::Upgrade,
+};
+
+pub(crate) const SERVER_NAME: &str = "butler";
+// what responses say they were sent by unless `Config::server_header` says otherwise
+pub(crate) const SERVER_SOFTWARE: &str = concat!("butler/", env!("CARGO_PKG_VERSION"));
+const DEFAULT_MIN_COMPRESS_SIZE: u64 = 1024;
+const DEFAULT_COMPRESSION_LEVEL: u32 = 6;
+#[cfg(feature = "brotli")]
+const BROTLI_BUFFER_SIZE: usize = 4096;
+// the largest window brotli allows without its large-window extension; its qualities go up to 11,
+// so the levels shared with gzip leave out its two slowest
+#[cfg(feature = "brotli")]
+const BROTLI_WINDOW: u32 = 22;
+const DIRECTORY_TEMPLATE: &str = "\
+<!DOCTYPE html>
+<html>
+<head><title>Index of {{title}}</title></head>
+<body>
+<h1>Index of {{title}}</h1>
+<table>
+<tr><th>Name</th><th>Size</th><th>Last modified</th></tr>
+{{#entries}}<tr><td><a href=\"{{href}}\">{{name}}</a></td><td>{{size}}</td><td>{{modified}}</td></tr>
+{{/entries}}</table>
+</body>
+</html>
+";
+const ERROR_TEMPLATE: &str = "\
+<!DOCTYPE html>
+<html>
+<head><title>{{status}} {{reason}}</title></head>
+<body>
+<h1>{{status}} {{reason}}</h1>
+{{#message}}<p>{{message}}</p>
+{{/message}}</body>
+</html>
+";
+
+// decides which responses are worth compressing and how hard to try
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompressionPolicy {
+    // bodies smaller than this many bytes are sent uncompressed
+    pub min_size: u64,
+    // from 0, which only frames the data, to 9, which is slowest but smallest
+    pub level: u32,
+    // only bodies of these types are compressed, `None` allows every type that isn't compressed already
+    pub content_types: Option<Vec<ContentType>>,
+}
+
+impl Default for CompressionPolicy {
+    fn default() -> Self {
+        Self {
+            min_size: DEFAULT_MIN_COMPRESS_SIZE,
+            level: DEFAULT_COMPRESSION_LEVEL,
+            content_types: None,
+        }
+    }
+}
+
+impl CompressionPolicy {
+    fn allows(&self, content_type: Option<&ContentType>) -> bool {
+        match (&self.content_types, content_type) {
+            (None, content_type) => !content_type.is_some_and(ContentType::is_compressed),
+            (Some(allowed), Some(content_type)) => allowed
+                .iter()
+                .any(|allowed| allowed.essence() == content_type.essence()),
+            // a body of unknown type isn't known to compress well
+            (Some(_), None) => false,
+        }
+    }
+}
+
+pub(crate) fn compress_bytes(body: &[u8], encoding: Encoding, level: u32) -> io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::new(level));
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+        Encoding::Deflate => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(level));
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+        #[cfg(feature = "brotli")]
+        Encoding::Brotli => {
+            let mut encoder =
+                brotli::CompressorWriter::new(Vec::new(), BROTLI_BUFFER_SIZE, level, BROTLI_WINDOW);
+            encoder.write_all(body)?;
+            Ok(encoder.into_inner())
+        }
+        #[cfg(feature = "zstd")]
+        Encoding::Zstd => zstd::encode_all(body, zstd_level(level)),
+        Encoding::Identity => Ok(body.to_vec()),
+    }
+}
+
+// compresses `body` as it is read
+fn compress_reader(
+    body: impl Read + Send + 'static,
+    encoding: Encoding,
+    level: u32,
+) -> io::Result<Box<dyn Read + Send>> {
+    Ok(match encoding {
+        Encoding::Gzip => Box::new(read::GzEncoder::new(body, Compression::new(level))),
+        Encoding::Deflate => Box::new(read::ZlibEncoder::new(body, Compression::new(level))),
+        #[cfg(feature = "brotli")]
+        Encoding::Brotli => Box::new(brotli::CompressorReader::new(
+            body,
+            BROTLI_BUFFER_SIZE,
+            level,
+            BROTLI_WINDOW,
+        )),
+        #[cfg(feature = "zstd")]
+        Encoding::Zstd => Box::new(zstd::stream::read::Encoder::new(body, zstd_level(level))?),
+        Encoding::Identity => Box::new(body),
+    })
+}
+
+// zstd's levels go up to 22 and take 0 for its default, so level 0 is its fastest instead
+#[cfg(feature = "zstd")]
+fn zstd_level(level: u32) -> i32 {
+    level.max(1) as i32
+}
+
+pub(crate) fn html_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// `s` as a quoted JSON string
+pub(crate) fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c < ' ' => quoted.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub(crate) status: StatusCode,
+    pub(crate) version: Version,
+    pub(crate) headers: Vec<Header>,
+    pub(crate) body: Option<Body>,
+    // what takes over the connection after a `101 Switching Protocols`
+    pub(crate) upgrade: Option<Box<Upgrade>>,
+}
+
+pub(crate) enum Body {
+    Bytes(Vec<u8>),
+    // streamed to the client as it is written, limited to the bytes being sent
+    File(io::Take<File>),
+    // a body whose length isn't known until it has been read to the end
+    Stream(Box<dyn Read + Send>),
+}
+
+impl Body {
+    pub(crate) fn into_reader(self) -> Box<dyn Read + Send> {
+        match self {
+            Self::Bytes(bytes) => Box::new(io::Cursor::new(bytes)),
+            Self::File(file) => Box::new(file),
+            Self::Stream(stream) => stream,
+        }
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bytes(bytes) => f.debug_tuple("Bytes").field(&bytes.len()).finish(),
+            Self::File(file) => f.debug_tuple("File").field(file).finish(),
+            Self::Stream(_) => f.debug_tuple("Stream").finish_non_exhaustive(),
+        }
+    }
+}
+
+impl Response {
+    pub fn new(status: StatusCode) -> Self {
+        Self {
+            status,
+            version: Version::Http11,
+            headers: Vec::new(),
+            body: None,
+            upgrade: None,
+        }
+    }
+
+    pub fn builder() -> ResponseBuilder {
+        ResponseBuilder {
+            status: StatusCode::Ok,
+            headers: Vec::new(),
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn headers(&self) -> &[Header] {
+        &self.headers
+    }
+
+    // the value of the first header called `name`, ignoring case
+    pub fn header(&self, name: &str) -> Option<String> {
+        self.headers
+            .iter()
+            .find(|header| header.name().eq_ignore_ascii_case(name))
+            .map(|header| {
+                let header = header.to_string();
+                header
+                    .split_once(": ")
+                    .map_or(String::new(), |(_, value)| value.to_owned())
+            })
+    }
+
+    // the body when it's held in memory, as it is in responses from `client`; `None` for files
+    // and streams, or without a body
+    pub fn body(&self) -> Option<&[u8]> {
+        match &self.body {
+            Some(Body::Bytes(bytes)) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub fn empty() -> Self {
+        Self::new(StatusCode::Ok)
+    }
+
+    pub fn not_found() -> Self {
+        Self::new(StatusCode::NotFound)
+    }
+
+    pub fn text(text: String) -> Self {
+        Self {
+            headers: vec![
+                Header::ContentType(ContentType::TextPlain),
+                Header::ContentLength(text.len() as u64),
+            ],
+            body: Some(Body::Bytes(text.into_bytes())),
+            ..Self::new(StatusCode::Ok)
+        }
+    }
+
+    // a response without a `content_type` lets the client guess what the bytes are
+    pub fn bytes(bytes: Vec<u8>, content_type: Option<&ContentType>) -> Self {
+        let mut headers = Vec::new();
+        if let Some(content_type) = content_type {
+            headers.push(Header::ContentType(content_type.clone()));
+        }
+        headers.push(Header::ContentLength(bytes.len() as u64));
+
+        Self {
+            headers,
+            body: Some(Body::Bytes(bytes)),
+            ..Self::new(StatusCode::Ok)
+        }
+    }
+
+    // sends whatever `body` yields until it reaches its end, chunked since the length isn't known;
+    // HTTP/1.0 clients get the raw bytes instead and the connection is closed after them
+    pub fn stream(body: impl Read + Send + 'static, content_type: Option<&ContentType>) -> Self {
+        Self {
+            headers: content_type
+                .map(|content_type| Header::ContentType(content_type.clone()))
+                .into_iter()
+                .collect(),
+            body: Some(Body::Stream(Box::new(body))),
+            ..Self::new(StatusCode::Ok)
+        }
+    }
+
+    // keeps the connection open and sends the events from `events` as they come, e.g.
+    // `Response::sse(receiver)` for the receiving end of an `mpsc::channel()`
+    pub fn sse(events: impl Into<EventStream>) -> Self {
+        Self::stream(
+            events.into(),
+            Some(&ContentType::Other("text/event-stream".to_owned())),
+        )
+        .with_header("Cache-Control", "no-cache")
+    }
+
+    // `json` has to be serialized already, it's sent as-is
+    pub fn json(json: String) -> Self {
+        Self::bytes(json.into_bytes(), Some(&ContentType::ApplicationJson))
+    }
+
+    // serializes `value` to send it like `json`
+    #[cfg(feature = "json")]
+    pub fn json_value<T: serde::Serialize + ?Sized>(value: &T) -> anyhow::Result<Self> {
+        let json = serde_json::to_string(value).context("failed to serialize JSON response")?;
+        Ok(Self::json(json))
+    }
+
+    // replaces the type of a response that has one, leaving responses without a body alone
+    pub fn with_content_type(mut self, content_type: ContentType) -> Self {
+        for header in &mut self.headers {
+            if let Header::ContentType(existing) = header {
+                *existing = content_type;
+                break;
+            }
+        }
+        self
+    }
+
+    // replaces the body with the file at `path`, keeping the status and the headers that don't
+    // describe the body; HTML pages are rendered as templates with `{{status}}` and `{{reason}}`,
+    // and the response is left as it was if the file can't be served
+    pub(crate) fn with_page(mut self, path: &Path) -> Self {
+        let mut page = Self::file(path, None, None, None);
+        if page.status != StatusCode::Ok {
+            log::error!(
+                "failed to serve page {path:?} for a {} response",
+                self.status
+            );
+            return self;
+        }
+        let is_html = page.headers.iter().any(|header| {
+            matches!(header, Header::ContentType(content_type) if content_type.essence() == "text/html")
+        });
+        if is_html {
+            match Template::from_file(path) {
+                Ok(template) => {
+                    let context = TemplateContext::new()
+                        .with("status", u64::from(self.status.code()))
+                        .with("reason", self.status.reason_phrase());
+                    page = Self::render(&template, &context);
+                }
+                // sent as it is, like pages of any other type
+                Err(err) => log::warn!("failed to render page {path:?}: {err:#}"),
+            }
+        }
+
+        self.headers.retain(|header| {
+            !matches!(
+                header,
+                Header::ContentType(_)
+                    | Header::ContentLength(_)
+                    | Header::ContentEncoding(_)
+                    | Header::TransferEncoding(_)
+            )
+        });
+        self.headers.extend(
+            page.headers.into_iter().filter(|header| {
+                matches!(header, Header::ContentType(_) | Header::ContentLength(_))
+            }),
+        );
+        self.body = page.body;
+        self
+    }
+
+    // an error with no body or a plain text one, such as butler's own, as an HTML page or a JSON
+    // object with its `status`, `reason` and `message` for clients that prefer either to plain text
+    pub(crate) fn negotiated(mut self, request: &Request) -> Self {
+        let is_error = self.status.code() >= 400 && self.upgrade.is_none();
+        let message = match &self.body {
+            None => String::new(),
+            Some(Body::Bytes(body))
+                if self
+                    .headers
+                    .contains(&Header::ContentType(ContentType::TextPlain)) =>
+            {
+                String::from_utf8_lossy(body).into_owned()
+            }
+            _ => return self,
+        };
+        if !is_error {
+            return self;
+        }
+        self = self.with_vary("Accept");
+
+        let offered = [
+            ContentType::TextPlain,
+            ContentType::TextHtml,
+            ContentType::ApplicationJson,
+        ];
+        let (code, reason) = (self.status.code(), self.status.reason_phrase());
+        let page = match request.preferred_type(&offered) {
+            Some(ContentType::TextHtml) => {
+                static TEMPLATE: OnceLock<Template> = OnceLock::new();
+                let template = TEMPLATE.get_or_init(|| {
+                    ERROR_TEMPLATE
+                        .parse()
+                        .expect("the error page template is valid")
+                });
+                let context = TemplateContext::new()
+                    .with("status", u64::from(code))
+                    .with("reason", reason)
+                    .with("message", message);
+                Self::render(template, &context)
+            }
+            Some(ContentType::ApplicationJson) => Self::json(format!(
+                "{{\"status\":{code},\"reason\":{},\"message\":{}}}",
+                json_string(reason),
+                json_string(&message)
+            )),
+            _ => return self,
+        };
+
+        self.headers
+            .retain(|header| !matches!(header, Header::ContentType(_) | Header::ContentLength(_)));
+        self.headers.extend(page.headers);
+        self.body = page.body;
+        self
+    }
+
+    // adds a header that has no variant in `Header`, such as `Set-Cookie` or `Cache-Control`
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        assert!(
+            !name.is_empty() && !name.contains([':', '\r', '\n']) && !value.contains(['\r', '\n']),
+            "header {name:?} would corrupt the response"
+        );
+
+        self.headers
+            .push(Header::Other(name.to_owned(), value.to_owned()));
+        self
+    }
+
+    // lists `name` in the `Vary` header, telling caches the response depends on that request
+    // header; one that's listed already isn't repeated
+    pub fn with_vary(mut self, name: &str) -> Self {
+        let vary = self.headers.iter_mut().find_map(|header| match header {
+            Header::Other(existing, value) if existing.eq_ignore_ascii_case("vary") => Some(value),
+            _ => None,
+        });
+        match vary {
+            Some(value)
+                if value.split(',').any(|listed| {
+                    listed.trim() == "*" || listed.trim().eq_ignore_ascii_case(name)
+                }) => {}
+            Some(value) => {
+                value.push_str(", ");
+                value.push_str(name);
+            }
+            None => self = self.with_header("Vary", name),
+        }
+        self
+    }
+
+    // adds a `Set-Cookie` header, once for every cookie
+    pub fn with_cookie(self, cookie: &SetCookie) -> Self {
+        self.with_header("Set-Cookie", &cookie.to_string())
+    }
+
+    // sends the client to `location`, with one of the redirect statuses: 301 and 308 are
+    // permanent, and 307 and 308 keep the request's method and body where 301 and 302 may not
+    pub fn redirect(location: &str, status: StatusCode) -> Self {
+        assert!(
+            matches!(
+                status,
+                StatusCode::MovedPermanently
+                    | StatusCode::Found
+                    | StatusCode::TemporaryRedirect
+                    | StatusCode::PermanentRedirect
+            ),
+            "{status} isn't a redirect"
+        );
+        Self::new(status).with_header("Location", location)
+    }
+
+    pub fn no_content() -> Self {
+        Self::new(StatusCode::NoContent)
+    }
+
+    pub fn method_not_allowed(allowed: &[Method]) -> Self {
+        Self {
+            headers: vec![Header::Allow(allowed.to_vec())],
+            ..Self::new(StatusCode::MethodNotAllowed)
+        }
+    }
+
+    pub fn bad_request(reason: String) -> Self {
+        Self {
+            status: StatusCode::BadRequest,
+            ..Self::text(reason)
+        }
+    }
+
+    pub fn forbidden() -> Self {
+        Self::new(StatusCode::Forbidden)
+    }
+
+    pub fn internal_server_error() -> Self {
+        Self::new(StatusCode::InternalServerError)
+    }
+
+    pub fn too_many_requests() -> Self {
+        Self::new(StatusCode::TooManyRequests)
+    }
+
+    pub fn service_unavailable() -> Self {
+        Self::new(StatusCode::ServiceUnavailable)
+    }
+
+    pub fn request_timeout() -> Self {
+        Self::new(StatusCode::RequestTimeout)
+    }
+
+    pub fn request_header_fields_too_large() -> Self {
+        Self::new(StatusCode::RequestHeaderFieldsTooLarge)
+    }
+
+    pub fn payload_too_large() -> Self {
+        Self::new(StatusCode::PayloadTooLarge)
+    }
+
+    pub fn http_version_not_supported() -> Self {
+        Self::new(StatusCode::HttpVersionNotSupported)
+    }
+
+    pub fn not_implemented() -> Self {
+        Self::new(StatusCode::NotImplemented)
+    }
+
+    pub fn expectation_failed() -> Self {
+        Self::new(StatusCode::ExpectationFailed)
+    }
+
+    pub fn created() -> Self {
+        Self::new(StatusCode::Created)
+    }
+
+    pub fn file(
+        path: &Path,
+        range: Option<&str>,
+        if_none_match: Option<&[String]>,
+        if_modified_since: Option<SystemTime>,
+    ) -> Self {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Self::not_found(),
+            Err(err) => {
+                log::error!("failed to open file {path:?}: {err}");
+                return Self::internal_server_error();
+            }
+        };
+
+        let metadata = match file.metadata() {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                log::error!("failed to read metadata of file {path:?}: {err}");
+                return Self::internal_server_error();
+            }
+        };
+
+        if metadata.is_dir() {
+            return Self::not_found();
+        }
+
+        let conditions = (range, if_none_match, if_modified_since);
+        Self::file_parts(path, &metadata, conditions, |start, len| {
+            if let Err(err) = file.seek(SeekFrom::Start(start)) {
+                log::error!("failed to seek to offset {start} of file {path:?}: {err}");
+                return None;
+            }
+            Some(Body::File(file.take(len)))
+        })
+    }
+
+    // `Response::file` for a file whose contents were read already
+    pub(crate) fn cached_file(
+        path: &Path,
+        file: &CachedFile,
+        range: Option<&str>,
+        if_none_match: Option<&[String]>,
+        if_modified_since: Option<SystemTime>,
+    ) -> Self {
+        let conditions = (range, if_none_match, if_modified_since);
+        Self::file_parts(path, &file.metadata, conditions, |start, len| {
+            let start = start as usize;
+            Some(Body::Bytes(
+                file.contents[start..start + len as usize].to_vec(),
+            ))
+        })
+    }
+
+    // the response for the file at `path` as `metadata` describes it, answering its range and
+    // conditions; `body` gives the `len` bytes from `start` on, or `None` when they can't be read
+    fn file_parts(
+        path: &Path,
+        metadata: &fs::Metadata,
+        (range, if_none_match, if_modified_since): (
+            Option<&str>,
+            Option<&[String]>,
+            Option<SystemTime>,
+        ),
+        body: impl FnOnce(u64, u64) -> Option<Body>,
+    ) -> Self {
+        let file_len = metadata.len();
+        let etag = file_etag(metadata);
+        let last_modified = metadata.modified().ok();
+
+        // the client's cached copy is still current, so there's nothing to send;
+        // dates are only looked at when there are no entity tags, which are more precise
+        let not_modified = match (if_none_match, if_modified_since, last_modified) {
+            (Some(tags), _, _) => etag_matches(tags, &etag),
+            (None, Some(since), Some(modified)) => !modified_after(modified, since),
+            _ => false,
+        };
+        if not_modified {
+            return Self {
+                headers: [Header::ETag(etag)]
+                    .into_iter()
+                    .chain(last_modified.map(Header::LastModified))
+                    .collect(),
+                ..Self::new(StatusCode::NotModified)
+            };
+        }
+
+        let Some(range) = range else {
+            let Some(body) = body(0, file_len) else {
+                return Self::internal_server_error();
+            };
+            return Self {
+                headers: vec![
+                    Header::ContentType(content_type_from_extension(path)),
+                    Header::ContentLength(file_len),
+                    Header::AcceptRanges,
+                    Header::ETag(etag),
+                ]
+                .into_iter()
+                .chain(last_modified.map(Header::LastModified))
+                .collect(),
+                body: Some(body),
+                ..Self::new(StatusCode::Ok)
+            };
+        };
+
+        let Some((start, end)) = range
+            .parse::<ByteRange>()
+            .inspect_err(|err| log::debug!("failed to parse range: {err}"))
+            .ok()
+            .and_then(|range| range.resolve(file_len))
+        else {
+            return Self::range_not_satisfiable(file_len);
+        };
+
+        let range_len = end - start + 1;
+        let Some(body) = body(start, range_len) else {
+            return Self::internal_server_error();
+        };
+
+        Self {
+            headers: vec![
+                Header::ContentType(content_type_from_extension(path)),
+                Header::ContentLength(range_len),
+                Header::ContentRange(ContentRange {
+                    range: Some((start, end)),
+                    complete_length: file_len,
+                }),
+                Header::AcceptRanges,
+                Header::ETag(etag),
+            ]
+            .into_iter()
+            .chain(last_modified.map(Header::LastModified))
+            .collect(),
+            body: Some(body),
+            ..Self::new(StatusCode::PartialContent)
+        }
+    }
+
+    // lists the entries of the directory at `path`, which is served under `url_path`, as an HTML page
+    pub fn directory(path: &Path, url_path: &str) -> Self {
+        Self::directory_as(path, url_path, &ContentType::TextHtml)
+    }
+
+    // like `directory`, as a JSON array of objects with the `name`, `href`, `size` and `modified`
+    // of each entry for `application/json`, or their names one per line for `text/plain`; any other
+    // type gets the HTML page
+    pub fn directory_as(path: &Path, url_path: &str, content_type: &ContentType) -> Self {
+        let entries = match fs::read_dir(path) {
+            Ok(entries) => entries,
+            Err(err) => {
+                log::error!("failed to read directory {path:?}: {err}");
+                return Self::internal_server_error();
+            }
+        };
+
+        let mut listed = Vec::new();
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    log::error!("failed to read entry of directory {path:?}: {err}");
+                    return Self::internal_server_error();
+                }
+            };
+
+            // names that aren't valid UTF-8 couldn't be requested anyway
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            if name.starts_with('.') {
+                continue;
+            }
+
+            // follows symlinks, so they are listed like what they point to
+            let metadata = entry.path().metadata().ok();
+            let modified = metadata
+                .as_ref()
+                .and_then(|metadata| metadata.modified().ok());
+            match metadata {
+                Some(metadata) if metadata.is_dir() => {
+                    listed.push((format!("{name}/"), None, modified))
+                }
+                metadata => listed.push((name, metadata.map(|metadata| metadata.len()), modified)),
+            }
+        }
+        listed.sort();
+
+        let base = if url_path.ends_with('/') {
+            url_path.to_owned()
+        } else {
+            format!("{url_path}/")
+        };
+
+        match content_type {
+            ContentType::ApplicationJson => {
+                let entries: Vec<_> = listed
+                    .iter()
+                    .map(|(name, size, modified)| {
+                        format!(
+                            "{{\"name\":{},\"href\":{},\"size\":{},\"modified\":{}}}",
+                            json_string(name),
+                            json_string(&percent_encode(&format!("{base}{name}"))),
+                            size.map_or_else(|| "null".to_owned(), |size| size.to_string()),
+                            modified.map_or_else(
+                                || "null".to_owned(),
+                                |modified| { json_string(&http_date(modified)) }
+                            ),
+                        )
+                    })
+                    .collect();
+                return Self::json(format!("[{}]", entries.join(",")));
+            }
+            ContentType::TextPlain => {
+                let names: String = listed
+                    .iter()
+                    .map(|(name, _, _)| format!("{name}\n"))
+                    .collect();
+                return Self::text(names);
+            }
+            _ => {}
+        }
+
+        let entries: Vec<_> = listed
+            .into_iter()
+            .map(|(name, size, modified)| {
+                TemplateContext::new()
+                    .with("href", percent_encode(&format!("{base}{name}")))
+                    .with("name", name)
+                    .with(
+                        "size",
+                        size.map_or_else(|| "-".to_owned(), |size| size.to_string()),
+                    )
+                    .with(
+                        "modified",
+                        modified.map_or_else(|| "-".to_owned(), http_date),
+                    )
+            })
+            .collect();
+        let context = TemplateContext::new()
+            .with("title", base)
+            .with("entries", entries);
+        static TEMPLATE: OnceLock<Template> = OnceLock::new();
+        let template = TEMPLATE.get_or_init(|| {
+            DIRECTORY_TEMPLATE
+                .parse()
+                .expect("the directory listing template is valid")
+        });
+        Self::render(template, &context)
+    }
+
+    // an HTML page rendered from `template` with the values in `context`
+    pub fn render(template: &Template, context: &TemplateContext) -> Self {
+        Self::bytes(
+            template.render(context).into_bytes(),
+            Some(&ContentType::TextHtml),
+        )
+    }
+
+    pub fn range_not_satisfiable(complete_length: u64) -> Self {
+        Self {
+            headers: vec![Header::ContentRange(ContentRange {
+                range: None,
+                complete_length,
+            })],
+            ..Self::new(StatusCode::RangeNotSatisfiable)
+        }
+    }
+
+    // bodies the policy doesn't allow, because they are too small or of the wrong type, are left as they are
+    pub fn compressed(
+        mut self,
+        encoding: Encoding,
+        policy: &CompressionPolicy,
+    ) -> anyhow::Result<Self> {
+        if encoding == Encoding::Identity || !self.is_compressible(policy) {
+            return Ok(self);
+        }
+
+        self.headers.push(Header::ContentEncoding(encoding));
+        self.weaken_etag();
+
+        self.body = match self.body.take() {
+            Some(Body::Bytes(body)) => {
+                let body = compress_bytes(&body, encoding, policy.level)
+                    .with_context(|| anyhow!("failed to compress response body with {encoding}"))?;
+
+                self.headers
+                    .retain(|header| !matches!(header, Header::ContentLength(_)));
+                self.headers.push(Header::ContentLength(body.len() as u64));
+
+                Some(Body::Bytes(body))
+            }
+            // files are compressed as they are streamed, so the compressed length isn't known up front
+            Some(Body::File(file)) => {
+                self.headers
+                    .retain(|header| !matches!(header, Header::ContentLength(_)));
+
+                Some(Body::Stream(
+                    compress_reader(file, encoding, policy.level).with_context(|| {
+                        anyhow!("failed to start compressing response body with {encoding}")
+                    })?,
+                ))
+            }
+            Some(Body::Stream(stream)) => Some(Body::Stream(
+                compress_reader(stream, encoding, policy.level).with_context(|| {
+                    anyhow!("failed to start compressing response body with {encoding}")
+                })?,
+            )),
+            None => None,
+        };
+
+        Ok(self)
+    }
+
+    // whether `policy` allows compressing the body, which isn't encoded already
+    pub(crate) fn is_compressible(&self, policy: &CompressionPolicy) -> bool {
+        // a body that is encoded already must not be encoded twice
+        if self
+            .headers
+            .iter()
+            .any(|header| matches!(header, Header::ContentEncoding(_)))
+        {
+            return false;
+        }
+
+        let body_len = match &self.body {
+            Some(Body::Bytes(bytes)) => Some(bytes.len() as u64),
+            Some(Body::File(_)) => self.headers.iter().find_map(|header| {
+                if let Header::ContentLength(length) = header {
+                    Some(*length)
+                } else {
+                    None
+                }
+            }),
+            // there's nothing to gain from compressing an empty body
+            None => Some(0),
+            Some(Body::Stream(_)) => None,
+        };
+        if body_len.is_some_and(|len| len < policy.min_size) {
+            return false;
+        }
+
+        let content_type = self.headers.iter().find_map(|header| {
+            if let Header::ContentType(content_type) = header {
+                Some(content_type)
+            } else {
+                None
+            }
+        });
+        // a compressor holds on to what it's given until it has enough to work with, which would
+        // keep events from reaching the client
+        policy.allows(content_type)
+            && content_type.is_none_or(|content_type| content_type.essence() != "text/event-stream")
+    }
+
+    // swaps the body for `body`, which is the old one encoded with `encoding`
+    pub(crate) fn with_encoded_body(mut self, encoding: Encoding, body: Vec<u8>) -> Self {
+        self.headers
+            .retain(|header| !matches!(header, Header::ContentLength(_)));
+        self.headers.push(Header::ContentEncoding(encoding));
+        self.headers.push(Header::ContentLength(body.len() as u64));
+        self.weaken_etag();
+        self.body = Some(Body::Bytes(body));
+        self
+    }
+
+    // an encoded body isn't byte for byte the one a strong entity tag was made for, while a weak
+    // one still lets clients revalidate it with `If-None-Match`; `If-Match` and ranges, which
+    // compare tags strongly, never match it
+    fn weaken_etag(&mut self) {
+        for header in &mut self.headers {
+            if let Header::ETag(etag) = header {
+                if !etag.starts_with("W/") {
+                    etag.insert_str(0, "W/");
+                }
+            }
+        }
+    }
+
+    // whether the end of the body can only be told by the connection closing
+    pub(crate) fn is_close_delimited(&self) -> bool {
+        self.version == Version::Http10 && matches!(self.body, Some(Body::Stream(_)))
+    }
+
+    // every response says when it was sent, and by `server` if there's one, unless a handler did
+    // so already
+    pub(crate) fn add_default_headers(&mut self, server: Option<&str>) {
+        if let Some(server) = server.filter(|_| {
+            !self
+                .headers
+                .iter()
+                .any(|header| matches!(header, Header::Server(_)))
+        }) {
+            self.headers.insert(0, Header::Server(server.to_owned()));
+        }
+        if !self
+            .headers
+            .iter()
+            .any(|header| matches!(header, Header::Date(_)))
+        {
+            self.headers.insert(0, Header::Date(SystemTime::now()));
+        }
+    }
+
+    // returns the number of body bytes written, not counting chunk framing
+    pub fn write_to(self, w: impl io::Write, include_body: bool) -> io::Result<u64> {
+        self.send(&mut Copying(w), include_body, Some(SERVER_SOFTWARE))
+    }
+
+    // `write_to`, with files sent the fastest way `w` knows and `server` in its `Server` header
+    pub(crate) fn send(
+        mut self,
+        w: &mut impl SendFile,
+        include_body: bool,
+        server: Option<&str>,
+    ) -> io::Result<u64> {
+        let has_content_length = self
+            .headers
+            .iter()
+            .any(|header| matches!(header, Header::ContentLength(_)));
+        let has_transfer_encoding = self
+            .headers
+            .iter()
+            .any(|header| matches!(header, Header::TransferEncoding(_)));
+
+        // a body without a known length has to be framed with chunks, or by closing the connection
+        // for clients that predate them
+        let close_delimited = self.is_close_delimited();
+        if matches!(self.body, Some(Body::Stream(_))) && !has_transfer_encoding && !close_delimited
+        {
+            self.headers
+                .push(Header::TransferEncoding(TransferCoding::Chunked));
+        }
+
+        let chunked =
+            matches!(self.body, Some(Body::Stream(_))) && !close_delimited || has_transfer_encoding;
+
+        self.add_default_headers(server);
+
+        if chunked && has_content_length {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a response can't have both 'Content-Length' and 'Transfer-Encoding' headers",
+            ));
+        }
+
+        // the head is written in one go, so that it isn't split over several packets with
+        // `TCP_NODELAY` set
+        let mut head = format!("{} {}\r\n", self.version, self.status);
+        for header in &self.headers {
+            head.push_str(&format!("{header}\r\n"));
+        }
+
+        // without a length the client would wait for the connection to close to find the end of the body
+        // interim, 204 and 304 responses never have a body, so they don't need a length either
+        if self.body.is_none()
+            && !chunked
+            && self.status.code() >= 200
+            && !matches!(self.status, StatusCode::NoContent | StatusCode::NotModified)
+        {
+            head.push_str(&format!("{}\r\n", Header::ContentLength(0)));
+        }
+
+        head.push_str("\r\n");
+        w.write_all(head.as_bytes())?;
+
+        if !include_body {
+            return Ok(0);
+        }
+
+        // a file or stream can come up short of the length the head promised, which the client
+        // can only notice if the connection goes away instead of being kept alive
+        let declared_length = match self.body {
+            Some(Body::File(_) | Body::Stream(_)) if !chunked => {
+                self.headers.iter().find_map(|header| match header {
+                    Header::ContentLength(length) => Some(*length),
+                    _ => None,
+                })
+            }
+            _ => None,
+        };
+
+        let bytes_sent = match self.body {
+            Some(Body::Bytes(bytes)) if chunked => {
+                let mut w = ChunkedWriter::new(&mut *w);
+                w.write_all(&bytes)?;
+                w.finish()?;
+                bytes.len() as u64
+            }
+            Some(Body::Bytes(bytes)) => {
+                w.write_all(&bytes)?;
+                bytes.len() as u64
+            }
+            Some(Body::File(mut file)) if chunked => {
+                let mut w = ChunkedWriter::new(&mut *w);
+                let bytes_sent = io::copy(&mut file, &mut w)?;
+                w.finish()?;
+                bytes_sent
+            }
+            Some(Body::File(mut file)) => w.send_file(&mut file)?,
+            Some(Body::Stream(mut stream)) if !chunked => io::copy(&mut stream, w)?,
+            Some(Body::Stream(mut stream)) => {
+                let mut w = ChunkedWriter::new(&mut *w);
+                let bytes_sent = io::copy(&mut stream, &mut w)?;
+                w.finish()?;
+                bytes_sent
+            }
+            None if chunked => {
+                ChunkedWriter::new(&mut *w).finish()?;
+                0
+            }
+            None => 0,
+        };
+
+        if let Some(length) = declared_length.filter(|&length| bytes_sent < length) {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("the body ended after {bytes_sent} of {length} bytes"),
+            ));
+        }
+
+        Ok(bytes_sent)
+    }
+}
+
+// assembles a response header by header, working out `Content-Length` or chunking from the body
+// it is finished with, e.g. `Response::builder().status(StatusCode::Created).body("done")`
+#[derive(Debug)]
+pub struct ResponseBuilder {
+    status: StatusCode,
+    headers: Vec<Header>,
+}
+
+impl ResponseBuilder {
+    pub fn status(mut self, status: StatusCode) -> Self {
+        self.status = status;
+        self
+    }
+
+    pub fn header(mut self, header: Header) -> Self {
+        self.headers.push(header);
+        self
+    }
+
+    pub fn content_type(self, content_type: ContentType) -> Self {
+        self.header(Header::ContentType(content_type))
+    }
+
+    // finishes the response without a body
+    pub fn build(self) -> anyhow::Result<Response> {
+        self.finish(None)
+    }
+
+    pub fn body(self, body: impl Into<Vec<u8>>) -> anyhow::Result<Response> {
+        let body = body.into();
+        let length = body.len() as u64;
+        let mut response = self.finish(Some(Body::Bytes(body)))?;
+        response.headers.push(Header::ContentLength(length));
+        Ok(response)
+    }
+
+    // the body is sent in chunks as it is read, since its length isn't known up front
+    pub fn stream(self, body: impl Read + Send + 'static) -> anyhow::Result<Response> {
+        self.finish(Some(Body::Stream(Box::new(body))))
+    }
+
+    fn finish(self, body: Option<Body>) -> anyhow::Result<Response> {
+        let mut seen = Vec::new();
+        for header in &self.headers {
+            match header {
+                Header::ContentLength(_) | Header::TransferEncoding(_) => {
+                    return Err(anyhow!(
+                        "'{}' is worked out from the body and can't be set by hand",
+                        header.name()
+                    ))
+                }
+                Header::Connection(_) => {
+                    return Err(anyhow!("'Connection' is managed by the server"))
+                }
+                // request headers mean nothing in a response
+                Header::UserAgent(_)
+                | Header::Host(_)
+                | Header::AcceptEncoding(_)
+                | Header::Range(_)
+                | Header::IfNoneMatch(_)
+                | Header::IfMatch(_)
+                | Header::IfModifiedSince(_)
+                | Header::Expect(_) => {
+                    return Err(anyhow!("'{}' can only be sent in a request", header.name()))
+                }
+                // these may be repeated, as `Set-Cookie` has to be
+                Header::Other(..) => continue,
+                _ => {}
+            }
+
+            if seen.contains(&header.name()) {
+                return Err(anyhow!("'{}' is set more than once", header.name()));
+            }
+            seen.push(header.name());
+        }
+
+        let code = self.status.code();
+        if body.is_some() && (code < 200 || code == 204 || code == 304) {
+            return Err(anyhow!("a {} response can't have a body", self.status));
+        }
+
+        Ok(Response {
+            headers: self.headers,
+            body,
+            ..Response::new(self.status)
+        })
+    }
+}
+
+// frames everything written to it as chunks of `Transfer-Encoding: chunked`
+#[derive(Debug)]
+struct ChunkedWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner }
+    }
+
+    // writes the terminating zero-length chunk
+    pub(crate) fn finish(mut self) -> io::Result<()> {
+        self.inner.write_all(b"0\r\n\r\n")?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // an empty chunk would end the body early
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        write!(self.inner, "{:X}\r\n", buf.len())?;
+        self.inner.write_all(buf)?;
+        self.inner.write_all(b"\r\n")?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// every status code in the IANA registry, except the unassigned and obsolete ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StatusCode {
+    Continue,
+    SwitchingProtocols,
+    Processing,
+    EarlyHints,
+    Ok,
+    Created,
+    Accepted,
+    NonAuthoritativeInformation,
+    NoContent,
+    ResetContent,
+    PartialContent,
+    MultiStatus,
+    AlreadyReported,
+    ImUsed,
+    MultipleChoices,
+    MovedPermanently,
+    Found,
+    SeeOther,
+    NotModified,
+    UseProxy,
+    TemporaryRedirect,
+    PermanentRedirect,
+    BadRequest,
+    Unauthorized,
+    PaymentRequired,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    NotAcceptable,
+    ProxyAuthenticationRequired,
+    RequestTimeout,
+    Conflict,
+    Gone,
+    LengthRequired,
+    PreconditionFailed,
+    PayloadTooLarge,
+    UriTooLong,
+    UnsupportedMediaType,
+    RangeNotSatisfiable,
+    ExpectationFailed,
+    MisdirectedRequest,
+    UnprocessableContent,
+    Locked,
+    FailedDependency,
+    TooEarly,
+    UpgradeRequired,
+    PreconditionRequired,
+    TooManyRequests,
+    RequestHeaderFieldsTooLarge,
+    UnavailableForLegalReasons,
+    InternalServerError,
+    NotImplemented,
+    BadGateway,
+    ServiceUnavailable,
+    GatewayTimeout,
+    HttpVersionNotSupported,
+    VariantAlsoNegotiates,
+    InsufficientStorage,
+    LoopDetected,
+    NotExtended,
+    NetworkAuthenticationRequired,
+}
+
+impl StatusCode {
+    pub fn code(self) -> u16 {
+        match self {
+            Self::Continue => 100,
+            Self::SwitchingProtocols => 101,
+            Self::Processing => 102,
+            Self::EarlyHints => 103,
+            Self::Ok => 200,
+            Self::Created => 201,
+            Self::Accepted => 202,
+            Self::NonAuthoritativeInformation => 203,
+            Self::NoContent => 204,
+            Self::ResetContent => 205,
+            Self::PartialContent => 206,
+            Self::MultiStatus => 207,
+            Self::AlreadyReported => 208,
+            Self::ImUsed => 226,
+            Self::MultipleChoices => 300,
+            Self::MovedPermanently => 301,
+            Self::Found => 302,
+            Self::SeeOther => 303,
+            Self::NotModified => 304,
+            Self::UseProxy => 305,
+            Self::TemporaryRedirect => 307,
+            Self::PermanentRedirect => 308,
+            Self::BadRequest => 400,
+            Self::Unauthorized => 401,
+            Self::PaymentRequired => 402,
+            Self::Forbidden => 403,
+            Self::NotFound => 404,
+            Self::MethodNotAllowed => 405,
+            Self::NotAcceptable => 406,
+            Self::ProxyAuthenticationRequired => 407,
+            Self::RequestTimeout => 408,
+            Self::Conflict => 409,
+            Self::Gone => 410,
+            Self::LengthRequired => 411,
+            Self::PreconditionFailed => 412,
+            Self::PayloadTooLarge => 413,
+            Self::UriTooLong => 414,
+            Self::UnsupportedMediaType => 415,
+            Self::RangeNotSatisfiable => 416,
+            Self::ExpectationFailed => 417,
+            Self::MisdirectedRequest => 421,
+            Self::UnprocessableContent => 422,
+            Self::Locked => 423,
+            Self::FailedDependency => 424,
+            Self::TooEarly => 425,
+            Self::UpgradeRequired => 426,
+            Self::PreconditionRequired => 428,
+            Self::TooManyRequests => 429,
+            Self::RequestHeaderFieldsTooLarge => 431,
+            Self::UnavailableForLegalReasons => 451,
+            Self::InternalServerError => 500,
+            Self::NotImplemented => 501,
+            Self::BadGateway => 502,
+            Self::ServiceUnavailable => 503,
+            Self::GatewayTimeout => 504,
+            Self::HttpVersionNotSupported => 505,
+            Self::VariantAlsoNegotiates => 506,
+            Self::InsufficientStorage => 507,
+            Self::LoopDetected => 508,
+            Self::NotExtended => 510,
+            Self::NetworkAuthenticationRequired => 511,
+        }
+    }
+
+    // `None` for codes that aren't registered
+    pub fn from_code(code: u16) -> Option<Self> {
+        match code {
+            100 => Some(Self::Continue),
+            101 => Some(Self::SwitchingProtocols),
+            102 => Some(Self::Processing),
+            103 => Some(Self::EarlyHints),
+            200 => Some(Self::Ok),
+            201 => Some(Self::Created),
+            202 => Some(Self::Accepted),
+            203 => Some(Self::NonAuthoritativeInformation),
+            204 => Some(Self::NoContent),
+            205 => Some(Self::ResetContent),
+            206 => Some(Self::PartialContent),
+            207 => Some(Self::MultiStatus),
+            208 => Some(Self::AlreadyReported),
+            226 => Some(Self::ImUsed),
+            300 => Some(Self::MultipleChoices),
+            301 => Some(Self::MovedPermanently),
+            302 => Some(Self::Found),
+            303 => Some(Self::SeeOther),
+            304 => Some(Self::NotModified),
+            305 => Some(Self::UseProxy),
+            307 => Some(Self::TemporaryRedirect),
+            308 => Some(Self::PermanentRedirect),
+            400 => Some(Self::BadRequest),
+            401 => Some(Self::Unauthorized),
+            402 => Some(Self::PaymentRequired),
+            403 => Some(Self::Forbidden),
+            404 => Some(Self::NotFound),
+            405 => Some(Self::MethodNotAllowed),
+            406 => Some(Self::NotAcceptable),
+            407 => Some(Self::ProxyAuthenticationRequired),
+            408 => Some(Self::RequestTimeout),
+            409 => Some(Self::Conflict),
+            410 => Some(Self::Gone),
+            411 => Some(Self::LengthRequired),
+            412 => Some(Self::PreconditionFailed),
+            413 => Some(Self::PayloadTooLarge),
+            414 => Some(Self::UriTooLong),
+            415 => Some(Self::UnsupportedMediaType),
+            416 => Some(Self::RangeNotSatisfiable),
+            417 => Some(Self::ExpectationFailed),
+            421 => Some(Self::MisdirectedRequest),
+            422 => Some(Self::UnprocessableContent),
+            423 => Some(Self::Locked),
+            424 => Some(Self::FailedDependency),
+            425 => Some(Self::TooEarly),
+            426 => Some(Self::UpgradeRequired),
+            428 => Some(Self::PreconditionRequired),
+            429 => Some(Self::TooManyRequests),
+            431 => Some(Self::RequestHeaderFieldsTooLarge),
+            451 => Some(Self::UnavailableForLegalReasons),
+            500 => Some(Self::InternalServerError),
+            501 => Some(Self::NotImplemented),
+            502 => Some(Self::BadGateway),
+            503 => Some(Self::ServiceUnavailable),
+            504 => Some(Self::GatewayTimeout),
+            505 => Some(Self::HttpVersionNotSupported),
+            506 => Some(Self::VariantAlsoNegotiates),
+            507 => Some(Self::InsufficientStorage),
+            508 => Some(Self::LoopDetected),
+            510 => Some(Self::NotExtended),
+            511 => Some(Self::NetworkAuthenticationRequired),
+            _ => None,
+        }
+    }
+
+    pub fn reason_phrase(self) -> &'static str {
+        match self {
+            Self::Continue => "Continue",
+            Self::SwitchingProtocols => "Switching Protocols",
+            Self::Processing => "Processing",
+            Self::EarlyHints => "Early Hints",
+            Self::Ok => "OK",
+            Self::Created => "Created",
+            Self::Accepted => "Accepted",
+            Self::NonAuthoritativeInformation => "Non-Authoritative Information",
+            Self::NoContent => "No Content",
+            Self::ResetContent => "Reset Content",
+            Self::PartialContent => "Partial Content",
+            Self::MultiStatus => "Multi-Status",
+            Self::AlreadyReported => "Already Reported",
+            Self::ImUsed => "IM Used",
+            Self::MultipleChoices => "Multiple Choices",
+            Self::MovedPermanently => "Moved Permanently",
+            Self::Found => "Found",
+            Self::SeeOther => "See Other",
+            Self::NotModified => "Not Modified",
+            Self::UseProxy => "Use Proxy",
+            Self::TemporaryRedirect => "Temporary Redirect",
+            Self::PermanentRedirect => "Permanent Redirect",
+            Self::BadRequest => "Bad Request",
+            Self::Unauthorized => "Unauthorized",
+            Self::PaymentRequired => "Payment Required",
+            Self::Forbidden => "Forbidden",
+            Self::NotFound => "Not Found",
+            Self::MethodNotAllowed => "Method Not Allowed",
+            Self::NotAcceptable => "Not Acceptable",
+            Self::ProxyAuthenticationRequired => "Proxy Authentication Required",
+            Self::RequestTimeout => "Request Timeout",
+            Self::Conflict => "Conflict",
+            Self::Gone => "Gone",
+            Self::LengthRequired => "Length Required",
+            Self::PreconditionFailed => "Precondition Failed",
+            Self::PayloadTooLarge => "Payload Too Large",
+            Self::UriTooLong => "URI Too Long",
+            Self::UnsupportedMediaType => "Unsupported Media Type",
+            Self::RangeNotSatisfiable => "Range Not Satisfiable",
+            Self::ExpectationFailed => "Expectation Failed",
+            Self::MisdirectedRequest => "Misdirected Request",
+            Self::UnprocessableContent => "Unprocessable Content",
+            Self::Locked => "Locked",
+            Self::FailedDependency => "Failed Dependency",
+            Self::TooEarly => "Too Early",
+            Self::UpgradeRequired => "Upgrade Required",
+            Self::PreconditionRequired => "Precondition Required",
+            Self::TooManyRequests => "Too Many Requests",
+            Self::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
+            Self::UnavailableForLegalReasons => "Unavailable For Legal Reasons",
+            Self::InternalServerError => "Internal Server Error",
+            Self::NotImplemented => "Not Implemented",
+            Self::BadGateway => "Bad Gateway",
+            Self::ServiceUnavailable => "Service Unavailable",
+            Self::GatewayTimeout => "Gateway Timeout",
+            Self::HttpVersionNotSupported => "HTTP Version Not Supported",
+            Self::VariantAlsoNegotiates => "Variant Also Negotiates",
+            Self::InsufficientStorage => "Insufficient Storage",
+            Self::LoopDetected => "Loop Detected",
+            Self::NotExtended => "Not Extended",
+            Self::NetworkAuthenticationRequired => "Network Authentication Required",
+        }
+    }
+}
+
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.code(), self.reason_phrase())
+    }
+}
+
+// identifies a version of a file without reading it, so changing its size or modification time changes the tag
+pub(crate) fn file_etag(metadata: &fs::Metadata) -> String {
+    let mut hasher = DefaultHasher::new();
+    metadata.len().hash(&mut hasher);
+    if let Ok(modified) = metadata.modified() {
+        modified.hash(&mut hasher);
+    }
+
+    format!("\"{:016x}\"", hasher.finish())
+}
+
+// `If-None-Match` uses the weak comparison, so `W/"tag"` matches `"tag"`
+fn etag_matches(tags: &[String], etag: &str) -> bool {
+    let strip_weak = |tag: &str| tag.strip_prefix("W/").unwrap_or(tag).to_owned();
+
+    tags.iter()
+        .any(|tag| tag == "*" || strip_weak(tag) == strip_weak(etag))
+}
+
+// whether a request with these `If-Match` and `If-None-Match` headers may replace a file with
+// `metadata`, `None` if there's no file yet; `If-Match` uses the strong comparison, so weak tags
+// never match
+pub(crate) fn write_preconditions_hold(
+    if_match: Option<&[String]>,
+    if_none_match: Option<&[String]>,
+    metadata: Option<&fs::Metadata>,
+) -> bool {
+    let etag = metadata.map(file_etag);
+    if let Some(tags) = if_match {
+        let Some(etag) = &etag else {
+            return false;
+        };
+        if !tags.iter().any(|tag| tag == "*" || tag == etag) {
+            return false;
+        }
+    }
+    match (if_none_match, &etag) {
+        (Some(tags), Some(etag)) => !etag_matches(tags, etag),
+        _ => true,
+    }
+}
+
+// HTTP dates only have whole seconds, so anything finer than that is ignored
+fn modified_after(modified: SystemTime, since: SystemTime) -> bool {
+    let secs = |time: SystemTime| {
+        time.duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs())
+    };
+
+    secs(modified) > secs(since)
+}
+
+pub(crate) fn content_type_from_extension(path: &Path) -> ContentType {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_lowercase);
+
+    let other = |content_type: &str| ContentType::Other(content_type.to_owned());
+    match extension.as_deref() {
+        Some("html" | "htm") => ContentType::TextHtml,
+        Some("css") => ContentType::TextCss,
+        Some("js" | "mjs") => ContentType::TextJavascript,
+        Some("json" | "map") => ContentType::ApplicationJson,
+        Some("png") => ContentType::ImagePng,
+        Some("jpg" | "jpeg") => ContentType::ImageJpeg,
+        Some("txt") => ContentType::TextPlain,
+        Some("csv") => other("text/csv"),
+        Some("md") => other("text/markdown"),
+        Some("xml") => other("application/xml"),
+        Some("wasm") => other("application/wasm"),
+        Some("pdf") => other("application/pdf"),
+        Some("zip") => other("application/zip"),
+        Some("gz") => other("application/gzip"),
+        Some("svg") => other("image/svg+xml"),
+        Some("gif") => other("image/gif"),
+        Some("webp") => other("image/webp"),
+        Some("avif") => other("image/avif"),
+        Some("ico") => other("image/x-icon"),
+        Some("woff") => other("font/woff"),
+        Some("woff2") => other("font/woff2"),
+        Some("ttf") => other("font/ttf"),
+        Some("otf") => other("font/otf"),
+        Some("mp3") => other("audio/mpeg"),
+        Some("ogg") => other("audio/ogg"),
+        Some("mp4") => other("video/mp4"),
+        Some("webm") => other("video/webm"),
+        _ => ContentType::ApplicationOctetStream,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builder_works_out_content_length() {
+        let response = Response::builder()
+            .status(StatusCode::Created)
+            .content_type(ContentType::ApplicationJson)
+            .header(Header::ETag("\"v1\"".to_owned()))
+            .body("{}")
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::Created);
+        assert_eq!(
+            response.headers(),
+            [
+                Header::ContentType(ContentType::ApplicationJson),
+                Header::ETag("\"v1\"".to_owned()),
+                Header::ContentLength(2),
+            ]
+        );
+    }
+
+    #[test]
+    fn builder_rejects_inconsistent_headers() {
+        assert!(Response::builder()
+            .header(Header::ContentLength(5))
+            .body("hello")
+            .is_err());
+        assert!(Response::builder()
+            .content_type(ContentType::TextPlain)
+            .content_type(ContentType::TextHtml)
+            .build()
+            .is_err());
+        assert!(Response::builder()
+            .header(Header::Host("localhost".to_owned()))
+            .build()
+            .is_err());
+        assert!(Response::builder()
+            .status(StatusCode::NoContent)
+            .body("hello")
+            .is_err());
+
+        // any number of headers without a variant are fine
+        assert!(Response::builder()
+            .header(Header::Other("Set-Cookie".to_owned(), "a=1".to_owned()))
+            .header(Header::Other("Set-Cookie".to_owned(), "b=2".to_owned()))
+            .status(StatusCode::NoContent)
+            .build()
+            .is_ok());
+    }
+
+    #[test]
+    fn status_code_from_code_inverts_code() {
+        for code in 0..1000 {
+            if let Some(status) = StatusCode::from_code(code) {
+                assert_eq!(status.code(), code);
+            }
+        }
+
+        assert_eq!(StatusCode::from_code(418), None);
+        assert_eq!(
+            StatusCode::from_code(308),
+            Some(StatusCode::PermanentRedirect)
+        );
+        assert_eq!(
+            StatusCode::UnavailableForLegalReasons.to_string(),
+            "451 Unavailable For Legal Reasons"
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_value_serializes_with_a_length() {
+        let response = Response::json_value(&serde_json::json!({ "id": 1 })).unwrap();
+
+        assert_eq!(
+            response.headers(),
+            [
+                Header::ContentType(ContentType::ApplicationJson),
+                Header::ContentLength(8),
+            ]
+        );
+        assert!(matches!(response.body, Some(Body::Bytes(body)) if body == br#"{"id":1}"#));
+    }
+
+    #[test]
+    fn file_that_shrinks_while_being_sent_fails_the_response() {
+        let path = std::env::temp_dir().join(format!("butler-shrink-{}.txt", std::process::id()));
+        fs::write(&path, vec![b'a'; 64 * 1024]).unwrap();
+
+        let response = Response::file(&path, None, None, None);
+        // truncated after the length went into the head, but before the body is sent
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(1000)
+            .unwrap();
+        let mut written = Vec::new();
+        let err = response.write_to(&mut written, true).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(String::from_utf8_lossy(&written).contains("Content-Length: 65536\r\n"));
+
+        // the same goes for a stream that promised a length, which is only sent unchunked to
+        // HTTP/1.0 clients
+        let mut response = Response::new(StatusCode::Ok);
+        response.version = Version::Http10;
+        response.headers.push(Header::ContentLength(10));
+        response.body = Some(Body::Stream(Box::new(io::Cursor::new("short"))));
+        let err = response.write_to(io::sink(), true).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn file_streams_from_disk_instead_of_reading_it_whole() {
+        let path = std::env::temp_dir().join(format!("butler-stream-{}.txt", std::process::id()));
+        let contents: Vec<u8> = (0..3 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, &contents).unwrap();
+
+        let response = Response::file(&path, None, None, None);
+        assert!(
+            matches!(&response.body, Some(Body::File(file)) if file.limit() == contents.len() as u64)
+        );
+        let mut written = Vec::new();
+        let sent = response.write_to(&mut written, true).unwrap();
+        assert_eq!(sent, contents.len() as u64);
+        assert!(written.ends_with(&contents));
+
+        let response = Response::file(&path, Some("bytes=1000-1999"), None, None);
+        assert!(matches!(&response.body, Some(Body::File(file)) if file.limit() == 1000));
+        let mut written = Vec::new();
+        response.write_to(&mut written, true).unwrap();
+        assert!(written.ends_with(&contents[1000..2000]));
+
+        // compressed as it's sent, so its length isn't known up front
+        let response = Response::file(&path, None, None, None)
+            .compressed(Encoding::Gzip, &CompressionPolicy::default())
+            .unwrap();
+        assert!(matches!(response.body, Some(Body::Stream(_))));
+        assert!(!response
+            .headers()
+            .iter()
+            .any(|header| matches!(header, Header::ContentLength(_))));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn with_vary_lists_every_header_once() {
+        let response = Response::empty()
+            .with_vary("Origin")
+            .with_vary("Accept-Encoding")
+            .with_vary("accept-encoding");
+        assert_eq!(
+            response.header("Vary").as_deref(),
+            Some("Origin, Accept-Encoding")
+        );
+
+        let response = Response::empty()
+            .with_header("Vary", "*")
+            .with_vary("Accept");
+        assert_eq!(response.header("Vary").as_deref(), Some("*"));
+    }
+
+    #[cfg(any(feature = "brotli", feature = "zstd"))]
+    #[test]
+    fn compressed_sets_the_coding_it_negotiated() {
+        let policy = CompressionPolicy {
+            min_size: 0,
+            ..CompressionPolicy::default()
+        };
+        // a body held in memory, and one that's compressed as it's streamed
+        let bodies = |encoding| {
+            let bytes = Response::text("abc".repeat(1024))
+                .compressed(encoding, &policy)
+                .unwrap();
+            let mut stream = Response::text(String::new());
+            stream.body = Some(Body::Stream(Box::new(io::Cursor::new("abc".repeat(1024)))));
+            let stream = stream.compressed(encoding, &policy).unwrap();
+            [bytes, stream].map(|response| {
+                assert!(response
+                    .headers()
+                    .contains(&Header::ContentEncoding(encoding)));
+                let mut body = Vec::new();
+                response
+                    .body
+                    .unwrap()
+                    .into_reader()
+                    .read_to_end(&mut body)
+                    .unwrap();
+                body
+            })
+        };
+
+        #[cfg(feature = "brotli")]
+        for body in bodies(Encoding::Brotli) {
+            let mut decoded = String::new();
+            brotli::Decompressor::new(&body[..], 4096)
+                .read_to_string(&mut decoded)
+                .unwrap();
+            assert_eq!(decoded, "abc".repeat(1024));
+        }
+        #[cfg(feature = "zstd")]
+        for body in bodies(Encoding::Zstd) {
+            assert_eq!(
+                zstd::decode_all(&body[..]).unwrap(),
+                "abc".repeat(1024).as_bytes()
+            );
+        }
+    }
+
+    #[test]
+    fn compressed_skips_bodies_that_are_encoded_already() {
+        let policy = CompressionPolicy {
+            min_size: 0,
+            ..CompressionPolicy::default()
+        };
+        let response = Response::text("a".repeat(64))
+            .compressed(Encoding::Gzip, &policy)
+            .unwrap()
+            .compressed(Encoding::Deflate, &policy)
+            .unwrap();
+
+        assert_eq!(
+            response
+                .headers()
+                .iter()
+                .filter(|header| matches!(header, Header::ContentEncoding(_)))
+                .collect::<Vec<_>>(),
+            [&Header::ContentEncoding(Encoding::Gzip)]
+        );
+    }
+
+    #[test]
+    fn compressed_follows_the_policy() {
+        let is_compressed = |response: Response, policy: &CompressionPolicy| {
+            response
+                .compressed(Encoding::Gzip, policy)
+                .unwrap()
+                .headers()
+                .contains(&Header::ContentEncoding(Encoding::Gzip))
+        };
+        let html = || Response::bytes(vec![b'a'; 64], Some(&ContentType::TextHtml));
+        let png = || Response::bytes(vec![b'a'; 64], Some(&ContentType::ImagePng));
+
+        let policy = CompressionPolicy {
+            min_size: 64,
+            ..CompressionPolicy::default()
+        };
+        assert!(is_compressed(html(), &policy));
+        assert!(!is_compressed(png(), &policy));
+        assert!(!is_compressed(Response::text("a".repeat(63)), &policy));
+
+        let policy = CompressionPolicy {
+            min_size: 0,
+            content_types: Some(vec!["text/html".parse().unwrap(), ContentType::ImagePng]),
+            ..CompressionPolicy::default()
+        };
+        assert!(is_compressed(html(), &policy));
+        assert!(is_compressed(png(), &policy));
+        assert!(!is_compressed(Response::text("a".repeat(64)), &policy));
+        assert!(is_compressed(
+            Response::bytes(
+                vec![b'a'; 64],
+                Some(&"TEXT/HTML; charset=utf-8".parse().unwrap())
+            ),
+            &policy
+        ));
+        assert!(!is_compressed(
+            Response::bytes(vec![b'a'; 64], None),
+            &policy
+        ));
+
+        let response = |level| {
+            Response::text("abc".repeat(1024))
+                .compressed(
+                    Encoding::Gzip,
+                    &CompressionPolicy {
+                        level,
+                        ..CompressionPolicy::default()
+                    },
+                )
+                .unwrap()
+        };
+        let length = |response: Response| {
+            response.headers().iter().find_map(|header| match header {
+                Header::ContentLength(length) => Some(*length),
+                _ => None,
+            })
+        };
+        assert!(length(response(0)) > length(response(9)));
+    }
+}
@@ -0,0 +1,241 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context};
+use regex::Regex;
+
+use crate::{
+    middleware::Middleware,
+    request::{parse_query, Request},
+    response::{Response, StatusCode},
+};
+
+// changes the path of requests that match it before they're routed, or redirects them, e.g.
+// `RewriteRule::prefix("/static/", "/files/assets/")` or
+// `RewriteRule::regex("^/posts/([0-9]+)$", "/blog?id=$1")?.redirect(StatusCode::MovedPermanently)`;
+// add it to `Config::rewrites`, where the first rule that matches a request is the one applied
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RewriteRule {
+    pattern: Pattern,
+    target: String,
+    // the status of the redirect to the target, `None` routes the request as if it had been sent
+    // for the target
+    redirect: Option<StatusCode>,
+}
+
+#[derive(Debug, Clone)]
+enum Pattern {
+    // the start of the path as it was sent, which the start of the target replaces
+    Prefix(String),
+    // the whole path as it was sent, where the target may use the groups it captured as `$1`
+    Regex(Regex),
+}
+
+// regexes are equal when they were compiled from the same pattern
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Prefix(a), Self::Prefix(b)) => a == b,
+            (Self::Regex(a), Self::Regex(b)) => a.as_str() == b.as_str(),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Pattern {}
+
+impl RewriteRule {
+    pub fn prefix(prefix: &str, target: &str) -> Self {
+        Self {
+            pattern: Pattern::Prefix(prefix.to_owned()),
+            target: target.to_owned(),
+            redirect: None,
+        }
+    }
+
+    pub fn regex(pattern: &str, target: &str) -> anyhow::Result<Self> {
+        let regex =
+            Regex::new(pattern).with_context(|| anyhow!("{pattern:?} is not a valid regex"))?;
+        Ok(Self {
+            pattern: Pattern::Regex(regex),
+            target: target.to_owned(),
+            redirect: None,
+        })
+    }
+
+    // redirects matching requests to the target with `status` instead, where the target may also
+    // be a URL on another server
+    pub fn redirect(mut self, status: StatusCode) -> Self {
+        assert!(is_redirect(status), "{status} isn't a redirect");
+        self.redirect = Some(status);
+        self
+    }
+
+    // what `path` becomes, `None` when the rule doesn't match it
+    fn apply(&self, path: &str) -> Option<String> {
+        match &self.pattern {
+            Pattern::Prefix(prefix) => {
+                let rest = path.strip_prefix(prefix.as_str())?;
+                Some(format!("{}{rest}", self.target))
+            }
+            Pattern::Regex(regex) => {
+                let captures = regex.captures(path)?;
+                let mut target = String::new();
+                captures.expand(&self.target, &mut target);
+                Some(target)
+            }
+        }
+    }
+}
+
+fn is_redirect(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::MovedPermanently
+            | StatusCode::Found
+            | StatusCode::TemporaryRedirect
+            | StatusCode::PermanentRedirect
+    )
+}
+
+// `PATTERN=TARGET`, or `PATTERN=TARGET=STATUS` to redirect with a 301, 302, 307 or 308; patterns
+// starting with `^` are regexes and others prefixes, e.g. `/static/=/files/assets/` or
+// `^/posts/([0-9]+)$=/blog?id=$1=301`
+impl FromStr for RewriteRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, target) = s
+            .split_once('=')
+            .with_context(|| anyhow!("{s:?} is not a rule such as /static/=/files/assets/"))?;
+        let (pattern, target) = (pattern.trim(), target.trim());
+        let redirect = target.rsplit_once('=').and_then(|(target, status)| {
+            let status = StatusCode::from_code(status.trim().parse().ok()?)?;
+            is_redirect(status).then_some((target.trim(), status))
+        });
+        let (target, redirect) = match redirect {
+            Some((target, status)) => (target, Some(status)),
+            None => (target, None),
+        };
+        if pattern.is_empty() || target.is_empty() {
+            return Err(anyhow!("{s:?} is not a valid rewrite rule"));
+        }
+        // a rewritten path is routed like one the client sent, so it can't be a URL
+        if redirect.is_none() && !target.starts_with('/') {
+            return Err(anyhow!("{target:?} has to be a path starting with '/'"));
+        }
+
+        let rule = if pattern.starts_with('^') {
+            Self::regex(pattern, target)?
+        } else {
+            Self::prefix(pattern, target)
+        };
+        Ok(match redirect {
+            Some(status) => rule.redirect(status),
+            None => rule,
+        })
+    }
+}
+
+// the path, without a query, that the first of `rules` to match `path` has it routed as; `None`
+// when none does, or the one that does redirects
+pub(crate) fn rewrite_path(rules: &[RewriteRule], path: &str) -> Option<String> {
+    let (rule, target) = rules
+        .iter()
+        .find_map(|rule| Some((rule, rule.apply(path)?)))?;
+    if rule.redirect.is_some() || !target.starts_with('/') {
+        return None;
+    }
+    Some(match target.split_once('?') {
+        Some((path, _)) => path.to_owned(),
+        None => target,
+    })
+}
+
+// applies `Config::rewrites` ahead of everything else that looks at the path
+#[derive(Debug)]
+pub(crate) struct Rewrites(pub(crate) Vec<RewriteRule>);
+
+impl Middleware for Rewrites {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        let (rule, target) = self
+            .0
+            .iter()
+            .find_map(|rule| Some((rule, rule.apply(&request.line.path)?)))?;
+
+        if let Some(status) = rule.redirect {
+            // the query is kept unless the target has one of its own
+            let location = match &request.line.raw_query {
+                Some(query) if !target.contains('?') => format!("{target}?{query}"),
+                _ => target,
+            };
+            return Some(Response::redirect(&location, status));
+        }
+
+        if !target.starts_with('/') {
+            log::warn!(
+                "request_id = {}, not rewriting {:?} to {target:?}, which isn't a path",
+                request.id,
+                request.line.path
+            );
+            return None;
+        }
+        log::debug!(
+            "request_id = {}, rewrote {:?} to {target:?}",
+            request.id,
+            request.line.path
+        );
+        // a query in the target comes before the one that was sent
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path.to_owned(), Some(query.to_owned())),
+            None => (target, None),
+        };
+        if let Some(query) = query {
+            let raw_query = match &request.line.raw_query {
+                Some(sent) => format!("{query}&{sent}"),
+                None => query,
+            };
+            match parse_query(&raw_query) {
+                Ok(pairs) => request.line.query = pairs,
+                Err(err) => {
+                    return Some(Response::bad_request(format!(
+                        "malformed query string: {err:#}"
+                    )))
+                }
+            }
+            request.line.raw_query = Some(raw_query);
+        }
+        request.line.path = path;
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewrite_rules_replace_prefixes_and_regex_matches() {
+        let statics: RewriteRule = "/static/=/files/assets/".parse().unwrap();
+        assert_eq!(
+            statics.apply("/static/app.js").as_deref(),
+            Some("/files/assets/app.js")
+        );
+        assert_eq!(statics.apply("/other/app.js"), None);
+        assert_eq!(statics.redirect, None);
+
+        let posts: RewriteRule = "^/posts/([0-9]+)$=/blog?id=$1=301".parse().unwrap();
+        assert_eq!(posts.apply("/posts/42").as_deref(), Some("/blog?id=42"));
+        assert_eq!(posts.apply("/posts/new"), None);
+        assert_eq!(posts.redirect, Some(StatusCode::MovedPermanently));
+
+        let moved: RewriteRule = "/old/=https://example.com/=308".parse().unwrap();
+        assert_eq!(
+            moved.apply("/old/page").as_deref(),
+            Some("https://example.com/page")
+        );
+
+        assert!("/no-target".parse::<RewriteRule>().is_err());
+        assert!("/a/=https://example.com/".parse::<RewriteRule>().is_err());
+        assert!("^/(unclosed=/b".parse::<RewriteRule>().is_err());
+    }
+}
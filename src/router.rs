@@ -0,0 +1,469 @@
+use std::{
+    fmt, mem,
+    panic::{self, AssertUnwindSafe},
+    sync::Arc,
+};
+
+use crate::{
+    header::Header,
+    middleware::Middleware,
+    request::{Method, Request},
+    response::{Response, StatusCode},
+    websocket::{self, WebSocket},
+};
+
+// answers the requests of a route; closures taking a `&Request` and returning either a `Response`
+// or an `anyhow::Result<Response>` are handlers, and an error or a panic is answered with a 500
+pub trait Handler: Send + Sync {
+    fn handle(&self, request: &Request) -> anyhow::Result<Response>;
+}
+
+impl<F, R> Handler for F
+where
+    F: Fn(&Request) -> R + Send + Sync,
+    R: IntoResponse,
+{
+    fn handle(&self, request: &Request) -> anyhow::Result<Response> {
+        self(request).into_response()
+    }
+}
+
+// what a handler closure may return
+pub trait IntoResponse {
+    fn into_response(self) -> anyhow::Result<Response>;
+}
+
+impl IntoResponse for Response {
+    fn into_response(self) -> anyhow::Result<Response> {
+        Ok(self)
+    }
+}
+
+impl IntoResponse for anyhow::Result<Response> {
+    fn into_response(self) -> anyhow::Result<Response> {
+        self
+    }
+}
+
+// dispatches requests to the first registered route whose method and pattern match,
+// passing them through the middleware on the way in and out; clones share their handlers and
+// middleware
+#[derive(Default, Clone)]
+pub struct Router {
+    routes: Vec<Route>,
+    middleware: Vec<Arc<dyn Middleware>>,
+    // answers requests no route matches in place of a 404, see `Router::fallback`
+    fallback: Option<Arc<dyn Handler>>,
+    // routers for requests meant for other hosts, see `Router::host`
+    hosts: Vec<(String, Router)>,
+    // requests for a host without a router of its own get a 421 instead of our routes
+    pub(crate) misdirect_unknown_hosts: bool,
+}
+
+#[derive(Clone)]
+struct Route {
+    method: Method,
+    pattern: Vec<Segment>,
+    handler: Arc<dyn Handler>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    // `{name}`, matches exactly one segment
+    Param(String),
+    // `{*name}`, matches the rest of the path including any '/' in it
+    Rest(String),
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // `pattern` is a path such as `/echo/{text}` or `/files/{*path}`, captured segments are
+    // available to the handler through `Request::param`
+    pub fn route<R: IntoResponse>(
+        self,
+        method: Method,
+        pattern: &str,
+        handler: impl Fn(&Request) -> R + Send + Sync + 'static,
+    ) -> Self {
+        self.handler(method, pattern, handler)
+    }
+
+    // like `route`, for handlers that aren't closures
+    pub fn handler(
+        mut self,
+        method: Method,
+        pattern: &str,
+        handler: impl Handler + 'static,
+    ) -> Self {
+        let pattern = parse_pattern(pattern);
+        assert!(
+            !pattern[..pattern.len() - 1]
+                .iter()
+                .any(|segment| matches!(segment, Segment::Rest(_))),
+            "a `{{*name}}` segment has to be the last one in a route pattern"
+        );
+
+        self.routes.push(Route {
+            method,
+            pattern,
+            handler: Arc::new(handler),
+        });
+        self
+    }
+
+    // answers WebSocket handshakes for `pattern`, after which `handler` has the connection to
+    // itself until it returns; other requests for it get a 426
+    pub fn websocket(
+        self,
+        pattern: &str,
+        handler: impl Fn(&Request, &mut WebSocket<'_>) -> anyhow::Result<()> + Send + Sync + 'static,
+    ) -> Self {
+        let handler: Arc<websocket::SessionHandler> = Arc::new(handler);
+        self.route(Method::Get, pattern, move |request: &Request| {
+            websocket::accept(request, Arc::clone(&handler))
+        })
+    }
+
+    // answers requests that no route matches with `handler` instead of a 404, e.g. with the
+    // `index.html` of a single-page app that routes them itself; methods a route doesn't answer
+    // still get a 405
+    pub fn fallback<R: IntoResponse>(
+        mut self,
+        handler: impl Fn(&Request) -> R + Send + Sync + 'static,
+    ) -> Self {
+        self.fallback = Some(Arc::new(handler));
+        self
+    }
+
+    // adds middleware inside any added before it, see `Middleware`
+    pub fn layer(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    // appends `other`'s routes, which only match when none of ours do, and its middleware,
+    // which runs inside ours; its fallback is only used when we don't have one
+    pub fn merge(mut self, other: Router) -> Self {
+        self.routes.extend(other.routes);
+        self.fallback = self.fallback.or(other.fallback);
+        self.middleware.extend(other.middleware);
+        self.hosts.extend(other.hosts);
+        self
+    }
+
+    // hands requests whose `Host` is `name` to `router` instead, after they've been through our
+    // middleware; `name` is a host name without a port, such as `example.com`, and `*.example.com`
+    // stands for any of its subdomains
+    pub fn host(mut self, name: &str, router: Router) -> Self {
+        self.hosts.push((name.to_lowercase(), router));
+        self
+    }
+
+    pub(crate) fn take_hosts(&mut self) -> Vec<(String, Router)> {
+        mem::take(&mut self.hosts)
+    }
+
+    // the router registered for the host in `host`, a `Host` header value; a name registered as is
+    // wins over a wildcard
+    pub(crate) fn host_router(&self, host: Option<&str>) -> Option<&Router> {
+        let name = host_name(host?);
+        let matches_wildcard = |pattern: &str| {
+            pattern.strip_prefix("*.").is_some_and(|domain| {
+                name.strip_suffix(domain)
+                    .is_some_and(|subdomain| subdomain.len() > 1 && subdomain.ends_with('.'))
+            })
+        };
+
+        self.hosts
+            .iter()
+            .find(|(pattern, _)| *pattern == name)
+            .or_else(|| {
+                self.hosts
+                    .iter()
+                    .find(|(pattern, _)| matches_wildcard(pattern))
+            })
+            .map(|(_, router)| router)
+    }
+
+    pub(crate) fn middleware(&self) -> &[Arc<dyn Middleware>] {
+        &self.middleware
+    }
+
+    // `path` is the percent-decoded request path
+    pub(crate) fn dispatch(&self, request: &mut Request, path: &str) -> Response {
+        let method = request.method();
+
+        // HEAD is answered like GET unless it has a route of its own, the body is dropped later
+        let found = self.find(method, path).or_else(|| {
+            (method == Method::Head)
+                .then(|| self.find(Method::Get, path))
+                .flatten()
+        });
+
+        if let Some((route, params)) = found {
+            request.params = params;
+            return call(route.handler.as_ref(), request, path);
+        }
+
+        let allowed = self.allowed_methods(path);
+        if allowed.is_empty() {
+            match &self.fallback {
+                Some(fallback) => call(fallback.as_ref(), request, path),
+                None => Response::not_found(),
+            }
+        } else if method == Method::Options {
+            Response {
+                headers: vec![Header::Allow(allowed)],
+                ..Response::no_content()
+            }
+        } else {
+            Response::method_not_allowed(&allowed)
+        }
+    }
+
+    // the methods any route matching `path` answers, `*` stands for the server as a whole
+    fn allowed_methods(&self, path: &str) -> Vec<Method> {
+        let mut allowed = Vec::new();
+        for route in &self.routes {
+            if path != "*" && match_pattern(&route.pattern, path).is_none() {
+                continue;
+            }
+
+            let methods: &[Method] = if route.method == Method::Get {
+                &[Method::Get, Method::Head]
+            } else {
+                &[route.method]
+            };
+            for &method in methods {
+                if !allowed.contains(&method) {
+                    allowed.push(method);
+                }
+            }
+        }
+
+        // OPTIONS is answered for anything that exists, unless a route handles it itself
+        if !allowed.is_empty() && !allowed.contains(&Method::Options) {
+            allowed.push(Method::Options);
+        }
+        allowed
+    }
+
+    fn find(&self, method: Method, path: &str) -> Option<(&Route, Vec<(String, String)>)> {
+        self.routes
+            .iter()
+            .filter(|route| route.method == method)
+            .find_map(|route| Some((route, match_pattern(&route.pattern, path)?)))
+    }
+}
+
+impl fmt::Debug for Router {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(
+                self.routes
+                    .iter()
+                    .map(|route| (route.method, &route.pattern)),
+            )
+            .finish()
+    }
+}
+
+// answers `request` with `handler`, or with a 500 if it fails or panics
+fn call(handler: &dyn Handler, request: &Request, path: &str) -> Response {
+    // a handler that panics is answered like one that failed, instead of the connection
+    // being dropped without a response
+    let handled = panic::catch_unwind(AssertUnwindSafe(|| handler.handle(request)));
+    let reason = match handled {
+        Ok(Ok(response)) => return response,
+        Ok(Err(err)) => format!("{err:#}"),
+        Err(payload) => {
+            let message = payload
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("no message");
+            format!("handler panicked: {message}")
+        }
+    };
+    log::error!(
+        "request_id = {}, failed to handle {} {path}: {reason}",
+        request.id,
+        request.method()
+    );
+    // debug builds tell the client what went wrong, release builds keep it to the log
+    if cfg!(debug_assertions) {
+        Response {
+            status: StatusCode::InternalServerError,
+            ..Response::text(reason)
+        }
+    } else {
+        Response::internal_server_error()
+    }
+}
+
+// the lowercase name in a `Host` header value, without its port or a trailing dot
+pub(crate) fn host_name(host: &str) -> String {
+    let name = match host.strip_prefix('[') {
+        // IPv6 addresses are bracketed, and have colons of their own
+        Some(rest) => rest.split_once(']').map_or(rest, |(addr, _)| addr),
+        None => host.split_once(':').map_or(host, |(name, _)| name),
+    };
+    name.trim_end_matches('.').to_lowercase()
+}
+
+fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    pattern
+        .split('/')
+        .map(|segment| {
+            match segment
+                .strip_prefix('{')
+                .and_then(|segment| segment.strip_suffix('}'))
+            {
+                Some(name) => match name.strip_prefix('*') {
+                    Some(name) => Segment::Rest(name.to_owned()),
+                    None => Segment::Param(name.to_owned()),
+                },
+                None => Segment::Literal(segment.to_owned()),
+            }
+        })
+        .collect()
+}
+
+// returns the captured segments if `path` matches `pattern`
+fn match_pattern(pattern: &[Segment], path: &str) -> Option<Vec<(String, String)>> {
+    let mut segments = path.split('/');
+    let mut params = Vec::new();
+
+    for segment in pattern {
+        match segment {
+            Segment::Literal(literal) => {
+                if segments.next()? != literal {
+                    return None;
+                }
+            }
+            Segment::Param(name) => params.push((name.clone(), segments.next()?.to_owned())),
+            Segment::Rest(name) => {
+                let rest: Vec<_> = segments.by_ref().collect();
+                if rest.is_empty() {
+                    return None;
+                }
+                params.push((name.clone(), rest.join("/")));
+            }
+        }
+    }
+
+    segments.next().is_none().then_some(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::response::StatusCode;
+
+    #[test]
+    fn host_router_matches_names_and_wildcards() {
+        let router = Router::new()
+            .host("*.example.com", Router::new())
+            .host("Example.com", Router::new())
+            .host("www.example.com", Router::new());
+        // the position of the router that was picked
+        let picked = |host| {
+            let picked = router.host_router(Some(host))?;
+            router
+                .hosts
+                .iter()
+                .position(|(_, router)| std::ptr::eq(router, picked))
+        };
+
+        assert_eq!(picked("example.com:8080"), Some(1));
+        assert_eq!(picked("WWW.example.com."), Some(2));
+        assert_eq!(picked("a.b.example.com"), Some(0));
+        assert_eq!(picked("badexample.com"), None);
+        assert_eq!(picked("[::1]:4221"), None);
+        assert!(router.host_router(None).is_none());
+    }
+
+    #[test]
+    fn dispatch_answers_options_with_the_methods_of_matching_routes() {
+        let router = Router::new()
+            .route(Method::Get, "/items", |_| Response::empty())
+            .route(Method::Post, "/items", |_| Response::empty())
+            .route(Method::Delete, "/items/{id}", |_| Response::empty())
+            .route(Method::Options, "/custom", |_| {
+                Response::text("mine".to_owned())
+            });
+        let allow = |path: &str| {
+            let mut request: Request = format!("OPTIONS {path} HTTP/1.1\r\n\r\n").parse().unwrap();
+            let response = router.dispatch(&mut request, path);
+            let allowed = response.headers.iter().find_map(|header| match header {
+                Header::Allow(methods) => Some(methods.clone()),
+                _ => None,
+            });
+            (response.status, allowed)
+        };
+
+        assert_eq!(
+            allow("/items"),
+            (
+                StatusCode::NoContent,
+                Some(vec![
+                    Method::Get,
+                    Method::Head,
+                    Method::Post,
+                    Method::Options
+                ])
+            )
+        );
+        assert_eq!(
+            allow("/items/7"),
+            (
+                StatusCode::NoContent,
+                Some(vec![Method::Delete, Method::Options])
+            )
+        );
+        assert_eq!(
+            allow("*"),
+            (
+                StatusCode::NoContent,
+                Some(vec![
+                    Method::Get,
+                    Method::Head,
+                    Method::Post,
+                    Method::Delete,
+                    Method::Options
+                ])
+            )
+        );
+        assert_eq!(allow("/custom"), (StatusCode::Ok, None));
+        assert_eq!(allow("/nowhere"), (StatusCode::NotFound, None));
+    }
+
+    #[test]
+    fn match_pattern_captures_segments() {
+        let echo = parse_pattern("/echo/{text}");
+        assert_eq!(
+            match_pattern(&echo, "/echo/hi"),
+            Some(vec![("text".to_owned(), "hi".to_owned())])
+        );
+        assert_eq!(match_pattern(&echo, "/echo/hi/there"), None);
+        assert_eq!(match_pattern(&echo, "/echo"), None);
+
+        let files = parse_pattern("/files/{*path}");
+        assert_eq!(
+            match_pattern(&files, "/files/nested/foo.txt"),
+            Some(vec![("path".to_owned(), "nested/foo.txt".to_owned())])
+        );
+        assert_eq!(
+            match_pattern(&files, "/files/"),
+            Some(vec![("path".to_owned(), String::new())])
+        );
+        assert_eq!(match_pattern(&files, "/files"), None);
+
+        assert_eq!(match_pattern(&parse_pattern("/"), "/"), Some(vec![]));
+        assert_eq!(match_pattern(&parse_pattern("/"), "/echo"), None);
+    }
+}
@@ -0,0 +1,930 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+};
+
+use anyhow::{anyhow, Context};
+
+use crate::{
+    auth::{ClientCerts, WriteTokens},
+    cache_control::CacheRule,
+    cors::Cors,
+    file_cache::FileCache,
+    header::{ContentType, Encoding, Header},
+    ip_filter::ForwardedFilter,
+    middleware::Compression,
+    multipart::Part,
+    rate_limit::RateLimiter,
+    request::{decode_path, Method, Request, Version},
+    response::{
+        content_type_from_extension, file_etag, html_escape, json_string, write_preconditions_hold,
+        CompressionPolicy, Response, StatusCode,
+    },
+    rewrite::Rewrites,
+    router::Router,
+    server::{Config, Stats},
+};
+
+// every method a proxied prefix forwards, or a script is run for
+const METHODS: [Method; 7] = [
+    Method::Get,
+    Method::Head,
+    Method::Post,
+    Method::Put,
+    Method::Delete,
+    Method::Options,
+    Method::Patch,
+];
+
+pub(crate) fn respond(request: &mut Request, router: &Router) -> Response {
+    let middleware = router.middleware();
+
+    let mut ran = 0;
+    let mut short_circuited = None;
+    for layer in middleware {
+        ran += 1;
+        if let Some(response) = layer.before(request) {
+            short_circuited = Some(response);
+            break;
+        }
+    }
+
+    let mut response = short_circuited.unwrap_or_else(|| route(request, router));
+    for layer in middleware[..ran].iter().rev() {
+        response = layer.after(request, response);
+    }
+
+    response
+}
+
+fn route(request: &mut Request, router: &Router) -> Response {
+    let path = match decode_path(&request.line.path) {
+        Ok(path) => path,
+        Err(err) => {
+            log::warn!(
+                "request_id = {}, failed to decode request path: {err}",
+                request.id
+            );
+            return Response::bad_request(format!("malformed request path: {err}"));
+        }
+    };
+
+    // HTTP/1.1 requires every request to name the host it is meant for
+    if request.line.version == Version::Http11 && request.host().is_none() {
+        log::warn!(
+            "request_id = {}, HTTP/1.1 request is missing a 'Host' header",
+            request.id
+        );
+        return Response::bad_request("HTTP/1.1 requests must have a 'Host' header".to_owned());
+    }
+
+    // a virtual host's own middleware runs inside the server's
+    if let Some(site) = router.host_router(request.host()) {
+        return respond(request, site);
+    }
+    if router.misdirect_unknown_hosts {
+        log::warn!(
+            "request_id = {}, no virtual host for {:?}",
+            request.id,
+            request.host().unwrap_or_default()
+        );
+        return Response::new(StatusCode::MisdirectedRequest);
+    }
+
+    router.dispatch(request, &path)
+}
+
+// appends the built-in routes to `router`, so routes registered by the user take precedence, and
+// does the same for every virtual host, whose `/files/` serve its own root if it has one
+pub(crate) fn with_default_routes(
+    mut router: Router,
+    config: &Config,
+    stats: &Arc<Stats>,
+) -> Router {
+    let mut hosts = router.take_hosts();
+    for name in config.virtual_hosts.keys() {
+        if !hosts
+            .iter()
+            .any(|(host, _)| host.eq_ignore_ascii_case(name))
+        {
+            hosts.push((name.to_owned(), Router::new()));
+        }
+    }
+
+    // compression wraps the user's middleware, so it sees the responses they make; authentication
+    // comes before it, so nothing the user added sees a request that's turned away, CORS before
+    // that, so preflights don't need credentials and refusals can be read by scripts, and the rate
+    // limit before everything but the filtering of proxied clients, so even preflights count;
+    // rewrites come right after compression, so everything else sees the path they leave
+    let mut layers = Router::new().layer(Compression(config.compression.clone()));
+    if !config.rewrites.is_empty() {
+        layers = layers.layer(Rewrites(config.rewrites.clone()));
+    }
+    if !config.ip_filter.is_empty() && !config.trusted_proxies.is_empty() {
+        layers = layers.layer(ForwardedFilter {
+            filter: config.ip_filter.clone(),
+        });
+    }
+    if let Some(limit) = config.rate_limit {
+        layers = layers.layer(RateLimiter::new(limit));
+    }
+    if let Some(policy) = &config.cors {
+        layers = layers.layer(Cors(policy.clone()));
+    }
+    if !config.client_cert_paths.is_empty() {
+        layers = layers.layer(ClientCerts(config.client_cert_paths.clone()));
+    }
+    for auth in &config.basic_auth {
+        layers = layers.layer(auth.clone());
+    }
+    if !config.write_tokens.is_empty() {
+        layers = layers.layer(WriteTokens(config.write_tokens.clone()));
+    }
+
+    let mut router = layers
+        .merge(router)
+        .merge(default_routes(&config.files_root, config, stats));
+    for (name, site) in hosts {
+        let files_root = config
+            .virtual_hosts
+            .iter()
+            .find(|(host, _)| host.eq_ignore_ascii_case(&name))
+            .map_or(&config.files_root, |(_, root)| root);
+        router = router.host(&name, site.merge(default_routes(files_root, config, stats)));
+    }
+    router.misdirect_unknown_hosts = config.misdirect_unknown_hosts;
+    router
+}
+
+// the routes every host has, with `/files/` serving `files_root`
+fn default_routes(files_root: &Path, config: &Config, stats: &Arc<Stats>) -> Router {
+    let files_root = files_root.to_owned();
+    let files_state = Arc::new(Files {
+        uploaded_types: Mutex::default(),
+        mime_types: config
+            .mime_types
+            .iter()
+            .map(|(extension, content_type)| (extension.to_lowercase(), content_type.clone()))
+            .collect(),
+        index_files: config.index_files.clone(),
+        directory_listing: config.directory_listing,
+        slash_redirects: config.slash_redirects,
+        recursive_deletes: config.recursive_deletes,
+        cache_control: config.cache_control.clone(),
+        cache: stats.file_cache.clone(),
+        compression: config.compression.clone(),
+    });
+
+    let files = |handler: fn(&Path, &str, &Request, &Files) -> anyhow::Result<Response>| {
+        let files_root = files_root.clone();
+        let files_state = Arc::clone(&files_state);
+        move |request: &Request| {
+            let file_name = request.param("path").unwrap_or_default();
+            match sanitize_file_path(&files_root, file_name) {
+                Some(path) => handler(&path, file_name, request, &files_state),
+                None => {
+                    log::warn!("rejected file path {file_name:?} outside of the files root");
+                    Ok(Response::forbidden())
+                }
+            }
+        }
+    };
+
+    // proxied prefixes and scripts take precedence over the built-in routes they overlap with
+    let mut proxies = Router::new();
+    for proxy in &config.proxies {
+        for pattern in proxy.patterns() {
+            for method in METHODS {
+                proxies = proxies.handler(method, &pattern, proxy.clone());
+            }
+        }
+    }
+    if let Some(cgi) = &config.cgi {
+        for method in METHODS {
+            proxies = proxies.handler(method, &cgi.pattern(), cgi.clone());
+        }
+    }
+
+    proxies
+        .merge(
+            Router::new()
+        .route(Method::Get, "/", |_| Response::empty())
+        .route(Method::Get, "/health", {
+            let stats = Arc::clone(stats);
+            move |_| {
+                Response::json(format!(
+                    r#"{{"status":"ok","uptime_secs":{},"active_connections":{},"queued_connections":{}}}"#,
+                    stats.started_at.elapsed().as_secs(),
+                    stats.active_connections.load(Ordering::SeqCst),
+                    stats.queued_connections.load(Ordering::SeqCst)
+                ))
+            }
+        })
+        // for probes that only need to know the process is up and accepting connections
+        .route(Method::Get, "/healthz", |_| Response::text("ok".to_owned()))
+        .route(Method::Get, "/readyz", {
+            let stats = Arc::clone(stats);
+            let files_root = files_root.clone();
+            move |_| readiness(&files_root, &stats)
+        })
+        .route(Method::Get, "/metrics", {
+            let stats = Arc::clone(stats);
+            move |_| {
+                let mut text = stats.metrics.render(
+                    stats.active_connections.load(Ordering::SeqCst),
+                    stats.queued_connections.load(Ordering::SeqCst),
+                );
+                text.push_str(&stats.concurrency.render());
+                Response::text(text)
+            }
+        })
+        .route(Method::Get, "/user-agent", user_agent)
+        // `/echo` without a path segment echoes the request body instead
+        .route(Method::Post, "/echo", |request| match request.body() {
+            Ok(body) => Response::bytes(body.unwrap_or_default().to_vec(), request.content_type()),
+            Err(err) => {
+                log::error!("request_id = {}, failed to read body: {err}", request.id());
+                Response::internal_server_error()
+            }
+        })
+        .route(Method::Get, "/echo/{text}", echo)
+        .route(Method::Get, "/files/{*path}", files(get_file))
+        .route(Method::Post, "/files/{*path}", files(upload_file))
+        .route(Method::Put, "/files/{*path}", files(replace_file))
+        .route(Method::Delete, "/files/{*path}", files(delete_file))
+    )
+}
+
+// the text as plain text, in an HTML page, or as a JSON object's `text`, whichever the client
+// prefers
+fn echo(request: &Request) -> Response {
+    let text = request.param("text").unwrap_or_default();
+    let offered = [
+        ContentType::TextPlain,
+        ContentType::TextHtml,
+        ContentType::ApplicationJson,
+    ];
+    let response = match request.preferred_type(&offered) {
+        Some(ContentType::TextHtml) => Response::bytes(
+            format!("<!DOCTYPE html>\n<p>{}</p>\n", html_escape(text)).into_bytes(),
+            Some(&ContentType::TextHtml),
+        ),
+        Some(ContentType::ApplicationJson) => {
+            Response::json(format!("{{\"text\":{}}}", json_string(text)))
+        }
+        Some(_) => Response::text(text.to_owned()),
+        None => Response::new(StatusCode::NotAcceptable),
+    };
+    response.with_vary("Accept")
+}
+
+// a 503 listing what's wrong while the server shouldn't be sent new traffic: it's shutting down,
+// the files root can't be read, or every worker is busy with connections waiting behind them
+fn readiness(files_root: &Path, stats: &Stats) -> Response {
+    let mut problems = Vec::new();
+    if stats.shutting_down.load(Ordering::SeqCst) {
+        problems.push("shutting down".to_owned());
+    }
+    if let Err(err) = fs::read_dir(files_root) {
+        problems.push(format!("files root {files_root:?} can't be read: {err}"));
+    }
+    let queued = stats.queued_connections.load(Ordering::SeqCst);
+    if queued > 0 {
+        problems.push(format!(
+            "every worker is busy, {queued} connections are waiting"
+        ));
+    }
+
+    if problems.is_empty() {
+        return Response::text("ready".to_owned());
+    }
+    Response {
+        status: StatusCode::ServiceUnavailable,
+        ..Response::text(problems.join("\n"))
+    }
+}
+
+fn user_agent(request: &Request) -> Response {
+    let user_agent = request.headers.iter().find_map(|header| {
+        if let Header::UserAgent(agent) = header {
+            Some(agent)
+        } else {
+            None
+        }
+    });
+
+    match user_agent {
+        Some(user_agent) => Response::text(user_agent.to_owned()),
+        None => Response::bad_request("request does not have a 'User-Agent' header".to_owned()),
+    }
+}
+
+// state shared by the `/files/` routes
+struct Files {
+    // the types uploads were declared with, so they are served back the same way
+    uploaded_types: Mutex<HashMap<PathBuf, ContentType>>,
+    // the custom mapping from `Config::mime_types`, keyed by lowercase extension, used for files
+    // that weren't uploaded; files matching neither get the built-in type for their extension
+    mime_types: HashMap<String, ContentType>,
+    // served in place of a directory, the first one that exists wins
+    index_files: Vec<String>,
+    directory_listing: bool,
+    slash_redirects: bool,
+    recursive_deletes: bool,
+    cache_control: Vec<CacheRule>,
+    cache: Option<Arc<FileCache>>,
+    // cached files are compressed ahead of the compression middleware, the way it would
+    compression: CompressionPolicy,
+}
+
+impl Files {
+    // drops the cached copy of a file that's been written or deleted
+    fn forget(&self, path: &Path) {
+        if let Some(cache) = &self.cache {
+            cache.remove(path);
+        }
+    }
+
+    // drops the cached copies of every file in a deleted directory
+    fn forget_all_under(&self, dir: &Path) {
+        if let Some(cache) = &self.cache {
+            cache.remove_all_under(dir);
+        }
+    }
+}
+
+fn get_file(
+    path: &Path,
+    file_name: &str,
+    request: &Request,
+    files: &Files,
+) -> anyhow::Result<Response> {
+    let wants_directory = file_name.is_empty() || file_name.ends_with('/');
+    if files.slash_redirects && path.exists() && path.is_dir() != wants_directory {
+        return Ok(slash_redirect(request, path.is_dir()));
+    }
+
+    let index;
+    let path = if path.is_dir() {
+        // an index file is checked like any other path, so it can't be a symlink out of the root
+        index = files
+            .index_files
+            .iter()
+            .filter_map(|name| sanitize_file_path(path, name))
+            .find(|index| index.is_file());
+        match &index {
+            Some(index) => index.as_path(),
+            None if files.directory_listing => {
+                let offered = [
+                    ContentType::TextHtml,
+                    ContentType::ApplicationJson,
+                    ContentType::TextPlain,
+                ];
+                let Some(content_type) = request.preferred_type(&offered) else {
+                    return Ok(Response::new(StatusCode::NotAcceptable).with_vary("Accept"));
+                };
+                let url_path = format!("/files/{file_name}");
+                return Ok(
+                    Response::directory_as(path, &url_path, &content_type).with_vary("Accept")
+                );
+            }
+            None => return Ok(Response::not_found()),
+        }
+    } else {
+        path
+    };
+
+    // the copy is only sent to clients that accept gzip, and ranges are only served from the
+    // file itself
+    let sidecar = precompressed_copy(path);
+    let precompressed = sidecar
+        .as_ref()
+        .filter(|_| accepts_gzip(request) && request.range().is_none());
+    let cached = files
+        .cache
+        .as_ref()
+        .filter(|_| precompressed.is_none())
+        .and_then(|cache| {
+            let metadata = fs::metadata(path).ok()?;
+            Some((cache, cache.get(path, &metadata)?))
+        });
+    let response = match (precompressed, &cached) {
+        // sent as it is, with the type of the file it's a copy of, and left alone by the
+        // compression middleware
+        (Some(gzipped), _) => {
+            let mut response = Response::file(
+                gzipped,
+                None,
+                request.if_none_match(),
+                request.if_modified_since(),
+            )
+            .with_content_type(content_type_from_extension(path));
+            if response.status == StatusCode::Ok {
+                response
+                    .headers
+                    .push(Header::ContentEncoding(Encoding::Gzip));
+            }
+            response
+        }
+        (None, Some((_, file))) => Response::cached_file(
+            path,
+            file,
+            request.range(),
+            request.if_none_match(),
+            request.if_modified_since(),
+        ),
+        (None, None) => Response::file(
+            path,
+            request.range(),
+            request.if_none_match(),
+            request.if_modified_since(),
+        ),
+    };
+    let uploaded = files
+        .uploaded_types
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(path)
+        .cloned();
+    let content_type = uploaded.or_else(|| {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        files.mime_types.get(&extension).cloned()
+    });
+
+    // a 304 has no `Content-Type`, so rules for types go by the one the file is served with
+    let cache_control = if matches!(
+        response.status,
+        StatusCode::Ok | StatusCode::PartialContent | StatusCode::NotModified
+    ) {
+        let served_type = content_type
+            .clone()
+            .unwrap_or_else(|| content_type_from_extension(path));
+        let url_path = format!("/files/{file_name}");
+        files
+            .cache_control
+            .iter()
+            .find(|rule| rule.matches(&url_path, &served_type))
+    } else {
+        None
+    };
+    let mut response = match content_type {
+        Some(content_type) => response.with_content_type(content_type),
+        None => response,
+    };
+    if let Some(rule) = cache_control {
+        response = response.with_header("Cache-Control", rule.value());
+    }
+
+    // a cached file is compressed once rather than for every client, the middleware leaves a
+    // response that's compressed already as it is
+    if let Some((cache, file)) = cached {
+        if request.preferred_encoding() == Some(Encoding::Gzip)
+            && response.status == StatusCode::Ok
+            && response.is_compressible(&files.compression)
+        {
+            if let Some(gzip) = cache.gzip(path, &file, files.compression.level) {
+                return Ok(response
+                    .with_encoded_body(Encoding::Gzip, gzip.to_vec())
+                    .with_vary("Accept-Encoding"));
+            }
+        }
+    }
+    // the middleware can't tell that clients accepting gzip would have gotten the copy
+    if sidecar.is_some() {
+        response = response.with_vary("Accept-Encoding");
+    }
+    Ok(response)
+}
+
+fn accepts_gzip(request: &Request) -> bool {
+    request.accept_encoding().is_some_and(|accepted| {
+        accepted
+            .iter()
+            .any(|accepted| accepted.encoding == Encoding::Gzip && accepted.quality > 0)
+    })
+}
+
+// the gzipped copy of the file at `path` saved next to it as `<name>.gz`, e.g. by `butler
+// precompress`, when the copy isn't older than the file
+fn precompressed_copy(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?.to_str()?;
+    let gzipped = sanitize_file_path(path.parent()?, &format!("{name}.gz"))?;
+    let modified = |path: &Path| {
+        let metadata = fs::metadata(path).ok().filter(fs::Metadata::is_file)?;
+        metadata.modified().ok()
+    };
+    (modified(&gzipped)? >= modified(path)?).then_some(gzipped)
+}
+
+// the path of `request` with a trailing '/' added or removed, and its query kept
+fn slash_redirect(request: &Request, is_directory: bool) -> Response {
+    let path = request.path().trim_end_matches('/');
+    let mut location = if is_directory {
+        format!("{path}/")
+    } else {
+        path.to_owned()
+    };
+    if let Some(query) = &request.line.raw_query {
+        location.push('?');
+        location.push_str(query);
+    }
+    Response::redirect(&location, StatusCode::MovedPermanently)
+}
+
+fn upload_file(path: &Path, _: &str, request: &Request, files: &Files) -> anyhow::Result<Response> {
+    if let Some(parts) = request.multipart() {
+        return upload_form(path, parts, files);
+    }
+
+    if request.body_len().is_none() {
+        return Ok(Response::bad_request(
+            "POST request to /files must have a body".to_owned(),
+        ));
+    }
+
+    let staged = Staged::request_body(path, request)
+        .with_context(|| anyhow!("failed to write file {path:?} to disk"))?;
+    // the lock is only held to rename the file into place, so the stored type always belongs to
+    // the file on disk
+    let mut content_types = files
+        .uploaded_types
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    staged
+        .commit()
+        .with_context(|| anyhow!("failed to move file {path:?} into place"))?;
+    files.forget(path);
+    match request.content_type() {
+        Some(content_type) => content_types.insert(path.to_owned(), content_type.clone()),
+        None => content_types.remove(path),
+    };
+
+    Ok(Response::created())
+}
+
+// stores every file in a `multipart/form-data` body in the directory `dir`, creating it if needed,
+// under the last component of the name it had on the client; other form fields are ignored
+fn upload_form(
+    dir: &Path,
+    parts: anyhow::Result<Vec<Part>>,
+    files: &Files,
+) -> anyhow::Result<Response> {
+    let parts = match parts {
+        Ok(parts) => parts,
+        Err(err) => return Ok(Response::bad_request(format!("{err:#}"))),
+    };
+
+    let mut uploads = Vec::new();
+    for part in parts {
+        let Some(filename) = &part.filename else {
+            continue;
+        };
+        // some browsers send the whole path the file had on the client
+        let name = filename.rsplit(['/', '\\']).next().unwrap_or_default();
+        if !matches!(
+            Path::new(name).components().collect::<Vec<_>>()[..],
+            [Component::Normal(_)]
+        ) {
+            return Ok(Response::bad_request(format!(
+                "can't store an upload named {filename:?}"
+            )));
+        }
+        uploads.push((name.to_owned(), part));
+    }
+    if uploads.is_empty() {
+        return Ok(Response::bad_request(
+            "multipart upload to /files doesn't have any files".to_owned(),
+        ));
+    }
+
+    fs::create_dir_all(dir).with_context(|| anyhow!("failed to create directory {dir:?}"))?;
+    let mut staged = Vec::new();
+    for (name, part) in &uploads {
+        let path = dir.join(name);
+        let file = Staged::write(&path, &mut part.data.as_slice())
+            .with_context(|| anyhow!("failed to write file {path:?} to disk"))?;
+        staged.push((path, file, part));
+    }
+    let mut content_types = files
+        .uploaded_types
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    for (path, file, part) in staged {
+        file.commit()
+            .with_context(|| anyhow!("failed to move file {path:?} into place"))?;
+        files.forget(&path);
+        match &part.content_type {
+            Some(content_type) => content_types.insert(path, content_type.clone()),
+            None => content_types.remove(&path),
+        };
+    }
+
+    // the names the files were stored under, one per line
+    Ok(Response {
+        status: StatusCode::Created,
+        ..Response::text(
+            uploads
+                .iter()
+                .map(|(name, _)| format!("{name}\n"))
+                .collect(),
+        )
+    })
+}
+
+// creates or replaces the file, answering 201 or 200 respectively
+fn replace_file(
+    path: &Path,
+    _: &str,
+    request: &Request,
+    files: &Files,
+) -> anyhow::Result<Response> {
+    let staged = Staged::request_body(path, request)
+        .with_context(|| anyhow!("failed to write file {path:?} to disk"))?;
+    // the preconditions are checked against the file the new one is about to replace
+    let mut content_types = files
+        .uploaded_types
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    let metadata = fs::metadata(path).ok().filter(fs::Metadata::is_file);
+    if !write_preconditions_hold(
+        request.if_match(),
+        request.if_none_match(),
+        metadata.as_ref(),
+    ) {
+        return Ok(Response::new(StatusCode::PreconditionFailed));
+    }
+
+    staged
+        .commit()
+        .with_context(|| anyhow!("failed to move file {path:?} into place"))?;
+    files.forget(path);
+
+    match request.content_type() {
+        Some(content_type) => content_types.insert(path.to_owned(), content_type.clone()),
+        None => content_types.remove(path),
+    };
+
+    let mut response = if metadata.is_some() {
+        Response::empty()
+    } else {
+        Response::created()
+    };
+    // lets the client make its next write conditional on this one
+    if let Ok(metadata) = fs::metadata(path) {
+        response.headers.push(Header::ETag(file_etag(&metadata)));
+    }
+    Ok(response)
+}
+
+// a file written next to the one it's going to replace, so a failed write never leaves that one
+// truncated, not even after a crash; it's removed unless `commit` renames it into place
+struct Staged {
+    temp_path: PathBuf,
+    path: PathBuf,
+    committed: bool,
+}
+
+impl Staged {
+    // streams `contents` to the temporary file and syncs it
+    fn write(path: &Path, contents: &mut dyn io::Read) -> io::Result<Self> {
+        let staged = Self::new(path)?;
+        let mut file = fs::File::create(&staged.temp_path)?;
+        io::copy(contents, &mut file)?;
+        file.sync_all()?;
+        Ok(staged)
+    }
+
+    // the body of `request`, linked to rather than copied when it was spooled to a file on the
+    // same filesystem
+    fn request_body(path: &Path, request: &Request) -> io::Result<Self> {
+        let Some(spooled) = request.spooled_body_path() else {
+            return Self::write(path, &mut request.body_reader()?);
+        };
+
+        // spool files are only readable by the server's user, an upload gets the permissions any
+        // new file would
+        let staged = Self::new(path)?;
+        let permissions = fs::File::create(&staged.temp_path)?
+            .metadata()?
+            .permissions();
+        fs::remove_file(&staged.temp_path)?;
+        if let Err(err) = fs::hard_link(spooled, &staged.temp_path) {
+            log::debug!("copying spooled body to {path:?} since it can't be linked: {err}");
+            return Self::write(path, &mut request.body_reader()?);
+        }
+        fs::set_permissions(&staged.temp_path, permissions)?;
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&staged.temp_path)?
+            .sync_all()?;
+        Ok(staged)
+    }
+
+    fn new(path: &Path) -> io::Result<Self> {
+        static TEMP_FILE_ID: AtomicUsize = AtomicUsize::new(0);
+
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+        let temp_path = path.with_file_name(format!(
+            ".{}.{}-{}.tmp",
+            file_name.to_string_lossy(),
+            std::process::id(),
+            TEMP_FILE_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        Ok(Self {
+            temp_path,
+            path: path.to_owned(),
+            committed: false,
+        })
+    }
+
+    fn commit(mut self) -> io::Result<()> {
+        fs::rename(&self.temp_path, &self.path)?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Drop for Staged {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+fn delete_file(
+    path: &Path,
+    file_name: &str,
+    _: &Request,
+    files: &Files,
+) -> anyhow::Result<Response> {
+    let mut content_types = files
+        .uploaded_types
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Response::not_found()),
+        Err(err) => {
+            return Err(err).with_context(|| anyhow!("failed to read metadata of {path:?}"))
+        }
+    };
+
+    if metadata.is_dir() {
+        let is_root = Path::new(file_name)
+            .components()
+            .all(|component| component == Component::CurDir);
+        if !files.recursive_deletes || is_root {
+            return Ok(Response {
+                status: StatusCode::Conflict,
+                ..Response::text(format!("/files/{file_name} is a directory"))
+            });
+        }
+        fs::remove_dir_all(path)
+            .with_context(|| anyhow!("failed to remove directory {path:?} from disk"))?;
+        content_types.retain(|uploaded, _| !uploaded.starts_with(path));
+        files.forget_all_under(path);
+        return Ok(Response::no_content());
+    }
+
+    match fs::remove_file(path) {
+        Ok(()) => {
+            content_types.remove(path);
+            files.forget(path);
+            Ok(Response::no_content())
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Response::not_found()),
+        Err(err) => Err(err).with_context(|| anyhow!("failed to remove file {path:?} from disk")),
+    }
+}
+
+// resolves `file_name` relative to `root`, returning `None` if the result would escape `root`
+fn sanitize_file_path(root: &Path, file_name: &str) -> Option<PathBuf> {
+    let relative = Path::new(file_name);
+    if !relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        return None;
+    }
+
+    let canonical_root = root.canonicalize().ok()?;
+
+    // the file may not exist yet, e.g. when uploading, and neither may the directories an upload
+    // creates above it; the nearest ancestor that does is resolved, since it or any directory above
+    // it may be a symlink out of the root, and the rest is appended to it
+    let mut existing = root.to_path_buf();
+    let mut missing = Vec::new();
+    for component in relative.components() {
+        if let Component::Normal(name) = component {
+            if missing.is_empty() && existing.join(name).symlink_metadata().is_ok() {
+                existing.push(name);
+            } else {
+                missing.push(name);
+            }
+        }
+    }
+    // a symlink whose target doesn't exist fails here too, writing through it would create the target
+    let resolved = existing.canonicalize().ok()?;
+    if !resolved.starts_with(&canonical_root) {
+        return None;
+    }
+    Some(
+        missing
+            .into_iter()
+            .fold(resolved, |path, name| path.join(name)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn files_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("butler-{name}-{}", std::process::id()));
+        fs::create_dir_all(root.join("nested")).unwrap();
+        fs::write(root.join("nested/foo.txt"), "foo").unwrap();
+        root
+    }
+
+    #[test]
+    fn sanitize_file_path_accepts_paths_inside_root() {
+        let root = files_root("inside");
+        let canonical_root = root.canonicalize().unwrap();
+
+        assert_eq!(
+            sanitize_file_path(&root, "nested/foo.txt"),
+            Some(canonical_root.join("nested/foo.txt"))
+        );
+        assert_eq!(
+            sanitize_file_path(&root, "new.txt"),
+            Some(canonical_root.join("new.txt"))
+        );
+    }
+
+    #[test]
+    fn sanitize_file_path_rejects_parent_components() {
+        let root = files_root("parent");
+
+        assert_eq!(sanitize_file_path(&root, ".."), None);
+        assert_eq!(sanitize_file_path(&root, "../../etc/passwd"), None);
+        assert_eq!(sanitize_file_path(&root, "nested/../../secret"), None);
+    }
+
+    #[test]
+    fn sanitize_file_path_keeps_encoded_parent_components_inside_root() {
+        let root = files_root("encoded");
+        let canonical_root = root.canonicalize().unwrap();
+
+        for file_name in ["%2e%2e/secret", "%2E%2E%2Fsecret", "..%2fsecret"] {
+            let path = sanitize_file_path(&root, file_name);
+            assert!(
+                path.as_ref()
+                    .is_none_or(|path| path.starts_with(&canonical_root)),
+                "{file_name:?} resolved to {path:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn sanitize_file_path_rejects_absolute_paths() {
+        let root = files_root("absolute");
+
+        assert_eq!(sanitize_file_path(&root, "/etc/passwd"), None);
+        assert_eq!(sanitize_file_path(&root, "//etc/passwd"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sanitize_file_path_rejects_symlinks_out_of_root() {
+        let root = files_root("symlink");
+        let link = root.join("escape");
+        let _ = fs::remove_file(&link);
+        std::os::unix::fs::symlink("/etc", &link).unwrap();
+
+        assert_eq!(sanitize_file_path(&root, "escape/passwd"), None);
+        // directories that don't exist yet below one that leaves the root
+        assert_eq!(sanitize_file_path(&root, "escape/new/dir/file"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sanitize_file_path_rejects_dangling_symlinks() {
+        let root = files_root("dangling");
+        let link = root.join("dangling");
+        let _ = fs::remove_file(&link);
+        std::os::unix::fs::symlink("/nonexistent/butler-target", &link).unwrap();
+
+        assert_eq!(sanitize_file_path(&root, "dangling"), None);
+        assert_eq!(sanitize_file_path(&root, "dangling/file"), None);
+    }
+}
@@ -0,0 +1,119 @@
+use std::{
+    fs::File,
+    io::{self, Write},
+};
+#[cfg(target_os = "linux")]
+use std::{os::unix::io::AsRawFd, ptr};
+
+// the most a single sendfile(2) call moves on Linux
+#[cfg(target_os = "linux")]
+const MAX_SENDFILE_SIZE: u64 = 0x7fff_f000;
+
+// something a response is written to, which may know a faster way to send the contents of a file
+// than copying them through a buffer
+pub(crate) trait SendFile: Write {
+    // sends what's left of `file`, returning the number of bytes sent
+    fn send_file(&mut self, file: &mut io::Take<File>) -> io::Result<u64> {
+        io::copy(file, self)
+    }
+}
+
+// any writer, sending files the portable way
+#[derive(Debug)]
+pub(crate) struct Copying<W: Write>(pub(crate) W);
+
+impl<W: Write> Write for Copying<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.0.write_all(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<W: Write> SendFile for Copying<W> {}
+
+// has the kernel copy `file` straight to `out`, which must not buffer anything written to it
+// earlier; falls back to copying it for files sendfile(2) can't read, such as those on some special
+// file systems
+#[cfg(target_os = "linux")]
+pub(crate) fn sendfile(
+    file: &mut io::Take<File>,
+    out: &mut (impl Write + AsRawFd),
+) -> io::Result<u64> {
+    let mut sent = 0;
+    while file.limit() > 0 {
+        let count = file.limit().min(MAX_SENDFILE_SIZE) as usize;
+        // SAFETY: both descriptors stay open for the whole call, and without an offset the file is
+        // read from its own position, which the call moves past what it sent
+        let n = unsafe {
+            libc::sendfile(
+                out.as_raw_fd(),
+                file.get_ref().as_raw_fd(),
+                ptr::null_mut(),
+                count,
+            )
+        };
+        match n {
+            -1 => {
+                let err = io::Error::last_os_error();
+                match err.raw_os_error() {
+                    Some(libc::EINTR) => continue,
+                    Some(libc::EINVAL | libc::ENOSYS) if sent == 0 => return io::copy(file, out),
+                    _ => return Err(err),
+                }
+            }
+            // the file has shrunk since the response was made; what was sent falls short of
+            // `Content-Length`, and the response's sender drops the connection for it
+            0 => break,
+            n => {
+                sent += n as u64;
+                file.set_limit(file.limit() - n as u64);
+            }
+        }
+    }
+    Ok(sent)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::{
+        io::{Read, Seek, SeekFrom},
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    use super::*;
+
+    #[test]
+    fn sendfile_sends_the_rest_of_the_file_up_to_its_limit() {
+        let path = std::env::temp_dir().join(format!("butler-sendfile-{}", std::process::id()));
+        std::fs::write(&path, b"0123456789").unwrap();
+        let mut file = File::open(&path).unwrap();
+        file.seek(SeekFrom::Start(2)).unwrap();
+        let mut file = file.take(5);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut received = Vec::new();
+            TcpStream::connect(addr)
+                .unwrap()
+                .read_to_end(&mut received)
+                .unwrap();
+            received
+        });
+        let (mut stream, _) = listener.accept().unwrap();
+
+        assert_eq!(sendfile(&mut file, &mut stream).unwrap(), 5);
+        assert_eq!(file.limit(), 0);
+        drop(stream);
+        assert_eq!(client.join().unwrap(), b"23456");
+        std::fs::remove_file(path).unwrap();
+    }
+}
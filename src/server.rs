@@ -0,0 +1,2019 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{self, prelude::*, BufReader},
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError, RwLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+#[cfg(unix)]
+use std::{
+    env, fs,
+    os::unix::{
+        fs::FileTypeExt,
+        io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    process,
+};
+
+use anyhow::{anyhow, Context};
+use rustls::{ServerConnection, StreamOwned};
+use socket2::{Domain, Protocol, Type};
+use threadpool::ThreadPool;
+
+#[cfg(feature = "event-loop")]
+use crate::event_loop::EventLoop;
+use crate::{
+    access_log::{log_timing, AccessLog, AccessLogFormat, Entry},
+    auth::{BasicAuth, ClientCertRule},
+    cache_control::CacheRule,
+    cgi::Cgi,
+    concurrency::{ConcurrencyLimit, Limiters},
+    cors::CorsPolicy,
+    file_cache::FileCache,
+    header::{ConnectionMode, ContentType, Header, HeaderMap},
+    http2,
+    ip_filter::{forwarded_client, Cidr, IpFilter},
+    metrics::Metrics,
+    middleware::Middleware,
+    proxy::Proxy,
+    rate_limit::RateLimit,
+    request::{Method, Request, UnsupportedMethod, UnsupportedVersion, Version},
+    request_id::{RequestIds, REQUEST_ID_HEADER},
+    response::{CompressionPolicy, Response, StatusCode, SERVER_SOFTWARE},
+    rewrite::RewriteRule,
+    router::{Handler, IntoResponse, Router},
+    routes::{respond, with_default_routes},
+    sendfile::{Copying, SendFile},
+    spool::Spooler,
+    tls::{cert_subject, TlsConfig, ALPN_HTTP2},
+    websocket::WebSocket,
+};
+
+const DEFAULT_WORKERS: usize = 500;
+const MAX_BODY_SIZE: u64 = 16 * 1024 * 1024;
+const DEFAULT_MAX_IN_MEMORY_BODY_SIZE: u64 = 1024 * 1024;
+const DEFAULT_FILES_ROOT: &str = "files";
+const DEFAULT_INDEX_FILE: &str = "index.html";
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(15);
+const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_CONCURRENCY_QUEUE_TIMEOUT: Duration = Duration::from_secs(1);
+const DEFAULT_MAX_HEADER_COUNT: usize = 100;
+const DEFAULT_MAX_HEADER_SIZE: usize = 8 * 1024;
+// how long clients turned away by `Config::max_queued` are told to wait before trying again
+const SHED_RETRY_AFTER: Duration = Duration::from_secs(1);
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+const DEFAULT_LISTEN_BACKLOG: u32 = 1024;
+// the first descriptor systemd passes sockets in, see sd_listen_fds(3)
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: RawFd = 3;
+// longest chunk size line accepted in a chunked body, extensions included
+const MAX_CHUNK_LINE_SIZE: usize = 1024;
+
+pub(crate) type ConnId = usize;
+
+#[derive(Debug)]
+pub struct Server {
+    // the one `bind` created comes first
+    listeners: Vec<Listener>,
+    config: Arc<Config>,
+    router: Router,
+    shutting_down: Arc<AtomicBool>,
+    // a configuration to switch to, see `Server::reloader`
+    reloaded: Arc<Mutex<Option<Config>>>,
+}
+
+impl Server {
+    pub fn bind(addr: impl ToSocketAddrs, config: Config) -> anyhow::Result<Self> {
+        let listener = bind_tcp(addr, &config).context("failed to bind listener")?;
+        Ok(Self::with_listener(Bound::Tcp(listener), config))
+    }
+
+    // listens on a Unix domain socket at `path` instead of a TCP port, e.g. for a reverse proxy on
+    // the same machine; a socket left behind by a server that's gone is replaced, and the socket is
+    // removed again once the server is dropped
+    #[cfg(unix)]
+    pub fn bind_unix(path: impl AsRef<Path>, config: Config) -> anyhow::Result<Self> {
+        let listener = bind_unix_socket(path.as_ref())?;
+        Ok(Self::with_listener(listener, config))
+    }
+
+    // whether systemd started the process with sockets to listen on, from a `.socket` unit
+    #[cfg(unix)]
+    pub fn socket_activated() -> bool {
+        listen_fds().is_some()
+    }
+
+    // listens on the sockets systemd passed on instead of binding any, so that it can bind
+    // privileged ports and start the server once the first client connects; TCP and Unix stream
+    // sockets are accepted, and an error is returned when there are none
+    #[cfg(unix)]
+    pub fn from_systemd(config: Config) -> anyhow::Result<Self> {
+        let count = listen_fds().context("systemd didn't pass any sockets to listen on")??;
+        let mut listeners = (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+            .map(|fd| {
+                // SAFETY: the descriptors from `SD_LISTEN_FDS_START` on are handed to this process
+                // for it to own, and nothing else in it knows about them
+                let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+                inherited_listener(fd)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?
+            .into_iter();
+
+        let first = listeners
+            .next()
+            .context("systemd didn't pass any sockets to listen on")?;
+        let mut server = Self::with_listener(first, config);
+        server.listeners.extend(listeners.map(|listener| Listener {
+            listener,
+            tls: server.config.tls.clone(),
+        }));
+        Ok(server)
+    }
+
+    fn with_listener(listener: Bound, config: Config) -> Self {
+        Self {
+            listeners: vec![Listener {
+                listener,
+                tls: config.tls.clone(),
+            }],
+            config: Arc::new(config),
+            router: Router::new(),
+            shutting_down: Arc::new(AtomicBool::new(false)),
+            reloaded: Arc::default(),
+        }
+    }
+
+    // listens on `addr` as well, e.g. `[::]:4221` next to a `bind` to `0.0.0.0:4221`, serving it
+    // the way `bind` does
+    pub fn listen(mut self, addr: impl ToSocketAddrs) -> anyhow::Result<Self> {
+        let listener = bind_tcp(addr, &self.config).context("failed to bind listener")?;
+        self.listeners.push(Listener {
+            listener: Bound::Tcp(listener),
+            tls: self.config.tls.clone(),
+        });
+        Ok(self)
+    }
+
+    // listens on `addr` as well, serving HTTPS there whether or not `Config::tls` is set
+    pub fn listen_tls(mut self, addr: impl ToSocketAddrs, tls: TlsConfig) -> anyhow::Result<Self> {
+        let listener = bind_tcp(addr, &self.config).context("failed to bind listener")?;
+        self.listeners.push(Listener {
+            listener: Bound::Tcp(listener),
+            tls: Some(tls),
+        });
+        Ok(self)
+    }
+
+    // listens on the Unix domain socket at `path` as well, see `bind_unix`
+    #[cfg(unix)]
+    pub fn listen_unix(mut self, path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let listener = bind_unix_socket(path.as_ref())?;
+        self.listeners.push(Listener {
+            listener,
+            tls: self.config.tls.clone(),
+        });
+        Ok(self)
+    }
+
+    // the address of the listener `bind` created, an error for a Unix socket
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listeners[0].listener.local_addr()
+    }
+
+    // the addresses of every listener, in the order they were added
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.listeners
+            .iter()
+            .map(|listener| listener.listener.local_addr())
+            .collect()
+    }
+
+    // registers a custom route, it takes precedence over the built-in ones
+    pub fn route<R: IntoResponse>(
+        mut self,
+        method: Method,
+        pattern: &str,
+        handler: impl Fn(&Request) -> R + Send + Sync + 'static,
+    ) -> Self {
+        self.router = self.router.route(method, pattern, handler);
+        self
+    }
+
+    // like `route`, for handlers that aren't closures
+    pub fn handler(
+        mut self,
+        method: Method,
+        pattern: &str,
+        handler: impl Handler + 'static,
+    ) -> Self {
+        self.router = self.router.handler(method, pattern, handler);
+        self
+    }
+
+    // hands WebSocket connections for `pattern` to `handler`, see `Router::websocket`
+    pub fn websocket(
+        mut self,
+        pattern: &str,
+        handler: impl Fn(&Request, &mut WebSocket<'_>) -> anyhow::Result<()> + Send + Sync + 'static,
+    ) -> Self {
+        self.router = self.router.websocket(pattern, handler);
+        self
+    }
+
+    // answers requests that no route matches with `handler` instead of a 404, see
+    // `Router::fallback`
+    pub fn fallback<R: IntoResponse>(
+        mut self,
+        handler: impl Fn(&Request) -> R + Send + Sync + 'static,
+    ) -> Self {
+        self.router = self.router.fallback(handler);
+        self
+    }
+
+    // serves requests for the host `name` with `router`'s routes and middleware, and then the
+    // built-in routes, see `Router::host`
+    pub fn host(mut self, name: &str, router: Router) -> Self {
+        self.router = self.router.host(name, router);
+        self
+    }
+
+    // wraps every request in `middleware`, inside any added before it
+    pub fn layer(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.router = self.router.layer(middleware);
+        self
+    }
+
+    // setting the returned flag makes `run` stop accepting connections and return
+    pub fn shutdown_flag(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.shutting_down)
+    }
+
+    // hands `run` a new configuration to switch to while it keeps running, see `Reloader`
+    pub fn reloader(&self) -> Reloader {
+        Reloader(Arc::clone(&self.reloaded))
+    }
+
+    // accepts connections until the shutdown flag is set, then waits for the in-flight ones to finish
+    pub fn run(self) -> anyhow::Result<()> {
+        let Self {
+            listeners,
+            config,
+            router: user_router,
+            shutting_down,
+            reloaded,
+        } = self;
+
+        let pool = ThreadPool::new(config.workers);
+        let stats = Arc::new(Stats {
+            started_at: Instant::now(),
+            shutting_down: Arc::clone(&shutting_down),
+            active_connections: AtomicUsize::new(0),
+            queued_connections: AtomicUsize::new(0),
+            connections_per_ip: Mutex::default(),
+            metrics: Metrics::default(),
+            access_log: AccessLog::start(&config)?,
+            request_ids: RequestIds::new(),
+            file_cache: (config.file_cache_size > 0)
+                .then(|| Arc::new(FileCache::new(config.file_cache_size))),
+            concurrency: Limiters::new(&config),
+        });
+        let site = Arc::new(CurrentSite(RwLock::new(Site {
+            router: Arc::new(with_default_routes(user_router.clone(), &config, &stats)),
+            config: Arc::clone(&config),
+        })));
+        let event_loop = match config.event_loop {
+            true => Some(start_event_loop(&pool, &site, &stats)?),
+            false => None,
+        };
+
+        for listener in &listeners {
+            let scheme = if listener.tls.is_some() {
+                "https"
+            } else {
+                "http"
+            };
+            log::info!("Listening on {scheme}://{}", listener.listener);
+
+            // listeners are polled so the shutdown flag gets checked even when no clients connect
+            listener
+                .listener
+                .set_nonblocking(true)
+                .context("failed to make the listener non-blocking")?;
+        }
+
+        // hands an accepted connection to a worker, or turns it away if the server is too busy for it
+        // clients of a Unix socket have no address, so only the file's permissions decide who may
+        // connect, and they aren't limited per address either
+        let dispatch = |mut stream: Socket,
+                        peer: Option<SocketAddr>,
+                        tls: Option<TlsConfig>,
+                        conn_id,
+                        site| {
+            let Site { config, router } = site;
+            if let Err(err) = stream.set_nonblocking(false) {
+                log::error!("failed to make connection blocking, dropping it: {err}");
+                return;
+            }
+
+            // requests coming through a trusted proxy are filtered once their headers have been read
+            let ip = peer.map(|peer| peer.ip());
+            let proxied = ip.is_some_and(|ip| {
+                config
+                    .trusted_proxies
+                    .iter()
+                    .any(|range| range.contains(ip))
+            });
+            if let Some(ip) = ip.filter(|ip| !proxied && !config.ip_filter.permits(*ip)) {
+                log::warn!("{ip} isn't permitted, rejecting connection");
+                reject(
+                    &mut stream,
+                    tls.is_some(),
+                    Response::forbidden(),
+                    &config,
+                    &stats,
+                );
+                return;
+            }
+
+            // shedding load here keeps clients from waiting on a queue that only grows
+            if config.max_queued.is_some_and(|max_queued| {
+                pool.active_count() >= pool.max_count() && pool.queued_count() >= max_queued
+            }) {
+                log::warn!(
+                    "all {} workers are busy and {} connections are queued, rejecting connection",
+                    pool.max_count(),
+                    pool.queued_count()
+                );
+
+                stats.metrics.record_shed();
+                reject(
+                    &mut stream,
+                    tls.is_some(),
+                    Response::service_unavailable()
+                        .with_header("Retry-After", &SHED_RETRY_AFTER.as_secs().to_string()),
+                    &config,
+                    &stats,
+                );
+                return;
+            }
+
+            let ip_slot = match (config.max_connections_per_ip, ip) {
+                (Some(max), Some(ip)) => match IpSlot::acquire(&stats, ip, max) {
+                    Some(slot) => Some(slot),
+                    None => {
+                        log::warn!("{ip} already has {max} connections, rejecting another one");
+                        reject(
+                            &mut stream,
+                            tls.is_some(),
+                            Response::too_many_requests(),
+                            &config,
+                            &stats,
+                        );
+                        return;
+                    }
+                },
+                _ => None,
+            };
+
+            let stats = Arc::clone(&stats);
+            let event_loop = event_loop.clone();
+            stats.queued_connections.fetch_add(1, Ordering::SeqCst);
+            pool.execute(move || {
+                stats.queued_connections.fetch_sub(1, Ordering::SeqCst);
+                stats.active_connections.fetch_add(1, Ordering::SeqCst);
+                if let Err(err) = serve(
+                    stream,
+                    tls.as_ref(),
+                    conn_id,
+                    ip_slot,
+                    &config,
+                    &stats,
+                    &router,
+                    event_loop.as_ref(),
+                ) {
+                    log::error!("error while handling connection: {err}");
+                }
+                stats.active_connections.fetch_sub(1, Ordering::SeqCst);
+            });
+        };
+
+        let mut conn_id: ConnId = 0;
+        while !shutting_down.load(Ordering::SeqCst) {
+            let pending = reloaded
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .take();
+            if let Some(config) = pending {
+                // the pool can grow or shrink as it goes, the rest of what's fixed once running is
+                // kept as it was
+                if config.workers != pool.max_count() {
+                    // clones share their workers, and `dispatch` holds on to this one
+                    pool.clone().set_num_threads(config.workers);
+                }
+                let router = with_default_routes(user_router.clone(), &config, &stats);
+                site.replace(Site {
+                    config: Arc::new(config),
+                    router: Arc::new(router),
+                });
+                log::info!("reloaded the configuration");
+            }
+
+            let mut accepted = false;
+            for listener in &listeners {
+                match listener.listener.accept() {
+                    Ok((stream, peer)) => {
+                        accepted = true;
+                        let site = site.get();
+                        if let Err(err) = stream.set_nodelay(site.config.tcp_nodelay) {
+                            log::warn!("failed to set TCP_NODELAY on a connection: {err}");
+                        }
+                        let tls = listener.tls.clone();
+                        dispatch(stream, peer, tls, conn_id, site);
+                        conn_id += 1;
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(err) => {
+                        log::error!("error while attempting to establish a connection: {err}")
+                    }
+                }
+            }
+
+            if !accepted {
+                thread::sleep(ACCEPT_POLL_INTERVAL);
+            }
+        }
+
+        let config = site.get().config;
+        log::info!(
+            "waiting up to {:?} for {} in-flight connections to finish",
+            config.drain_timeout,
+            pool.active_count() + pool.queued_count()
+        );
+
+        let deadline = Instant::now() + config.drain_timeout;
+        let in_flight = || pool.active_count() + pool.queued_count();
+        while in_flight() > 0 && Instant::now() < deadline {
+            thread::sleep(ACCEPT_POLL_INTERVAL);
+        }
+
+        // the remaining workers are cut off when the process exits
+        if in_flight() > 0 {
+            log::warn!(
+                "giving up on {} connections that didn't finish in time",
+                in_flight()
+            );
+        } else {
+            log::info!("shut down");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    // every file served or written through `/files/` lives under this directory
+    pub files_root: PathBuf,
+    // also the most a body sent with `Content-Encoding: gzip` or `deflate` may decompress to
+    pub max_body_size: u64,
+    // request bodies larger than this are written to a temporary file as they arrive instead of
+    // being kept in memory, so that large uploads take little of it
+    pub max_in_memory_body_size: u64,
+    pub workers: usize,
+    // connections waiting for a free worker beyond this are turned away, `None` means no limit
+    pub max_queued: Option<usize>,
+    // connections a single client address may have open or queued at once, more get a 429
+    pub max_connections_per_ip: Option<usize>,
+    // how long a client may go without sending anything before its connection is dropped
+    pub read_timeout: Duration,
+    // how long a client may take to send a whole request head once it has started,
+    // those that take longer get a 408
+    pub header_timeout: Duration,
+    // how long a kept-alive connection may wait for its next request before it's closed
+    pub idle_timeout: Duration,
+    // connections are closed after answering this many requests, `None` means no limit
+    pub max_requests_per_connection: Option<usize>,
+    // how long writing a response may be stuck on a client that doesn't read it
+    pub write_timeout: Duration,
+    pub compression: CompressionPolicy,
+    // file extensions, without the dot, mapped to the type files under `/files/` are served with;
+    // these take precedence over the built-in mapping but not over the type a file was uploaded with
+    pub mime_types: HashMap<String, ContentType>,
+    // files sent as the body of responses with these status codes, e.g. `404 -> errors/404.html`,
+    // in place of whatever body they would have had
+    pub error_pages: HashMap<StatusCode, PathBuf>,
+    // sent in the `Server` header of responses whose handler didn't set one, `None` leaves it out
+    pub server_header: Option<String>,
+    // file names served in place of a directory under `/files/`, the first one it contains wins
+    pub index_files: Vec<String>,
+    // directories without an index file get an HTML list of their entries, or a 404 when this is off
+    pub directory_listing: bool,
+    // directories under `/files/` asked for without a trailing '/' get a 301 to the path with one,
+    // so the relative links of their index files resolve, and files asked for with one to the
+    // path without it
+    pub slash_redirects: bool,
+    // `DELETE` removes directories under `/files/` along with everything in them, instead of
+    // answering 409; `/files/` itself is never removed
+    pub recursive_deletes: bool,
+    // `Cache-Control` values for the files under `/files/` each rule matches, the first one wins
+    pub cache_control: Vec<CacheRule>,
+    // rules that change the path of the requests they match before anything else looks at it, or
+    // redirect them, the first one that matches a request is the only one applied
+    pub rewrites: Vec<RewriteRule>,
+    // requests with more headers than this, or a larger head, get a 431
+    pub max_header_count: usize,
+    // in bytes, counting the request line and line endings
+    pub max_header_size: usize,
+    // bytes of small files under `/files/` kept in memory, along with their compressed form, so
+    // that hot ones aren't read from disk for every request; 0 turns it off
+    pub file_cache_size: u64,
+    // how long `Server::run` waits for in-flight connections once it's been told to shut down
+    pub drain_timeout: Duration,
+    // lets a restarted server bind its port while connections of the last one are still closing
+    // down, only applied on Unix where it can't be used to take over a port in use
+    pub reuse_address: bool,
+    // lets several servers bind the same port, with the kernel spreading connections among them;
+    // only supported on Unix
+    pub reuse_port: bool,
+    // sends what's written to a TCP connection right away instead of holding small writes back to
+    // be batched with the next ones
+    pub tcp_nodelay: bool,
+    // connections the kernel queues for a listener before they're accepted, the kernel may cap it
+    pub listen_backlog: u32,
+    // connections are served over TLS when this is set, and as plain HTTP otherwise
+    pub tls: Option<TlsConfig>,
+    // kept-alive HTTP/1.x connections wait for their next request in an event loop instead of
+    // holding on to a worker, so a few workers can keep many connections open;
+    // needs the `event-loop` feature
+    pub event_loop: bool,
+    // completed requests are appended to this file, or written to stdout for `-`, from a thread of
+    // their own; `None` logs them under the `access` target instead
+    pub access_log: Option<PathBuf>,
+    pub access_log_format: AccessLogFormat,
+    // requests under each of these path prefixes need a user name and password
+    pub basic_auth: Vec<BasicAuth>,
+    // when there are any, uploading, replacing or deleting files under `/files/` needs one of
+    // these in an `Authorization: Bearer` header, while reading them stays public
+    pub write_tokens: Vec<String>,
+    // requests under these path prefixes are turned away with a 403 unless the client presented a
+    // certificate, which needs `tls` to ask for one
+    pub client_cert_paths: Vec<ClientCertRule>,
+    // lets browsers on the allowed origins make requests to the server, `None` leaves cross-origin
+    // requests to the browser's same-origin policy
+    pub cors: Option<CorsPolicy>,
+    // requests a single client address may make, those over it get a 429 before they're routed
+    pub rate_limit: Option<RateLimit>,
+    // requests handled at once across the server, those over it wait up to
+    // `concurrency_queue_timeout` for one to finish and then get a 503; `None` for no limit
+    pub max_concurrent_requests: Option<usize>,
+    // the same for the requests under path prefixes, such as slow uploads or proxied ones, so they
+    // can't take every worker from cheap requests; the first one that covers a request's path as
+    // it was sent applies, on top of `max_concurrent_requests`
+    pub concurrency_limits: Vec<ConcurrencyLimit>,
+    // how long a request over a concurrency limit waits in line for a slot, 0 to turn it away at once
+    pub concurrency_queue_timeout: Duration,
+    // clients it doesn't permit get a 403 as soon as they connect, before anything is read
+    pub ip_filter: IpFilter,
+    // requests from these addresses are logged, rate limited and filtered by the client named in
+    // their `Forwarded` or `X-Forwarded-For` headers instead of by the proxy's own address
+    pub trusted_proxies: Vec<Cidr>,
+    // host names, such as `example.com` or `*.example.com` for its subdomains, mapped to the
+    // directory their `/files/` serve; requests for other hosts are served as usual
+    pub virtual_hosts: HashMap<String, PathBuf>,
+    // requests for a host that's neither in `virtual_hosts` nor given routes with `Server::host`
+    // get a 421 instead
+    pub misdirect_unknown_hosts: bool,
+    // path prefixes forwarded to upstream servers, ahead of the built-in routes but after custom ones
+    pub proxies: Vec<Proxy>,
+    // runs the scripts in a directory for the requests under a path prefix, next to the proxies
+    pub cgi: Option<Cgi>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            files_root: PathBuf::from(DEFAULT_FILES_ROOT),
+            max_body_size: MAX_BODY_SIZE,
+            max_in_memory_body_size: DEFAULT_MAX_IN_MEMORY_BODY_SIZE,
+            workers: DEFAULT_WORKERS,
+            max_queued: None,
+            max_connections_per_ip: None,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            header_timeout: DEFAULT_HEADER_TIMEOUT,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            max_requests_per_connection: None,
+            write_timeout: DEFAULT_WRITE_TIMEOUT,
+            compression: CompressionPolicy::default(),
+            mime_types: HashMap::new(),
+            error_pages: HashMap::new(),
+            server_header: Some(SERVER_SOFTWARE.to_owned()),
+            index_files: vec![DEFAULT_INDEX_FILE.to_owned()],
+            directory_listing: true,
+            slash_redirects: false,
+            recursive_deletes: false,
+            cache_control: Vec::new(),
+            rewrites: Vec::new(),
+            max_header_count: DEFAULT_MAX_HEADER_COUNT,
+            max_header_size: DEFAULT_MAX_HEADER_SIZE,
+            file_cache_size: 0,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            reuse_address: true,
+            reuse_port: false,
+            tcp_nodelay: true,
+            listen_backlog: DEFAULT_LISTEN_BACKLOG,
+            tls: None,
+            event_loop: false,
+            access_log: None,
+            access_log_format: AccessLogFormat::default(),
+            basic_auth: Vec::new(),
+            write_tokens: Vec::new(),
+            client_cert_paths: Vec::new(),
+            cors: None,
+            rate_limit: None,
+            max_concurrent_requests: None,
+            concurrency_limits: Vec::new(),
+            concurrency_queue_timeout: DEFAULT_CONCURRENCY_QUEUE_TIMEOUT,
+            ip_filter: IpFilter::default(),
+            trusted_proxies: Vec::new(),
+            virtual_hosts: HashMap::new(),
+            misdirect_unknown_hosts: false,
+            proxies: Vec::new(),
+            cgi: None,
+        }
+    }
+}
+
+// hands a running server a new configuration, e.g. once its file changed; connections accepted from
+// then on, and kept-alive ones once they're idle, are served with it and the routes it makes.
+// What's set up when `run` starts stays as it was: the listeners, TLS, the event loop, the access
+// log and the file cache
+#[derive(Debug, Clone)]
+pub struct Reloader(Arc<Mutex<Option<Config>>>);
+
+impl Reloader {
+    // the server switches to `config` within a moment; a configuration handed over before it did
+    // is dropped for this one
+    pub fn reload(&self, config: Config) {
+        assert!(config.workers > 0, "a server needs at least one worker");
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner) = Some(config);
+    }
+}
+
+// the configuration and routes requests are served with, replaced together on reload
+#[derive(Debug, Clone)]
+pub(crate) struct Site {
+    pub(crate) config: Arc<Config>,
+    pub(crate) router: Arc<Router>,
+}
+
+// the `Site` connections are handed as they're accepted or resumed
+#[derive(Debug)]
+pub(crate) struct CurrentSite(RwLock<Site>);
+
+impl CurrentSite {
+    pub(crate) fn get(&self) -> Site {
+        self.0
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    fn replace(&self, site: Site) {
+        *self.0.write().unwrap_or_else(PoisonError::into_inner) = site;
+    }
+}
+
+// state shared by every connection that changes while the server runs
+#[derive(Debug)]
+pub(crate) struct Stats {
+    pub(crate) started_at: Instant,
+    // set once the server stops accepting connections, kept-alive ones are closed after their
+    // next response
+    pub(crate) shutting_down: Arc<AtomicBool>,
+    // connections currently held by a worker, queued ones don't count
+    pub(crate) active_connections: AtomicUsize,
+    // connections waiting for a free worker
+    pub(crate) queued_connections: AtomicUsize,
+    // held and queued connections by client address, only kept with `Config::max_connections_per_ip`
+    pub(crate) connections_per_ip: Mutex<HashMap<IpAddr, usize>>,
+    pub(crate) metrics: Metrics,
+    pub(crate) access_log: AccessLog,
+    pub(crate) request_ids: RequestIds,
+    // shared by the `/files/` of every host, `None` when `Config::file_cache_size` is 0
+    pub(crate) file_cache: Option<Arc<FileCache>>,
+    pub(crate) concurrency: Limiters,
+}
+
+impl Stats {
+    // what's done with every answered request: it's timed, logged and counted
+    pub(crate) fn record(&self, entry: &Entry<'_>) {
+        log_timing(entry);
+        self.access_log.log(entry);
+        self.metrics.record(
+            entry.request.map(Request::method),
+            entry.status,
+            entry.bytes_sent,
+            entry.latency,
+        );
+    }
+}
+
+#[derive(Debug)]
+struct Listener {
+    listener: Bound,
+    // connections accepted by this listener are served over TLS when this is set
+    tls: Option<TlsConfig>,
+}
+
+// a socket clients connect to
+#[derive(Debug)]
+enum Bound {
+    Tcp(TcpListener),
+    // with the path it was bound to, which is removed along with it; `None` for sockets systemd
+    // passed on, which it removes itself
+    #[cfg(unix)]
+    Unix(UnixListener, Option<PathBuf>),
+}
+
+impl Bound {
+    fn accept(&self) -> io::Result<(Socket, Option<SocketAddr>)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, peer) = listener.accept()?;
+                Ok((Socket::Tcp(stream), Some(peer)))
+            }
+            #[cfg(unix)]
+            Self::Unix(listener, _) => {
+                let (stream, _) = listener.accept()?;
+                Ok((Socket::Unix(stream), None))
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Self::Tcp(listener) => listener.local_addr(),
+            #[cfg(unix)]
+            Self::Unix(..) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("listening on the Unix socket {self}, which has no address"),
+            )),
+        }
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Self::Tcp(listener) => listener.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Self::Unix(listener, _) => listener.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl fmt::Display for Bound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{addr}"),
+                Err(_) => f.write_str("an unknown address"),
+            },
+            #[cfg(unix)]
+            Self::Unix(listener, _) => {
+                let addr = listener.local_addr().ok();
+                match addr.as_ref().and_then(|addr| addr.as_pathname()) {
+                    Some(path) => write!(f, "unix:{}", path.display()),
+                    None => f.write_str("an unnamed Unix socket"),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Bound {
+    fn drop(&mut self) {
+        if let Self::Unix(_, Some(path)) = self {
+            if let Err(err) = fs::remove_file(&*path) {
+                log::warn!("failed to remove socket {path:?}: {err}");
+            }
+        }
+    }
+}
+
+// the number of sockets `LISTEN_FDS` says systemd passed on, as long as `LISTEN_PID` says they're
+// meant for this process rather than a parent it inherited the variables from
+#[cfg(unix)]
+fn listen_fds() -> Option<anyhow::Result<RawFd>> {
+    let pid = env::var("LISTEN_PID").ok()?;
+    if pid.parse::<u32>().ok()? != process::id() {
+        return None;
+    }
+    let count = env::var("LISTEN_FDS").ok()?;
+    Some(
+        count
+            .parse()
+            .with_context(|| anyhow!("LISTEN_FDS={count:?} isn't a number of sockets")),
+    )
+}
+
+#[cfg(unix)]
+fn inherited_listener(fd: OwnedFd) -> anyhow::Result<Bound> {
+    let raw_fd = fd.as_raw_fd();
+    let socket = socket2::Socket::from(fd);
+    // scripts run by the server shouldn't inherit the listeners, as they would by default
+    socket
+        .set_cloexec(true)
+        .with_context(|| anyhow!("descriptor {raw_fd} isn't a socket"))?;
+    if socket.r#type()? != Type::STREAM {
+        return Err(anyhow!("socket {raw_fd} isn't a stream socket"));
+    }
+
+    let addr = socket.local_addr()?;
+    if addr.is_unix() {
+        Ok(Bound::Unix(socket.into(), None))
+    } else if addr.as_socket().is_some() {
+        Ok(Bound::Tcp(socket.into()))
+    } else {
+        Err(anyhow!(
+            "socket {raw_fd} is neither a TCP nor a Unix socket"
+        ))
+    }
+}
+
+// binds the first of `addrs` that can be bound, like `TcpListener::bind`, except that IPv6
+// listeners only take IPv6 connections, so `[::]` and `0.0.0.0` can share a port, and that the
+// socket options come from `config`
+fn bind_tcp(addrs: impl ToSocketAddrs, config: &Config) -> io::Result<TcpListener> {
+    let mut last_err = None;
+    for addr in addrs.to_socket_addrs()? {
+        match bind_tcp_addr(addr, config) {
+            Ok(listener) => return Ok(listener),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "could not resolve to any addresses",
+        )
+    }))
+}
+
+fn bind_tcp_addr(addr: SocketAddr, config: &Config) -> io::Result<TcpListener> {
+    let socket =
+        socket2::Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    // on Windows it would let another process bind the port out from under the server instead
+    #[cfg(unix)]
+    socket.set_reuse_address(config.reuse_address)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(config.reuse_port)?;
+    #[cfg(not(unix))]
+    if config.reuse_port {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "SO_REUSEPORT isn't supported on this platform",
+        ));
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(i32::try_from(config.listen_backlog).unwrap_or(i32::MAX))?;
+    Ok(socket.into())
+}
+
+// binds a Unix socket at `path`, replacing one that's there already unless a server still
+// accepts connections on it
+#[cfg(unix)]
+fn bind_unix_socket(path: &Path) -> anyhow::Result<Bound> {
+    let is_socket =
+        fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket());
+    if is_socket {
+        if UnixStream::connect(path).is_ok() {
+            return Err(anyhow!("{path:?} is in use by another server"));
+        }
+        fs::remove_file(path).with_context(|| anyhow!("failed to remove stale socket {path:?}"))?;
+    }
+
+    let listener =
+        UnixListener::bind(path).with_context(|| anyhow!("failed to bind to {path:?}"))?;
+    Ok(Bound::Unix(listener, Some(path.to_owned())))
+}
+
+// a connection accepted by a `Bound`
+#[derive(Debug)]
+pub(crate) enum Socket {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Socket {
+    // `None` for Unix sockets, and for TCP ones that couldn't tell
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Self::Tcp(stream) => stream.peer_addr().ok(),
+            #[cfg(unix)]
+            Self::Unix(_) => None,
+        }
+    }
+
+    // a no-op for Unix sockets, which don't batch writes
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.set_nodelay(nodelay),
+            #[cfg(unix)]
+            Self::Unix(_) => Ok(()),
+        }
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Self::Tcp(stream) => stream.try_clone().map(Self::Tcp),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.try_clone().map(Self::Unix),
+        }
+    }
+
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+impl SendFile for Socket {
+    #[cfg(target_os = "linux")]
+    fn send_file(&mut self, file: &mut io::Take<File>) -> io::Result<u64> {
+        crate::sendfile::sendfile(file, self)
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for Socket {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Self::Tcp(stream) => stream.as_raw_fd(),
+            Self::Unix(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+#[cfg(feature = "event-loop")]
+fn start_event_loop(
+    pool: &ThreadPool,
+    site: &Arc<CurrentSite>,
+    stats: &Arc<Stats>,
+) -> anyhow::Result<Arc<EventLoop>> {
+    EventLoop::start(pool.clone(), Arc::clone(site), Arc::clone(stats))
+        .context("failed to start the event loop")
+}
+
+#[cfg(not(feature = "event-loop"))]
+fn start_event_loop(
+    _pool: &ThreadPool,
+    _site: &Arc<CurrentSite>,
+    _stats: &Arc<Stats>,
+) -> anyhow::Result<Arc<EventLoop>> {
+    Err(anyhow::anyhow!(
+        "`Config::event_loop` needs butler to be built with the `event-loop` feature"
+    ))
+}
+
+// without the feature there's never an event loop to park connections with
+#[cfg(not(feature = "event-loop"))]
+#[derive(Debug)]
+pub(crate) enum EventLoop {}
+
+#[cfg(not(feature = "event-loop"))]
+impl EventLoop {
+    fn park(&self, _conn: Http1Connection) {
+        match *self {}
+    }
+}
+
+// answers a connection that won't be served with `response`, from the accept loop
+fn reject(stream: &mut Socket, tls: bool, response: Response, config: &Config, stats: &Stats) {
+    // a TLS client would need a handshake first, which would stall the accept loop,
+    // so it just sees the connection close
+    if tls {
+        return;
+    }
+
+    let status = response.status;
+    match close_with(stream, response, config) {
+        Ok(bytes_sent) => stats.metrics.record(None, status, bytes_sent, None),
+        Err(err) => log::error!("failed to reject connection: {err}"),
+    }
+}
+
+// a connection counted against the limit of its client's address, until it's dropped
+struct IpSlot {
+    stats: Arc<Stats>,
+    ip: IpAddr,
+}
+
+impl IpSlot {
+    // `None` if the address already has `max` connections
+    fn acquire(stats: &Arc<Stats>, ip: IpAddr, max: usize) -> Option<Self> {
+        let mut counts = stats
+            .connections_per_ip
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let count = counts.entry(ip).or_default();
+        if *count >= max {
+            return None;
+        }
+        *count += 1;
+
+        Some(Self {
+            stats: Arc::clone(stats),
+            ip,
+        })
+    }
+}
+
+impl Drop for IpSlot {
+    fn drop(&mut self) {
+        let mut counts = self
+            .stats
+            .connections_per_ip
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(count) = counts.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn serve(
+    stream: Socket,
+    tls: Option<&TlsConfig>,
+    id: ConnId,
+    ip_slot: Option<IpSlot>,
+    config: &Config,
+    stats: &Stats,
+    router: &Router,
+    event_loop: Option<&Arc<EventLoop>>,
+) -> anyhow::Result<()> {
+    log::info!(conn_id = id; "accepted connection {id}");
+
+    let peer = stream.peer_addr();
+
+    // the timeout also covers the TLS handshake
+    stream
+        .set_read_timeout(Some(config.read_timeout))
+        .context("failed to set read timeout")?;
+    // a client that stops reading can't be told anything anymore, so its connection is just dropped
+    stream
+        .set_write_timeout(Some(config.write_timeout))
+        .context("failed to set write timeout")?;
+
+    // a second handle on the socket, so its timeouts can be changed while it's being read through TLS
+    let socket = stream.try_clone().context("failed to clone socket")?;
+
+    let mut client_cert = None;
+    let transport = match tls {
+        None => Transport::Plain(stream),
+        Some(tls) => {
+            let conn =
+                ServerConnection::new(Arc::clone(&tls.0)).context("failed to start TLS session")?;
+            let mut stream = StreamOwned::new(conn, stream);
+
+            // the protocol is agreed on during the handshake, so it has to be done before anything is read
+            while stream.conn.is_handshaking() {
+                stream
+                    .conn
+                    .complete_io(&mut stream.sock)
+                    .context("TLS handshake failed")?;
+            }
+
+            // only certificates the verifier accepted get this far
+            client_cert = stream
+                .conn
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(|cert| cert_subject(cert))
+                .map(Arc::from);
+
+            if stream.conn.alpn_protocol() == Some(ALPN_HTTP2) {
+                http2::serve(
+                    &mut stream,
+                    &socket,
+                    peer,
+                    client_cert,
+                    id,
+                    config,
+                    stats,
+                    router,
+                )?;
+                return Transport::Tls(Box::new(stream))
+                    .close()
+                    .context("failed to write to client");
+            }
+
+            Transport::Tls(Box::new(stream))
+        }
+    };
+
+    let conn = Http1Connection {
+        reader: BufReader::new(transport),
+        socket,
+        peer,
+        id,
+        requests_served: 0,
+        client_cert,
+        _ip_slot: ip_slot,
+    };
+    resume(conn, config, stats, router, event_loop)
+}
+
+// serves requests on `conn` until it closes, or until it's left waiting for the next one while
+// there's an event loop to do the waiting instead of a worker
+pub(crate) fn resume(
+    mut conn: Http1Connection,
+    config: &Config,
+    stats: &Stats,
+    router: &Router,
+    event_loop: Option<&Arc<EventLoop>>,
+) -> anyhow::Result<()> {
+    match (
+        handle_connection(&mut conn, config, stats, router, event_loop.is_some())?,
+        event_loop,
+    ) {
+        (Next::Park, Some(event_loop)) => {
+            log::debug!("id = {}, waiting for the next request", conn.id);
+            event_loop.park(conn);
+            Ok(())
+        }
+        _ => conn.close(),
+    }
+}
+
+// what becomes of a connection once `handle_connection` is done with it
+#[derive(Debug, PartialEq, Eq)]
+enum Next {
+    Close,
+    // it's between requests, with nothing of the next one read yet
+    Park,
+}
+
+// what an HTTP/1.x connection is read from and written to
+pub(crate) enum Transport {
+    Plain(Socket),
+    Tls(Box<StreamOwned<ServerConnection, Socket>>),
+}
+
+impl Transport {
+    // whether decrypted bytes are waiting that the socket won't signal anymore
+    fn has_buffered(&mut self) -> bool {
+        match self {
+            Self::Plain(_) => false,
+            // an error is left for the next read to report
+            Self::Tls(stream) => stream
+                .conn
+                .process_new_packets()
+                .map_or(true, |state| state.plaintext_bytes_to_read() > 0),
+        }
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(_) => Ok(()),
+            // lets the client tell a finished response apart from a truncated one
+            Self::Tls(stream) => {
+                stream.conn.send_close_notify();
+                stream.flush()
+            }
+        }
+    }
+}
+
+impl SendFile for Transport {
+    fn send_file(&mut self, file: &mut io::Take<File>) -> io::Result<u64> {
+        match self {
+            Self::Plain(socket) => socket.send_file(file),
+            // the file has to be encrypted on its way anyway
+            Self::Tls(stream) => io::copy(file, stream),
+        }
+    }
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(stream) => stream.read(buf),
+            Self::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(stream) => stream.write(buf),
+            Self::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(stream) => stream.flush(),
+            Self::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+// a connection handed over to another protocol, read through the buffer so bytes the client sent
+// right after its request aren't lost
+struct Upgraded<'a>(&'a mut BufReader<Transport>);
+
+impl Read for Upgraded<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for Upgraded<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.get_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.get_mut().flush()
+    }
+}
+
+// an HTTP/1.x connection, with what has to be kept about it from one request to the next
+pub(crate) struct Http1Connection {
+    // kept across requests so bytes belonging to the next request aren't lost,
+    // responses are written to the transport it wraps
+    reader: BufReader<Transport>,
+    socket: Socket,
+    peer: Option<SocketAddr>,
+    pub(crate) id: ConnId,
+    requests_served: usize,
+    // the subject of the client's certificate, see `Request::client_cert_subject`
+    client_cert: Option<Arc<str>>,
+    // counts against the client's limit for as long as the connection is open, parked or not
+    _ip_slot: Option<IpSlot>,
+}
+
+impl Http1Connection {
+    #[cfg(feature = "event-loop")]
+    pub(crate) fn socket(&self) -> &Socket {
+        &self.socket
+    }
+
+    pub(crate) fn close(mut self) -> anyhow::Result<()> {
+        self.reader
+            .get_mut()
+            .close()
+            .context("failed to write to client")?;
+        log::info!(conn_id = self.id; "closing connection {}", self.id);
+        Ok(())
+    }
+}
+
+fn handle_connection(
+    conn: &mut Http1Connection,
+    config: &Config,
+    stats: &Stats,
+    router: &Router,
+    can_park: bool,
+) -> anyhow::Result<Next> {
+    let Http1Connection {
+        reader,
+        socket,
+        peer,
+        id,
+        requests_served,
+        client_cert,
+        ..
+    } = conn;
+    let (peer, id) = (*peer, *id);
+
+    let record = |request: Option<&Request>,
+                  received: Option<Instant>,
+                  status: StatusCode,
+                  bytes_sent: u64| {
+        stats.record(&Entry {
+            id,
+            peer,
+            request,
+            status,
+            bytes_sent,
+            latency: received.map(|received| received.elapsed()),
+        });
+    };
+
+    loop {
+        // a kept-alive connection waits for its next request no longer than the idle timeout
+        let first_byte_timeout = if *requests_served == 0 {
+            config.read_timeout
+        } else {
+            config.idle_timeout
+        };
+        let mut head_reader = HeadDeadline::new(reader, socket, first_byte_timeout, config);
+        let head = read_head(&mut head_reader, config);
+        let head_started = head_reader.started();
+        socket
+            .set_read_timeout(Some(config.read_timeout))
+            .context("failed to set read timeout")?;
+
+        let head = match head {
+            Ok(Some(head)) => head,
+            Ok(None) => break,
+            Err(err) if is_timeout(&err) && *requests_served > 0 && !head_started => {
+                log::info!("id = {id}, closing idle connection");
+                break;
+            }
+            Err(err) if is_timeout(&err) => {
+                log::info!("id = {id}, timed out waiting for a request");
+
+                let response = Response::request_timeout();
+                let status = response.status;
+                let bytes_sent = close_with(reader.get_mut(), response, config)?;
+                record(None, None, status, bytes_sent);
+                break;
+            }
+            Err(err) if is_head_too_large(&err) => {
+                log::warn!("id = {id}, {err}");
+
+                // the rest of the head is still in the stream, so the connection can't be reused
+                let response = Response::request_header_fields_too_large();
+                let status = response.status;
+                let bytes_sent = close_with(reader.get_mut(), response, config)?;
+                record(None, None, status, bytes_sent);
+                break;
+            }
+            Err(err) => return Err(err).context("failed to read from client"),
+        };
+
+        let received = Instant::now();
+        log::debug!("id = {id}, request head = {head}");
+
+        let mut request: Request = match head.parse() {
+            Ok(request) => request,
+            Err(err) => {
+                let err = err.context("failed to parse request");
+                log::warn!("id = {id}, {err:#}");
+
+                // we can't know where the malformed request ends, so the connection can't be reused
+                let response = if err.downcast_ref::<UnsupportedVersion>().is_some() {
+                    Response::http_version_not_supported()
+                } else if err.downcast_ref::<UnsupportedMethod>().is_some() {
+                    Response::not_implemented()
+                } else {
+                    Response::bad_request(format!("{err:#}"))
+                };
+                let status = response.status;
+                let bytes_sent = close_with(reader.get_mut(), response, config)?;
+                record(None, Some(received), status, bytes_sent);
+                break;
+            }
+        };
+        request.id = stats.request_ids.assign(&request);
+        request.peer = peer;
+        request.client_cert = client_cert.clone();
+        request.client = forwarded_client(&request, &config.trusted_proxies);
+
+        let expects_continue = match request.expect() {
+            Some(expectation) if expectation.eq_ignore_ascii_case("100-continue") => true,
+            Some(expectation) => {
+                log::warn!(
+                    "request_id = {}, can't meet expectation {expectation:?}",
+                    request.id
+                );
+
+                // the client may still send a body we aren't going to read
+                let response = Response::expectation_failed();
+                let status = response.status;
+                let response = response.with_header(REQUEST_ID_HEADER, &request.id);
+                let bytes_sent = close_with(reader.get_mut(), response, config)?;
+                record(Some(&request), Some(received), status, bytes_sent);
+                break;
+            }
+            None => false,
+        };
+
+        // every line counts, a peer that only looks at the first or the last one would find the
+        // end of the body somewhere else
+        let codings = request.transfer_codings();
+        let chunked = codings == ["chunked"];
+        if request.raw_headers.contains("transfer-encoding") && !chunked {
+            log::warn!(
+                "request_id = {}, unsupported transfer codings {codings:?}",
+                request.id,
+            );
+
+            // without understanding the coding we can't tell where the body ends
+            let response = if codings.len() > 1 && codings.iter().all(|coding| coding == "chunked")
+            {
+                Response::bad_request("requests can't be chunked more than once".to_owned())
+            } else {
+                Response::not_implemented()
+            };
+            let status = response.status;
+            let response = response.with_header(REQUEST_ID_HEADER, &request.id);
+            let bytes_sent = close_with(reader.get_mut(), response, config)?;
+            record(Some(&request), Some(received), status, bytes_sent);
+            break;
+        }
+
+        if chunked && request.content_length().is_some() {
+            log::warn!(
+                "request_id = {}, request has both a Content-Length and a Transfer-Encoding",
+                request.id
+            );
+
+            // peers that disagree on which of the two wins can be tricked into seeing different requests
+            let response = Response::bad_request(
+                "requests can't have both 'Content-Length' and 'Transfer-Encoding'".to_owned(),
+            );
+            let status = response.status;
+            let response = response.with_header(REQUEST_ID_HEADER, &request.id);
+            let bytes_sent = close_with(reader.get_mut(), response, config)?;
+            record(Some(&request), Some(received), status, bytes_sent);
+            break;
+        }
+
+        // waiting for a slot happens before the body is read, so a request that doesn't get one
+        // leaves it unread and the connection can't be reused
+        let permits = match stats.concurrency.acquire(&request, &config.rewrites) {
+            Ok(permits) => permits,
+            Err(response) => {
+                let status = response.status;
+                let response = response.with_header(REQUEST_ID_HEADER, &request.id);
+                let bytes_sent = close_with(reader.get_mut(), response, config)?;
+                record(Some(&request), Some(received), status, bytes_sent);
+                break;
+            }
+        };
+
+        if chunked {
+            if expects_continue && request.line.version == Version::Http11 {
+                send_continue(reader.get_mut())?;
+            }
+
+            let mut body = Spooler::new(config.max_in_memory_body_size);
+            match read_chunked_body(reader, config, &mut request.raw_headers, &mut body) {
+                Ok(()) => {
+                    let body = body.finish().context("failed to store request body")?;
+                    request.body = Some(body.into());
+                }
+                Err(ChunkedError::Io(err)) => {
+                    return Err(err).context("failed to read request body from client")
+                }
+                Err(err) => {
+                    log::warn!("request_id = {}, {err}", request.id);
+
+                    // the rest of the body is still in the stream, so the connection can't be reused
+                    let response = match err {
+                        ChunkedError::TooLarge => Response::payload_too_large(),
+                        _ => Response::bad_request(err.to_string()),
+                    };
+                    let status = response.status;
+                    let response = response.with_header(REQUEST_ID_HEADER, &request.id);
+                    let bytes_sent = close_with(reader.get_mut(), response, config)?;
+                    record(Some(&request), Some(received), status, bytes_sent);
+                    break;
+                }
+            }
+        } else if let Some(content_length) = request.content_length() {
+            if content_length > config.max_body_size {
+                log::warn!(
+                    "request_id = {}, request body of {content_length} bytes exceeds the limit of {} bytes",
+                    request.id,
+                    config.max_body_size
+                );
+
+                // a client waiting for `100 Continue` gets this in its place and never sends the body;
+                // one that didn't wait left the unread body in the stream, so either way the
+                // connection can't be reused
+                let response = Response::payload_too_large();
+                let status = response.status;
+                let response = response.with_header(REQUEST_ID_HEADER, &request.id);
+                let bytes_sent = close_with(reader.get_mut(), response, config)?;
+                record(Some(&request), Some(received), status, bytes_sent);
+                break;
+            }
+
+            // HTTP/1.0 clients don't know about interim responses
+            if expects_continue && content_length > 0 && request.line.version == Version::Http11 {
+                send_continue(reader.get_mut())?;
+            }
+
+            let mut body = Spooler::new(config.max_in_memory_body_size);
+            let body_len = io::copy(&mut reader.by_ref().take(content_length), &mut body)
+                .context("failed to read request body from client")?;
+            if body_len < content_length {
+                return Err(anyhow!(
+                    "client closed the connection in the middle of the request body"
+                ));
+            }
+            let body = body.finish().context("failed to store request body")?;
+            request.body = Some(body.into());
+        }
+
+        // the body has been read in full, so the connection stays usable if it can't be decoded
+        let decoded = request.decode_body(config.max_body_size, config.max_in_memory_body_size);
+
+        log::trace!("request_id = {}, request = {request:#?}", request.id);
+
+        // persistent connections are opt-in before HTTP/1.1
+        let connection_mode = request.connection().unwrap_or(match request.line.version {
+            Version::Http10 => ConnectionMode::Close,
+            Version::Http11 | Version::Http2 => ConnectionMode::KeepAlive,
+        });
+
+        let response = match decoded {
+            Ok(()) => respond(&mut request, router),
+            Err(response) => response,
+        };
+        let mut response = with_error_page(response, Some(&request), config)
+            .with_header(REQUEST_ID_HEADER, &request.id);
+        response.version = request.line.version;
+        *requests_served += 1;
+
+        // the client can only find the end of a close-delimited body by the connection closing
+        let connection_mode = if response.is_close_delimited()
+            || stats.shutting_down.load(Ordering::SeqCst)
+            || config.max_requests_per_connection == Some(*requests_served)
+        {
+            ConnectionMode::Close
+        } else {
+            connection_mode
+        };
+        // a `101` already says the connection is being upgraded
+        let upgrade = response
+            .upgrade
+            .take()
+            .filter(|_| response.status == StatusCode::SwitchingProtocols);
+        if upgrade.is_none() {
+            response.headers.push(Header::Connection(connection_mode));
+        }
+
+        log::trace!("request_id = {}, response = {response:#?}", request.id);
+
+        let status = response.status;
+
+        // HEAD responses carry the same headers as GET, but never a body
+        let stream = reader.get_mut();
+        let bytes_sent = response
+            .send(
+                &mut *stream,
+                request.line.method != Method::Head,
+                config.server_header.as_deref(),
+            )
+            .context("failed to write to client")?;
+
+        stream.flush().context("failed to write to client")?;
+
+        record(Some(&request), Some(received), status, bytes_sent);
+        drop(permits);
+
+        // the connection isn't HTTP anymore, and is closed once the new protocol is done with it;
+        // it may sit quiet for as long as an idle one
+        if let Some(upgrade) = upgrade {
+            socket
+                .set_read_timeout(Some(config.idle_timeout))
+                .context("failed to set read timeout")?;
+            if let Err(err) = upgrade.run(&mut Upgraded(reader)) {
+                log::warn!("request_id = {}, {err:#}", request.id);
+            }
+            break;
+        }
+
+        if connection_mode == ConnectionMode::Close {
+            break;
+        }
+
+        // a pipelined request that's been read already won't make the socket readable again
+        if can_park && reader.buffer().is_empty() && !reader.get_mut().has_buffered() {
+            return Ok(Next::Park);
+        }
+    }
+
+    Ok(Next::Close)
+}
+
+// writes a final response that tells the client the connection won't be reused,
+// returning the number of body bytes sent
+fn close_with(mut stream: impl Write, response: Response, config: &Config) -> anyhow::Result<u64> {
+    let mut response = with_error_page(response, None, config);
+    response
+        .headers
+        .push(Header::Connection(ConnectionMode::Close));
+
+    let bytes_sent = response
+        .send(
+            &mut Copying(&mut stream),
+            true,
+            config.server_header.as_deref(),
+        )
+        .context("failed to write to client")?;
+
+    stream.flush().context("failed to write to client")?;
+
+    Ok(bytes_sent)
+}
+
+// the configured page for the response's status, or else the error in a type `request` prefers
+pub(crate) fn with_error_page(
+    response: Response,
+    request: Option<&Request>,
+    config: &Config,
+) -> Response {
+    match (config.error_pages.get(&response.status), request) {
+        (Some(page), _) => response.with_page(page),
+        (None, Some(request)) => response.negotiated(request),
+        (None, None) => response,
+    }
+}
+
+// tells a client waiting on `Expect: 100-continue` to go ahead and send the body
+fn send_continue(mut stream: impl Write) -> anyhow::Result<()> {
+    write!(
+        stream,
+        "{} {}\r\n\r\n",
+        Version::Http11,
+        StatusCode::Continue
+    )
+    .and_then(|()| stream.flush())
+    .context("failed to write to client")
+}
+
+// a read timeout surfaces as either kind depending on the platform
+pub(crate) fn is_timeout(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+// reads the request line and headers, up to and including the empty line that ends them,
+// returns `None` if the client closed the connection before sending anything;
+// the body that follows stays in `reader`
+fn read_head(reader: &mut impl BufRead, config: &Config) -> io::Result<Option<String>> {
+    let mut head = Vec::new();
+    let mut header_count = 0;
+
+    loop {
+        // reading one byte past the limit is enough to tell that the head is too large
+        let remaining = (config.max_header_size + 1).saturating_sub(head.len());
+        let line_start = head.len();
+
+        match reader
+            .by_ref()
+            .take(remaining as u64)
+            .read_until(b'\n', &mut head)
+        {
+            Ok(0) if head.is_empty() => return Ok(None),
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed before the end of the request head",
+                ))
+            }
+            Ok(_) => {}
+            Err(err) => return Err(err),
+        }
+
+        if head.len() > config.max_header_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                HeadTooLarge(format!(
+                    "request head is larger than {} bytes",
+                    config.max_header_size
+                )),
+            ));
+        }
+
+        // empty lines before the request line are ignored
+        if head == b"\r\n" || head == b"\n" {
+            head.clear();
+        } else if head.ends_with(b"\r\n\r\n") {
+            // header values may carry arbitrary bytes, which we don't interpret
+            return Ok(Some(String::from_utf8_lossy(&head).into_owned()));
+        } else if line_start > 0 && head.ends_with(b"\n") {
+            header_count += 1;
+            if header_count > config.max_header_count {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    HeadTooLarge(format!(
+                        "request has more than {} headers",
+                        config.max_header_count
+                    )),
+                ));
+            }
+        }
+    }
+}
+
+// reads the request head through `reader`, failing with `TimedOut` once the head has taken longer
+// than `Config::header_timeout` to arrive, counted from its first byte, so that a client can't
+// hold on to a worker by sending a little at a time; waiting for that first byte is only bounded
+// by `first_byte_timeout`
+struct HeadDeadline<'a, S> {
+    reader: &'a mut BufReader<S>,
+    socket: &'a Socket,
+    first_byte_timeout: Duration,
+    read_timeout: Duration,
+    header_timeout: Duration,
+    deadline: Option<Instant>,
+}
+
+impl<'a, S: Read> HeadDeadline<'a, S> {
+    fn new(
+        reader: &'a mut BufReader<S>,
+        socket: &'a Socket,
+        first_byte_timeout: Duration,
+        config: &Config,
+    ) -> Self {
+        Self {
+            reader,
+            socket,
+            first_byte_timeout,
+            read_timeout: config.read_timeout,
+            header_timeout: config.header_timeout,
+            deadline: None,
+        }
+    }
+
+    // whether any of the head has arrived
+    fn started(&self) -> bool {
+        self.deadline.is_some()
+    }
+}
+
+impl<S: Read> Read for HeadDeadline<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+impl<S: Read> BufRead for HeadDeadline<'_, S> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        // the socket is only read when nothing is buffered, so that's when the timeout matters
+        if self.reader.buffer().is_empty() {
+            let timeout = match self.deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "request head took too long to arrive",
+                        ));
+                    }
+                    remaining.min(self.read_timeout)
+                }
+                None => self.first_byte_timeout,
+            };
+            self.socket.set_read_timeout(Some(timeout))?;
+        }
+
+        let available = self.reader.fill_buf()?;
+        if !available.is_empty() && self.deadline.is_none() {
+            self.deadline = Some(Instant::now() + self.header_timeout);
+        }
+        Ok(available)
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.reader.consume(amt);
+    }
+}
+
+// the request line and headers exceed one of the configured limits
+#[derive(Debug)]
+struct HeadTooLarge(String);
+
+impl fmt::Display for HeadTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for HeadTooLarge {}
+
+fn is_head_too_large(err: &io::Error) -> bool {
+    err.get_ref().is_some_and(|err| err.is::<HeadTooLarge>())
+}
+
+// why a chunked request body couldn't be read
+#[derive(Debug)]
+enum ChunkedError {
+    Malformed(String),
+    // the decoded body would exceed `Config::max_body_size`
+    TooLarge,
+    Io(io::Error),
+}
+
+impl fmt::Display for ChunkedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Malformed(reason) => write!(f, "malformed chunked body: {reason}"),
+            Self::TooLarge => f.write_str("chunked body exceeds the size limit"),
+            Self::Io(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl From<io::Error> for ChunkedError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+// decodes a `Transfer-Encoding: chunked` body into `body`, the fields of its trailer are added to
+// `headers`
+fn read_chunked_body(
+    reader: &mut impl BufRead,
+    config: &Config,
+    headers: &mut HeaderMap,
+    body: &mut Spooler,
+) -> Result<(), ChunkedError> {
+    loop {
+        let line = read_line(reader, MAX_CHUNK_LINE_SIZE)?;
+
+        // chunk extensions may follow the size after a ';', we don't use any
+        let size = line.split(';').next().unwrap_or_default().trim();
+        if size.is_empty() || !size.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(ChunkedError::Malformed(format!(
+                "invalid chunk size {size:?}"
+            )));
+        }
+        let size = u64::from_str_radix(size, 16)
+            .map_err(|_| ChunkedError::Malformed(format!("chunk size {size:?} is too large")))?;
+
+        if size == 0 {
+            break;
+        }
+        // the size is the client's, so adding it to what's been read could overflow
+        if size > config.max_body_size.saturating_sub(body.len()) {
+            return Err(ChunkedError::TooLarge);
+        }
+
+        if io::copy(&mut reader.by_ref().take(size), body)? < size {
+            return Err(ChunkedError::Io(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed in the middle of a chunked body",
+            )));
+        }
+
+        let mut line_ending = [0; 2];
+        reader.read_exact(&mut line_ending)?;
+        if line_ending != *b"\r\n" {
+            return Err(ChunkedError::Malformed(
+                "chunk data isn't followed by a CRLF".to_owned(),
+            ));
+        }
+    }
+
+    // the trailer is held to the same limits as the head
+    let mut trailer_size = 0;
+    let mut trailer_count = 0;
+    loop {
+        let line = read_line(reader, config.max_header_size)?;
+        if line.is_empty() {
+            break;
+        }
+
+        trailer_size += line.len();
+        trailer_count += 1;
+        if trailer_size > config.max_header_size || trailer_count > config.max_header_count {
+            return Err(ChunkedError::Malformed("trailer is too large".to_owned()));
+        }
+
+        let (name, value) = line.split_once(':').ok_or_else(|| {
+            ChunkedError::Malformed(format!("trailer field {line:?} is missing a ':'"))
+        })?;
+        headers.append(name.trim(), value.trim());
+    }
+
+    Ok(())
+}
+
+// reads a line of at most `max_size` bytes, not counting the line ending, which is stripped
+fn read_line(reader: &mut impl BufRead, max_size: usize) -> Result<String, ChunkedError> {
+    let mut line = Vec::new();
+    reader
+        .by_ref()
+        .take(max_size as u64 + 2)
+        .read_until(b'\n', &mut line)?;
+
+    if !line.ends_with(b"\n") {
+        if line.len() > max_size {
+            return Err(ChunkedError::Malformed(format!(
+                "line is longer than {max_size} bytes"
+            )));
+        }
+        return Err(ChunkedError::Io(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "connection closed in the middle of a chunked body",
+        )));
+    }
+
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+
+    String::from_utf8(line)
+        .map_err(|_| ChunkedError::Malformed("line isn't valid UTF-8".to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn inherited_listener_takes_tcp_and_unix_stream_sockets() {
+        use std::net::UdpSocket;
+
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let bound = inherited_listener(OwnedFd::from(tcp)).unwrap();
+        assert_eq!(bound.local_addr().unwrap(), addr);
+
+        let path = env::temp_dir().join(format!("butler-inherited-{}.sock", process::id()));
+        let _ = fs::remove_file(&path);
+        let unix = UnixListener::bind(&path).unwrap();
+        let bound = inherited_listener(OwnedFd::from(unix)).unwrap();
+        assert_eq!(bound.to_string(), format!("unix:{}", path.display()));
+        // systemd's sockets are left for it to remove
+        drop(bound);
+        assert!(path.exists());
+        fs::remove_file(&path).unwrap();
+
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(inherited_listener(OwnedFd::from(udp)).is_err());
+    }
+}
@@ -0,0 +1,378 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use ring::{
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+
+use crate::{
+    base64,
+    cookie::{SameSite, SetCookie},
+    middleware::Middleware,
+    request::{parse_query, percent_encode, Request},
+    response::Response,
+};
+
+const DEFAULT_COOKIE_NAME: &str = "butler_session";
+const DEFAULT_EXPIRY: Duration = Duration::from_secs(24 * 60 * 60);
+// browsers drop cookies larger than this
+const MAX_COOKIE_SIZE: usize = 4096;
+// bytes of randomness in an in-memory session's ID
+const ID_LEN: usize = 32;
+
+// gives every request a `Session` its handler can read and write through `request.session()`,
+// kept in a cookie between requests; add it with `Server::layer`
+pub struct Sessions {
+    store: Store,
+    cookie_name: String,
+    // how long a session lasts after it was last changed
+    expiry: Duration,
+    secure: bool,
+}
+
+enum Store {
+    // the values themselves are the cookie, signed so clients can't change them, though they can
+    // still read them
+    Signed(hmac::Key),
+    // the cookie is a random ID, the values stay with the server and are gone once it stops
+    Memory(Mutex<HashMap<String, Stored>>),
+}
+
+struct Stored {
+    values: HashMap<String, String>,
+    expires: Instant,
+}
+
+impl Sessions {
+    // keeps sessions in a cookie signed with HMAC-SHA256; `secret` should be at least 32 random
+    // bytes, and every session signed with it is invalidated once it changes
+    pub fn signed(secret: &[u8]) -> Self {
+        assert!(
+            secret.len() >= 32,
+            "session secrets need at least 32 bytes, got {}",
+            secret.len()
+        );
+        Self::new(Store::Signed(hmac::Key::new(hmac::HMAC_SHA256, secret)))
+    }
+
+    // keeps sessions in memory, keyed by a random ID stored in the cookie
+    pub fn in_memory() -> Self {
+        Self::new(Store::Memory(Mutex::new(HashMap::new())))
+    }
+
+    fn new(store: Store) -> Self {
+        Self {
+            store,
+            cookie_name: DEFAULT_COOKIE_NAME.to_owned(),
+            expiry: DEFAULT_EXPIRY,
+            secure: false,
+        }
+    }
+
+    pub fn cookie_name(mut self, name: &str) -> Self {
+        // panics on an invalid name now rather than when the first cookie is set
+        SetCookie::new(name, "");
+        self.cookie_name = name.to_owned();
+        self
+    }
+
+    pub fn expiry(mut self, expiry: Duration) -> Self {
+        self.expiry = expiry;
+        self
+    }
+
+    // only lets the cookie be sent over HTTPS
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    // the values and ID of the session `cookie` refers to, `None` if it's expired or was tampered with
+    fn load(&self, cookie: &str) -> Option<(HashMap<String, String>, Option<String>)> {
+        match &self.store {
+            Store::Signed(key) => {
+                let (payload, signature) = cookie.rsplit_once('.')?;
+                hmac::verify(key, payload.as_bytes(), &base64::decode(signature)?).ok()?;
+
+                let (expires, values) = payload.split_once('&').unwrap_or((payload, ""));
+                if expires.parse::<u64>().ok()? <= unix_time() {
+                    return None;
+                }
+                Some((parse_query(values).ok()?.into_iter().collect(), None))
+            }
+            Store::Memory(sessions) => {
+                let sessions = lock(sessions);
+                let stored = sessions
+                    .get(cookie)
+                    .filter(|stored| stored.expires > Instant::now())?;
+                Some((stored.values.clone(), Some(cookie.to_owned())))
+            }
+        }
+    }
+
+    // stores `state`'s values and returns the cookie that refers to them
+    fn save(&self, state: &mut State) -> String {
+        match &self.store {
+            Store::Signed(key) => {
+                let mut payload = (unix_time() + self.expiry.as_secs()).to_string();
+                let mut values: Vec<_> = state.values.iter().collect();
+                values.sort_unstable();
+                for (name, value) in values {
+                    payload.push_str(&format!(
+                        "&{}={}",
+                        percent_encode(name),
+                        percent_encode(value)
+                    ));
+                }
+                let signature = hmac::sign(key, payload.as_bytes());
+                format!("{payload}.{}", base64::encode(signature.as_ref()))
+            }
+            Store::Memory(sessions) => {
+                let id = state.id.get_or_insert_with(new_id).clone();
+                let now = Instant::now();
+                let mut sessions = lock(sessions);
+                sessions.retain(|_, stored| stored.expires > now);
+                sessions.insert(
+                    id.clone(),
+                    Stored {
+                        values: state.values.clone(),
+                        expires: now + self.expiry,
+                    },
+                );
+                id
+            }
+        }
+    }
+
+    fn forget(&self, id: &str) {
+        if let Store::Memory(sessions) = &self.store {
+            lock(sessions).remove(id);
+        }
+    }
+}
+
+impl Middleware for Sessions {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        let cookie = request.cookie(&self.cookie_name);
+        let (values, id) = cookie
+            .as_deref()
+            .and_then(|cookie| self.load(cookie))
+            .unwrap_or_default();
+
+        request.session = Some(Session(Arc::new(Mutex::new(State {
+            values,
+            id,
+            cleared_id: None,
+            had_cookie: cookie.is_some(),
+            changed: false,
+        }))));
+        None
+    }
+
+    fn after(&self, request: &Request, response: Response) -> Response {
+        let Some(session) = &request.session else {
+            return response;
+        };
+        let mut state = session.state();
+        if !state.changed {
+            return response;
+        }
+
+        if let Some(id) = state.cleared_id.take() {
+            self.forget(&id);
+        }
+        if state.values.is_empty() {
+            if let Some(id) = state.id.take() {
+                self.forget(&id);
+            }
+            if !state.had_cookie {
+                return response;
+            }
+            return response.with_cookie(&SetCookie::removal(&self.cookie_name).path("/"));
+        }
+
+        let cookie = SetCookie::new(&self.cookie_name, &self.save(&mut state))
+            .path("/")
+            .max_age(self.expiry)
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(SameSite::Lax);
+        if cookie.to_string().len() > MAX_COOKIE_SIZE {
+            log::warn!(
+                "request_id = {}, session cookie is over {MAX_COOKIE_SIZE} bytes, browsers will drop it",
+                request.id
+            );
+        }
+        response.with_cookie(&cookie)
+    }
+}
+
+impl fmt::Debug for Sessions {
+    // keeps the key and the sessions out of logs
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let store = match self.store {
+            Store::Signed(_) => "signed",
+            Store::Memory(_) => "memory",
+        };
+        f.debug_struct("Sessions")
+            .field("store", &store)
+            .field("cookie_name", &self.cookie_name)
+            .field("expiry", &self.expiry)
+            .field("secure", &self.secure)
+            .finish()
+    }
+}
+
+// the values a client's session holds, shared by every clone; changes are sent back with the
+// response to the request it came with
+#[derive(Debug, Clone)]
+pub struct Session(Arc<Mutex<State>>);
+
+#[derive(Debug)]
+struct State {
+    values: HashMap<String, String>,
+    // set for in-memory sessions once they've been stored
+    id: Option<String>,
+    // the ID of a session cleared while handling the request
+    cleared_id: Option<String>,
+    had_cookie: bool,
+    changed: bool,
+}
+
+impl Session {
+    pub fn get(&self, name: &str) -> Option<String> {
+        self.state().values.get(name).cloned()
+    }
+
+    pub fn insert(&self, name: &str, value: impl Into<String>) {
+        let mut state = self.state();
+        state.values.insert(name.to_owned(), value.into());
+        state.changed = true;
+    }
+
+    pub fn remove(&self, name: &str) -> Option<String> {
+        let mut state = self.state();
+        state.changed = true;
+        state.values.remove(name)
+    }
+
+    // forgets every value and tells the client to drop its cookie, e.g. on logout; values inserted
+    // afterwards start a session with a new ID
+    pub fn clear(&self) {
+        let mut state = self.state();
+        state.values.clear();
+        if let Some(id) = state.id.take() {
+            state.cleared_id = Some(id);
+        }
+        state.changed = true;
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        lock(&self.0)
+    }
+}
+
+// a handler that panicked while holding the lock doesn't make the sessions unusable
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn new_id() -> String {
+    let mut bytes = [0; ID_LEN];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("the system's random number generator failed");
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(cookie: Option<&str>) -> Request {
+        let cookie = cookie.map_or(String::new(), |cookie| format!("Cookie: {cookie}\r\n"));
+        format!("GET / HTTP/1.1\r\n{cookie}\r\n").parse().unwrap()
+    }
+
+    // runs a request through `sessions`, letting `handle` change its session, and returns the
+    // `Set-Cookie` value it was answered with
+    fn round_trip(
+        sessions: &Sessions,
+        cookie: Option<&str>,
+        handle: impl FnOnce(&Session),
+    ) -> Option<String> {
+        let mut request = request(cookie);
+        assert!(sessions.before(&mut request).is_none());
+        handle(request.session().unwrap());
+        let response = sessions.after(&request, Response::no_content());
+        let set_cookie = response.headers.iter().find_map(|header| match header {
+            crate::header::Header::Other(name, value) if name == "Set-Cookie" => {
+                Some(value.clone())
+            }
+            _ => None,
+        })?;
+        Some(set_cookie.split(';').next().unwrap().to_owned())
+    }
+
+    #[test]
+    fn signed_sessions_survive_round_trips_but_not_tampering() {
+        let sessions = Sessions::signed(&[7; 32]);
+        let cookie = round_trip(&sessions, None, |session| {
+            session.insert("user", "ada lovelace");
+            session.insert("role", "a&b=c");
+        })
+        .unwrap();
+
+        assert!(round_trip(&sessions, Some(&cookie), |session| {
+            assert_eq!(session.get("user").as_deref(), Some("ada lovelace"));
+            assert_eq!(session.get("role").as_deref(), Some("a&b=c"));
+        })
+        .is_none());
+
+        let tampered = cookie.replace("ada", "bob");
+        round_trip(&sessions, Some(&tampered), |session| {
+            assert_eq!(session.get("user"), None);
+        });
+        let other_key = Sessions::signed(&[8; 32]);
+        round_trip(&other_key, Some(&cookie), |session| {
+            assert_eq!(session.get("user"), None);
+        });
+    }
+
+    #[test]
+    fn in_memory_sessions_expire_and_clear() {
+        let sessions = Sessions::in_memory().expiry(Duration::from_millis(50));
+        let cookie = round_trip(&sessions, None, |session| session.insert("n", "1")).unwrap();
+        let (_, id) = cookie.split_once('=').unwrap();
+        assert_eq!(id.len(), ID_LEN * 2);
+
+        round_trip(&sessions, Some(&cookie), |session| {
+            assert_eq!(session.get("n").as_deref(), Some("1"));
+        });
+        assert_eq!(
+            round_trip(&sessions, Some(&cookie), Session::clear).as_deref(),
+            Some("butler_session=")
+        );
+        round_trip(&sessions, Some(&cookie), |session| {
+            assert_eq!(session.get("n"), None);
+        });
+
+        let cookie = round_trip(&sessions, None, |session| session.insert("n", "2")).unwrap();
+        std::thread::sleep(Duration::from_millis(60));
+        round_trip(&sessions, Some(&cookie), |session| {
+            assert_eq!(session.get("n"), None);
+        });
+    }
+}
@@ -0,0 +1,159 @@
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+// a request body too large to keep in memory, written to a temporary file as it arrived; the file
+// is removed once the last request referring to it is dropped
+#[derive(Debug)]
+pub(crate) struct SpooledFile {
+    path: PathBuf,
+    len: u64,
+}
+
+impl SpooledFile {
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+}
+
+impl Drop for SpooledFile {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(&self.path) {
+            log::warn!("failed to remove spooled body {:?}: {err}", self.path);
+        }
+    }
+}
+
+// collects a request body in memory until it grows past `threshold`, and in a temporary file from
+// then on
+#[derive(Debug)]
+pub(crate) struct Spooler {
+    threshold: u64,
+    memory: Vec<u8>,
+    file: Option<(File, SpooledFile)>,
+}
+
+// what a `Spooler` collected
+#[derive(Debug)]
+pub(crate) enum Spooled {
+    Memory(Vec<u8>),
+    File(SpooledFile),
+}
+
+impl Spooled {
+    pub(crate) fn len(&self) -> u64 {
+        match self {
+            Self::Memory(bytes) => bytes.len() as u64,
+            Self::File(file) => file.len,
+        }
+    }
+}
+
+impl Spooler {
+    pub(crate) fn new(threshold: u64) -> Self {
+        Self {
+            threshold,
+            memory: Vec::new(),
+            file: None,
+        }
+    }
+
+    pub(crate) fn len(&self) -> u64 {
+        match &self.file {
+            Some((_, spooled)) => spooled.len,
+            None => self.memory.len() as u64,
+        }
+    }
+
+    pub(crate) fn finish(self) -> io::Result<Spooled> {
+        match self.file {
+            Some((mut file, spooled)) => {
+                file.flush()?;
+                Ok(Spooled::File(spooled))
+            }
+            None => Ok(Spooled::Memory(self.memory)),
+        }
+    }
+}
+
+impl Write for Spooler {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.file.is_none() && (self.memory.len() + buf.len()) as u64 > self.threshold {
+            let (mut file, mut spooled) = create_spool_file()?;
+            file.write_all(&self.memory)?;
+            spooled.len = self.memory.len() as u64;
+            self.file = Some((file, spooled));
+            self.memory = Vec::new();
+        }
+
+        match &mut self.file {
+            Some((file, spooled)) => {
+                let n = file.write(buf)?;
+                spooled.len += n as u64;
+                Ok(n)
+            }
+            None => self.memory.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.file {
+            Some((file, _)) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+// a new file in the system's temporary directory, only readable by the server's user
+fn create_spool_file() -> io::Result<(File, SpooledFile)> {
+    static SPOOL_FILE_ID: AtomicUsize = AtomicUsize::new(0);
+
+    let path = env::temp_dir().join(format!(
+        "butler-body-{}-{}",
+        process::id(),
+        SPOOL_FILE_ID.fetch_add(1, Ordering::Relaxed)
+    ));
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let file = options.open(&path)?;
+    Ok((file, SpooledFile { path, len: 0 }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spooler_moves_to_a_file_past_its_threshold() {
+        let mut spooler = Spooler::new(8);
+        spooler.write_all(b"12345").unwrap();
+        assert!(spooler.file.is_none());
+        spooler.write_all(b"67890").unwrap();
+        assert_eq!(spooler.len(), 10);
+
+        let Spooled::File(spooled) = spooler.finish().unwrap() else {
+            panic!("a body past the threshold was kept in memory");
+        };
+        assert_eq!(spooled.len(), 10);
+        assert_eq!(fs::read(spooled.path()).unwrap(), b"1234567890");
+
+        let path = spooled.path().to_owned();
+        drop(spooled);
+        assert!(!path.exists());
+
+        let mut spooler = Spooler::new(8);
+        spooler.write_all(b"12345678").unwrap();
+        assert!(matches!(spooler.finish().unwrap(), Spooled::Memory(body) if body == b"12345678"));
+    }
+}
@@ -0,0 +1,143 @@
+use std::{
+    fmt::Write as _,
+    io::{self, Read},
+    sync::mpsc::{Receiver, RecvTimeoutError},
+    time::Duration,
+};
+
+const DEFAULT_HEARTBEAT: Duration = Duration::from_secs(15);
+
+// one server-sent event, e.g. `Event::new("42").event("price").id("7")`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Event {
+    data: String,
+    event: Option<String>,
+    id: Option<String>,
+    retry: Option<Duration>,
+}
+
+impl Event {
+    // `data` may span several lines, the browser gets them joined by '\n'
+    pub fn new(data: impl Into<String>) -> Self {
+        Self {
+            data: data.into(),
+            ..Self::default()
+        }
+    }
+
+    // the type of event, which browsers dispatch to `addEventListener(name, ..)` instead of `onmessage`
+    pub fn event(mut self, name: impl Into<String>) -> Self {
+        self.event = Some(name.into());
+        self
+    }
+
+    // sent back by a browser in `Last-Event-ID` when it reconnects
+    pub fn id(mut self, id: impl Into<String>) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    // how long a browser waits before reconnecting once the stream ends
+    pub fn retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    fn encode(&self) -> String {
+        let mut s = String::new();
+        // a line break in a field would end it early, so those are dropped from the single-line ones
+        if let Some(event) = &self.event {
+            let _ = writeln!(s, "event: {}", event.replace(['\r', '\n'], ""));
+        }
+        if let Some(id) = &self.id {
+            let _ = writeln!(s, "id: {}", id.replace(['\r', '\n', '\0'], ""));
+        }
+        if let Some(retry) = self.retry {
+            let _ = writeln!(s, "retry: {}", retry.as_millis());
+        }
+        for line in self.data.split('\n') {
+            let _ = writeln!(s, "data: {}", line.strip_suffix('\r').unwrap_or(line));
+        }
+        s.push('\n');
+        s
+    }
+}
+
+// a `text/event-stream` body made of the events sent on a channel, see `Response::sse`; it ends
+// once every sender has been dropped, and a comment is sent whenever it has been quiet for a while
+// so that proxies don't give up on the connection and a client that has gone away is noticed
+#[derive(Debug)]
+pub struct EventStream {
+    events: Receiver<Event>,
+    heartbeat: Duration,
+    // the part of the last event that didn't fit in the reader's buffer
+    pending: io::Cursor<Vec<u8>>,
+}
+
+impl EventStream {
+    pub fn new(events: Receiver<Event>) -> Self {
+        Self {
+            events,
+            heartbeat: DEFAULT_HEARTBEAT,
+            pending: io::Cursor::default(),
+        }
+    }
+
+    pub fn heartbeat(mut self, interval: Duration) -> Self {
+        self.heartbeat = interval;
+        self
+    }
+}
+
+impl From<Receiver<Event>> for EventStream {
+    fn from(events: Receiver<Event>) -> Self {
+        Self::new(events)
+    }
+}
+
+impl Read for EventStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.position() as usize == self.pending.get_ref().len() {
+            let next = match self.events.recv_timeout(self.heartbeat) {
+                Ok(event) => event.encode(),
+                Err(RecvTimeoutError::Timeout) => ":\n\n".to_owned(),
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+            };
+            self.pending = io::Cursor::new(next.into_bytes());
+        }
+        self.pending.read(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+
+    #[test]
+    fn event_stream_encodes_events_and_heartbeats() {
+        let (sender, receiver) = mpsc::channel();
+        sender
+            .send(Event::new("one\ntwo").event("update").id("1"))
+            .unwrap();
+        sender
+            .send(Event::new("three").retry(Duration::from_secs(5)))
+            .unwrap();
+
+        let mut stream = EventStream::new(receiver).heartbeat(Duration::from_millis(10));
+        let mut read = |len: usize| {
+            let mut buf = vec![0; len];
+            let n = stream.read(&mut buf).unwrap();
+            String::from_utf8(buf[..n].to_vec()).unwrap()
+        };
+
+        assert_eq!(read(1024), "event: update\nid: 1\ndata: one\ndata: two\n\n");
+        assert_eq!(read(8), "retry: 5");
+        assert_eq!(read(1024), "000\ndata: three\n\n");
+        assert_eq!(read(1024), ":\n\n");
+
+        drop(sender);
+        assert_eq!(read(1024), "");
+    }
+}
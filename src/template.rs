@@ -0,0 +1,282 @@
+use std::{collections::HashMap, fs, path::Path, str::FromStr};
+
+use anyhow::{anyhow, Context};
+
+use crate::response::html_escape;
+
+// a page whose `{{name}}` tags are filled in from a `TemplateContext` when it's rendered, a small
+// part of Mustache: `{{name}}` is replaced with the value HTML-escaped and `{{{name}}}` with it as
+// it is, `{{#name}}..{{/name}}` is rendered once for each context in a list, or once when the value
+// is true or non-empty text, and `{{^name}}..{{/name}}` only when it's none of those; inside a
+// section names are looked up in its context first, then in the ones around it, and `{{! ..}}` is
+// a comment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    Text(String),
+    Value {
+        name: String,
+        escape: bool,
+    },
+    Section {
+        name: String,
+        inverted: bool,
+        nodes: Vec<Node>,
+    },
+}
+
+impl Template {
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        fs::read_to_string(path)
+            .with_context(|| anyhow!("failed to read template {path:?}"))?
+            .parse()
+            .with_context(|| anyhow!("failed to parse template {path:?}"))
+    }
+
+    pub fn render(&self, context: &TemplateContext) -> String {
+        let mut out = String::new();
+        render_nodes(&self.nodes, &mut vec![context], &mut out);
+        out
+    }
+}
+
+impl FromStr for Template {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rest = s;
+        let nodes = parse_nodes(&mut rest, None)?;
+        Ok(Self { nodes })
+    }
+}
+
+// the nodes up to the `{{/name}}` closing `section`, or to the end of the template without one
+fn parse_nodes(rest: &mut &str, section: Option<&str>) -> anyhow::Result<Vec<Node>> {
+    let mut nodes = Vec::new();
+    loop {
+        let Some(start) = rest.find("{{") else {
+            if let Some(section) = section {
+                return Err(anyhow!("section {section:?} is never closed"));
+            }
+            if !rest.is_empty() {
+                nodes.push(Node::Text(rest.to_string()));
+            }
+            *rest = "";
+            return Ok(nodes);
+        };
+        if start > 0 {
+            nodes.push(Node::Text(rest[..start].to_owned()));
+        }
+        *rest = &rest[start..];
+
+        let (tag, raw) = match rest.strip_prefix("{{{") {
+            Some(after) => {
+                let end = after.find("}}}").context("a `{{{` tag is never closed")?;
+                *rest = &after[end + 3..];
+                (after[..end].trim(), true)
+            }
+            None => {
+                let after = &rest[2..];
+                let end = after.find("}}").context("a `{{` tag is never closed")?;
+                *rest = &after[end + 2..];
+                (after[..end].trim(), false)
+            }
+        };
+
+        let name = |tag: &str| {
+            let name = tag.trim();
+            match name.is_empty() {
+                true => Err(anyhow!("a tag has no name")),
+                false => Ok(name.to_owned()),
+            }
+        };
+        match tag.chars().next() {
+            _ if raw => nodes.push(Node::Value {
+                name: name(tag)?,
+                escape: false,
+            }),
+            Some('!') => {}
+            Some('&') => nodes.push(Node::Value {
+                name: name(&tag[1..])?,
+                escape: false,
+            }),
+            Some(kind @ ('#' | '^')) => {
+                let name = name(&tag[1..])?;
+                let inner = parse_nodes(rest, Some(&name))?;
+                nodes.push(Node::Section {
+                    name,
+                    inverted: kind == '^',
+                    nodes: inner,
+                });
+            }
+            Some('/') => {
+                let closed = name(&tag[1..])?;
+                return match section {
+                    Some(section) if section == closed => Ok(nodes),
+                    _ => Err(anyhow!("{{{{/{closed}}}}} doesn't close an open section")),
+                };
+            }
+            _ => nodes.push(Node::Value {
+                name: name(tag)?,
+                escape: true,
+            }),
+        }
+    }
+}
+
+fn render_nodes<'a>(nodes: &'a [Node], stack: &mut Vec<&'a TemplateContext>, out: &mut String) {
+    for node in nodes {
+        match node {
+            Node::Text(text) => out.push_str(text),
+            Node::Value { name, escape } => {
+                let value = match lookup(stack, name) {
+                    Some(Value::Text(text)) => text.clone(),
+                    Some(Value::Bool(value)) => value.to_string(),
+                    Some(Value::List(_)) | None => String::new(),
+                };
+                match escape {
+                    true => out.push_str(&html_escape(&value)),
+                    false => out.push_str(&value),
+                }
+            }
+            Node::Section {
+                name,
+                inverted,
+                nodes,
+            } => {
+                let value = lookup(stack, name);
+                let shown = match value {
+                    Some(Value::Text(text)) => !text.is_empty(),
+                    Some(Value::Bool(value)) => *value,
+                    Some(Value::List(items)) => !items.is_empty(),
+                    None => false,
+                };
+                match (value, inverted) {
+                    (_, true) if !shown => render_nodes(nodes, stack, out),
+                    (Some(Value::List(items)), false) => {
+                        for item in items {
+                            stack.push(item);
+                            render_nodes(nodes, stack, out);
+                            stack.pop();
+                        }
+                    }
+                    (_, false) if shown => render_nodes(nodes, stack, out),
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+fn lookup<'a>(stack: &[&'a TemplateContext], name: &str) -> Option<&'a Value> {
+    stack.iter().rev().find_map(|context| context.0.get(name))
+}
+
+// the values a `Template` is rendered with, e.g.
+// `TemplateContext::new().with("title", "Hello").with("admin", true)`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TemplateContext(HashMap<String, Value>);
+
+impl TemplateContext {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, name: &str, value: impl Into<Value>) -> Self {
+        self.insert(name, value);
+        self
+    }
+
+    pub fn insert(&mut self, name: &str, value: impl Into<Value>) {
+        self.0.insert(name.to_owned(), value.into());
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Text(String),
+    Bool(bool),
+    // for sections repeated once for each of them
+    List(Vec<TemplateContext>),
+}
+
+impl From<&str> for Value {
+    fn from(text: &str) -> Self {
+        Self::Text(text.to_owned())
+    }
+}
+
+impl From<String> for Value {
+    fn from(text: String) -> Self {
+        Self::Text(text)
+    }
+}
+
+impl From<u64> for Value {
+    fn from(number: u64) -> Self {
+        Self::Text(number.to_string())
+    }
+}
+
+impl From<i64> for Value {
+    fn from(number: i64) -> Self {
+        Self::Text(number.to_string())
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Self::Bool(value)
+    }
+}
+
+impl From<Vec<TemplateContext>> for Value {
+    fn from(items: Vec<TemplateContext>) -> Self {
+        Self::List(items)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn templates_fill_in_values_and_sections() {
+        let template: Template = concat!(
+            "<h1>{{ title }}</h1>{{! not rendered }}",
+            "{{#items}}<li>{{name}} by {{author}}</li>{{/items}}",
+            "{{^items}}nothing{{/items}}{{#admin}} [admin]{{/admin}} {{{raw}}}{{&raw}}"
+        )
+        .parse()
+        .unwrap();
+        let context = TemplateContext::new()
+            .with("title", "Tom & Jerry")
+            .with("author", "anonymous")
+            .with(
+                "items",
+                vec![
+                    TemplateContext::new().with("name", "<b>"),
+                    TemplateContext::new()
+                        .with("name", "b")
+                        .with("author", "ada"),
+                ],
+            )
+            .with("admin", true)
+            .with("raw", "<br>");
+        assert_eq!(
+            template.render(&context),
+            "<h1>Tom &amp; Jerry</h1><li>&lt;b&gt; by anonymous</li><li>b by ada</li> [admin] <br><br>"
+        );
+
+        let empty = TemplateContext::new().with("items", Vec::new());
+        assert_eq!(template.render(&empty), "<h1></h1>nothing ");
+
+        assert!("{{#open}}never closed".parse::<Template>().is_err());
+        assert!("{{#a}}{{/b}}".parse::<Template>().is_err());
+        assert!("{{unclosed".parse::<Template>().is_err());
+    }
+}
@@ -0,0 +1,185 @@
+use std::{
+    io::{BufReader, Read, Write},
+    net::{SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context};
+
+use crate::{
+    client::{read_response_head, ChunkedReader},
+    request::Method,
+    response::StatusCode,
+    server::{Config, Server},
+};
+
+// how long a test waits on the server before giving up on it
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// runs a server on an ephemeral port for as long as it's kept, so tests can make real requests to
+// it, e.g. `TestServer::start(Server::bind("127.0.0.1:0", config)?.route(..))?.get("/")?`; the
+// server stops accepting connections once it's dropped
+#[derive(Debug)]
+pub struct TestServer {
+    addr: SocketAddr,
+    shutting_down: Arc<AtomicBool>,
+}
+
+impl TestServer {
+    // serves the built-in routes with `config` on 127.0.0.1
+    pub fn new(config: Config) -> anyhow::Result<Self> {
+        Self::start(Server::bind("127.0.0.1:0", config)?)
+    }
+
+    // runs `server`, which has to listen on a TCP port first, on a thread of its own
+    pub fn start(server: Server) -> anyhow::Result<Self> {
+        let addr = server.local_addr()?;
+        let shutting_down = server.shutdown_flag();
+        thread::Builder::new()
+            .name(format!("test-server-{addr}"))
+            .spawn(move || {
+                if let Err(err) = server.run() {
+                    log::error!("test server failed: {err:#}");
+                }
+            })
+            .context("failed to spawn the test server thread")?;
+        Ok(Self {
+            addr,
+            shutting_down,
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    // a request to `path` on a connection of its own
+    pub fn get(&self, path: &str) -> anyhow::Result<TestResponse> {
+        self.request(Method::Get, path, &[], b"")
+    }
+
+    // a request with `headers` and `body` on a connection of its own, which is closed afterwards;
+    // `Host`, `Connection` and `Content-Length` are added to it
+    pub fn request(
+        &self,
+        method: Method,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> anyhow::Result<TestResponse> {
+        let mut headers = headers.to_vec();
+        headers.push(("Connection", "close"));
+        self.connect()?.request(method, path, &headers, body)
+    }
+
+    // a connection requests are sent one after the other on, as long as the server keeps it open
+    pub fn connect(&self) -> anyhow::Result<TestConnection> {
+        let stream = TcpStream::connect_timeout(&self.addr, TIMEOUT)
+            .context("failed to connect to the test server")?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        Ok(TestConnection {
+            reader: BufReader::new(stream),
+        })
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
+}
+
+// a kept-alive connection to a `TestServer`
+#[derive(Debug)]
+pub struct TestConnection {
+    reader: BufReader<TcpStream>,
+}
+
+impl TestConnection {
+    // sends a request and reads the response to it; `Host` and `Content-Length` are added unless
+    // `headers` has them
+    pub fn request(
+        &mut self,
+        method: Method,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> anyhow::Result<TestResponse> {
+        let has = |name: &str| headers.iter().any(|(n, _)| n.eq_ignore_ascii_case(name));
+        let mut head = format!("{method} {path} HTTP/1.1\r\n");
+        if !has("host") {
+            head.push_str("Host: localhost\r\n");
+        }
+        for (name, value) in headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        if !has("content-length") && !has("transfer-encoding") && !body.is_empty() {
+            head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        }
+        head.push_str("\r\n");
+
+        let stream = self.reader.get_mut();
+        stream.write_all(head.as_bytes())?;
+        stream.write_all(body)?;
+        stream.flush()?;
+        self.read_response(method)
+    }
+
+    fn read_response(&mut self, method: Method) -> anyhow::Result<TestResponse> {
+        let (status, headers) = read_response_head(&mut self.reader)?;
+        let response = TestResponse {
+            status,
+            headers,
+            body: Vec::new(),
+        };
+
+        let mut body = Vec::new();
+        let has_body = !matches!(status, StatusCode::NoContent | StatusCode::NotModified);
+        if method != Method::Head && has_body {
+            let chunked = response
+                .header("transfer-encoding")
+                .is_some_and(|coding| coding.to_lowercase().contains("chunked"));
+            let length = response
+                .header("content-length")
+                .map(|length| length.parse::<u64>())
+                .transpose()
+                .map_err(|_| anyhow!("the server sent an invalid Content-Length"))?;
+            match (chunked, length) {
+                (true, _) => ChunkedReader::new(&mut self.reader).read_to_end(&mut body)?,
+                (false, Some(length)) => (&mut self.reader).take(length).read_to_end(&mut body)?,
+                (false, None) => self.reader.read_to_end(&mut body)?,
+            };
+        }
+        Ok(TestResponse { body, ..response })
+    }
+}
+
+// what a `TestServer` answered with, its body with any chunked framing removed
+#[derive(Debug, Clone)]
+pub struct TestResponse {
+    pub status: StatusCode,
+    // in the order they were sent, names as the server wrote them
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl TestResponse {
+    // the value of the first header called `name`, ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // the body as text, with anything that isn't UTF-8 replaced
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
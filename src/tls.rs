@@ -0,0 +1,252 @@
+use std::{fmt, path::Path, sync::Arc};
+
+use anyhow::{anyhow, Context};
+use rustls::{
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{danger::ClientCertVerifier, WebPkiClientVerifier},
+    RootCertStore, ServerConfig,
+};
+
+pub(crate) const ALPN_HTTP2: &[u8] = b"h2";
+
+// the certificate chain and private key connections are encrypted with
+#[derive(Clone)]
+pub struct TlsConfig(pub(crate) Arc<ServerConfig>);
+
+// whether clients have to present a certificate signed by the CA given to
+// `TlsConfig::from_pem_files_with_client_auth`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientAuth {
+    // the handshake fails without one
+    Required,
+    // clients without one are let in too, for `Config::client_cert_paths` to turn away where
+    // one is needed
+    Optional,
+}
+
+impl TlsConfig {
+    // `cert_path` holds the PEM encoded certificate chain, leaf first, and `key_path` its private key
+    pub fn from_pem_files(cert_path: &Path, key_path: &Path) -> anyhow::Result<Self> {
+        Self::build(cert_path, key_path, None)
+    }
+
+    // like `from_pem_files`, but asks clients for a certificate, which is verified against the
+    // PEM encoded CA certificates in `ca_path`; the subject of the one a client presented is
+    // `Request::client_cert_subject`
+    pub fn from_pem_files_with_client_auth(
+        cert_path: &Path,
+        key_path: &Path,
+        ca_path: &Path,
+        auth: ClientAuth,
+    ) -> anyhow::Result<Self> {
+        let mut roots = RootCertStore::empty();
+        for cert in read_certs(ca_path)? {
+            roots
+                .add(cert)
+                .with_context(|| anyhow!("invalid CA certificate in {ca_path:?}"))?;
+        }
+
+        let builder =
+            WebPkiClientVerifier::builder_with_provider(Arc::new(roots), Arc::new(provider()));
+        let builder = match auth {
+            ClientAuth::Required => builder,
+            ClientAuth::Optional => builder.allow_unauthenticated(),
+        };
+        let verifier = builder
+            .build()
+            .context("failed to set up client certificate verification")?;
+        Self::build(cert_path, key_path, Some(verifier))
+    }
+
+    fn build(
+        cert_path: &Path,
+        key_path: &Path,
+        verifier: Option<Arc<dyn ClientCertVerifier>>,
+    ) -> anyhow::Result<Self> {
+        let certs = read_certs(cert_path)?;
+        let key = PrivateKeyDer::from_pem_file(key_path)
+            .with_context(|| anyhow!("failed to read private key from {key_path:?}"))?;
+
+        let builder = ServerConfig::builder_with_provider(Arc::new(provider()))
+            .with_safe_default_protocol_versions()
+            .context("failed to select TLS protocol versions")?;
+        let builder = match verifier {
+            Some(verifier) => builder.with_client_cert_verifier(verifier),
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder
+            .with_single_cert(certs, key)
+            .context("invalid certificate or private key")?;
+
+        // clients that offer HTTP/2 get it, the rest fall back to HTTP/1.1
+        config.alpn_protocols = vec![ALPN_HTTP2.to_vec(), b"http/1.1".to_vec()];
+
+        Ok(Self(Arc::new(config)))
+    }
+}
+
+fn provider() -> rustls::crypto::CryptoProvider {
+    rustls::crypto::ring::default_provider()
+}
+
+fn read_certs(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| anyhow!("failed to read certificates from {path:?}"))?;
+    if certs.is_empty() {
+        return Err(anyhow!("{path:?} contains no certificates"));
+    }
+    Ok(certs)
+}
+
+impl fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsConfig").finish_non_exhaustive()
+    }
+}
+
+// the subject of a DER encoded X.509 certificate as an RFC 4514 string, most specific name first,
+// e.g. `CN=alice,O=Example,C=NL`; `None` when the certificate can't be read
+pub(crate) fn cert_subject(cert: &[u8]) -> Option<String> {
+    let mut input = cert;
+    let (_, mut cert) = der_next(&mut input, SEQUENCE)?;
+    let (_, mut tbs) = der_next(&mut cert, SEQUENCE)?;
+    // the version is only there for v2 and v3 certificates
+    if tbs.first() == Some(&0xa0) {
+        der_any(&mut tbs)?;
+    }
+    // the serial number, signature algorithm, issuer and validity come before the subject
+    for _ in 0..4 {
+        der_any(&mut tbs)?;
+    }
+    let (_, mut name) = der_next(&mut tbs, SEQUENCE)?;
+
+    let mut rdns = Vec::new();
+    while !name.is_empty() {
+        let (_, mut set) = der_next(&mut name, SET)?;
+        let mut attributes = Vec::new();
+        while !set.is_empty() {
+            let (_, mut attribute) = der_next(&mut set, SEQUENCE)?;
+            let (_, oid) = der_next(&mut attribute, OID)?;
+            let encoded = attribute;
+            let (tag, value) = der_any(&mut attribute)?;
+            let value = match tag {
+                // UTF8String, PrintableString, TeletexString and IA5String
+                0x0c | 0x13 | 0x14 | 0x16 => escape_dn_value(&String::from_utf8_lossy(value)),
+                // anything else is written as the hex of its DER encoding
+                _ => encoded.iter().fold("#".to_owned(), |mut hex, byte| {
+                    hex.push_str(&format!("{byte:02x}"));
+                    hex
+                }),
+            };
+            attributes.push(format!("{}={value}", attribute_name(oid)));
+        }
+        rdns.push(attributes.join("+"));
+    }
+    rdns.reverse();
+    Some(rdns.join(","))
+}
+
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const OID: u8 = 0x06;
+
+// the contents of the next DER value in `input`, which has to be tagged `tag`
+fn der_next<'a>(input: &mut &'a [u8], tag: u8) -> Option<(u8, &'a [u8])> {
+    der_any(input).filter(|(found, _)| *found == tag)
+}
+
+// the tag and contents of the next DER value in `input`, whatever it is
+fn der_any<'a>(input: &mut &'a [u8]) -> Option<(u8, &'a [u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, mut rest) = rest.split_first()?;
+    let len = match first {
+        0..=0x7f => usize::from(first),
+        // the long form gives the number of length bytes that follow
+        0x81..=0x84 => {
+            let (bytes, after) = rest.split_at_checked(usize::from(first & 0x7f))?;
+            rest = after;
+            bytes
+                .iter()
+                .fold(0usize, |len, byte| len << 8 | usize::from(*byte))
+        }
+        _ => return None,
+    };
+    let (contents, rest) = rest.split_at_checked(len)?;
+    *input = rest;
+    Some((tag, contents))
+}
+
+// the short name of a well-known attribute, or its dotted OID
+fn attribute_name(oid: &[u8]) -> String {
+    match oid {
+        [0x55, 0x04, 0x03] => "CN".to_owned(),
+        [0x55, 0x04, 0x06] => "C".to_owned(),
+        [0x55, 0x04, 0x07] => "L".to_owned(),
+        [0x55, 0x04, 0x08] => "ST".to_owned(),
+        [0x55, 0x04, 0x0a] => "O".to_owned(),
+        [0x55, 0x04, 0x0b] => "OU".to_owned(),
+        [0x09, 0x92, 0x26, 0x89, 0x93, 0xf2, 0x2c, 0x64, 0x01, 0x19] => "DC".to_owned(),
+        [0x09, 0x92, 0x26, 0x89, 0x93, 0xf2, 0x2c, 0x64, 0x01, 0x01] => "UID".to_owned(),
+        _ => {
+            let mut arcs = Vec::new();
+            let mut arc = 0u64;
+            for byte in oid {
+                arc = arc << 7 | u64::from(byte & 0x7f);
+                if byte & 0x80 == 0 {
+                    arcs.push(arc);
+                    arc = 0;
+                }
+            }
+            // the first byte packs the first two arcs together
+            let mut dotted = match arcs.first() {
+                Some(&first) if first < 80 => format!("{}.{}", first / 40, first % 40),
+                Some(&first) => format!("2.{}", first - 80),
+                None => return String::new(),
+            };
+            for arc in &arcs[1..] {
+                dotted.push_str(&format!(".{arc}"));
+            }
+            dotted
+        }
+    }
+}
+
+fn escape_dn_value(value: &str) -> String {
+    let chars: Vec<char> = value.chars().collect();
+    let mut escaped = String::new();
+    for (i, &c) in chars.iter().enumerate() {
+        let edge = (i == 0 && matches!(c, ' ' | '#')) || (i + 1 == chars.len() && c == ' ');
+        if edge || matches!(c, ',' | '+' | '"' | '\\' | '<' | '>' | ';') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cert_subject_reads_the_subject_name() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/certs/client.pem");
+        let cert = CertificateDer::from_pem_file(path).unwrap();
+        assert_eq!(
+            cert_subject(&cert).as_deref(),
+            Some("CN=alice,O=Butler Tests,C=NL")
+        );
+
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/certs/cert.pem");
+        let cert = CertificateDer::from_pem_file(path).unwrap();
+        assert_eq!(cert_subject(&cert).as_deref(), Some("CN=localhost"));
+
+        assert_eq!(cert_subject(&cert[..cert.len() / 2]), None);
+        assert_eq!(
+            attribute_name(&[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d]),
+            "1.2.840.113549"
+        );
+        assert_eq!(escape_dn_value(" a,b=ü "), "\\ a\\,b=ü\\ ");
+    }
+}
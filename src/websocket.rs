@@ -0,0 +1,496 @@
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    sync::Arc,
+};
+
+use ring::digest;
+
+use crate::{
+    base64,
+    request::{Method, Request, Version},
+    response::{Response, StatusCode},
+};
+
+// appended to the client's key before hashing it, as RFC 6455 section 1.3 specifies
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+// control frames can't be fragmented and carry at most this much
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+// the close codes the server itself sends
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_INVALID_DATA: u16 = 1007;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+// serves a WebSocket connection once its handshake is done, see `Router::websocket`
+pub(crate) type SessionHandler =
+    dyn Fn(&Request, &mut WebSocket<'_>) -> anyhow::Result<()> + Send + Sync;
+
+// what a connection is once it's been handed over from HTTP
+pub(crate) trait Stream: Read + Write {}
+
+impl<T: Read + Write> Stream for T {}
+
+// takes over the connection after a `101 Switching Protocols` response has been sent
+pub(crate) struct Upgrade {
+    request: Request,
+    handler: Arc<SessionHandler>,
+}
+
+impl Upgrade {
+    pub(crate) fn run(self, stream: &mut dyn Stream) -> anyhow::Result<()> {
+        (self.handler)(&self.request, &mut WebSocket::new(stream))
+    }
+}
+
+impl fmt::Debug for Upgrade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Upgrade")
+            .field("request", &self.request.id)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    // answered with a pong by `WebSocket::recv` before it's returned
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    // the status code and reason the closing side gave, if any
+    Close(Option<(u16, String)>),
+}
+
+// one end of a WebSocket connection, as handed to the handler registered with `Router::websocket`;
+// it belongs to the worker serving the connection until the handler returns
+pub struct WebSocket<'a> {
+    stream: &'a mut dyn Stream,
+    max_message_size: usize,
+    // whether we've sent a close frame, after which only control frames are read
+    close_sent: bool,
+    // whether the client has sent a close frame, after which there's nothing left to read
+    close_received: bool,
+    // the opcode and payload of a message whose fragments are still arriving; control frames
+    // can come in between them
+    fragmented: Option<(u8, Vec<u8>)>,
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+impl<'a> WebSocket<'a> {
+    pub(crate) fn new(stream: &'a mut dyn Stream) -> Self {
+        Self {
+            stream,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            close_sent: false,
+            close_received: false,
+            fragmented: None,
+        }
+    }
+
+    // messages larger than this, across all their fragments, close the connection with a 1009
+    pub fn set_max_message_size(&mut self, size: usize) {
+        self.max_message_size = size;
+    }
+
+    // waits for the next message, returning `None` once the connection has been closed; pings
+    // are answered and a close is echoed before they're returned, and a client breaking the
+    // protocol is sent a close frame and reported as an `InvalidData` error
+    pub fn recv(&mut self) -> io::Result<Option<Message>> {
+        if self.close_received {
+            return Ok(None);
+        }
+
+        loop {
+            let Some(frame) = self.read_frame()? else {
+                return Ok(None);
+            };
+
+            let data = match frame.opcode {
+                0x8 => return self.received_close(frame.payload).map(Some),
+                0x9 => {
+                    if !self.close_sent {
+                        self.write_frame(0xa, &frame.payload)?;
+                    }
+                    return Ok(Some(Message::Ping(frame.payload)));
+                }
+                0xa => return Ok(Some(Message::Pong(frame.payload))),
+                0x0 => match self.fragmented.as_mut() {
+                    Some((_, data)) => {
+                        if data.len() + frame.payload.len() > self.max_message_size {
+                            return Err(self.fail(CLOSE_TOO_BIG, "message is too large"));
+                        }
+                        data.extend_from_slice(&frame.payload);
+                        if !frame.fin {
+                            continue;
+                        }
+                        self.fragmented.take().unwrap()
+                    }
+                    None => return Err(self.fail(CLOSE_PROTOCOL_ERROR, "unexpected continuation")),
+                },
+                0x1 | 0x2 if self.fragmented.is_some() => {
+                    return Err(self.fail(CLOSE_PROTOCOL_ERROR, "message interrupted by another"))
+                }
+                0x1 | 0x2 if !frame.fin => {
+                    self.fragmented = Some((frame.opcode, frame.payload));
+                    continue;
+                }
+                0x1 | 0x2 => (frame.opcode, frame.payload),
+                opcode => {
+                    return Err(
+                        self.fail(CLOSE_PROTOCOL_ERROR, &format!("unknown opcode {opcode:#x}"))
+                    )
+                }
+            };
+
+            // data that arrives after we've asked to close is dropped
+            if self.close_sent {
+                continue;
+            }
+            return match data {
+                (0x1, payload) => match String::from_utf8(payload) {
+                    Ok(text) => Ok(Some(Message::Text(text))),
+                    Err(_) => Err(self.fail(CLOSE_INVALID_DATA, "text message isn't UTF-8")),
+                },
+                (_, payload) => Ok(Some(Message::Binary(payload))),
+            };
+        }
+    }
+
+    pub fn send(&mut self, message: Message) -> io::Result<()> {
+        match message {
+            Message::Text(text) => self.write_frame(0x1, text.as_bytes()),
+            Message::Binary(data) => self.write_frame(0x2, &data),
+            Message::Ping(data) => self.write_control(0x9, data),
+            Message::Pong(data) => self.write_control(0xa, data),
+            Message::Close(None) => self.send_close(Vec::new()),
+            Message::Close(Some((code, reason))) => self.send_close(close_payload(code, &reason)),
+        }
+    }
+
+    // starts the closing handshake, `recv` returns the client's answer and then `None`
+    pub fn close(&mut self, code: u16, reason: &str) -> io::Result<()> {
+        self.send(Message::Close(Some((code, reason.to_owned()))))
+    }
+
+    fn received_close(&mut self, payload: Vec<u8>) -> io::Result<Message> {
+        self.close_received = true;
+        let close = match payload.len() {
+            0 => None,
+            1 => return Err(self.fail(CLOSE_PROTOCOL_ERROR, "close frame is truncated")),
+            _ => {
+                let code = u16::from_be_bytes([payload[0], payload[1]]);
+                let Ok(reason) = String::from_utf8(payload[2..].to_vec()) else {
+                    return Err(self.fail(CLOSE_INVALID_DATA, "close reason isn't UTF-8"));
+                };
+                Some((code, reason))
+            }
+        };
+
+        // the client's code is echoed, as the standard suggests
+        if !self.close_sent {
+            let code = close.as_ref().map_or(CLOSE_NORMAL, |(code, _)| *code);
+            self.send_close(close_payload(code, ""))?;
+        }
+        Ok(Message::Close(close))
+    }
+
+    fn send_close(&mut self, payload: Vec<u8>) -> io::Result<()> {
+        if self.close_sent {
+            return Ok(());
+        }
+        self.write_control(0x8, payload)?;
+        self.close_sent = true;
+        Ok(())
+    }
+
+    // tells the client why the connection is being given up on, returning the error to report
+    fn fail(&mut self, code: u16, reason: &str) -> io::Error {
+        log::warn!("closing WebSocket connection: {reason}");
+        // the connection is closed either way, so a failure to say why doesn't matter
+        let _ = self.send_close(close_payload(code, ""));
+        self.close_received = true;
+        io::Error::new(io::ErrorKind::InvalidData, reason.to_owned())
+    }
+
+    // `None` if the client closed the connection between frames
+    fn read_frame(&mut self) -> io::Result<Option<Frame>> {
+        let mut head = [0; 2];
+        match self.stream.read(&mut head[..1])? {
+            0 => {
+                self.close_received = true;
+                return Ok(None);
+            }
+            _ => self.stream.read_exact(&mut head[1..])?,
+        }
+
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0f;
+        if head[0] & 0x70 != 0 {
+            return Err(self.fail(CLOSE_PROTOCOL_ERROR, "reserved bits are set"));
+        }
+        // clients have to mask their frames, so that what they send can't pass for something else
+        // to a proxy in between
+        if head[1] & 0x80 == 0 {
+            return Err(self.fail(CLOSE_PROTOCOL_ERROR, "client frame isn't masked"));
+        }
+
+        let len = match head[1] & 0x7f {
+            126 => {
+                let mut len = [0; 2];
+                self.stream.read_exact(&mut len)?;
+                u64::from(u16::from_be_bytes(len))
+            }
+            127 => {
+                let mut len = [0; 8];
+                self.stream.read_exact(&mut len)?;
+                u64::from_be_bytes(len)
+            }
+            len => u64::from(len),
+        };
+        let is_control = opcode & 0x8 != 0;
+        if is_control && (!fin || len > MAX_CONTROL_PAYLOAD as u64) {
+            return Err(self.fail(
+                CLOSE_PROTOCOL_ERROR,
+                "control frame is fragmented or too large",
+            ));
+        }
+        if len > self.max_message_size as u64 {
+            return Err(self.fail(CLOSE_TOO_BIG, "message is too large"));
+        }
+
+        let mut mask = [0; 4];
+        self.stream.read_exact(&mut mask)?;
+        let mut payload = vec![0; len as usize];
+        self.stream.read_exact(&mut payload)?;
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+
+        Ok(Some(Frame {
+            fin,
+            opcode,
+            payload,
+        }))
+    }
+
+    fn write_control(&mut self, opcode: u8, payload: Vec<u8>) -> io::Result<()> {
+        if payload.len() > MAX_CONTROL_PAYLOAD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("control frames carry at most {MAX_CONTROL_PAYLOAD} bytes"),
+            ));
+        }
+        self.write_frame(opcode, &payload)
+    }
+
+    // frames from the server are sent whole and unmasked
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        if self.close_sent {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "the WebSocket connection is closing",
+            ));
+        }
+
+        let mut frame = Vec::with_capacity(payload.len() + 10);
+        frame.push(0x80 | opcode);
+        match payload.len() {
+            len @ 0..=125 => frame.push(len as u8),
+            len @ 126..=0xffff => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+
+        self.stream.write_all(&frame)?;
+        self.stream.flush()
+    }
+}
+
+impl fmt::Debug for WebSocket<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WebSocket")
+            .field("max_message_size", &self.max_message_size)
+            .field("close_sent", &self.close_sent)
+            .field("close_received", &self.close_received)
+            .finish_non_exhaustive()
+    }
+}
+
+fn close_payload(code: u16, reason: &str) -> Vec<u8> {
+    let mut payload = code.to_be_bytes().to_vec();
+    payload.extend_from_slice(reason.as_bytes());
+    payload
+}
+
+// answers a WebSocket handshake with a `101 Switching Protocols` that hands the connection to
+// `handler` once it's been sent, or says what's wrong with the request
+pub(crate) fn accept(request: &Request, handler: Arc<SessionHandler>) -> Response {
+    let has_token = |name: &str, token: &str| {
+        request
+            .raw_headers()
+            .get_all(name)
+            .flat_map(|value| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(token))
+    };
+
+    if !has_token("upgrade", "websocket") {
+        return Response::new(StatusCode::UpgradeRequired)
+            .with_header("Upgrade", "websocket")
+            .with_header("Connection", "Upgrade");
+    }
+    if request.method() != Method::Get
+        || request.line.version != Version::Http11
+        || !has_token("connection", "upgrade")
+    {
+        return Response::bad_request("not a WebSocket handshake".to_owned());
+    }
+    if request.header("sec-websocket-version") != Some("13") {
+        return Response::new(StatusCode::UpgradeRequired)
+            .with_header("Sec-WebSocket-Version", "13");
+    }
+    let Some(key) = request
+        .header("sec-websocket-key")
+        .filter(|key| base64::decode(key).is_some_and(|nonce| nonce.len() == 16))
+    else {
+        return Response::bad_request("missing or malformed 'Sec-WebSocket-Key'".to_owned());
+    };
+
+    log::info!("request_id = {}, upgrading to WebSocket", request.id);
+
+    Response {
+        upgrade: Some(Box::new(Upgrade {
+            request: request.clone(),
+            handler,
+        })),
+        ..Response::new(StatusCode::SwitchingProtocols)
+            .with_header("Upgrade", "websocket")
+            .with_header("Connection", "Upgrade")
+            .with_header("Sec-WebSocket-Accept", &accept_key(key))
+    }
+}
+
+// proves to the client that the server understood its handshake
+fn accept_key(key: &str) -> String {
+    let digest = digest::digest(
+        &digest::SHA1_FOR_LEGACY_USE_ONLY,
+        format!("{key}{ACCEPT_GUID}").as_bytes(),
+    );
+    base64::encode(digest.as_ref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // reads what a client sent, and collects what the server writes back
+    struct Pipe {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![u8::from(fin) << 7 | opcode, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    fn pipe(frames: &[Vec<u8>]) -> Pipe {
+        Pipe {
+            input: io::Cursor::new(frames.concat()),
+            output: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn accept_key_matches_the_rfc_example() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn websocket_reassembles_fragments_and_answers_control_frames() {
+        let mut pipe = pipe(&[
+            client_frame(false, 0x1, b"Hel"),
+            client_frame(true, 0x9, b"are you there"),
+            client_frame(true, 0x0, b"lo"),
+            client_frame(true, 0x8, &close_payload(1001, "bye")),
+        ]);
+        let mut socket = WebSocket::new(&mut pipe);
+
+        assert_eq!(
+            socket.recv().unwrap(),
+            Some(Message::Ping(b"are you there".to_vec()))
+        );
+        assert_eq!(
+            socket.recv().unwrap(),
+            Some(Message::Text("Hello".to_owned()))
+        );
+        socket.send(Message::Binary(vec![1, 2, 3])).unwrap();
+        assert_eq!(
+            socket.recv().unwrap(),
+            Some(Message::Close(Some((1001, "bye".to_owned()))))
+        );
+        assert_eq!(socket.recv().unwrap(), None);
+        assert!(socket.send(Message::Text("late".to_owned())).is_err());
+
+        let mut expected = vec![0x8a, 13];
+        expected.extend_from_slice(b"are you there");
+        expected.extend_from_slice(&[0x82, 3, 1, 2, 3]);
+        expected.extend_from_slice(&[0x88, 2, 0x03, 0xe9]);
+        assert_eq!(pipe.output, expected);
+    }
+
+    #[test]
+    fn websocket_closes_on_protocol_errors() {
+        let mut unmasked = pipe(&[vec![0x81, 0x02, b'h', b'i']]);
+        let err = WebSocket::new(&mut unmasked).recv().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(unmasked.output, [0x88, 2, 0x03, 0xea]);
+
+        let mut too_big = pipe(&[client_frame(true, 0x2, &[0; 100])]);
+        let mut socket = WebSocket::new(&mut too_big);
+        socket.set_max_message_size(10);
+        assert!(socket.recv().is_err());
+        assert_eq!(too_big.output, [0x88, 2, 0x03, 0xf1]);
+
+        let mut not_utf8 = pipe(&[client_frame(true, 0x1, &[0xff])]);
+        assert!(WebSocket::new(&mut not_utf8).recv().is_err());
+        assert_eq!(not_utf8.output, [0x88, 2, 0x03, 0xef]);
+    }
+}
@@ -0,0 +1,3938 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, prelude::*, BufReader},
+    net::{SocketAddr, TcpStream},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use regex::Regex;
+use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+
+use butler::{
+    client, AccessLogFormat, BasicAuth, CacheRule, Cgi, ClientAuth, ClientCertRule,
+    ConcurrencyLimit, Config, CorsPolicy, Event, Handler, IpFilter, Message, Method, Middleware,
+    Proxy, RateLimit, Request, Response, RewriteRule, Router, SameSite, Server, Sessions,
+    SetCookie, StatusCode, Template, TemplateContext, TestServer, TlsConfig, WebSocket,
+};
+
+fn test_config(files_root: PathBuf) -> Config {
+    Config {
+        files_root,
+        workers: 4,
+        ..Config::default()
+    }
+}
+
+// starts a server on an ephemeral port, returning its address
+fn spawn_server(files_root: PathBuf) -> SocketAddr {
+    spawn_server_with(test_config(files_root))
+}
+
+fn spawn_server_with(config: Config) -> SocketAddr {
+    let server = Server::bind("127.0.0.1:0", config).unwrap();
+    let addr = server.local_addr().unwrap();
+
+    thread::spawn(move || server.run());
+
+    addr
+}
+
+// sends `request` on a fresh connection and returns everything the server sends back
+fn send(addr: SocketAddr, request: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+fn get(addr: SocketAddr, path: &str, headers: &str) -> String {
+    send(
+        addr,
+        &format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n{headers}Connection: close\r\n\r\n"),
+    )
+}
+
+#[test]
+fn server_responds_to_root() {
+    let addr = spawn_server(files_root("server-root"));
+
+    let response = get(addr, "/", "X-Request-Id: root\r\n");
+    assert!(
+        response.starts_with("HTTP/1.1 200 OK\r\nDate: "),
+        "{response}"
+    );
+    assert!(
+        response.ends_with(&format!(
+            " GMT\r\nServer: butler/{}\r\nX-Request-Id: root\r\nConnection: close\r\nContent-Length: 0\r\n\r\n",
+            env!("CARGO_PKG_VERSION")
+        )),
+        "{response}"
+    );
+}
+
+#[test]
+fn server_gives_every_request_an_id() {
+    let addr = spawn_server(files_root("request-id"));
+
+    let id = |response: &str| {
+        response
+            .lines()
+            .find_map(|line| line.strip_prefix("X-Request-Id: "))
+            .unwrap_or_else(|| panic!("no X-Request-Id in {response}"))
+            .to_owned()
+    };
+
+    let first = id(&get(addr, "/", ""));
+    let second = id(&get(addr, "/nope", ""));
+    assert_ne!(first, second);
+
+    // ones that could garble the logs are replaced
+    let response = get(addr, "/", "X-Request-Id: not \"safe\"\r\n");
+    assert!(!id(&response).contains("safe"), "{response}");
+}
+
+#[test]
+fn server_echoes_path() {
+    let addr = spawn_server(files_root("server-echo"));
+
+    let response = get(addr, "/echo/foo", "X-Request-Id: echo\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(
+        response.contains("Content-Type: text/plain\r\n"),
+        "{response}"
+    );
+    assert!(
+        response
+            .ends_with("Content-Length: 3\r\nVary: Accept\r\nX-Request-Id: echo\r\nConnection: close\r\n\r\nfoo"),
+        "{response}"
+    );
+}
+
+#[test]
+fn server_returns_user_agent() {
+    let addr = spawn_server(files_root("server-user-agent"));
+
+    let response = get(addr, "/user-agent", "User-Agent: butler-test/1.0\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(response.ends_with("\r\n\r\nbutler-test/1.0"), "{response}");
+}
+
+#[cfg(unix)]
+#[test]
+fn server_distinguishes_missing_and_unreadable_files() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = files_root("server-unreadable");
+    let path = root.join("unreadable.txt");
+    fs::write(&path, "secret").unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o000)).unwrap();
+
+    // permissions don't apply to root, so there is nothing to test
+    if File::open(&path).is_ok() {
+        return;
+    }
+
+    let addr = spawn_server(root);
+
+    assert!(get(addr, "/files/missing.txt", "").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    assert!(get(addr, "/files/unreadable.txt", "")
+        .starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+}
+
+#[test]
+fn server_returns_not_found_for_unknown_paths() {
+    let addr = spawn_server(files_root("server-not-found"));
+
+    assert!(get(addr, "/nope", "").starts_with("HTTP/1.1 404 Not Found\r\n"));
+}
+
+fn files_root(name: &str) -> PathBuf {
+    let root = std::env::temp_dir().join(format!("butler-{name}-{}", std::process::id()));
+    fs::create_dir_all(root.join("nested")).unwrap();
+    fs::write(root.join("nested/foo.txt"), "foo").unwrap();
+    root
+}
+
+#[test]
+fn server_echoes_binary_request_bodies() {
+    let addr = spawn_server(files_root("echo-body"));
+    let body = [0u8, 159, 146, 150, 255, b'\r', b'\n'];
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(
+            format!(
+                "POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/x-thing; v=1\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+    stream.write_all(&body).unwrap();
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+
+    let head_end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+    let head = String::from_utf8_lossy(&response[..head_end]);
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+    assert!(
+        head.contains("Content-Type: application/x-thing; v=1\r\n"),
+        "{head}"
+    );
+    assert_eq!(&response[head_end..], body);
+
+    let response = send(
+        addr,
+        "POST /echo HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(response.contains("\r\nContent-Length: 0\r\n"), "{response}");
+}
+
+#[test]
+fn server_stores_binary_uploads_unchanged() {
+    let root = files_root("upload");
+    let addr = spawn_server(root.clone());
+    let body: Vec<u8> = (0..=255).collect();
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(
+            format!(
+                "POST /files/upload.bin HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+    stream.write_all(&body).unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    assert!(
+        response.starts_with("HTTP/1.1 201 Created\r\n"),
+        "{response}"
+    );
+    assert_eq!(fs::read(root.join("upload.bin")).unwrap(), body);
+}
+
+#[test]
+fn server_decompresses_gzipped_uploads() {
+    let root = files_root("gzip-upload");
+    let addr = spawn_server_with(Config {
+        max_body_size: 4096,
+        ..test_config(root.clone())
+    });
+    let gzip = |body: &[u8]| {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(body).unwrap();
+        encoder.finish().unwrap()
+    };
+    let upload = |name: &str, body: &[u8]| {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(
+                format!(
+                    "POST /files/{name} HTTP/1.1\r\nHost: localhost\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+        stream.write_all(body).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+
+    let contents: Vec<u8> = (0..=255).cycle().take(4096).collect();
+    let response = upload("unzipped.bin", &gzip(&contents));
+    assert!(
+        response.starts_with("HTTP/1.1 201 Created\r\n"),
+        "{response}"
+    );
+    assert_eq!(fs::read(root.join("unzipped.bin")).unwrap(), contents);
+
+    // a small body that would expand past the limit is refused
+    let response = upload("bomb.bin", &gzip(&[0; 1024 * 1024]));
+    assert!(response.starts_with("HTTP/1.1 413 "), "{response}");
+    assert!(!root.join("bomb.bin").exists());
+    let response = upload("broken.bin", b"definitely not gzip");
+    assert!(response.starts_with("HTTP/1.1 400 "), "{response}");
+}
+
+#[test]
+fn server_writes_large_uploads_to_disk_as_they_arrive() {
+    let root = files_root("large-upload");
+    let addr = spawn_server_with(Config {
+        max_in_memory_body_size: 1024,
+        ..test_config(root.clone())
+    });
+    let body: Vec<u8> = (0..256 * 1024).map(|i| (i % 251) as u8).collect();
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "PUT /files/large.bin HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )
+    .unwrap();
+    stream.write_all(&body).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(
+        response.starts_with("HTTP/1.1 201 Created\r\n"),
+        "{response}"
+    );
+    assert_eq!(fs::read(root.join("large.bin")).unwrap(), body);
+    // the spooled body is moved into place, but without keeping the spool file's permissions
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        fs::write(root.join("new.bin"), b"").unwrap();
+        let mode = |name: &str| fs::metadata(root.join(name)).unwrap().permissions().mode();
+        assert_eq!(mode("large.bin"), mode("new.bin"));
+    }
+    let leftovers: Vec<_> = fs::read_dir(&root)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .filter(|name| name.to_string_lossy().ends_with(".tmp"))
+        .collect();
+    assert!(leftovers.is_empty(), "{leftovers:?}");
+
+    // chunked bodies are spooled the same way, and handlers that want the whole body still get it
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(b"POST /echo HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    for chunk in body.chunks(10_000) {
+        write!(stream, "{:x}\r\n", chunk.len()).unwrap();
+        stream.write_all(chunk).unwrap();
+        stream.write_all(b"\r\n").unwrap();
+    }
+    stream.write_all(b"0\r\n\r\n").unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    assert!(response.ends_with(&body));
+}
+
+#[cfg(unix)]
+#[test]
+fn server_never_writes_through_symlinks_out_of_the_files_root() {
+    let root = files_root("symlinked-uploads");
+    let outside = root.with_file_name(format!(
+        "butler-symlinked-uploads-outside-{}",
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&outside);
+    fs::create_dir_all(&outside).unwrap();
+    let _ = fs::remove_file(root.join("link"));
+    std::os::unix::fs::symlink(&outside, root.join("link")).unwrap();
+    let addr = spawn_server(root);
+
+    let body = "------form\r\n\
+        Content-Disposition: form-data; name=\"file\"; filename=\"evil.txt\"\r\n\r\n\
+        evil\r\n\
+        ------form--\r\n";
+    let multipart = format!(
+        "POST /files/link/a/b/ HTTP/1.1\r\nHost: localhost\r\n\
+         Content-Type: multipart/form-data; boundary=----form\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    for request in [
+        multipart.as_str(),
+        "PUT /files/link/a/evil.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\nConnection: close\r\n\r\nevil",
+        "POST /files/link/a/evil.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\nConnection: close\r\n\r\nevil",
+    ] {
+        let response = send(addr, request);
+        assert!(
+            ["400", "403", "404"]
+                .iter()
+                .any(|status| response.starts_with(&format!("HTTP/1.1 {status} "))),
+            "{request}: {response}"
+        );
+    }
+    assert_eq!(fs::read_dir(&outside).unwrap().count(), 0);
+}
+
+#[test]
+fn server_stores_the_files_of_multipart_uploads() {
+    let root = files_root("multipart");
+    let addr = spawn_server(root.clone());
+    let upload = |path: &str, body: &[u8]| {
+        let mut request = format!(
+            "POST {path} HTTP/1.1\r\nHost: localhost\r\n\
+             Content-Type: multipart/form-data; boundary=----form\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        )
+        .into_bytes();
+        request.extend_from_slice(body);
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(&request).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+
+    let body = b"------form\r\n\
+        Content-Disposition: form-data; name=\"comment\"\r\n\r\n\
+        not a file\r\n\
+        ------form\r\n\
+        Content-Disposition: form-data; name=\"photo\"; filename=\"C:\\Users\\me\\cat.png\"\r\n\
+        Content-Type: image/png\r\n\r\n\
+        \x89PNG\r\n\
+        ------form\r\n\
+        Content-Disposition: form-data; name=\"notes\"; filename=\"notes.txt\"\r\n\r\n\
+        meow\r\n\
+        ------form--\r\n";
+    let response = upload("/files/pets", body);
+    assert!(
+        response.starts_with("HTTP/1.1 201 Created\r\n"),
+        "{response}"
+    );
+    assert!(
+        response.ends_with("\r\n\r\ncat.png\nnotes.txt\n"),
+        "{response}"
+    );
+    assert_eq!(fs::read(root.join("pets/cat.png")).unwrap(), b"\x89PNG");
+    assert_eq!(fs::read(root.join("pets/notes.txt")).unwrap(), b"meow");
+    assert!(!root.join("pets/comment").exists());
+
+    // the type the browser gave the file is served with it
+    let response = send(
+        addr,
+        "HEAD /files/pets/cat.png HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    );
+    assert!(
+        response.contains("Content-Type: image/png\r\n"),
+        "{response}"
+    );
+
+    let response = upload(
+        "/files/pets",
+        b"------form\r\nContent-Disposition: form-data; name=\"f\"; filename=\"..\"\r\n\r\nx\r\n------form--\r\n",
+    );
+    assert!(
+        response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+        "{response}"
+    );
+}
+
+#[test]
+fn server_reports_health() {
+    let addr = spawn_server(files_root("health"));
+
+    let response = get(addr, "/health", "");
+
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(
+        response.contains("Content-Type: application/json\r\n"),
+        "{response}"
+    );
+    let health =
+        Regex::new(r#"\r\n\r\n\{"status":"ok","uptime_secs":\d+,"active_connections":1,"queued_connections":0\}$"#)
+            .unwrap();
+    assert!(health.is_match(&response), "{response}");
+}
+
+#[test]
+fn server_answers_liveness_and_readiness_probes() {
+    let root = files_root("probes");
+    let addr = spawn_server(root.clone());
+
+    let response = get(addr, "/healthz", "");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(response.ends_with("\r\n\r\nok"), "{response}");
+    let response = get(addr, "/readyz", "");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(response.ends_with("\r\n\r\nready"), "{response}");
+
+    // a server without its files root is alive, but can't serve
+    let addr = spawn_server(root.join("missing"));
+    assert!(get(addr, "/healthz", "").starts_with("HTTP/1.1 200 OK\r\n"));
+    let response = get(addr, "/readyz", "");
+    assert!(
+        response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+        "{response}"
+    );
+    assert!(response.contains("can't be read"), "{response}");
+}
+
+#[test]
+fn server_exposes_prometheus_metrics() {
+    let addr = spawn_server(files_root("metrics"));
+
+    get(addr, "/echo/hi", "");
+    get(addr, "/nope", "");
+    let response = get(addr, "/metrics", "");
+
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    for line in [
+        "http_requests_total{method=\"GET\",status=\"200\"} 1",
+        "http_requests_total{method=\"GET\",status=\"404\"} 1",
+        "http_request_duration_seconds_count 2",
+        "http_active_connections 1",
+    ] {
+        assert!(response.contains(&format!("\n{line}\n")), "{response}");
+    }
+}
+
+#[test]
+fn server_limits_requests_handled_at_once() {
+    let config = Config {
+        max_concurrent_requests: Some(8),
+        concurrency_limits: vec![ConcurrencyLimit::new("/slow/", 1)],
+        concurrency_queue_timeout: Duration::from_millis(200),
+        rewrites: vec![RewriteRule::prefix("/alias/", "/slow/")],
+        ..test_config(files_root("concurrency"))
+    };
+    let (entered, handling) = mpsc::channel();
+    let (release, released) = mpsc::channel::<()>();
+    let (entered, released) = (Mutex::new(entered), Mutex::new(released));
+    let server = Server::bind("127.0.0.1:0", config).unwrap().route(
+        Method::Get,
+        "/slow/{*rest}",
+        move |_| {
+            entered.lock().unwrap().send(()).unwrap();
+            released.lock().unwrap().recv().unwrap();
+            Response::text("done".to_owned())
+        },
+    );
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+
+    let first = thread::spawn(move || get(addr, "/slow/first", ""));
+    handling.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    // the second one waits in line for the queue timeout, and other paths aren't held up
+    let started = Instant::now();
+    let response = get(addr, "/slow/second", "");
+    assert!(
+        response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+        "{response}"
+    );
+    assert!(response.contains("Retry-After: 1\r\n"), "{response}");
+    assert!(started.elapsed() >= Duration::from_millis(200));
+    let response = get(addr, "/echo/fast", "");
+    assert!(response.ends_with("\r\n\r\nfast"), "{response}");
+    // a path rewritten to a limited one is under its limit too
+    let response = get(addr, "/alias/second", "");
+    assert!(
+        response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+        "{response}"
+    );
+
+    // the metrics request holds a global slot of its own
+    let response = get(addr, "/metrics", "");
+    for line in [
+        "http_concurrency_limit{limit=\"/slow\"} 1",
+        "http_concurrency_in_use{limit=\"/slow\"} 1",
+        "http_concurrency_rejected_total{limit=\"/slow\"} 2",
+        "http_concurrency_limit{limit=\"global\"} 8",
+        "http_concurrency_in_use{limit=\"global\"} 2",
+        "http_concurrency_rejected_total{limit=\"global\"} 0",
+    ] {
+        assert!(response.contains(&format!("\n{line}\n")), "{response}");
+    }
+
+    release.send(()).unwrap();
+    let response = first.join().unwrap();
+    assert!(response.ends_with("\r\n\r\ndone"), "{response}");
+
+    // a request that arrives while the slot is taken gets it once it's freed
+    let second = thread::spawn(move || get(addr, "/slow/second", ""));
+    handling.recv_timeout(Duration::from_secs(5)).unwrap();
+    let third = thread::spawn(move || get(addr, "/slow/third", ""));
+    thread::sleep(Duration::from_millis(50));
+    release.send(()).unwrap();
+    handling.recv_timeout(Duration::from_secs(5)).unwrap();
+    release.send(()).unwrap();
+    for waiter in [second, third] {
+        let response = waiter.join().unwrap();
+        assert!(response.ends_with("\r\n\r\ndone"), "{response}");
+    }
+}
+
+#[test]
+fn server_keeps_workers_for_other_routes_while_a_limit_is_saturated() {
+    let config = Config {
+        concurrency_limits: vec![ConcurrencyLimit::new("/slow/", 1)],
+        concurrency_queue_timeout: Duration::from_secs(30),
+        ..test_config(files_root("concurrency-queue"))
+    };
+    let (entered, handling) = mpsc::channel();
+    let (release, released) = mpsc::channel::<()>();
+    let (entered, released) = (Mutex::new(entered), Mutex::new(released));
+    let server = Server::bind("127.0.0.1:0", config).unwrap().route(
+        Method::Get,
+        "/slow/{*rest}",
+        move |_| {
+            entered.lock().unwrap().send(()).unwrap();
+            released.lock().unwrap().recv().unwrap();
+            Response::text("done".to_owned())
+        },
+    );
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+
+    let first = thread::spawn(move || get(addr, "/slow/first", ""));
+    handling.recv_timeout(Duration::from_secs(5)).unwrap();
+    let second = thread::spawn(move || get(addr, "/slow/second", ""));
+    while !get(addr, "/metrics", "").contains("\nhttp_concurrency_queued{limit=\"/slow\"} 1\n") {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    // with one request in line for the limit's only slot, more of them would take every one of
+    // the 4 workers while they wait; they're turned away at once instead
+    let started = Instant::now();
+    let rejected: Vec<_> = (0..4)
+        .map(|i| thread::spawn(move || get(addr, &format!("/slow/{i}"), "")))
+        .collect();
+    for rejected in rejected {
+        let response = rejected.join().unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+            "{response}"
+        );
+    }
+    assert!(started.elapsed() < Duration::from_secs(5));
+    let response = get(addr, "/echo/fast", "");
+    assert!(response.ends_with("\r\n\r\nfast"), "{response}");
+
+    release.send(()).unwrap();
+    handling.recv_timeout(Duration::from_secs(5)).unwrap();
+    release.send(()).unwrap();
+    for waiter in [first, second] {
+        let response = waiter.join().unwrap();
+        assert!(response.ends_with("\r\n\r\ndone"), "{response}");
+    }
+}
+
+#[test]
+fn server_answers_matching_if_none_match_with_not_modified() {
+    let addr = spawn_server(files_root("etag"));
+
+    let response = get(addr, "/files/nested/foo.txt", "");
+    let etag = response
+        .lines()
+        .find_map(|line| line.strip_prefix("ETag: "))
+        .unwrap_or_else(|| panic!("missing ETag in {response}"));
+
+    let response = get(
+        addr,
+        "/files/nested/foo.txt",
+        &format!("If-None-Match: W/\"other\", {etag}\r\n"),
+    );
+    assert!(
+        response.starts_with("HTTP/1.1 304 Not Modified\r\n"),
+        "{response}"
+    );
+    assert!(
+        response.contains(&format!("ETag: {etag}\r\n")),
+        "{response}"
+    );
+    assert!(!response.contains("Content-Length"), "{response}");
+    assert!(response.ends_with("\r\n\r\n"), "{response}");
+
+    let response = get(
+        addr,
+        "/files/nested/foo.txt",
+        "If-None-Match: \"other\"\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+}
+
+#[test]
+fn server_answers_unchanged_if_modified_since_with_not_modified() {
+    let addr = spawn_server(files_root("last-modified"));
+
+    let response = get(addr, "/files/nested/foo.txt", "");
+    let last_modified = response
+        .lines()
+        .find_map(|line| line.strip_prefix("Last-Modified: "))
+        .unwrap_or_else(|| panic!("missing Last-Modified in {response}"));
+
+    let response = get(
+        addr,
+        "/files/nested/foo.txt",
+        &format!("If-Modified-Since: {last_modified}\r\n"),
+    );
+    assert!(
+        response.starts_with("HTTP/1.1 304 Not Modified\r\n"),
+        "{response}"
+    );
+    assert!(
+        response.contains(&format!("Last-Modified: {last_modified}\r\n")),
+        "{response}"
+    );
+
+    // an older date, in the obsolete asctime format
+    let response = get(
+        addr,
+        "/files/nested/foo.txt",
+        "If-Modified-Since: Sun Nov  6 08:49:37 1994\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+
+    // entity tags win over dates
+    let response = get(
+        addr,
+        "/files/nested/foo.txt",
+        &format!("If-None-Match: \"other\"\r\nIf-Modified-Since: {last_modified}\r\n"),
+    );
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+}
+
+#[test]
+fn server_never_serves_files_outside_the_files_root() {
+    let root = files_root("traversal");
+    let secret = root.with_file_name(format!("butler-traversal-secret-{}", std::process::id()));
+    fs::write(&secret, "top secret").unwrap();
+    let secret_name = secret.file_name().unwrap().to_str().unwrap();
+    // a directory inside the root that's a symlink to the one the secret is in
+    #[cfg(unix)]
+    {
+        let _ = fs::remove_file(root.join("link"));
+        std::os::unix::fs::symlink(secret.parent().unwrap(), root.join("link")).unwrap();
+    }
+    let addr = spawn_server(root);
+
+    #[cfg(unix)]
+    {
+        let response = get(addr, &format!("/files/link/{secret_name}"), "");
+        assert!(!response.contains("top secret"), "{response}");
+        // nor are directories created below it that don't exist yet
+        let dir = format!("butler-traversal-created-{}", std::process::id());
+        let body = "------form\r\nContent-Disposition: form-data; name=\"file\"; filename=\"evil.txt\"\r\n\r\nevil\r\n------form--\r\n";
+        let response = send(
+            addr,
+            &format!(
+                "POST /files/link/{dir}/sub/ HTTP/1.1\r\nHost: localhost\r\n\
+                 Content-Type: multipart/form-data; boundary=----form\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            ),
+        );
+        assert!(!response.starts_with("HTTP/1.1 2"), "{response}");
+        assert!(!secret.with_file_name(dir).exists());
+    }
+
+    for path in [
+        format!("/files/../{secret_name}"),
+        format!("/files/nested/../../{secret_name}"),
+        format!("/files/%2e%2e/{secret_name}"),
+        format!("/files/%2E%2E%2F{secret_name}"),
+        format!("/files/nested/..%2f..%2f{secret_name}"),
+        format!("/files//..//{secret_name}"),
+    ] {
+        let response = get(addr, &path, "");
+        assert!(
+            ["400", "403", "404"]
+                .iter()
+                .any(|status| response.starts_with(&format!("HTTP/1.1 {status} "))),
+            "{path}: {response}"
+        );
+        assert!(!response.contains("top secret"), "{path}: {response}");
+    }
+}
+
+#[test]
+fn server_lists_directories() {
+    let root = files_root("listing");
+    fs::write(root.join(".hidden"), "secret").unwrap();
+    fs::write(root.join("a & b.txt"), "").unwrap();
+    let addr = spawn_server(root.clone());
+
+    let response = get(addr, "/files/", "");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(
+        response.contains("Content-Type: text/html\r\n"),
+        "{response}"
+    );
+    assert!(
+        response.contains(r#"<a href="/files/a%20%26%20b.txt">a &amp; b.txt</a>"#),
+        "{response}"
+    );
+    assert!(
+        response.contains(r#"<a href="/files/nested/">nested/</a>"#),
+        "{response}"
+    );
+    assert!(!response.contains(".hidden"), "{response}");
+
+    let response = get(addr, "/files/nested", "");
+    assert!(
+        response.contains(r#"<a href="/files/nested/foo.txt">foo.txt</a></td><td>3</td><td>"#),
+        "{response}"
+    );
+    assert!(
+        Regex::new(
+            r"foo\.txt</a></td><td>3</td><td>\w{3}, \d{2} \w{3} \d{4} \d{2}:\d{2}:\d{2} GMT</td>"
+        )
+        .unwrap()
+        .is_match(&response),
+        "{response}"
+    );
+
+    let addr = spawn_server_with(Config {
+        directory_listing: false,
+        ..test_config(root)
+    });
+    let response = get(addr, "/files/nested/", "");
+    assert!(
+        response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+        "{response}"
+    );
+    let response = get(addr, "/files/nested/foo.txt", "");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+}
+
+#[test]
+fn server_answers_built_in_endpoints_in_the_type_the_client_accepts() {
+    let addr = spawn_server(files_root("negotiation"));
+    let json = "Accept: application/json\r\n";
+    let html = "Accept: text/html,application/xhtml+xml,*/*;q=0.8\r\n";
+
+    let response = get(addr, "/echo/a%22b", json);
+    assert!(
+        response.contains("Content-Type: application/json\r\n"),
+        "{response}"
+    );
+    assert!(
+        response.ends_with("\r\n\r\n{\"text\":\"a\\\"b\"}"),
+        "{response}"
+    );
+    let response = get(addr, "/echo/%3Cb%3E", html);
+    assert!(
+        response.ends_with("\r\n\r\n<!DOCTYPE html>\n<p>&lt;b&gt;</p>\n"),
+        "{response}"
+    );
+    let response = get(addr, "/echo/hi", "Accept: */*\r\n");
+    assert!(response.ends_with("\r\n\r\nhi"), "{response}");
+    assert!(response.contains("Vary: Accept\r\n"), "{response}");
+    let response = get(addr, "/echo/hi", "Accept: image/png\r\n");
+    assert!(
+        response.starts_with("HTTP/1.1 406 Not Acceptable\r\n"),
+        "{response}"
+    );
+    assert!(response.contains("Vary: Accept\r\n"), "{response}");
+
+    let response = get(addr, "/files/nested/", json);
+    assert!(
+        Regex::new(r#"\r\n\r\n\[\{"name":"foo\.txt","href":"/files/nested/foo\.txt","size":3,"modified":"\w{3}, [^"]+ GMT"\}\]$"#)
+            .unwrap()
+            .is_match(&response),
+        "{response}"
+    );
+    let response = get(addr, "/files/nested/", "Accept: text/plain\r\n");
+    assert!(response.ends_with("\r\n\r\nfoo.txt\n"), "{response}");
+    assert!(response.contains("Vary: Accept\r\n"), "{response}");
+    let response = get(addr, "/files/nested/", "Accept: image/*\r\n");
+    assert!(
+        response.starts_with("HTTP/1.1 406 Not Acceptable\r\n"),
+        "{response}"
+    );
+    assert!(response.contains("Vary: Accept\r\n"), "{response}");
+
+    // errors keep their status whatever is accepted, and stay plain text for clients without a
+    // preference
+    let response = get(addr, "/nowhere", json);
+    assert!(
+        response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+        "{response}"
+    );
+    assert!(response.contains("Vary: Accept\r\n"), "{response}");
+    assert!(
+        response.ends_with("\r\n\r\n{\"status\":404,\"reason\":\"Not Found\",\"message\":\"\"}"),
+        "{response}"
+    );
+    let response = get(addr, "/files/%zz", html);
+    assert!(
+        response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+        "{response}"
+    );
+    assert!(
+        response.contains("<h1>400 Bad Request</h1>\n<p>"),
+        "{response}"
+    );
+    let response = get(addr, "/nowhere", "Accept: image/png\r\n");
+    assert!(
+        response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+        "{response}"
+    );
+    assert!(!response.contains("Content-Type"), "{response}");
+}
+
+#[test]
+fn server_serves_index_files_in_place_of_directories() {
+    let root = files_root("index");
+    fs::create_dir_all(root.join("site/docs")).unwrap();
+    fs::write(root.join("site/index.html"), "<h1>home</h1>").unwrap();
+    fs::write(root.join("site/docs/index.htm"), "<h1>docs</h1>").unwrap();
+    let addr = spawn_server_with(Config {
+        index_files: vec!["index.html".to_owned(), "index.htm".to_owned()],
+        ..test_config(root)
+    });
+
+    let response = get(addr, "/files/site/", "");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(
+        response.contains("Content-Type: text/html\r\n"),
+        "{response}"
+    );
+    assert!(response.ends_with("<h1>home</h1>"), "{response}");
+
+    let response = get(addr, "/files/site/docs", "");
+    assert!(response.ends_with("<h1>docs</h1>"), "{response}");
+
+    // directories without one are still listed
+    let response = get(addr, "/files/nested/", "");
+    assert!(response.contains("Index of /files/nested/"), "{response}");
+}
+
+#[test]
+fn server_redirects_paths_to_their_trailing_slash_form() {
+    let root = files_root("slash-redirects");
+    fs::create_dir_all(root.join("site")).unwrap();
+    fs::write(root.join("site/index.html"), "<h1>home</h1>").unwrap();
+    let addr = spawn_server_with(Config {
+        slash_redirects: true,
+        ..test_config(root)
+    });
+
+    let response = get(addr, "/files/site?lang=en", "");
+    assert!(
+        response.starts_with("HTTP/1.1 301 Moved Permanently\r\n"),
+        "{response}"
+    );
+    assert!(
+        response.contains("Location: /files/site/?lang=en\r\n"),
+        "{response}"
+    );
+
+    let response = get(addr, "/files/site/index.html/", "");
+    assert!(
+        response.contains("Location: /files/site/index.html\r\n"),
+        "{response}"
+    );
+
+    let response = get(addr, "/files/site/", "");
+    assert!(response.ends_with("<h1>home</h1>"), "{response}");
+    let response = get(addr, "/files/missing", "");
+    assert!(response.starts_with("HTTP/1.1 404 "), "{response}");
+}
+
+// sends a `DELETE` for `path` and returns the response
+fn delete(addr: SocketAddr, path: &str) -> String {
+    send(
+        addr,
+        &format!("DELETE {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n"),
+    )
+}
+
+#[test]
+fn server_only_deletes_directories_when_told_to() {
+    let root = files_root("delete");
+    fs::write(root.join("nested/bar.txt"), "bar").unwrap();
+    let addr = spawn_server(root.clone());
+
+    let response = delete(addr, "/files/nested/bar.txt");
+    assert!(
+        response.starts_with("HTTP/1.1 204 No Content\r\n"),
+        "{response}"
+    );
+    assert!(!root.join("nested/bar.txt").exists());
+    let response = delete(addr, "/files/nested/bar.txt");
+    assert!(response.starts_with("HTTP/1.1 404 "), "{response}");
+
+    let response = delete(addr, "/files/nested");
+    assert!(
+        response.starts_with("HTTP/1.1 409 Conflict\r\n"),
+        "{response}"
+    );
+    assert!(root.join("nested/foo.txt").exists());
+
+    let addr = spawn_server_with(Config {
+        recursive_deletes: true,
+        ..test_config(root.clone())
+    });
+    // the root itself stays, whatever the setting
+    let response = delete(addr, "/files/");
+    assert!(
+        response.starts_with("HTTP/1.1 409 Conflict\r\n"),
+        "{response}"
+    );
+    let response = delete(addr, "/files/nested");
+    assert!(
+        response.starts_with("HTTP/1.1 204 No Content\r\n"),
+        "{response}"
+    );
+    assert!(!root.join("nested").exists() && root.exists());
+}
+
+#[test]
+fn server_sends_cache_control_for_matching_files() {
+    let root = files_root("cache-control");
+    fs::create_dir_all(root.join("assets")).unwrap();
+    fs::write(root.join("assets/app.js"), "app()").unwrap();
+    fs::write(root.join("assets/page.html"), "<p>asset</p>").unwrap();
+    fs::write(root.join("index.html"), "<h1>home</h1>").unwrap();
+    let addr = spawn_server_with(Config {
+        cache_control: vec![
+            CacheRule::for_path("/files/assets/*", "public, max-age=31536000, immutable"),
+            "text/html=no-cache".parse().unwrap(),
+        ],
+        ..test_config(root)
+    });
+
+    let response = get(addr, "/files/assets/app.js", "");
+    assert!(
+        response.contains("\r\nCache-Control: public, max-age=31536000, immutable\r\n"),
+        "{response}"
+    );
+    // the first rule that matches wins
+    let response = get(addr, "/files/assets/page.html", "");
+    assert!(
+        response.contains("\r\nCache-Control: public, max-age"),
+        "{response}"
+    );
+
+    let response = get(addr, "/files/index.html", "");
+    assert!(
+        response.contains("\r\nCache-Control: no-cache\r\n"),
+        "{response}"
+    );
+    let etag = response
+        .lines()
+        .find_map(|line| line.strip_prefix("ETag: "))
+        .unwrap();
+    let response = get(
+        addr,
+        "/files/index.html",
+        &format!("If-None-Match: {etag}\r\n"),
+    );
+    assert!(response.starts_with("HTTP/1.1 304 "), "{response}");
+    assert!(
+        response.contains("\r\nCache-Control: no-cache\r\n"),
+        "{response}"
+    );
+
+    let response = get(addr, "/files/nested/foo.txt", "");
+    assert!(!response.contains("Cache-Control"), "{response}");
+    let response = get(addr, "/files/assets/missing.js", "");
+    assert!(!response.contains("Cache-Control"), "{response}");
+}
+
+#[test]
+fn server_asks_for_passwords_under_protected_prefixes() {
+    let root = files_root("basic-auth");
+    let htpasswd = root.join("users.htpasswd");
+    fs::write(
+        &htpasswd,
+        "# alice:secret, bob:hunter2\n\
+         alice:{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=\n\
+         bob:$pbkdf2-sha256$1000$c2FsdHNhbHRzYWx0MTIzNA$fIKZdZ7B5XJPx7My3DohkKloWvdeNcMNXs3u1sIBy.Q\n",
+    )
+    .unwrap();
+    let addr = spawn_server_with(Config {
+        basic_auth: vec![BasicAuth::from_htpasswd_file("/files/", &htpasswd).unwrap()],
+        ..test_config(root)
+    });
+
+    for (path, headers) in [
+        ("/files/nested/foo.txt", ""),
+        (
+            "/files/nested/foo.txt",
+            "Authorization: Basic YWxpY2U6d3Jvbmc=\r\n",
+        ),
+        // encoding the path doesn't get around it
+        ("/fil%65s/nested/foo.txt", ""),
+    ] {
+        let response = get(addr, path, headers);
+        assert!(
+            response.starts_with("HTTP/1.1 401 Unauthorized\r\n"),
+            "{response}"
+        );
+        assert!(
+            response
+                .contains("\r\nWWW-Authenticate: Basic realm=\"butler\", charset=\"UTF-8\"\r\n"),
+            "{response}"
+        );
+    }
+
+    for credentials in ["YWxpY2U6c2VjcmV0", "Ym9iOmh1bnRlcjI="] {
+        let response = get(
+            addr,
+            "/files/nested/foo.txt",
+            &format!("Authorization: basic {credentials}\r\n"),
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\nfoo"), "{response}");
+    }
+
+    // paths outside the prefix stay public
+    assert!(get(addr, "/echo/hi", "").starts_with("HTTP/1.1 200 OK\r\n"));
+}
+
+#[test]
+fn server_needs_a_token_to_write_files() {
+    let root = files_root("write-tokens");
+    let addr = spawn_server_with(Config {
+        write_tokens: vec!["first".to_owned(), "second".to_owned()],
+        ..test_config(root.clone())
+    });
+
+    let upload = |headers: &str| {
+        send(
+            addr,
+            &format!(
+                "POST /files/token.txt HTTP/1.1\r\nHost: localhost\r\n{headers}Content-Length: 2\r\nConnection: close\r\n\r\nhi"
+            ),
+        )
+    };
+
+    for headers in ["", "Authorization: Bearer third\r\n"] {
+        let response = upload(headers);
+        assert!(
+            response.starts_with("HTTP/1.1 403 Forbidden\r\n"),
+            "{response}"
+        );
+    }
+    assert!(!root.join("token.txt").exists());
+
+    let response = upload("Authorization: Bearer second\r\n");
+    assert!(
+        response.starts_with("HTTP/1.1 201 Created\r\n"),
+        "{response}"
+    );
+
+    // reading stays public
+    let response = get(addr, "/files/token.txt", "");
+    assert!(response.ends_with("\r\n\r\nhi"), "{response}");
+}
+
+#[test]
+fn server_follows_its_cors_policy() {
+    let addr = spawn_server_with(Config {
+        cors: Some(CorsPolicy {
+            allowed_origins: vec!["https://app.example".to_owned()],
+            ..CorsPolicy::default()
+        }),
+        ..test_config(files_root("cors"))
+    });
+
+    let preflight = |origin: &str, headers: &str| {
+        send(
+            addr,
+            &format!(
+                "OPTIONS /files/a.txt HTTP/1.1\r\nHost: localhost\r\nOrigin: {origin}\r\nAccess-Control-Request-Method: PUT\r\n{headers}Connection: close\r\n\r\n"
+            ),
+        )
+    };
+
+    let response = preflight(
+        "https://app.example",
+        "Access-Control-Request-Headers: content-type\r\n",
+    );
+    assert!(
+        response.starts_with("HTTP/1.1 204 No Content\r\n"),
+        "{response}"
+    );
+    assert!(response.contains("\r\nAccess-Control-Allow-Origin: https://app.example\r\n"));
+    assert!(response.contains("\r\nAccess-Control-Allow-Methods: GET, HEAD, POST, PUT, DELETE\r\n"));
+    assert!(response.contains("\r\nAccess-Control-Allow-Headers: Content-Type, Authorization\r\n"));
+    assert!(response.contains("\r\nAccess-Control-Max-Age: 600\r\n"));
+
+    // refused preflights are answered without the headers that would let the request through
+    for response in [
+        preflight("https://evil.example", ""),
+        preflight(
+            "https://app.example",
+            "Access-Control-Request-Headers: x-secret\r\n",
+        ),
+    ] {
+        assert!(
+            response.starts_with("HTTP/1.1 204 No Content\r\n"),
+            "{response}"
+        );
+        assert!(
+            !response.contains("Access-Control-Allow-Methods"),
+            "{response}"
+        );
+    }
+
+    let response = get(addr, "/echo/hi", "Origin: https://app.example\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(response.contains("\r\nAccess-Control-Allow-Origin: https://app.example\r\n"));
+    assert!(response.contains("\r\nVary: Accept, Origin\r\n"));
+
+    let response = get(addr, "/echo/hi", "Origin: https://evil.example\r\n");
+    assert!(
+        !response.contains("Access-Control-Allow-Origin"),
+        "{response}"
+    );
+    assert!(!get(addr, "/echo/hi", "").contains("Access-Control-Allow-Origin"));
+}
+
+#[test]
+fn server_limits_the_rate_of_requests_per_client() {
+    let addr = spawn_server_with(Config {
+        rate_limit: Some(RateLimit { rate: 1, burst: 2 }),
+        ..test_config(files_root("rate-limit"))
+    });
+
+    for _ in 0..2 {
+        let response = get(addr, "/echo/hi", "");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    }
+
+    let response = get(addr, "/echo/hi", "");
+    assert!(
+        response.starts_with("HTTP/1.1 429 Too Many Requests\r\n"),
+        "{response}"
+    );
+    assert!(response.contains("\r\nRetry-After: 1\r\n"), "{response}");
+}
+
+#[test]
+fn server_rejects_denied_clients() {
+    let addr = spawn_server_with(Config {
+        ip_filter: IpFilter {
+            allow: Vec::new(),
+            deny: vec!["127.0.0.0/8".parse().unwrap()],
+        },
+        ..test_config(files_root("deny-ips"))
+    });
+
+    // turned away as soon as it connects, writing a request would only get it reset
+    let mut response = String::new();
+    TcpStream::connect(addr)
+        .unwrap()
+        .read_to_string(&mut response)
+        .unwrap();
+    assert!(
+        response.starts_with("HTTP/1.1 403 Forbidden\r\n"),
+        "{response}"
+    );
+}
+
+#[test]
+fn server_filters_clients_forwarded_by_trusted_proxies() {
+    let addr = spawn_server_with(Config {
+        ip_filter: IpFilter {
+            allow: vec!["198.51.100.0/24".parse().unwrap()],
+            deny: Vec::new(),
+        },
+        trusted_proxies: vec!["127.0.0.1".parse().unwrap()],
+        ..test_config(files_root("trusted-proxies"))
+    });
+
+    let response = get(
+        addr,
+        "/echo/hi",
+        "X-Forwarded-For: 203.0.113.7, 198.51.100.1\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+
+    for headers in ["X-Forwarded-For: 198.51.100.1, 203.0.113.7\r\n", ""] {
+        let response = get(addr, "/echo/hi", headers);
+        assert!(
+            response.starts_with("HTTP/1.1 403 Forbidden\r\n"),
+            "{response}"
+        );
+    }
+}
+
+#[test]
+fn server_tells_handlers_the_client_a_trusted_proxy_forwarded_for() {
+    let config = Config {
+        rate_limit: Some(RateLimit { rate: 1, burst: 1 }),
+        trusted_proxies: vec!["127.0.0.1".parse().unwrap()],
+        ..test_config(files_root("client-ip"))
+    };
+    let server = Server::bind("127.0.0.1:0", config).unwrap().route(
+        Method::Get,
+        "/client",
+        |request: &Request| Response::text(format!("{:?}", request.client_ip())),
+    );
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+
+    let response = get(addr, "/client", "X-Forwarded-For: 203.0.113.7\r\n");
+    assert!(
+        response.ends_with("\r\n\r\nSome(203.0.113.7)"),
+        "{response}"
+    );
+    let response = get(
+        addr,
+        "/client",
+        "Forwarded: for=\"[2001:db8::1]:4711\";proto=https\r\n",
+    );
+    assert!(
+        response.ends_with("\r\n\r\nSome(2001:db8::1)"),
+        "{response}"
+    );
+
+    // each forwarded client has a rate limit of its own
+    let response = get(addr, "/client", "X-Forwarded-For: 203.0.113.7\r\n");
+    assert!(
+        response.starts_with("HTTP/1.1 429 Too Many Requests\r\n"),
+        "{response}"
+    );
+    let response = get(addr, "/client", "");
+    assert!(response.ends_with("\r\n\r\nSome(127.0.0.1)"), "{response}");
+}
+
+#[test]
+fn server_routes_requests_by_host() {
+    let site = files_root("virtual-host");
+    fs::write(site.join("site.txt"), "site").unwrap();
+    let config = Config {
+        virtual_hosts: HashMap::from([("*.site.test".to_owned(), site)]),
+        ..test_config(files_root("default-host"))
+    };
+    let server = Server::bind("127.0.0.1:0", config).unwrap().host(
+        "api.test",
+        Router::new().route(Method::Get, "/hello", |_| Response::text("hi".to_owned())),
+    );
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+
+    let get_from = |host: &str, path: &str| {
+        send(
+            addr,
+            &format!("GET {path} HTTP/1.1\r\nHost: {host}\r\nConnection: close\r\n\r\n"),
+        )
+    };
+
+    let response = get_from("www.site.test:4221", "/files/site.txt");
+    assert!(response.ends_with("\r\n\r\nsite"), "{response}");
+    let response = get_from("localhost", "/files/site.txt");
+    assert!(
+        response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+        "{response}"
+    );
+
+    let response = get_from("API.test", "/hello");
+    assert!(response.ends_with("\r\n\r\nhi"), "{response}");
+    // the built-in routes are there for every host
+    let response = get_from("api.test", "/echo/hey");
+    assert!(response.ends_with("\r\n\r\nhey"), "{response}");
+    let response = get_from("localhost", "/hello");
+    assert!(
+        response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+        "{response}"
+    );
+
+    // the host of an absolute-form target wins over the `Host` header
+    let response = get_from("localhost", "http://api.test/hello");
+    assert!(response.ends_with("\r\n\r\nhi"), "{response}");
+    let response = get_from("api.test", "http://www.site.test:4221/files/site.txt");
+    assert!(response.ends_with("\r\n\r\nsite"), "{response}");
+}
+
+#[test]
+fn server_can_misdirect_unknown_hosts() {
+    let addr = spawn_server_with(Config {
+        virtual_hosts: HashMap::from([("known.test".to_owned(), files_root("known-host"))]),
+        misdirect_unknown_hosts: true,
+        ..test_config(files_root("misdirect"))
+    });
+
+    let response = send(
+        addr,
+        "GET /echo/hi HTTP/1.1\r\nHost: known.test\r\nConnection: close\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+
+    let response = get(addr, "/echo/hi", "");
+    assert!(
+        response.starts_with("HTTP/1.1 421 Misdirected Request\r\n"),
+        "{response}"
+    );
+}
+
+#[test]
+fn server_proxies_prefixes_to_an_upstream() {
+    let upstream = Server::bind("127.0.0.1:0", test_config(files_root("upstream")))
+        .unwrap()
+        .route(Method::Post, "/v1/{*rest}", |request| {
+            Response::text(format!(
+                "{} {:?} for={} via={} body={}",
+                request.path(),
+                request.query_pairs(),
+                request.header("x-forwarded-for").unwrap_or_default(),
+                request.header("via").unwrap_or_default(),
+                String::from_utf8_lossy(request.body().unwrap().unwrap_or_default()),
+            ))
+        })
+        .route(Method::Get, "/v1/stream", |_| {
+            Response::stream(io::Cursor::new("streamed"), None)
+        });
+    let upstream_addr = upstream.local_addr().unwrap();
+    thread::spawn(move || upstream.run());
+
+    let addr = spawn_server_with(Config {
+        proxies: vec![Proxy::new("/api/", &format!("http://{upstream_addr}/v1")).unwrap()],
+        ..test_config(files_root("proxy"))
+    });
+
+    let response = send(
+        addr,
+        "POST /api/a%20b?x=1 HTTP/1.1\r\nHost: localhost\r\nX-Forwarded-For: 203.0.113.7\r\nContent-Length: 2\r\nConnection: close\r\n\r\nhi",
+    );
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(response.contains("\r\nVia: 1.1 butler\r\n"), "{response}");
+    assert_eq!(response.matches("X-Request-Id").count(), 1, "{response}");
+    assert!(
+        response.ends_with(
+            r#"/v1/a%20b [("x", "1")] for=203.0.113.7, 127.0.0.1 via=1.1 butler body=hi"#
+        ),
+        "{response}"
+    );
+
+    let response = get(addr, "/api/stream", "");
+    assert!(
+        response.contains("Transfer-Encoding: chunked\r\n"),
+        "{response}"
+    );
+    assert!(
+        response.ends_with("\r\n8\r\nstreamed\r\n0\r\n\r\n"),
+        "{response}"
+    );
+
+    // a bare LF could start a header of its own for an upstream that ends lines with it
+    let response = get(addr, "/api/stream", "X-Foo: a\nInjected: yes\r\n");
+    assert!(
+        response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+        "{response}"
+    );
+
+    // routes outside the prefix are answered as usual
+    assert!(get(addr, "/echo/hi", "").ends_with("\r\n\r\nhi"));
+}
+
+#[test]
+fn server_answers_502_when_the_upstream_is_down() {
+    // a port nothing listens on once the listener is dropped
+    let closed = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let addr = spawn_server_with(Config {
+        proxies: vec![Proxy::new("/api", &format!("http://{closed}")).unwrap()],
+        ..test_config(files_root("proxy-down"))
+    });
+
+    let response = get(addr, "/api/anything", "");
+    assert!(
+        response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"),
+        "{response}"
+    );
+}
+
+#[test]
+fn server_hands_websocket_connections_to_their_handler() {
+    let server = Server::bind("127.0.0.1:0", test_config(files_root("websocket")))
+        .unwrap()
+        .websocket("/ws/{name}", |request: &Request, socket: &mut WebSocket| {
+            let name = request.param("name").unwrap_or_default().to_owned();
+            while let Some(message) = socket.recv()? {
+                if let Message::Text(text) = message {
+                    socket.send(Message::Text(format!("{name}: {text}")))?;
+                }
+            }
+            Ok(())
+        });
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+
+    // a plain request for the route is told to upgrade
+    let response = get(addr, "/ws/echo", "");
+    assert!(
+        response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"),
+        "{response}"
+    );
+
+    // the first frame is sent along with the handshake, so it's in the server's buffer already
+    let masked = |opcode: u8, payload: &[u8]| {
+        let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8, 1, 2, 3, 4];
+        frame.extend(
+            payload
+                .iter()
+                .zip([1, 2, 3, 4].iter().cycle())
+                .map(|(b, m)| b ^ m),
+        );
+        frame
+    };
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut request = b"GET /ws/echo HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+        Connection: keep-alive, Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+        Sec-WebSocket-Version: 13\r\n\r\n"
+        .to_vec();
+    request.extend(masked(0x1, b"hello"));
+    stream.write_all(&request).unwrap();
+
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut head = String::new();
+    while !head.ends_with("\r\n\r\n") {
+        reader.read_line(&mut head).unwrap();
+    }
+    assert!(
+        head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"),
+        "{head}"
+    );
+    assert!(
+        head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"),
+        "{head}"
+    );
+    assert!(!head.contains("Content-Length"), "{head}");
+
+    let mut read_frame = || {
+        let mut head = [0; 2];
+        reader.read_exact(&mut head).unwrap();
+        let mut payload = vec![0; usize::from(head[1])];
+        reader.read_exact(&mut payload).unwrap();
+        (head[0], payload)
+    };
+    assert_eq!(read_frame(), (0x81, b"echo: hello".to_vec()));
+
+    stream.write_all(&masked(0x9, b"ping")).unwrap();
+    assert_eq!(read_frame(), (0x8a, b"ping".to_vec()));
+
+    stream
+        .write_all(&masked(0x8, &1000u16.to_be_bytes()))
+        .unwrap();
+    assert_eq!(read_frame(), (0x88, 1000u16.to_be_bytes().to_vec()));
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+}
+
+#[test]
+fn server_streams_server_sent_events() {
+    // the second event is only sent once the client has seen the first
+    let (seen, wait_for_seen) = mpsc::channel::<()>();
+    let wait_for_seen = Mutex::new(Some(wait_for_seen));
+    let server = Server::bind("127.0.0.1:0", test_config(files_root("sse")))
+        .unwrap()
+        .route(Method::Get, "/events", move |_| {
+            let wait_for_seen = wait_for_seen.lock().unwrap().take().unwrap();
+            let (sender, events) = mpsc::channel();
+            thread::spawn(move || {
+                sender.send(Event::new("first").id("1")).unwrap();
+                wait_for_seen.recv().unwrap();
+                sender.send(Event::new("second").event("done")).unwrap();
+            });
+            Response::sse(events)
+        });
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(
+            b"GET /events HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: gzip\r\n\
+            Connection: close\r\n\r\n",
+        )
+        .unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut response = String::new();
+    while !response.contains("data: first\n") {
+        assert_ne!(reader.read_line(&mut response).unwrap(), 0, "{response}");
+    }
+    seen.send(()).unwrap();
+    reader.read_to_string(&mut response).unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(
+        response.contains("Content-Type: text/event-stream\r\n"),
+        "{response}"
+    );
+    assert!(
+        response.contains("Cache-Control: no-cache\r\n"),
+        "{response}"
+    );
+    // events are never held back by compression
+    assert!(!response.contains("Content-Encoding"), "{response}");
+    assert!(response.contains("id: 1\ndata: first\n\n"), "{response}");
+    assert!(
+        response.contains("event: done\ndata: second\n\n"),
+        "{response}"
+    );
+    assert!(response.ends_with("0\r\n\r\n"), "{response}");
+}
+
+#[cfg(unix)]
+#[test]
+fn server_runs_cgi_scripts() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let scripts = files_root("cgi-scripts");
+    let script = |name: &str, source: &str| {
+        let path = scripts.join(name);
+        fs::write(&path, source).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    };
+    script(
+        "env",
+        "#!/bin/sh\nprintf 'Content-Type: text/plain\\r\\nX-Script: env\\r\\n\\r\\n'\n\
+         echo \"$REQUEST_METHOD $SCRIPT_NAME $PATH_INFO $QUERY_STRING $HTTP_X_TEST\"\n\
+         echo \"auth=$HTTP_AUTHORIZATION\"\ncat\n",
+    );
+    script(
+        "missing",
+        "#!/bin/sh\nprintf 'Status: 404 Not Found\\n\\n'\n",
+    );
+    // `exec` so that killing the script kills the sleep, which would hold its output open otherwise
+    script("slow", "#!/bin/sh\nexec sleep 5\n");
+    fs::write(scripts.join("plain"), "not a script").unwrap();
+
+    let config = Config {
+        cgi: Some(Cgi::new("/cgi-bin/", &scripts).with_timeout(Duration::from_millis(300))),
+        max_in_memory_body_size: 1024,
+        ..test_config(files_root("cgi"))
+    };
+    let addr = spawn_server_with(config);
+
+    // a body spooled to disk is passed on from there
+    let body = "0123456789".repeat(10_000);
+    let response = send(
+        addr,
+        &format!(
+            "POST /cgi-bin/env HTTP/1.0\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        ),
+    );
+    assert!(response.starts_with("HTTP/1.0 200 OK\r\n"), "{response}");
+    assert!(
+        response.ends_with(&format!("auth=\n{body}")),
+        "{response:.200}"
+    );
+
+    // HTTP/1.0 gets the output unchunked, however the script writes it; `X_Test` would be
+    // `HTTP_X_TEST` as well, so it isn't passed on
+    let response = send(
+        addr,
+        "POST /cgi-bin/env/a/b?x=1 HTTP/1.0\r\nHost: localhost\r\nX_Test: forged\r\n\
+         X-Test: yes\r\nAuthorization: Bearer secret\r\nContent-Length: 5\r\n\r\nhello",
+    );
+    assert!(response.starts_with("HTTP/1.0 200 OK\r\n"), "{response}");
+    assert!(response.contains("X-Script: env\r\n"), "{response}");
+    assert!(
+        response.contains("POST /cgi-bin/env /a/b x=1 yes\nauth=\nhello"),
+        "{response}"
+    );
+
+    let response = get(addr, "/cgi-bin/missing", "");
+    assert!(
+        response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+        "{response}"
+    );
+    let response = get(addr, "/cgi-bin/plain", "");
+    assert!(
+        response.starts_with("HTTP/1.1 403 Forbidden\r\n"),
+        "{response}"
+    );
+    let response = get(addr, "/cgi-bin/slow", "");
+    assert!(
+        response.starts_with("HTTP/1.1 504 Gateway Timeout\r\n"),
+        "{response}"
+    );
+}
+
+#[test]
+fn server_reads_and_sets_cookies() {
+    let server = Server::bind("127.0.0.1:0", test_config(files_root("cookies")))
+        .unwrap()
+        .route(Method::Get, "/visit", |request: &Request| {
+            let visits: u32 = request
+                .cookie("visits")
+                .and_then(|visits| visits.parse().ok())
+                .unwrap_or(0);
+            Response::text(format!("visit {}", visits + 1))
+                .with_cookie(
+                    &SetCookie::new("visits", &(visits + 1).to_string())
+                        .path("/")
+                        .http_only(true)
+                        .same_site(SameSite::Strict),
+                )
+                .with_cookie(&SetCookie::removal("legacy"))
+        });
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+
+    let response = get(addr, "/visit", "Cookie: theme=dark; visits=41\r\n");
+    assert!(response.ends_with("\r\n\r\nvisit 42"), "{response}");
+    assert!(
+        response.contains("Set-Cookie: visits=42; Path=/; HttpOnly; SameSite=Strict\r\n"),
+        "{response}"
+    );
+    assert!(
+        response.contains("Set-Cookie: legacy=; Max-Age=0\r\n"),
+        "{response}"
+    );
+}
+
+#[test]
+fn server_keeps_sessions_between_requests() {
+    let server = Server::bind("127.0.0.1:0", test_config(files_root("sessions")))
+        .unwrap()
+        .layer(Sessions::signed(&[42; 32]).cookie_name("sid"))
+        .route(Method::Post, "/login", |request: &Request| {
+            request.session().unwrap().insert("user", "ada");
+            Response::no_content()
+        })
+        .route(Method::Get, "/whoami", |request: &Request| {
+            match request.session().unwrap().get("user") {
+                Some(user) => Response::text(user),
+                None => Response::new(StatusCode::Unauthorized),
+            }
+        })
+        .route(Method::Post, "/logout", |request: &Request| {
+            request.session().unwrap().clear();
+            Response::no_content()
+        });
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+
+    let response = send(
+        addr,
+        "POST /login HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+    );
+    let cookie =
+        Regex::new(r"Set-Cookie: (sid=[^;]+); Path=/; Max-Age=86400; HttpOnly; SameSite=Lax\r\n")
+            .unwrap()
+            .captures(&response)
+            .unwrap_or_else(|| panic!("{response}"))[1]
+            .to_owned();
+
+    let response = get(addr, "/whoami", &format!("Cookie: {cookie}\r\n"));
+    assert!(response.ends_with("\r\n\r\nada"), "{response}");
+    assert!(!response.contains("Set-Cookie"), "{response}");
+
+    let forged = cookie.replace("ada", "eve");
+    let response = get(addr, "/whoami", &format!("Cookie: {forged}\r\n"));
+    assert!(response.starts_with("HTTP/1.1 401 "), "{response}");
+
+    let response = send(
+        addr,
+        &format!("POST /logout HTTP/1.1\r\nHost: localhost\r\nCookie: {cookie}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"),
+    );
+    assert!(
+        response.contains("Set-Cookie: sid=; Path=/; Max-Age=0\r\n"),
+        "{response}"
+    );
+}
+
+#[test]
+fn server_listens_on_every_address_it_is_given() {
+    let server = Server::bind("127.0.0.1:0", test_config(files_root("listeners"))).unwrap();
+    let port = server.local_addr().unwrap().port();
+    // IPv6 listeners only take IPv6 connections, so both can have the same port
+    let server = server.listen(("::1", port)).unwrap();
+    let addrs = server.local_addrs().unwrap();
+    assert_eq!(addrs.len(), 2);
+    assert_eq!(addrs[1], format!("[::1]:{port}").parse().unwrap());
+    thread::spawn(move || server.run());
+
+    for addr in addrs {
+        let response = get(addr, "/echo/hi", "");
+        assert!(response.ends_with("\r\n\r\nhi"), "{addr}: {response}");
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn servers_share_a_port_with_reuse_port() {
+    let config = Config {
+        reuse_port: true,
+        ..test_config(files_root("reuse-port"))
+    };
+    let first = Server::bind("127.0.0.1:0", config.clone()).unwrap();
+    let addr = first.local_addr().unwrap();
+    let second = Server::bind(addr, config.clone()).unwrap();
+    assert!(Server::bind(addr, test_config(files_root("reuse-port"))).is_err());
+
+    thread::spawn(move || first.run());
+    thread::spawn(move || second.run());
+    for _ in 0..4 {
+        let response = get(addr, "/echo/shared", "");
+        assert!(response.ends_with("\r\n\r\nshared"), "{response}");
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn server_listens_on_unix_sockets() {
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    let root = files_root("unix-socket");
+    let path = root.join("butler.sock");
+    // a socket nothing listens on anymore is replaced
+    drop(UnixListener::bind(&path).unwrap());
+
+    let server = Server::bind_unix(&path, test_config(root)).unwrap();
+    assert!(server.local_addr().is_err());
+    thread::spawn(move || server.run());
+
+    let mut stream = UnixStream::connect(&path).unwrap();
+    stream
+        .write_all(b"GET /echo/unix HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(response.ends_with("\r\n\r\nunix"), "{response}");
+
+    // one that's in use isn't
+    let err = Server::bind_unix(&path, test_config(files_root("unix-socket"))).unwrap_err();
+    assert!(err.to_string().contains("in use"), "{err}");
+}
+
+#[test]
+fn server_rewrites_and_redirects_paths_before_routing() {
+    let root = files_root("rewrites");
+    fs::create_dir_all(root.join("assets")).unwrap();
+    fs::write(root.join("assets/app.js"), "let app;").unwrap();
+    let server = TestServer::start(
+        Server::bind(
+            "127.0.0.1:0",
+            Config {
+                rewrites: vec![
+                    "/static/=/files/assets/".parse().unwrap(),
+                    "/app/=/".parse().unwrap(),
+                    "^/posts/([0-9]+)$=/blog?id=$1".parse().unwrap(),
+                    RewriteRule::prefix("/old/", "/static/").redirect(StatusCode::MovedPermanently),
+                ],
+                ..test_config(root)
+            },
+        )
+        .unwrap()
+        .route(Method::Get, "/blog", |request: &Request| {
+            Response::text(format!("{:?}", request.query_pairs()))
+        }),
+    )
+    .unwrap();
+
+    let response = server.get("/static/app.js").unwrap();
+    assert_eq!(response.status, StatusCode::Ok);
+    assert_eq!(response.text(), "let app;");
+    assert_eq!(server.get("/app/echo/hi").unwrap().text(), "hi");
+
+    // a query in the target comes before the one that was sent
+    let response = server.get("/posts/42?page=2").unwrap();
+    assert_eq!(response.text(), r#"[("id", "42"), ("page", "2")]"#);
+    assert_eq!(
+        server.get("/posts/new").unwrap().status,
+        StatusCode::NotFound
+    );
+
+    let response = server.get("/old/app.js?v=1").unwrap();
+    assert_eq!(response.status, StatusCode::MovedPermanently);
+    assert_eq!(response.header("Location"), Some("/static/app.js?v=1"));
+}
+
+#[test]
+fn server_answers_unmatched_requests_with_the_fallback() {
+    let root = files_root("fallback");
+    fs::write(root.join("index.html"), "<div id=app></div>").unwrap();
+    let index = root.join("index.html");
+    let server = TestServer::start(
+        Server::bind("127.0.0.1:0", test_config(root))
+            .unwrap()
+            .fallback(move |_: &Request| Response::file(&index, None, None, None)),
+    )
+    .unwrap();
+
+    let response = server.get("/settings/profile").unwrap();
+    assert_eq!(response.status, StatusCode::Ok);
+    assert_eq!(response.header("Content-Type"), Some("text/html"));
+    assert_eq!(response.text(), "<div id=app></div>");
+
+    // routes that match keep answering for themselves
+    let response = server.get("/files/missing.js").unwrap();
+    assert_eq!(response.status, StatusCode::NotFound);
+    let response = server.request(Method::Put, "/echo/hi", &[], b"").unwrap();
+    assert_eq!(response.status, StatusCode::MethodNotAllowed);
+}
+
+#[test]
+fn server_sends_configured_error_pages() {
+    let root = files_root("error-pages");
+    let pages = root.with_file_name(format!("butler-error-pages-{}", std::process::id()));
+    fs::create_dir_all(&pages).unwrap();
+    fs::write(pages.join("404.html"), "<h1>lost?</h1>").unwrap();
+    fs::write(pages.join("431.txt"), "too much").unwrap();
+    let addr = spawn_server_with(Config {
+        error_pages: HashMap::from([
+            (StatusCode::NotFound, pages.join("404.html")),
+            (
+                StatusCode::RequestHeaderFieldsTooLarge,
+                pages.join("431.txt"),
+            ),
+            (StatusCode::InternalServerError, pages.join("missing.html")),
+        ]),
+        max_header_count: 2,
+        ..test_config(root)
+    });
+
+    let response = get(addr, "/nowhere", "");
+    assert!(
+        response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+        "{response}"
+    );
+    assert!(
+        response.contains("Content-Type: text/html\r\nContent-Length: 14\r\n"),
+        "{response}"
+    );
+    assert!(response.ends_with("\r\n\r\n<h1>lost?</h1>"), "{response}");
+
+    // responses the server makes up itself get them too
+    let response = get(addr, "/", "Accept: */*\r\n");
+    assert!(
+        response.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"),
+        "{response}"
+    );
+    assert!(response.ends_with("\r\n\r\ntoo much"), "{response}");
+}
+
+#[test]
+fn server_renders_templates_for_error_pages_and_handlers() {
+    let root = files_root("templates");
+    let pages = root.with_file_name(format!("butler-template-pages-{}", std::process::id()));
+    fs::create_dir_all(&pages).unwrap();
+    fs::write(pages.join("error.html"), "<h1>{{status}} {{reason}}</h1>").unwrap();
+    fs::write(pages.join("broken.html"), "<h1>{{#open}}</h1>").unwrap();
+    let template: Template = "<ul>{{#items}}<li>{{name}}</li>{{/items}}</ul>"
+        .parse()
+        .unwrap();
+    let server = TestServer::start(
+        Server::bind(
+            "127.0.0.1:0",
+            Config {
+                error_pages: HashMap::from([
+                    (StatusCode::NotFound, pages.join("error.html")),
+                    (StatusCode::MethodNotAllowed, pages.join("broken.html")),
+                ]),
+                ..test_config(root)
+            },
+        )
+        .unwrap()
+        .route(Method::Get, "/list", move |_: &Request| {
+            let items = ["tea", "<cake>"]
+                .map(|name| TemplateContext::new().with("name", name))
+                .to_vec();
+            Response::render(&template, &TemplateContext::new().with("items", items))
+        }),
+    )
+    .unwrap();
+
+    let response = server.get("/nowhere").unwrap();
+    assert_eq!(response.status, StatusCode::NotFound);
+    assert_eq!(response.header("Content-Type"), Some("text/html"));
+    assert_eq!(response.text(), "<h1>404 Not Found</h1>");
+
+    // pages that aren't valid templates are sent as they are
+    let response = server.request(Method::Put, "/list", &[], b"").unwrap();
+    assert_eq!(response.status, StatusCode::MethodNotAllowed);
+    assert_eq!(response.text(), "<h1>{{#open}}</h1>");
+
+    let response = server.get("/list").unwrap();
+    assert_eq!(response.header("Content-Type"), Some("text/html"));
+    assert_eq!(
+        response.text(),
+        "<ul><li>tea</li><li>&lt;cake&gt;</li></ul>"
+    );
+}
+
+#[test]
+fn server_times_out_silent_clients() {
+    let addr = spawn_server_with(Config {
+        read_timeout: Duration::from_millis(200),
+        ..test_config(files_root("timeout"))
+    });
+
+    let started = Instant::now();
+    let mut stream = TcpStream::connect(addr).unwrap();
+    // guards against the server never closing, so the test fails instead of hanging
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    assert!(
+        response.starts_with("HTTP/1.1 408 Request Timeout\r\n"),
+        "{response}"
+    );
+    assert!(response.contains("Connection: close\r\n"), "{response}");
+    assert!(started.elapsed() < Duration::from_secs(2));
+}
+
+#[test]
+fn server_drops_clients_that_stop_reading() {
+    let root = files_root("write-timeout");
+    // far more than the socket buffers hold, so writing it blocks on the client
+    fs::write(root.join("big.bin"), vec![0; 64 * 1024 * 1024]).unwrap();
+    let addr = spawn_server_with(Config {
+        write_timeout: Duration::from_millis(200),
+        ..test_config(root)
+    });
+
+    let mut stalled = TcpStream::connect(addr).unwrap();
+    stalled
+        .write_all(b"GET /files/big.bin HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+
+    let started = Instant::now();
+    let health = Regex::new(r#""active_connections":1,"#).unwrap();
+    while !health.is_match(&get(addr, "/health", "")) {
+        assert!(
+            started.elapsed() < Duration::from_secs(5),
+            "the stalled connection is still being served"
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn server_times_out_request_heads_sent_a_little_at_a_time() {
+    let addr = spawn_server_with(Config {
+        header_timeout: Duration::from_millis(300),
+        ..test_config(files_root("header-timeout"))
+    });
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+
+    // each header comes well within the read timeout, but the head as a whole never ends
+    stream
+        .set_read_timeout(Some(Duration::from_millis(50)))
+        .unwrap();
+    let started = Instant::now();
+    while stream.peek(&mut [0]).is_err() {
+        assert!(
+            started.elapsed() < Duration::from_secs(2),
+            "still no response"
+        );
+        stream.write_all(b"X-A: b\r\n").unwrap();
+    }
+    stream.set_read_timeout(None).unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(
+        response.starts_with("HTTP/1.1 408 Request Timeout\r\n"),
+        "{response}"
+    );
+}
+
+#[test]
+fn server_limits_connections_per_client_address() {
+    let addr = spawn_server_with(Config {
+        max_connections_per_ip: Some(1),
+        ..test_config(files_root("per-ip"))
+    });
+
+    // held open by not finishing its request
+    let mut first = TcpStream::connect(addr).unwrap();
+    first.write_all(b"GET /echo/first HTTP/1.1\r\n").unwrap();
+    thread::sleep(Duration::from_millis(200));
+
+    // turned away as soon as it connects, writing a request would only get it reset
+    let mut response = String::new();
+    TcpStream::connect(addr)
+        .unwrap()
+        .read_to_string(&mut response)
+        .unwrap();
+    assert!(
+        response.starts_with("HTTP/1.1 429 Too Many Requests\r\n"),
+        "{response}"
+    );
+
+    first
+        .write_all(b"Host: localhost\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    first.read_to_string(&mut response).unwrap();
+    assert!(response.ends_with("\r\n\r\nfirst"), "{response}");
+
+    // the slot is given back once the connection is done
+    let started = Instant::now();
+    while !get(addr, "/", "").starts_with("HTTP/1.1 200 OK\r\n") {
+        assert!(started.elapsed() < Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn server_dispatches_custom_routes() {
+    let server = Server::bind("127.0.0.1:0", test_config(files_root("custom-route")))
+        .unwrap()
+        .route(Method::Get, "/greet/{name}", |request| {
+            Response::text(format!("hello, {}!", request.param("name").unwrap()))
+        });
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+
+    let response = get(addr, "/greet/world", "");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(response.ends_with("\r\n\r\nhello, world!"), "{response}");
+
+    let response = send(
+        addr,
+        "POST /greet/world HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    );
+    assert!(
+        response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"),
+        "{response}"
+    );
+    assert!(
+        response.contains("Allow: GET, HEAD, OPTIONS\r\n"),
+        "{response}"
+    );
+
+    // built-in routes are still served alongside custom ones
+    assert!(get(addr, "/echo/hi", "").ends_with("\r\n\r\nhi"));
+}
+
+#[test]
+fn server_answers_failed_handlers_with_internal_server_error() {
+    // counts how often it was called, to show handlers can be types of their own
+    struct Counter(AtomicUsize);
+
+    impl Handler for Counter {
+        fn handle(&self, _: &Request) -> anyhow::Result<Response> {
+            let count = self.0.fetch_add(1, Ordering::SeqCst) + 1;
+            Ok(Response::text(count.to_string()))
+        }
+    }
+
+    let server = Server::bind("127.0.0.1:0", test_config(files_root("failing-handler")))
+        .unwrap()
+        .route(Method::Get, "/number/{n}", |request| {
+            let n: u32 = request.param("n").unwrap().parse()?;
+            Ok(Response::text((n * 2).to_string()))
+        })
+        .handler(Method::Get, "/count", Counter(AtomicUsize::new(0)))
+        .route(Method::Get, "/panic", |_: &Request| -> Response {
+            panic!("the handler gave up")
+        });
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+
+    assert!(get(addr, "/number/21", "").ends_with("\r\n\r\n42"));
+
+    // debug builds say why
+    let response = get(addr, "/number/many", "");
+    assert!(
+        response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"),
+        "{response}"
+    );
+    assert!(
+        response.ends_with("invalid digit found in string"),
+        "{response}"
+    );
+
+    // a panic is answered too, and the connection stays usable
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(b"GET /panic HTTP/1.1\r\nHost: localhost\r\n\r\nGET /count HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(
+        response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"),
+        "{response}"
+    );
+    assert!(
+        response.contains("handler panicked: the handler gave up"),
+        "{response}"
+    );
+    assert!(response.ends_with("\r\n\r\n1"), "{response}");
+
+    get(addr, "/count", "");
+    assert!(get(addr, "/count", "").ends_with("\r\n\r\n3"));
+}
+
+#[test]
+fn server_runs_requests_through_middleware() {
+    // lets requests through with `Authorization: secret` only
+    struct RequireToken;
+
+    impl Middleware for RequireToken {
+        fn before(&self, request: &mut Request) -> Option<Response> {
+            (request.header("authorization") != Some("secret")).then(|| {
+                Response::builder()
+                    .status(StatusCode::Unauthorized)
+                    .build()
+                    .unwrap()
+            })
+        }
+    }
+
+    struct Tag(&'static str);
+
+    impl Middleware for Tag {
+        fn after(&self, _: &Request, response: Response) -> Response {
+            response.with_header("X-Layers", self.0)
+        }
+    }
+
+    let server = Server::bind("127.0.0.1:0", test_config(files_root("middleware")))
+        .unwrap()
+        .layer(Tag("outer"))
+        .layer(RequireToken)
+        .layer(Tag("inner"));
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+
+    let response = get(addr, "/echo/hi", "");
+    assert!(
+        response.starts_with("HTTP/1.1 401 Unauthorized\r\n"),
+        "{response}"
+    );
+    assert!(response.contains("X-Layers: outer\r\n"), "{response}");
+    assert!(!response.contains("X-Layers: inner"), "{response}");
+
+    let response = get(addr, "/echo/hi", "Authorization: secret\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(
+        response.contains("X-Layers: inner\r\nX-Layers: outer\r\n"),
+        "{response}"
+    );
+    assert!(response.ends_with("\r\n\r\nhi"), "{response}");
+}
+
+#[test]
+fn server_only_compresses_large_enough_bodies() {
+    let addr = spawn_server(files_root("compress"));
+    let echo = |body: &str| {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "POST /echo HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: gzip\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+        .unwrap();
+
+        // a compressed body isn't valid UTF-8
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        String::from_utf8_lossy(&response).into_owned()
+    };
+
+    let response = echo("hi");
+    assert!(!response.contains("Content-Encoding"), "{response}");
+    assert!(!response.contains("Accept-Encoding"), "{response}");
+    assert!(response.ends_with("\r\n\r\nhi"), "{response}");
+
+    let response = echo(&"a".repeat(2048));
+    assert!(
+        response.contains("Content-Encoding: gzip\r\n"),
+        "{response}"
+    );
+    assert!(response.contains("Vary: Accept-Encoding\r\n"), "{response}");
+}
+
+#[test]
+fn server_marks_compressed_files_as_negotiated() {
+    let root = files_root("compress-files");
+    fs::write(root.join("page.txt"), "a".repeat(2048)).unwrap();
+    let server = TestServer::new(test_config(root)).unwrap();
+    let gzip = [("Accept-Encoding", "gzip")];
+
+    // the file as it is varies with `Accept-Encoding` as well, since other clients get it gzipped
+    let identity = server.get("/files/page.txt").unwrap();
+    assert_eq!(identity.header("Content-Encoding"), None);
+    assert_eq!(identity.header("Vary"), Some("Accept-Encoding"));
+    let etag = identity.header("ETag").unwrap().to_owned();
+    assert!(!etag.starts_with("W/"), "{etag}");
+
+    // the gzipped body isn't the one the strong tag was made for
+    let gzipped = server
+        .request(Method::Get, "/files/page.txt", &gzip, b"")
+        .unwrap();
+    assert_eq!(gzipped.header("Content-Encoding"), Some("gzip"));
+    assert_eq!(gzipped.header("Vary"), Some("Accept-Encoding"));
+    assert_eq!(gzipped.header("ETag"), Some(format!("W/{etag}").as_str()));
+
+    // but it can still be revalidated
+    let revalidate = [
+        ("Accept-Encoding", "gzip"),
+        ("If-None-Match", &format!("W/{etag}")),
+    ];
+    let response = server
+        .request(Method::Get, "/files/page.txt", &revalidate, b"")
+        .unwrap();
+    assert_eq!(response.status, StatusCode::NotModified);
+    assert_eq!(response.header("Vary"), Some("Accept-Encoding"));
+}
+
+#[test]
+fn server_sends_precompressed_copies_of_files() {
+    let root = files_root("precompressed");
+    fs::write(root.join("app.js"), "let fresh = true;").unwrap();
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(b"let precompressed = true;").unwrap();
+    fs::write(root.join("app.js.gz"), encoder.finish().unwrap()).unwrap();
+    let server = TestServer::new(test_config(root.clone())).unwrap();
+    let gzip = [("Accept-Encoding", "gzip")];
+
+    let response = server
+        .request(Method::Get, "/files/app.js", &gzip, b"")
+        .unwrap();
+    assert_eq!(response.status, StatusCode::Ok);
+    assert_eq!(response.header("Content-Encoding"), Some("gzip"));
+    assert_eq!(response.header("Content-Type"), Some("text/javascript"));
+    let mut body = String::new();
+    flate2::read::GzDecoder::new(&response.body[..])
+        .read_to_string(&mut body)
+        .unwrap();
+    assert_eq!(body, "let precompressed = true;");
+    assert_eq!(response.header("Vary"), Some("Accept-Encoding"));
+
+    // clients that don't accept gzip, and ranges, get the file itself
+    let response = server.get("/files/app.js").unwrap();
+    assert_eq!(response.header("Content-Encoding"), None);
+    assert_eq!(response.header("Vary"), Some("Accept-Encoding"));
+    assert_eq!(response.text(), "let fresh = true;");
+    let ranged = [("Accept-Encoding", "gzip"), ("Range", "bytes=0-2")];
+    let response = server
+        .request(Method::Get, "/files/app.js", &ranged, b"")
+        .unwrap();
+    assert_eq!(response.text(), "let");
+
+    // as does everyone once the copy is older than the file
+    File::options()
+        .write(true)
+        .open(root.join("app.js.gz"))
+        .unwrap()
+        .set_modified(SystemTime::now() - Duration::from_secs(60))
+        .unwrap();
+    let response = server
+        .request(Method::Get, "/files/app.js", &gzip, b"")
+        .unwrap();
+    assert_eq!(response.header("Content-Encoding"), None);
+    assert_eq!(response.text(), "let fresh = true;");
+}
+
+#[test]
+fn server_serves_cached_files_until_they_change() {
+    let root = files_root("file-cache");
+    fs::write(root.join("page.txt"), "a".repeat(2048)).unwrap();
+    let addr = spawn_server_with(Config {
+        file_cache_size: 1024 * 1024,
+        ..test_config(root)
+    });
+
+    let response = get(addr, "/files/page.txt", "Range: bytes=0-2\r\n");
+    assert!(
+        response.starts_with("HTTP/1.1 206 Partial Content\r\n"),
+        "{response}"
+    );
+    assert!(response.ends_with("\r\n\r\naaa"), "{response}");
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(b"GET /files/page.txt HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: gzip\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+    let head = String::from_utf8_lossy(&response[..split]);
+    assert!(head.contains("Content-Encoding: gzip\r\n"), "{head}");
+    assert!(head.contains("Vary: Accept-Encoding\r\n"), "{head}");
+    assert!(head.contains("ETag: W/\""), "{head}");
+    assert!(
+        head.contains(&format!("Content-Length: {}\r\n", response.len() - split)),
+        "{head}"
+    );
+    let mut body = String::new();
+    flate2::read::GzDecoder::new(&response[split..])
+        .read_to_string(&mut body)
+        .unwrap();
+    assert_eq!(body, "a".repeat(2048));
+
+    let response = send(
+        addr,
+        "PUT /files/page.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: 3\r\nConnection: close\r\n\r\nnew",
+    );
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    let response = get(addr, "/files/page.txt", "");
+    assert!(response.ends_with("\r\n\r\nnew"), "{response}");
+}
+
+#[test]
+fn server_serves_uploads_with_their_declared_type() {
+    let addr = spawn_server(files_root("upload-type"));
+
+    let response = send(
+        addr,
+        "POST /files/data.bin HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{}",
+    );
+    assert!(
+        response.starts_with("HTTP/1.1 201 Created\r\n"),
+        "{response}"
+    );
+
+    let response = get(addr, "/files/data.bin", "");
+    assert!(
+        response.contains("Content-Type: application/json\r\n"),
+        "{response}"
+    );
+}
+
+#[test]
+fn server_serves_files_with_the_type_of_their_extension() {
+    let root = files_root("mime-types");
+    fs::write(root.join("logo.svg"), "<svg/>").unwrap();
+    fs::write(root.join("notes.MD"), "# notes").unwrap();
+    fs::write(root.join("page.html"), "<p>hi</p>").unwrap();
+    let addr = spawn_server_with(Config {
+        mime_types: HashMap::from([
+            ("md".to_owned(), "text/markdown".parse().unwrap()),
+            ("html".to_owned(), "text/plain".parse().unwrap()),
+        ]),
+        ..test_config(root)
+    });
+
+    for (path, content_type) in [
+        ("/files/logo.svg", "image/svg+xml"),
+        ("/files/notes.MD", "text/markdown"),
+        ("/files/page.html", "text/plain"),
+        ("/files/nested/foo.txt", "text/plain"),
+    ] {
+        let response = get(addr, path, "");
+        assert!(
+            response.contains(&format!("Content-Type: {content_type}\r\n")),
+            "{path}: {response}"
+        );
+    }
+}
+
+#[test]
+fn server_rejects_oversized_header_blocks() {
+    let addr = spawn_server_with(Config {
+        max_header_count: 10,
+        max_header_size: 1024,
+        ..test_config(files_root("header-limits"))
+    });
+
+    let many_headers: String = (0..11).map(|i| format!("X-Header-{i}: {i}\r\n")).collect();
+    let response = send(
+        addr,
+        &format!("GET / HTTP/1.1\r\nHost: localhost\r\n{many_headers}\r\n"),
+    );
+    assert!(
+        response.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"),
+        "{response}"
+    );
+
+    let response = get(addr, "/", &format!("X-Big: {}\r\n", "a".repeat(1024)));
+    assert!(
+        response.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"),
+        "{response}"
+    );
+
+    // exactly at the limit is still fine
+    let headers: String = (0..8).map(|i| format!("X-Header-{i}: {i}\r\n")).collect();
+    assert!(get(addr, "/", &headers).starts_with("HTTP/1.1 200 OK\r\n"));
+}
+
+#[test]
+fn server_rejects_oversized_bodies_before_reading_them() {
+    let addr = spawn_server_with(Config {
+        max_body_size: 4,
+        ..test_config(files_root("body-limit"))
+    });
+
+    let response = send(
+        addr,
+        "POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello",
+    );
+    assert!(
+        response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"),
+        "{response}"
+    );
+    assert!(response.contains("Connection: close\r\n"), "{response}");
+
+    let response = send(
+        addr,
+        "POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\nConnection: close\r\n\r\nhell",
+    );
+    assert!(response.ends_with("\r\n\r\nhell"), "{response}");
+}
+
+#[test]
+fn server_sends_date_and_server_headers() {
+    let addr = spawn_server(files_root("date"));
+
+    let response = get(addr, "/", "");
+
+    let date = Regex::new(
+        r"\r\nDate: (Mon|Tue|Wed|Thu|Fri|Sat|Sun), \d{2} (Jan|Feb|Mar|Apr|May|Jun|Jul|Aug|Sep|Oct|Nov|Dec) \d{4} \d{2}:\d{2}:\d{2} GMT\r\n",
+    )
+    .unwrap();
+    assert!(date.is_match(&response), "{response}");
+    assert!(
+        response.contains(&format!(
+            "\r\nServer: butler/{}\r\n",
+            env!("CARGO_PKG_VERSION")
+        )),
+        "{response}"
+    );
+
+    let addr = spawn_server_with(Config {
+        server_header: Some("example".to_owned()),
+        ..test_config(files_root("date"))
+    });
+    let response = get(addr, "/", "");
+    assert!(response.contains("\r\nServer: example\r\n"), "{response}");
+    // even responses to requests that couldn't be read
+    let response = send(addr, "NONSENSE\r\n\r\n");
+    assert!(response.contains("\r\nServer: example\r\n"), "{response}");
+
+    let addr = spawn_server_with(Config {
+        server_header: None,
+        ..test_config(files_root("date"))
+    });
+    let response = get(addr, "/", "");
+    assert!(date.is_match(&response), "{response}");
+    assert!(!response.contains("\r\nServer: "), "{response}");
+}
+
+#[test]
+fn server_writes_access_log_lines_to_a_file() {
+    let root = files_root("access-log");
+    let log = root.join("access.log");
+    let _ = fs::remove_file(&log);
+    let addr = spawn_server_with(Config {
+        access_log: Some(log.clone()),
+        access_log_format: AccessLogFormat::Combined,
+        ..test_config(root)
+    });
+
+    get(addr, "/echo/hi", "User-Agent: butler-test/1.0\r\n");
+
+    // lines are written by a thread of their own, so they may show up a little after the response
+    let line = Regex::new(
+        r#"^127\.0\.0\.1 - - \[[^\]]+\] "GET /echo/hi HTTP/1\.1" 200 2 "-" "butler-test/1\.0" \d+\n$"#,
+    )
+    .unwrap();
+    let started = Instant::now();
+    loop {
+        let contents = fs::read_to_string(&log).unwrap();
+        if line.is_match(&contents) {
+            break;
+        }
+        assert!(started.elapsed() < Duration::from_secs(5), "{contents}");
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn server_puts_files_with_created_or_replaced_status() {
+    let root = files_root("put");
+    let _ = fs::remove_file(root.join("put.txt"));
+    let addr = spawn_server(root.clone());
+    let put = |body: &str| {
+        send(
+            addr,
+            &format!(
+                "PUT /files/put.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            ),
+        )
+    };
+
+    let response = put("first");
+    assert!(
+        response.starts_with("HTTP/1.1 201 Created\r\n"),
+        "{response}"
+    );
+    assert_eq!(fs::read_to_string(root.join("put.txt")).unwrap(), "first");
+
+    let response = put("second");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert_eq!(fs::read_to_string(root.join("put.txt")).unwrap(), "second");
+
+    // nothing but the file itself is left behind
+    let names: Vec<_> = fs::read_dir(&root)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert!(
+        !names
+            .iter()
+            .any(|name| name.to_string_lossy().ends_with(".tmp")),
+        "{names:?}"
+    );
+}
+
+#[test]
+fn server_checks_put_preconditions() {
+    let root = files_root("put-preconditions");
+    let _ = fs::remove_file(root.join("put.txt"));
+    let addr = spawn_server(root.clone());
+    let put = |headers: &str, body: &str| {
+        send(
+            addr,
+            &format!(
+                "PUT /files/put.txt HTTP/1.1\r\nHost: localhost\r\n{headers}Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            ),
+        )
+    };
+    let etag = |response: &str| {
+        response
+            .lines()
+            .find_map(|line| line.strip_prefix("ETag: "))
+            .unwrap()
+            .to_owned()
+    };
+
+    // there's nothing to match yet
+    let response = put("If-Match: *\r\n", "first");
+    assert!(response.starts_with("HTTP/1.1 412 "), "{response}");
+    let response = put("If-None-Match: *\r\n", "first");
+    assert!(
+        response.starts_with("HTTP/1.1 201 Created\r\n"),
+        "{response}"
+    );
+    let first = etag(&response);
+    assert!(get(addr, "/files/put.txt", "").contains(&format!("ETag: {first}\r\n")));
+
+    // only creating it once
+    let response = put("If-None-Match: *\r\n", "again");
+    assert!(response.starts_with("HTTP/1.1 412 "), "{response}");
+
+    let response = put(&format!("If-Match: {first}\r\n"), "second!");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert_ne!(etag(&response), first);
+
+    // a write based on what's no longer there is refused
+    let response = put(&format!("If-Match: {first}\r\n"), "lost update");
+    assert!(response.starts_with("HTTP/1.1 412 "), "{response}");
+    let response = put(&format!("If-Match: W/{first}\r\n"), "weak");
+    assert!(response.starts_with("HTTP/1.1 412 "), "{response}");
+    assert_eq!(fs::read_to_string(root.join("put.txt")).unwrap(), "second!");
+}
+
+#[test]
+fn server_answers_pipelined_requests_in_order() {
+    let addr = spawn_server(files_root("pipelining"));
+    // larger than the connection's read buffer, so it arrives over several reads
+    let body = "b".repeat(20_000);
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{body}\
+         GET /echo/two HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        body.len()
+    )
+    .unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    let (first, second) = response
+        .split_once(&body)
+        .unwrap_or_else(|| panic!("first body missing from {response}"));
+    assert!(first.starts_with("HTTP/1.1 200 OK\r\n"), "{first}");
+    assert!(
+        first.contains(&format!("Content-Length: {}\r\n", body.len())),
+        "{first}"
+    );
+    assert!(second.starts_with("HTTP/1.1 200 OK\r\n"), "{second}");
+    assert!(second.ends_with("\r\n\r\ntwo"), "{second}");
+}
+
+#[test]
+fn server_reads_bodies_strictly_by_content_length() {
+    let root = files_root("content-length");
+    let addr = spawn_server(root.clone());
+
+    // a zero length is an empty body, not a missing one
+    let response = send(
+        addr,
+        "POST /files/empty.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+    );
+    assert!(
+        response.starts_with("HTTP/1.1 201 Created\r\n"),
+        "{response}"
+    );
+    assert_eq!(fs::read(root.join("empty.txt")).unwrap(), b"");
+
+    let response = send(
+        addr,
+        "POST /files/missing.txt HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    );
+    assert!(
+        response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+        "{response}"
+    );
+
+    // only the declared number of bytes belongs to the body, the rest is the next request
+    let response = send(
+        addr,
+        "POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 3\r\n\r\nabcGET /echo/next HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    );
+    assert!(
+        response.contains("\r\n\r\nabcHTTP/1.1 200 OK\r\n"),
+        "{response}"
+    );
+    assert!(response.ends_with("\r\n\r\nnext"), "{response}");
+}
+
+// a self-signed certificate for `localhost`, with its private key
+fn test_certs() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/certs")
+}
+
+fn test_tls_config() -> TlsConfig {
+    let certs = test_certs();
+    TlsConfig::from_pem_files(&certs.join("cert.pem"), &certs.join("key.pem")).unwrap()
+}
+
+// connects over TLS, trusting the test certificate and offering `alpn_protocols`
+fn connect_tls(
+    addr: SocketAddr,
+    alpn_protocols: &[&[u8]],
+) -> rustls::StreamOwned<rustls::ClientConnection, TcpStream> {
+    connect_tls_with(addr, alpn_protocols, false)
+}
+
+// like `connect_tls`, presenting the test client certificate signed by `ca.pem` when `client_cert`
+fn connect_tls_with(
+    addr: SocketAddr,
+    alpn_protocols: &[&[u8]],
+    client_cert: bool,
+) -> rustls::StreamOwned<rustls::ClientConnection, TcpStream> {
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(test_certs().join("cert.pem")).unwrap() {
+        roots.add(cert.unwrap()).unwrap();
+    }
+    let client_config = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .unwrap()
+    .with_root_certificates(roots);
+    let mut client_config = match client_cert {
+        true => {
+            let certs = CertificateDer::pem_file_iter(test_certs().join("client.pem"))
+                .unwrap()
+                .map(Result::unwrap)
+                .collect();
+            let key = PrivateKeyDer::from_pem_file(test_certs().join("client-key.pem")).unwrap();
+            client_config.with_client_auth_cert(certs, key).unwrap()
+        }
+        false => client_config.with_no_client_auth(),
+    };
+    client_config.alpn_protocols = alpn_protocols.iter().map(|p| p.to_vec()).collect();
+    let conn =
+        rustls::ClientConnection::new(Arc::new(client_config), "localhost".try_into().unwrap())
+            .unwrap();
+
+    rustls::StreamOwned::new(conn, TcpStream::connect(addr).unwrap())
+}
+
+// sends `request` over TLS and returns the whole response
+fn send_tls(addr: SocketAddr, request: &str) -> String {
+    try_send_tls(addr, request, false).unwrap()
+}
+
+// like `send_tls`, with the test client certificate when `client_cert`, failing when the server
+// turns the connection away
+fn try_send_tls(addr: SocketAddr, request: &str, client_cert: bool) -> io::Result<String> {
+    let mut stream = connect_tls_with(addr, &[], client_cert);
+    stream.write_all(request.as_bytes())?;
+
+    // the server ends the session with a close_notify, so this doesn't fail on a truncated stream
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+#[test]
+fn server_serves_https_when_tls_is_configured() {
+    let addr = spawn_server_with(Config {
+        tls: Some(test_tls_config()),
+        ..test_config(files_root("tls"))
+    });
+
+    let response = send_tls(
+        addr,
+        "GET /echo/secure HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(response.ends_with("\r\n\r\nsecure"), "{response}");
+
+    // plain HTTP isn't accepted on a TLS listener
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response);
+    assert!(!response.starts_with(b"HTTP/1.1 200"));
+}
+
+fn write_h2_frame(stream: &mut impl Write, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) {
+    let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+    frame.extend([kind, flags]);
+    frame.extend(stream_id.to_be_bytes());
+    frame.extend(payload);
+    stream.write_all(&frame).unwrap();
+}
+
+// returns the type, flags, stream and payload of the next frame
+fn read_h2_frame(stream: &mut impl Read) -> (u8, u8, u32, Vec<u8>) {
+    let mut header = [0; 9];
+    stream.read_exact(&mut header).unwrap();
+    let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload).unwrap();
+    let stream_id = u32::from_be_bytes([header[5], header[6], header[7], header[8]]);
+    (header[3], header[4], stream_id, payload)
+}
+
+// encodes each field as a literal with a new name, which every HPACK decoder understands
+fn h2_header_block(fields: &[(&str, &str)]) -> Vec<u8> {
+    let mut block = Vec::new();
+    for (name, value) in fields {
+        block.push(0);
+        block.push(name.len() as u8);
+        block.extend(name.bytes());
+        block.push(value.len() as u8);
+        block.extend(value.bytes());
+    }
+    block
+}
+
+#[test]
+fn server_speaks_http2_to_clients_that_negotiate_it() {
+    let addr = spawn_server_with(Config {
+        tls: Some(test_tls_config()),
+        ..test_config(files_root("http2"))
+    });
+
+    let mut stream = connect_tls(addr, &[b"h2", b"http/1.1"]);
+    stream
+        .write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n")
+        .unwrap();
+    write_h2_frame(&mut stream, 0x4, 0, 0, &[]);
+
+    // stream 1 is still sending its body when stream 3 is complete, so 3 is answered first
+    let request = |method, path| {
+        h2_header_block(&[
+            (":method", method),
+            (":scheme", "https"),
+            (":path", path),
+            (":authority", "localhost"),
+        ])
+    };
+    write_h2_frame(&mut stream, 0x1, 0x4, 1, &request("POST", "/echo"));
+    write_h2_frame(
+        &mut stream,
+        0x1,
+        0x4 | 0x1,
+        3,
+        &request("GET", "/echo/second"),
+    );
+    write_h2_frame(&mut stream, 0x0, 0x1, 1, b"first");
+
+    let mut ended = Vec::new();
+    let mut headers: HashMap<u32, Vec<u8>> = HashMap::new();
+    let mut bodies: HashMap<u32, Vec<u8>> = HashMap::new();
+    while ended.len() < 2 {
+        let (kind, flags, stream_id, payload) = read_h2_frame(&mut stream);
+        match kind {
+            0x0 => bodies.entry(stream_id).or_default().extend(payload),
+            0x1 => {
+                headers.insert(stream_id, payload);
+            }
+            _ => continue,
+        }
+        if flags & 0x1 != 0 {
+            ended.push(stream_id);
+        }
+    }
+    assert_eq!(stream.conn.alpn_protocol(), Some(&b"h2"[..]));
+
+    assert_eq!(ended, [3, 1]);
+    // both start with the static table's `:status: 200`
+    assert_eq!(headers[&1][0], 0x88);
+    assert_eq!(headers[&3][0], 0x88);
+    assert_eq!(bodies[&1], b"first");
+    assert_eq!(bodies[&3], b"second");
+
+    // a field name with a `:` in it is malformed, rather than split in two
+    let mut block = request("GET", "/echo/split");
+    block.extend(h2_header_block(&[("x:y", "z")]));
+    write_h2_frame(&mut stream, 0x1, 0x4 | 0x1, 5, &block);
+    let (kind, _, stream_id, payload) = loop {
+        let frame = read_h2_frame(&mut stream);
+        if frame.0 != 0x4 && frame.0 != 0x8 {
+            break frame;
+        }
+    };
+    // RST_STREAM with PROTOCOL_ERROR
+    assert_eq!((kind, stream_id, payload), (0x3, 5, vec![0, 0, 0, 1]));
+
+    // clients that don't offer HTTP/2 still get HTTP/1.1
+    let response = send_tls(
+        addr,
+        "GET /echo/old HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+}
+
+#[test]
+fn server_bounds_what_http2_requests_take_up() {
+    let addr = spawn_server_with(Config {
+        tls: Some(test_tls_config()),
+        max_in_memory_body_size: 4,
+        ..test_config(files_root("http2-limits"))
+    });
+
+    let mut stream = connect_tls(addr, &[b"h2"]);
+    stream
+        .write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n")
+        .unwrap();
+    write_h2_frame(&mut stream, 0x4, 0, 0, &[]);
+
+    let request = |method, path| {
+        h2_header_block(&[
+            (":method", method),
+            (":scheme", "https"),
+            (":path", path),
+            (":authority", "localhost"),
+        ])
+    };
+    // a 1000 byte entry added to the table, then referred to a thousand times in a small block
+    let mut block = request("GET", "/echo/big");
+    block.extend([0x40, 5]);
+    block.extend(b"x-big");
+    block.extend([0x7f, 0xe9, 0x06]);
+    block.extend([b'x'; 1000]);
+    block.extend([0xbe; 1000]);
+    write_h2_frame(&mut stream, 0x1, 0x4 | 0x1, 1, &block);
+    // the entry is still in the table for the next request, and a body larger than what's kept in
+    // memory arrives in full
+    let mut block = request("POST", "/echo");
+    block.push(0xbe);
+    write_h2_frame(&mut stream, 0x1, 0x4, 3, &block);
+    write_h2_frame(&mut stream, 0x0, 0x1, 3, b"spooled body");
+
+    let mut headers: HashMap<u32, Vec<u8>> = HashMap::new();
+    let mut bodies: HashMap<u32, Vec<u8>> = HashMap::new();
+    let mut ended = 0;
+    while ended < 2 {
+        let (kind, flags, stream_id, payload) = read_h2_frame(&mut stream);
+        match kind {
+            0x0 => bodies.entry(stream_id).or_default().extend(payload),
+            0x1 => {
+                headers.insert(stream_id, payload);
+            }
+            _ => continue,
+        }
+        if flags & 0x1 != 0 {
+            ended += 1;
+        }
+    }
+
+    // a literal `:status` of 431
+    assert!(headers[&1].starts_with(&[0x08, 3, b'4', b'3', b'1']));
+    assert_eq!(headers[&3][0], 0x88);
+    assert_eq!(bodies[&3], b"spooled body");
+}
+
+#[test]
+fn server_never_holds_up_http2_streams_for_a_concurrency_limit() {
+    let config = Config {
+        tls: Some(test_tls_config()),
+        concurrency_limits: vec![ConcurrencyLimit::new("/slow/", 1)],
+        concurrency_queue_timeout: Duration::from_secs(30),
+        ..test_config(files_root("http2-concurrency"))
+    };
+    let (entered, handling) = mpsc::channel();
+    let (release, released) = mpsc::channel::<()>();
+    let (entered, released) = (Mutex::new(entered), Mutex::new(released));
+    let server = Server::bind("127.0.0.1:0", config).unwrap().route(
+        Method::Get,
+        "/slow/{*rest}",
+        move |_| {
+            entered.lock().unwrap().send(()).unwrap();
+            released.lock().unwrap().recv().unwrap();
+            Response::text("done".to_owned())
+        },
+    );
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+
+    let first = thread::spawn(move || {
+        send_tls(
+            addr,
+            "GET /slow/first HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        )
+    });
+    handling.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    let mut stream = connect_tls(addr, &[b"h2"]);
+    stream
+        .sock
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream
+        .write_all(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n")
+        .unwrap();
+    write_h2_frame(&mut stream, 0x4, 0, 0, &[]);
+    let request = |path| {
+        h2_header_block(&[
+            (":method", "GET"),
+            (":scheme", "https"),
+            (":path", path),
+            (":authority", "localhost"),
+        ])
+    };
+    // waiting for the slot would keep stream 3 waiting as well
+    write_h2_frame(&mut stream, 0x1, 0x4 | 0x1, 1, &request("/slow/second"));
+    write_h2_frame(&mut stream, 0x1, 0x4 | 0x1, 3, &request("/echo/fast"));
+
+    let mut headers: HashMap<u32, Vec<u8>> = HashMap::new();
+    let mut ended = 0;
+    while ended < 2 {
+        let (kind, flags, stream_id, payload) = read_h2_frame(&mut stream);
+        match kind {
+            0x0 => {}
+            0x1 => {
+                headers.insert(stream_id, payload);
+            }
+            _ => continue,
+        }
+        if flags & 0x1 != 0 {
+            ended += 1;
+        }
+    }
+
+    // a literal `:status` of 503
+    assert!(headers[&1].starts_with(&[0x08, 3, b'5', b'0', b'3']));
+    assert_eq!(headers[&3][0], 0x88);
+
+    release.send(()).unwrap();
+    let response = first.join().unwrap();
+    assert!(response.ends_with("\r\n\r\ndone"), "{response}");
+}
+
+#[test]
+fn server_verifies_client_certificates_where_configured() {
+    let certs = test_certs();
+    let root = files_root("mtls");
+    fs::write(root.join("public.txt"), "public").unwrap();
+    let tls = TlsConfig::from_pem_files_with_client_auth(
+        &certs.join("cert.pem"),
+        &certs.join("key.pem"),
+        &certs.join("ca.pem"),
+        ClientAuth::Optional,
+    )
+    .unwrap();
+    let config = Config {
+        tls: Some(tls),
+        client_cert_paths: vec![
+            ClientCertRule::prefix("/files/").writes_only(),
+            "/admin/".parse().unwrap(),
+        ],
+        ..test_config(root.clone())
+    };
+    let server = Server::bind("127.0.0.1:0", config)
+        .unwrap()
+        .route(Method::Get, "/whoami", |request: &Request| {
+            Response::text(
+                request
+                    .client_cert_subject()
+                    .unwrap_or("anonymous")
+                    .to_owned(),
+            )
+        })
+        .route(Method::Get, "/admin/panel", |_| {
+            Response::text("panel".to_owned())
+        });
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+
+    let send = |request: &str, client_cert: bool| {
+        let request = format!("{request} HTTP/1.1\r\nHost: localhost\r\nContent-Length: 2\r\nConnection: close\r\n\r\nhi");
+        try_send_tls(addr, &request, client_cert).unwrap()
+    };
+
+    let response = send("GET /whoami", true);
+    assert!(
+        response.ends_with("\r\n\r\nCN=alice,O=Butler Tests,C=NL"),
+        "{response}"
+    );
+    let response = send("GET /whoami", false);
+    assert!(response.ends_with("\r\n\r\nanonymous"), "{response}");
+
+    // reads stay open, writes and the admin paths need a certificate
+    let response = send("GET /files/public.txt", false);
+    assert!(response.ends_with("\r\n\r\npublic"), "{response}");
+    for request in [
+        "PUT /files/upload.txt",
+        "DELETE /files/public.txt",
+        "GET /admin/panel",
+    ] {
+        let response = send(request, false);
+        assert!(
+            response.starts_with("HTTP/1.1 403 Forbidden\r\n"),
+            "{request}: {response}"
+        );
+    }
+    assert!(!root.join("upload.txt").exists());
+
+    let response = send("PUT /files/upload.txt", true);
+    assert!(
+        response.starts_with("HTTP/1.1 201 Created\r\n"),
+        "{response}"
+    );
+    assert_eq!(fs::read_to_string(root.join("upload.txt")).unwrap(), "hi");
+    let response = send("GET /admin/panel", true);
+    assert!(response.ends_with("\r\n\r\npanel"), "{response}");
+
+    // a required certificate is asked for during the handshake
+    let tls = TlsConfig::from_pem_files_with_client_auth(
+        &certs.join("cert.pem"),
+        &certs.join("key.pem"),
+        &certs.join("ca.pem"),
+        ClientAuth::Required,
+    )
+    .unwrap();
+    let addr = spawn_server_with(Config {
+        tls: Some(tls),
+        ..test_config(files_root("mtls-required"))
+    });
+    let request = "GET /echo/hi HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+    let response = try_send_tls(addr, request, true).unwrap();
+    assert!(response.ends_with("\r\n\r\nhi"), "{response}");
+    assert!(try_send_tls(addr, request, false).is_err());
+}
+
+#[test]
+fn server_serves_http_and_https_side_by_side() {
+    let server = Server::bind("127.0.0.1:0", test_config(files_root("tls-side-by-side")))
+        .unwrap()
+        .listen_tls("127.0.0.1:0", test_tls_config())
+        .unwrap();
+    let [http, https] = server.local_addrs().unwrap()[..] else {
+        panic!("expected two listeners");
+    };
+    thread::spawn(move || server.run());
+
+    let request = "GET /echo/both HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+    let response = send(http, request);
+    assert!(response.ends_with("\r\n\r\nboth"), "{response}");
+    let response = send_tls(https, request);
+    assert!(response.ends_with("\r\n\r\nboth"), "{response}");
+}
+
+#[test]
+fn server_acknowledges_expect_continue_before_reading_the_body() {
+    let root = files_root("expect");
+    let addr = spawn_server_with(Config {
+        max_body_size: 8,
+        ..test_config(root.clone())
+    });
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(b"POST /files/upload.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: 3\r\nExpect: 100-continue\r\nConnection: close\r\n\r\n")
+        .unwrap();
+
+    // the body is only sent once the interim response arrives
+    let mut interim = [0; 25];
+    stream.read_exact(&mut interim).unwrap();
+    assert_eq!(&interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+
+    stream.write_all(b"abc").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(
+        response.starts_with("HTTP/1.1 201 Created\r\n"),
+        "{response}"
+    );
+    assert_eq!(fs::read(root.join("upload.txt")).unwrap(), b"abc");
+
+    // a body over the limit is refused before the client sends it
+    let response = send(
+        addr,
+        "POST /files/big.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: 9\r\nExpect: 100-continue\r\n\r\n",
+    );
+    assert!(
+        response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"),
+        "{response}"
+    );
+    assert!(!root.join("big.txt").exists());
+
+    let response = send(
+        addr,
+        "POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 1\r\nExpect: something-else\r\n\r\n",
+    );
+    assert!(
+        response.starts_with("HTTP/1.1 417 Expectation Failed\r\n"),
+        "{response}"
+    );
+}
+
+#[test]
+fn server_answers_head_like_get_without_the_body() {
+    let server = Server::bind("127.0.0.1:0", test_config(files_root("head")))
+        .unwrap()
+        .route(Method::Get, "/greeting", |_| {
+            Response::text("hello".to_owned()).with_header("X-Greeting", "yes")
+        });
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+
+    // the GET behind it on the same connection is only read correctly if HEAD sent no body
+    let response = send(
+        addr,
+        "HEAD /greeting HTTP/1.1\r\nHost: localhost\r\n\r\n\
+         GET /echo/after HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    );
+    let (head, rest) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(head.contains("\r\nContent-Length: 5"), "{response}");
+    assert!(head.contains("\r\nContent-Type: text/plain"), "{response}");
+    assert!(head.contains("\r\nX-Greeting: yes"), "{response}");
+    assert!(rest.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(rest.ends_with("\r\n\r\nafter"), "{response}");
+}
+
+#[test]
+fn server_answers_options_and_rejects_unsupported_methods() {
+    let addr = spawn_server(files_root("options"));
+
+    let response = send(
+        addr,
+        "OPTIONS /files/nested/foo.txt HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    );
+    assert!(
+        response.starts_with("HTTP/1.1 204 No Content\r\n"),
+        "{response}"
+    );
+    assert!(
+        response.contains("Allow: GET, HEAD, POST, PUT, DELETE, OPTIONS\r\n"),
+        "{response}"
+    );
+
+    let response = send(
+        addr,
+        "OPTIONS * HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    );
+    assert!(
+        response.starts_with("HTTP/1.1 204 No Content\r\n"),
+        "{response}"
+    );
+    // the server as a whole supports every method some route does
+    assert!(
+        response.contains("Allow: GET, HEAD, POST, PUT, DELETE, OPTIONS\r\n"),
+        "{response}"
+    );
+
+    let response = send(
+        addr,
+        "OPTIONS /nowhere HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    );
+    assert!(
+        response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+        "{response}"
+    );
+
+    let response = send(
+        addr,
+        "PATCH /echo/hi HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+    );
+    assert!(
+        response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"),
+        "{response}"
+    );
+    assert!(
+        response.contains("Allow: GET, HEAD, OPTIONS\r\n"),
+        "{response}"
+    );
+
+    // every method HTTP defines is understood, and refused like PATCH where there's no route for it
+    let response = send(
+        addr,
+        "TRACE /echo/hi HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    );
+    assert!(
+        response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"),
+        "{response}"
+    );
+    assert!(
+        response.contains("Allow: GET, HEAD, OPTIONS\r\n"),
+        "{response}"
+    );
+    let response = send(
+        addr,
+        "CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\nConnection: close\r\n\r\n",
+    );
+    assert!(
+        response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"),
+        "{response}"
+    );
+    assert!(
+        response.contains("Allow: GET, HEAD, POST, PUT, DELETE, OPTIONS\r\n"),
+        "{response}"
+    );
+
+    // methods it doesn't know aren't implemented, while ones that aren't tokens are malformed
+    let response = send(
+        addr,
+        "PROPFIND /files/ HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    );
+    assert!(
+        response.starts_with("HTTP/1.1 501 Not Implemented\r\n"),
+        "{response}"
+    );
+    let response = send(
+        addr,
+        "GE(T /files/ HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    );
+    assert!(
+        response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+        "{response}"
+    );
+}
+
+#[test]
+fn server_routes_by_path_without_the_query_string() {
+    let server = Server::bind("127.0.0.1:0", test_config(files_root("query")))
+        .unwrap()
+        .route(Method::Get, "/search", |request| {
+            let tags: Vec<_> = request
+                .query_pairs()
+                .iter()
+                .filter(|(key, _)| key == "tag")
+                .map(|(_, value)| value.as_str())
+                .collect();
+            Response::text(format!(
+                "{} {}",
+                request.query("q").unwrap_or_default(),
+                tags.join(",")
+            ))
+        });
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+
+    let response = get(addr, "/echo/hello?x=1", "");
+    assert!(response.ends_with("\r\n\r\nhello"), "{response}");
+
+    let response = get(addr, "/search?q=a%20b+c&tag=x&tag=y", "");
+    assert!(response.ends_with("\r\n\r\na b c x,y"), "{response}");
+}
+
+#[test]
+fn server_decodes_chunked_request_bodies() {
+    let root = files_root("chunked");
+    let server = Server::bind(
+        "127.0.0.1:0",
+        Config {
+            max_body_size: 16,
+            ..test_config(root.clone())
+        },
+    )
+    .unwrap()
+    .route(Method::Post, "/trailer", |request| {
+        Response::text(request.header("x-checksum").unwrap_or_default().to_owned())
+    });
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+
+    let response = send(
+        addr,
+        "POST /files/chunked.txt HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n4\r\nWiki\r\n5;ext=1\r\npedia\r\n0\r\n\r\n",
+    );
+    assert!(
+        response.starts_with("HTTP/1.1 201 Created\r\n"),
+        "{response}"
+    );
+    assert_eq!(fs::read(root.join("chunked.txt")).unwrap(), b"Wikipedia");
+
+    // trailer fields are available like headers, and the connection stays usable afterwards
+    let response = send(
+        addr,
+        "POST /trailer HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\nX-Checksum: 900150983cd2\r\n\r\nGET /echo/next HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    );
+    assert!(
+        response.contains("\r\n\r\n900150983cd2HTTP/1.1 200 OK\r\n"),
+        "{response}"
+    );
+    assert!(response.ends_with("\r\n\r\nnext"), "{response}");
+
+    let response = send(
+        addr,
+        "POST /echo HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n11\r\n",
+    );
+    assert!(
+        response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"),
+        "{response}"
+    );
+
+    // a size that would overflow once added to the chunks before it
+    let response = send(
+        addr,
+        "POST /echo HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\nffffffffffffffff\r\n",
+    );
+    assert!(
+        response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"),
+        "{response}"
+    );
+
+    let response = send(
+        addr,
+        "POST /echo HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n",
+    );
+    assert!(
+        response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+        "{response}"
+    );
+
+    let response = send(
+        addr,
+        "POST /echo HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n",
+    );
+    assert!(
+        response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+        "{response}"
+    );
+
+    let response = send(
+        addr,
+        "POST /echo HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: gzip, chunked\r\n\r\n",
+    );
+    assert!(
+        response.starts_with("HTTP/1.1 501 Not Implemented\r\n"),
+        "{response}"
+    );
+
+    // every line of the header counts, not only the first one
+    for (codings, status) in [
+        (
+            "Transfer-Encoding: chunked\r\nTransfer-Encoding: gzip\r\n",
+            "501 Not Implemented",
+        ),
+        (
+            "Transfer-Encoding: chunked\r\nTransfer-Encoding: chunked, gzip\r\n",
+            "501 Not Implemented",
+        ),
+        (
+            "Transfer-Encoding: chunked\r\nTransfer-Encoding: chunked\r\n",
+            "400 Bad Request",
+        ),
+    ] {
+        let response = send(
+            addr,
+            &format!(
+                "POST /echo HTTP/1.1\r\nHost: localhost\r\n{codings}\r\n3\r\nabc\r\n0\r\n\r\n"
+            ),
+        );
+        assert!(
+            response.starts_with(&format!("HTTP/1.1 {status}\r\n")),
+            "{codings:?}: {response}"
+        );
+    }
+}
+
+#[test]
+fn server_streams_bodies_of_unknown_length() {
+    let server = Server::bind("127.0.0.1:0", test_config(files_root("stream")))
+        .unwrap()
+        .route(Method::Get, "/count", |_| {
+            Response::stream(io::Cursor::new(b"one two three".to_vec()), None)
+        });
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+
+    let response = get(addr, "/count", "");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(
+        response.contains("Transfer-Encoding: chunked\r\n"),
+        "{response}"
+    );
+    assert!(!response.contains("Content-Length"), "{response}");
+    assert!(
+        response.ends_with("\r\n\r\nD\r\none two three\r\n0\r\n\r\n"),
+        "{response}"
+    );
+
+    // without chunks the body ends where the connection does
+    let response = send(
+        addr,
+        "GET /count HTTP/1.0\r\nConnection: keep-alive\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.0 200 OK\r\n"), "{response}");
+    assert!(response.contains("Connection: close\r\n"), "{response}");
+    assert!(!response.contains("Transfer-Encoding"), "{response}");
+    assert!(response.ends_with("\r\n\r\none two three"), "{response}");
+}
+
+#[test]
+fn server_keeps_connections_alive_until_asked_to_close() {
+    let addr = spawn_server(files_root("keep-alive"));
+
+    // reads one response framed by its Content-Length, leaving the connection open
+    fn read_response(reader: &mut BufReader<&TcpStream>) -> String {
+        let mut response = String::new();
+        while !response.ends_with("\r\n\r\n") {
+            assert_ne!(reader.read_line(&mut response).unwrap(), 0, "{response}");
+        }
+        let length: usize = response
+            .lines()
+            .find_map(|line| line.strip_prefix("Content-Length: "))
+            .unwrap_or_else(|| panic!("no Content-Length in {response}"))
+            .parse()
+            .unwrap();
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        response + &String::from_utf8(body).unwrap()
+    }
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let mut reader = BufReader::new(&stream);
+
+    (&stream)
+        .write_all(b"GET /echo/one HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let response = read_response(&mut reader);
+    assert!(
+        response.contains("Connection: keep-alive\r\n"),
+        "{response}"
+    );
+    assert!(response.ends_with("\r\n\r\none"), "{response}");
+
+    // HTTP/1.0 clients have to ask for the connection to stay open
+    (&stream)
+        .write_all(b"GET /echo/two HTTP/1.0\r\nConnection: keep-alive\r\n\r\n")
+        .unwrap();
+    let response = read_response(&mut reader);
+    assert!(response.starts_with("HTTP/1.0 200 OK\r\n"), "{response}");
+    assert!(
+        response.contains("Connection: keep-alive\r\n"),
+        "{response}"
+    );
+
+    (&stream)
+        .write_all(b"GET /echo/three HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let response = read_response(&mut reader);
+    assert!(response.contains("Connection: close\r\n"), "{response}");
+
+    // the server closes its end after a `Connection: close` response
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+}
+
+#[test]
+fn server_closes_connections_after_the_last_allowed_request() {
+    let addr = spawn_server_with(Config {
+        max_requests_per_connection: Some(2),
+        ..test_config(files_root("max-requests"))
+    });
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(
+            b"GET /echo/one HTTP/1.1\r\nHost: localhost\r\n\r\n\
+              GET /echo/two HTTP/1.1\r\nHost: localhost\r\n\r\n\
+              GET /echo/three HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        )
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    assert!(
+        response.contains("Connection: keep-alive\r\n\r\none"),
+        "{response}"
+    );
+    assert!(
+        response.ends_with("Connection: close\r\n\r\ntwo"),
+        "{response}"
+    );
+}
+
+#[test]
+fn server_closes_connections_idle_between_requests() {
+    let addr = spawn_server_with(Config {
+        idle_timeout: Duration::from_millis(200),
+        ..test_config(files_root("idle-timeout"))
+    });
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(b"GET /echo/one HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+
+    // the connection is closed without a 408, since no request was started
+    let started = Instant::now();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(
+        response.ends_with("Connection: keep-alive\r\n\r\none"),
+        "{response}"
+    );
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn server_sheds_connections_once_the_queue_is_full() {
+    let addr = spawn_server_with(Config {
+        workers: 1,
+        max_queued: Some(0),
+        ..test_config(files_root("max-queued"))
+    });
+
+    // the kept-alive connection holds on to the only worker
+    let mut first = TcpStream::connect(addr).unwrap();
+    first
+        .write_all(b"GET /echo/one HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut response = Vec::new();
+    let mut buf = [0; 1024];
+    while !response.ends_with(b"\r\n\r\none") {
+        let len = first.read(&mut buf).unwrap();
+        assert_ne!(len, 0, "{}", String::from_utf8_lossy(&response));
+        response.extend_from_slice(&buf[..len]);
+    }
+
+    // turned away as soon as it connects, writing a request would only get it reset
+    let mut response = String::new();
+    TcpStream::connect(addr)
+        .unwrap()
+        .read_to_string(&mut response)
+        .unwrap();
+    assert!(
+        response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+        "{response}"
+    );
+    assert!(response.contains("Retry-After: 1\r\n"), "{response}");
+
+    first
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    first.read_to_string(&mut response).unwrap();
+    assert!(
+        response.contains("\nhttp_connections_shed_total 1\n"),
+        "{response}"
+    );
+    assert!(
+        response.contains("\nhttp_queued_connections 0\n"),
+        "{response}"
+    );
+}
+
+#[cfg(feature = "event-loop")]
+#[test]
+fn server_keeps_idle_connections_open_without_holding_a_worker() {
+    let addr = spawn_server_with(Config {
+        workers: 1,
+        event_loop: true,
+        ..test_config(files_root("event-loop"))
+    });
+
+    // with a single worker, the second client would wait for the first to go away if its
+    // kept-alive connection held on to the worker
+    let first = TcpStream::connect(addr).unwrap();
+    let second = TcpStream::connect(addr).unwrap();
+    for (mut stream, path) in [(&first, "one"), (&second, "two"), (&first, "three")] {
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+            .write_all(format!("GET /echo/{path} HTTP/1.1\r\nHost: localhost\r\n\r\n").as_bytes())
+            .unwrap();
+
+        let mut response = Vec::new();
+        let mut buf = [0; 1024];
+        while !response.ends_with(format!("\r\n\r\n{path}").as_bytes()) {
+            let len = stream.read(&mut buf).unwrap();
+            assert_ne!(len, 0, "{}", String::from_utf8_lossy(&response));
+            response.extend_from_slice(&buf[..len]);
+        }
+    }
+}
+
+#[test]
+fn server_closes_kept_alive_connections_when_shutting_down() {
+    let server = Server::bind("127.0.0.1:0", test_config(files_root("shutdown"))).unwrap();
+    let addr = server.local_addr().unwrap();
+    let shutting_down = server.shutdown_flag();
+    let running = thread::spawn(move || server.run());
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(b"GET /echo/one HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut response = [0; 16];
+    stream.read_exact(&mut response).unwrap();
+    assert_eq!(&response, b"HTTP/1.1 200 OK\r");
+
+    shutting_down.store(true, Ordering::SeqCst);
+
+    // the response already on its way is finished, and the next one is the last
+    stream
+        .write_all(b"GET /echo/two HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(
+        response.contains("Connection: close\r\n\r\ntwo"),
+        "{response}"
+    );
+
+    running.join().unwrap().unwrap();
+}
+
+#[test]
+fn server_switches_to_a_reloaded_configuration() {
+    let old_root = files_root("reload-old");
+    fs::write(old_root.join("old.txt"), "old").unwrap();
+    let new_root = files_root("reload-new");
+    fs::write(new_root.join("new.txt"), "new").unwrap();
+
+    let server = Server::bind("127.0.0.1:0", test_config(old_root))
+        .unwrap()
+        .route(Method::Get, "/custom", |_: &Request| {
+            Response::text("custom".to_owned())
+        });
+    let addr = server.local_addr().unwrap();
+    let reloader = server.reloader();
+    thread::spawn(move || server.run());
+    assert!(get(addr, "/files/old.txt", "").starts_with("HTTP/1.1 200 OK\r\n"));
+
+    reloader.reload(Config {
+        cache_control: vec![CacheRule::for_path("/files/*", "no-cache")],
+        ..test_config(new_root)
+    });
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let response = loop {
+        let response = get(addr, "/files/new.txt", "");
+        if response.starts_with("HTTP/1.1 200 OK\r\n") || Instant::now() > deadline {
+            break response;
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    assert!(
+        response.contains("Cache-Control: no-cache\r\n"),
+        "{response}"
+    );
+    assert!(response.ends_with("\r\n\r\nnew"), "{response}");
+    assert!(get(addr, "/files/old.txt", "").starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+    // routes added to the server are kept
+    assert!(get(addr, "/custom", "").ends_with("\r\n\r\ncustom"));
+}
+
+#[test]
+fn server_answers_malformed_requests_with_bad_request() {
+    let addr = spawn_server(files_root("malformed"));
+
+    for request in [
+        "NONSENSE\r\n\r\n",
+        "GET / HTTP/1.1\r\nHost: localhost\r\nno colon here\r\n\r\n",
+        "GET / HTTP/1.1\r\nHost : localhost\r\n\r\n",
+        "POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: lots\r\n\r\n",
+        "POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 2\r\nContent-Length: 3\r\n\r\nabc",
+        "GET / HTTP/1.1\r\nHost: localhost\r\nHost: example.com\r\n\r\n",
+        "GET / HTTP/1.1\r\nHost: user@localhost\r\n\r\n",
+        "GET / HTTP/1.1\r\nHost: localhost:http\r\n\r\n",
+        "GET / HTTP/1.1\r\n\r\n",
+        "GET /files/%zz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    ] {
+        // the connection is closed after the response, rather than reset without one
+        let response = send(addr, request);
+        assert!(
+            response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+            "{request:?}: {response}"
+        );
+        let (_, reason) = response.split_once("\r\n\r\n").unwrap();
+        assert!(!reason.is_empty(), "{request:?}: {response}");
+    }
+}
+
+#[test]
+fn server_rejects_unsupported_http_versions() {
+    let addr = spawn_server(files_root("versions"));
+
+    let response = send(addr, "GET / HTTP/2.0\r\nHost: localhost\r\n\r\n");
+    assert!(
+        response.starts_with("HTTP/1.1 505 HTTP Version Not Supported\r\n"),
+        "{response}"
+    );
+
+    let response = send(addr, "GET / HTTQ/1.1\r\nHost: localhost\r\n\r\n");
+    assert!(
+        response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+        "{response}"
+    );
+
+    // HTTP/1.0 needs no Host and gets a response in kind that closes the connection
+    let response = send(addr, "GET /echo/old HTTP/1.0\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.0 200 OK\r\n"), "{response}");
+    assert!(response.contains("Connection: close\r\n"), "{response}");
+}
+
+#[test]
+fn test_server_sends_requests_on_kept_alive_connections() {
+    let root = files_root("test-server");
+    let text = "the quick brown fox jumps over the lazy dog\n".repeat(100);
+    fs::write(root.join("fox.txt"), &text).unwrap();
+    let server = TestServer::start(
+        Server::bind("127.0.0.1:0", test_config(root))
+            .unwrap()
+            .route(Method::Get, "/stream", |_: &Request| {
+                Response::stream(io::Cursor::new(b"streamed".to_vec()), None)
+            }),
+    )
+    .unwrap();
+    let mut conn = server.connect().unwrap();
+
+    let response = conn
+        .request(
+            Method::Get,
+            "/files/fox.txt",
+            &[("Accept-Encoding", "gzip")],
+            b"",
+        )
+        .unwrap();
+    assert_eq!(response.status, StatusCode::Ok);
+    assert_eq!(response.header("content-encoding"), Some("gzip"));
+    let mut decoded = String::new();
+    flate2::read::GzDecoder::new(&response.body[..])
+        .read_to_string(&mut decoded)
+        .unwrap();
+    assert_eq!(decoded, text);
+
+    let response = conn
+        .request(
+            Method::Get,
+            "/files/fox.txt",
+            &[("Range", "bytes=4-8")],
+            b"",
+        )
+        .unwrap();
+    assert_eq!(response.status, StatusCode::PartialContent);
+    assert_eq!(response.text(), "quick");
+
+    let response = conn
+        .request(Method::Head, "/files/fox.txt", &[], b"")
+        .unwrap();
+    assert_eq!(response.header("Content-Length"), Some("4400"));
+    assert!(response.body.is_empty());
+
+    let response = conn.request(Method::Get, "/stream", &[], b"").unwrap();
+    assert_eq!(response.header("Transfer-Encoding"), Some("chunked"));
+    assert_eq!(response.text(), "streamed");
+
+    let response = server.get("/echo/hi").unwrap();
+    assert_eq!(response.header("Connection"), Some("close"));
+    assert_eq!(response.text(), "hi");
+}
+
+#[test]
+fn client_decodes_chunked_and_compressed_responses() {
+    let root = files_root("client");
+    let text = "the quick brown fox jumps over the lazy dog\n".repeat(100);
+    fs::write(root.join("fox.txt"), &text).unwrap();
+    let server = TestServer::start(
+        Server::bind("127.0.0.1:0", test_config(root))
+            .unwrap()
+            .route(Method::Get, "/stream", |_: &Request| {
+                Response::stream(io::Cursor::new(b"streamed".to_vec()), None)
+            })
+            .route(Method::Post, "/length", |request: &Request| {
+                Response::text(format!("{:?}", request.body_len()))
+            }),
+    )
+    .unwrap();
+    let addr = server.addr();
+
+    let response = client::get(&format!("http://{addr}/files/fox.txt")).unwrap();
+    assert_eq!(response.status(), StatusCode::Ok);
+    assert_eq!(response.header("content-encoding"), None);
+    assert_eq!(response.header("Content-Length"), Some("4400".to_owned()));
+    assert_eq!(response.body(), Some(text.as_bytes()));
+
+    let response = client::get(&format!("http://{addr}/stream")).unwrap();
+    assert_eq!(response.header("Transfer-Encoding"), None);
+    assert_eq!(response.body(), Some(&b"streamed"[..]));
+
+    let request: Request = format!("POST /length HTTP/1.1\r\nHost: {addr}\r\n\r\n")
+        .parse()
+        .unwrap();
+    let response = client::request(&request.with_body("hello")).unwrap();
+    assert_eq!(response.body(), Some(&b"Some(5)"[..]));
+
+    let response = client::get(&format!("http://{addr}/missing")).unwrap();
+    assert_eq!(response.status(), StatusCode::NotFound);
+    assert!(client::get("https://example.com").is_err());
+}
+
+#[test]
+fn server_serves_byte_ranges_of_files() {
+    let root = files_root("ranges");
+    fs::write(root.join("digits.txt"), "0123456789").unwrap();
+    let addr = spawn_server(root);
+
+    let response = get(addr, "/files/digits.txt", "");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(response.contains("Accept-Ranges: bytes\r\n"), "{response}");
+
+    let response = get(addr, "/files/digits.txt", "Range: bytes=2-4\r\n");
+    assert!(
+        response.starts_with("HTTP/1.1 206 Partial Content\r\n"),
+        "{response}"
+    );
+    assert!(
+        response.contains("Content-Range: bytes 2-4/10\r\n"),
+        "{response}"
+    );
+    assert!(response.contains("Accept-Ranges: bytes\r\n"), "{response}");
+    assert!(response.ends_with("\r\n\r\n234"), "{response}");
+
+    let response = get(addr, "/files/digits.txt", "Range: bytes=-3\r\n");
+    assert!(response.ends_with("\r\n\r\n789"), "{response}");
+
+    let response = get(addr, "/files/digits.txt", "Range: bytes=7-\r\n");
+    assert!(response.ends_with("\r\n\r\n789"), "{response}");
+
+    let response = get(addr, "/files/digits.txt", "Range: bytes=10-20\r\n");
+    assert!(
+        response.starts_with("HTTP/1.1 416 Range Not Satisfiable\r\n"),
+        "{response}"
+    );
+    assert!(
+        response.contains("Content-Range: bytes */10\r\n"),
+        "{response}"
+    );
+}